@@ -0,0 +1,149 @@
+// Dumping parsed configurations as JSON.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cfgfile::{Action, Config, ConfigLine, Span};
+use std::io::{self, Write};
+
+/// Writes every binding in a configuration as a JSON array, with
+/// where each part of it is in the source, for editors and other
+/// tools that work on configuration files. Each binding looks like:
+///
+/// ```text
+/// {"file": "foo", "keys": {"line": 1, "start": 5, "end": 12,
+///  "sequence": ["C-x", "C-f"]}, "action": {"line": 1, "start": 15,
+///  "end": 20, "kind": "bind", "text": "bind emacs"}, "sync": false,
+///  "double_tap": false, "release": false, "repeat_while_held": false,
+///  "device": null, "mode": null}
+/// ```
+///
+/// Columns are byte offsets, counting from 0, and `end` is
+/// exclusive.
+pub fn write_ast(config: &Config, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, line) in config.commands.iter().enumerate() {
+        let comma = if i + 1 < config.commands.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(out, "  {}{}", binding(line), comma)?;
+    }
+    writeln!(out, "]")
+}
+
+/// Converts one binding to a JSON object.
+fn binding(line: &ConfigLine) -> String {
+    let sequence: Vec<_> = line
+        .keyseq
+        .keys
+        .iter()
+        .map(|key| string(&key.to_string()))
+        .collect();
+
+    format!(
+        "{{\"file\": {}, \"keys\": {{{}, \"sequence\": [{}]}}, \
+         \"action\": {{{}, \"kind\": {}, \"text\": {}}}, \
+         \"sync\": {}, \"double_tap\": {}, \"release\": {}, \
+         \"repeat_while_held\": {}, \"device\": {}, \"mode\": {}}}",
+        string(&line.location.file_name),
+        span(&line.spans.keys),
+        sequence.join(", "),
+        span(&line.spans.action),
+        string(kind(&line.action)),
+        string(&line.action.to_string()),
+        line.sync,
+        line.double_tap,
+        line.release,
+        line.repeat_while_held,
+        line.device.map_or("null".to_string(), |id| id.to_string()),
+        line.mode.as_deref().map_or("null".to_string(), string)
+    )
+}
+
+/// Converts a span to the fields of a JSON object.
+fn span(span: &Span) -> String {
+    format!(
+        "\"line\": {}, \"start\": {}, \"end\": {}",
+        span.line_num, span.columns.start, span.columns.end
+    )
+}
+
+/// Gets the name of the command that makes a kind of action.
+fn kind(action: &Action) -> &'static str {
+    match action {
+        Action::Bind { .. } => "bind",
+        Action::Map { .. } => "map",
+        Action::Passthrough => "passthrough",
+        Action::Clipboard { .. } => "clipboard",
+        Action::Notify { .. } => "notify",
+        Action::EnterMode { .. } => "enter-mode",
+        Action::ExitMode => "exit-mode",
+    }
+}
+
+/// Quotes and escapes a string for JSON.
+fn string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::parse_config;
+    use std::io::BufReader;
+
+    #[test]
+    fn ast_test() {
+        let text = "bind sync C-x C-f : emacs\n\npassthrough C-a\n";
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+
+        let mut out = Vec::new();
+        write_ast(&config, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[\n  \
+             {\"file\": \"foo\", \"keys\": {\"line\": 1, \"start\": 5, \"end\": 17, \
+             \"sequence\": [\"C-x\", \"C-f\"]}, \"action\": {\"line\": 1, \"start\": 20, \
+             \"end\": 25, \"kind\": \"bind\", \"text\": \"bind emacs\"}, \"sync\": true, \
+             \"double_tap\": false, \"release\": false, \"repeat_while_held\": false, \"device\": null, \"mode\": null},\n  \
+             {\"file\": \"foo\", \"keys\": {\"line\": 3, \"start\": 12, \"end\": 15, \
+             \"sequence\": [\"C-a\"]}, \"action\": {\"line\": 3, \"start\": 15, \"end\": 15, \
+             \"kind\": \"passthrough\", \"text\": \"passthrough\"}, \"sync\": false, \
+             \"double_tap\": false, \"release\": false, \"repeat_while_held\": false, \"device\": null, \"mode\": null}\n\
+             ]\n"
+        );
+    }
+
+    #[test]
+    fn string_test() {
+        assert_eq!(string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+}
@@ -16,18 +16,248 @@
 // You should have received a copy of the GNU General Public License
 // along with ahkd. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::keyseq::KeySequence;
-use std::convert::TryInto;
+use crate::keyseq::{Key, KeySequence, Keysym, Modifier};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Read};
 use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// The largest count allowed in a `repeat` prefix, so that a typo
+/// can't have us spawn thousands of processes.
+pub const MAX_REPEAT: u32 = 100;
+
+/// The most lines a `{FROM..TO}` range can expand into, so that a
+/// typo can't have us parse billions of them.
+pub const MAX_RANGE: u32 = 100;
+
+/// How long the user has to tap a key a second time to trigger a
+/// `2x` binding, unless `set double-tap-timeout` says otherwise.
+pub const DEFAULT_DOUBLE_TAP_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long the key of a `bind-repeat` binding has to be held before
+/// its commands run again, unless `set bind-repeat-delay` says
+/// otherwise.
+pub const DEFAULT_BIND_REPEAT_DELAY: Duration = Duration::from_millis(400);
+
+/// How often the commands of a `bind-repeat` binding run again once
+/// they've started repeating, unless `set bind-repeat-interval` says
+/// otherwise.
+pub const DEFAULT_BIND_REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The key that abandons a key sequence partway through, unless `set
+/// cancel-key` says otherwise.
+pub const DEFAULT_CANCEL_KEY: &str = "Escape";
 
 /// The information from the configuration file.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Config {
     /// The set of commands specified in the file.
     pub commands: Vec<ConfigLine>,
+
+    /// The global options set in the file.
+    pub settings: Settings,
+
+    /// The commands to run when the daemon starts, from `on-startup`
+    /// lines, in the order they were given. Each is a `bind` action.
+    pub startup: Vec<Action>,
+
+    /// Problems found while reading the file that don't stop it from
+    /// being used, each saying where it was found.
+    pub warnings: Vec<String>,
+}
+
+/// Global options, changed using the `set` command.
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
+    /// How long to wait between consecutive keys of a key sequence
+    /// before giving up on the sequence, if at all. The wait starts
+    /// over after every key.
+    pub key_timeout: Option<Duration>,
+
+    /// How long to wait for an entire key sequence to be typed,
+    /// counting from its first key, before giving up on it, if at
+    /// all.
+    pub sequence_timeout: Option<Duration>,
+
+    /// Whether to run commands in a session of their own, so that
+    /// they're unaffected by anything that happens to our session.
+    pub detach: bool,
+
+    /// How long we may hold a grab on the whole keyboard without
+    /// getting a key before a watchdog forcibly releases it, if at
+    /// all.
+    pub watchdog: Option<Duration>,
+
+    /// The character that separates the keys of a key sequence, if
+    /// it's not whitespace. This only affects lines after the one
+    /// that sets it.
+    pub seq_separator: Option<char>,
+
+    /// How long the user has to tap a key a second time to trigger a
+    /// `2x` binding, or None for `DEFAULT_DOUBLE_TAP_TIMEOUT`.
+    pub double_tap_timeout: Option<Duration>,
+
+    /// How long the key of a `bind-repeat` binding has to be held
+    /// before its commands run again, or None for
+    /// `DEFAULT_BIND_REPEAT_DELAY`.
+    pub bind_repeat_delay: Option<Duration>,
+
+    /// How often the commands of a `bind-repeat` binding run again
+    /// after that, or None for `DEFAULT_BIND_REPEAT_INTERVAL`.
+    pub bind_repeat_interval: Option<Duration>,
+
+    /// Names for keysyms given with `set keysym-alias`, which take
+    /// precedence over the standard names. These only affect lines
+    /// after the one that sets them.
+    pub keysym_aliases: HashMap<String, Keysym>,
+
+    /// Whether to reject the single-letter modifier names other than
+    /// `C`, `M` and `S`, which are easily mistaken for others. This
+    /// only affects lines after the one that sets it.
+    pub strict_modifiers: bool,
+
+    /// The modifier that Num Lock sets, or None for Mod2, which it
+    /// almost always is.
+    pub num_lock: Option<Modifier>,
+
+    /// The key that abandons a key sequence partway through, or None
+    /// for `DEFAULT_CANCEL_KEY`.
+    pub cancel_key: Option<Key>,
+
+    /// Whether to ring the bell whenever a key leaves a key sequence
+    /// unfinished, so the user knows we're waiting for more.
+    pub prefix_bell: bool,
+
+    /// Whether to show a popup listing the keys that can come next
+    /// whenever a key leaves a key sequence unfinished.
+    pub which_key: bool,
+
+    /// Whether the presses a held key auto-repeats count as keys of
+    /// a key sequence partway through, rather than being ignored.
+    pub key_repeat: bool,
+
+    /// Whether to grab the first key of every binding synchronously,
+    /// as though they were all written with `sync`.
+    pub sync_grabs: bool,
+
+    /// Whether key presses that some program sent with SendEvent,
+    /// rather than typed on a keyboard, can trigger bindings.
+    pub synthetic_events: bool,
+
+    /// The directory to run commands in, as written in a `cwd` line,
+    /// or None for our own. This only affects lines after the one
+    /// that sets it.
+    pub cwd: Option<String>,
+
+    /// The shell to run commands with, as set with a `shell` line, or
+    /// None to run them directly. This only affects lines after the
+    /// one that sets it.
+    pub shell: Option<String>,
+}
+
+/// A change to one of the global options.
+#[derive(Debug)]
+enum Setting {
+    /// `set key-timeout <milliseconds>`.
+    KeyTimeout(Option<Duration>),
+
+    /// `set sequence-timeout <milliseconds>`.
+    SequenceTimeout(Option<Duration>),
+
+    /// `set detach <boolean>`.
+    Detach(bool),
+
+    /// `set watchdog <milliseconds>`.
+    Watchdog(Option<Duration>),
+
+    /// `set seq-separator <character>`.
+    SeqSeparator(Option<char>),
+
+    /// `set double-tap-timeout <milliseconds>`.
+    DoubleTapTimeout(Duration),
+
+    /// `set bind-repeat-delay <milliseconds>`.
+    BindRepeatDelay(Duration),
+
+    /// `set bind-repeat-interval <milliseconds>`.
+    BindRepeatInterval(Duration),
+
+    /// `set keysym-alias <name> = <keysym>`.
+    KeysymAlias(String, Keysym),
+
+    /// `set strict-modifiers <boolean>`.
+    StrictModifiers(bool),
+
+    /// `set num-lock <modifier>`.
+    NumLock(Modifier),
+
+    /// `set cancel-key <key>`.
+    CancelKey(Key),
+
+    /// `set prefix-bell <boolean>`.
+    PrefixBell(bool),
+
+    /// `set which-key <boolean>`.
+    WhichKey(bool),
+
+    /// `set key-repeat <boolean>`.
+    KeyRepeat(bool),
+
+    /// `set sync-grabs <boolean>`.
+    SyncGrabs(bool),
+
+    /// `set synthetic-events <boolean>`.
+    SyntheticEvents(bool),
+
+    /// `cwd <directory>`.
+    Cwd(String),
+
+    /// `shell <program>`, or `shell none` for None.
+    Shell(Option<String>),
+}
+
+/// A functional line in the configuration file, of any kind.
+#[derive(Debug)]
+enum Directive {
+    /// A key binding. It's boxed, being so much bigger than the rest.
+    Command(Box<ConfigLine>),
+
+    /// A `set` command.
+    Set(Setting),
+
+    /// An `unbind` command, which removes the earlier bindings for a
+    /// key sequence.
+    Unbind(KeySequence, Location),
+
+    /// An `include` command, which reads the bindings from another
+    /// file, named as written.
+    Include(String),
+
+    /// A `mode NAME {` line, which puts the bindings up to the
+    /// matching `}` in the named mode.
+    BeginMode(String),
+
+    /// The `}` that closes a `mode` block.
+    EndMode,
+
+    /// An `on-startup` command, with the commands to run when the
+    /// daemon starts.
+    Startup(Action),
+}
+
+impl Directive {
+    /// Makes a directive for a key binding.
+    fn command(line: ConfigLine) -> Self {
+        Directive::Command(Box::new(line))
+    }
 }
 
 /// A functional line in the configuration file.
@@ -38,16 +268,119 @@ pub struct ConfigLine {
 
     /// The action that will occur when that key sequence is pressed.
     pub action: Action,
+
+    /// Where the line came from.
+    pub location: Location,
+
+    /// Whether the first key of the key sequence should be grabbed
+    /// synchronously, freezing the keyboard until we're ready for
+    /// the next key.
+    pub sync: bool,
+
+    /// Whether the binding is for tapping its key twice in quick
+    /// succession, rather than once. Its key sequence is always a
+    /// single key.
+    pub double_tap: bool,
+
+    /// Whether the binding fires when its key is released, rather
+    /// than when it's pressed. Its key sequence is always a single
+    /// key.
+    pub release: bool,
+
+    /// Whether the binding was written with `bind-repeat`, and so
+    /// runs its commands again and again for as long as its last key
+    /// is held down.
+    pub repeat_while_held: bool,
+
+    /// The XInput2 ID of the keyboard the keys must be typed on, or
+    /// None to accept them from any keyboard.
+    pub device: Option<u16>,
+
+    /// The comment written directly above the line, if any, which
+    /// describes what the binding is for.
+    pub description: Option<String>,
+
+    /// A short name for the binding, given with `label "..."`, to show
+    /// in the popup of keys that can follow a prefix.
+    pub label: Option<String>,
+
+    /// The mode the binding belongs to, if it was written inside a
+    /// `mode` block, or None for an ordinary binding. A mode's
+    /// bindings only work while that mode is active, and ordinary
+    /// bindings only work while no mode is.
+    pub mode: Option<String>,
+
+    /// Where the parts of the line are, for tools that work on the
+    /// source of a configuration.
+    pub spans: Spans,
+}
+
+/// Where the parts of a configuration line are in its file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Spans {
+    /// The key sequence, including any prefixes such as `sync`.
+    pub keys: Span,
+
+    /// The action: everything after the `:`, or an empty span at the
+    /// end of the line for actions such as `passthrough` that have
+    /// nothing there.
+    pub action: Span,
+}
+
+/// A range of columns on one line of a configuration file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Span {
+    /// The line number (starting from 1).
+    pub line_num: usize,
+
+    /// The byte columns (starting from 0) the span covers.
+    pub columns: Range<usize>,
+}
+
+/// A place in a configuration file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Location {
+    /// The name of the file.
+    pub file_name: String,
+
+    /// The line number (starting from 1).
+    pub line_num: usize,
 }
 
 /// An action implied by a configuration line.
 #[derive(Debug)]
 pub enum Action {
     /// A `bind` command, indicating that a particular key sequence
-    /// should run a shell command.
+    /// should run one or more shell commands.
     Bind {
-        /// The shell command to execute.
-        command: Vec<String>,
+        /// The commands to execute, one after the other, each split
+        /// into words. There's always at least one.
+        commands: Vec<Vec<String>>,
+
+        /// Text to feed to the last command's standard input, if any.
+        stdin: Option<String>,
+
+        /// How many times to run the commands each time the key
+        /// sequence is typed.
+        repeat: u32,
+
+        /// Whether to let go of the keyboard while the command runs,
+        /// for commands like screen lockers that need to grab it
+        /// themselves.
+        ungrab: bool,
+
+        /// Environment variables to set for the commands, as names
+        /// and values, in the order they were written.
+        env: Vec<(String, String)>,
+
+        /// The directory to run the commands in, as written, or None
+        /// for our own.
+        cwd: Option<String>,
+
+        /// Whether the command is run through a shell, which expands
+        /// environment variables in it itself. `commands` is then just
+        /// the shell, `-c` and the command as written.
+        shell: bool,
     },
 
     /// A `map` command, indicating that a key sequence should trigger
@@ -55,7 +388,50 @@ pub enum Action {
     Map {
         /// The KeySequence to trigger.
         to: KeySequence,
+
+        /// How many times to trigger it each time the key sequence
+        /// is typed.
+        repeat: u32,
+
+        /// Whether to look the KeySequence up in our own bindings
+        /// first, and do what its binding does rather than sending it
+        /// to the focused window, if it's bound.
+        chain: bool,
+    },
+
+    /// A `passthrough` command, indicating that a key sequence should
+    /// be sent on to the focused window, as though we hadn't grabbed
+    /// it.
+    Passthrough,
+
+    /// A `clipboard` command, indicating that a key sequence should
+    /// put some fixed text on the clipboard.
+    Clipboard {
+        /// The text to put on the clipboard.
+        text: String,
     },
+
+    /// A `notify` command, indicating that a key sequence should show
+    /// a desktop notification.
+    Notify {
+        /// The notification's title.
+        summary: String,
+
+        /// The notification's text, which may be empty.
+        body: String,
+    },
+
+    /// An `enter-mode` command, indicating that a key sequence should
+    /// switch to the bindings of a mode.
+    EnterMode {
+        /// The name of the mode.
+        mode: String,
+    },
+
+    /// An `exit-mode` command, indicating that a key sequence should
+    /// leave the current mode, going back to the one it was entered
+    /// from.
+    ExitMode,
 }
 
 /// A substring of a line of text obtained from an input file.
@@ -70,6 +446,10 @@ pub struct LineText<'a> {
     /// The text of the complete line.
     text: &'a str,
 
+    /// A shared copy of `text`, if one exists, which errors can hold
+    /// on to instead of copying the whole line.
+    shared: Option<&'a Rc<str>>,
+
     /// The range of characters delimiting the substring.
     range: Range<usize>,
 }
@@ -102,8 +482,10 @@ pub struct SyntaxError {
     /// The file from which the error originated.
     file_name: String,
 
-    /// The text of the line on which the error occurred.
-    line: String,
+    /// The text of the line on which the error occurred. This is
+    /// shared with the parser where possible, since machine-generated
+    /// configs can have very long lines.
+    line: Rc<str>,
 
     /// The line number (starting from 1) on which the error occurred.
     line_num: usize,
@@ -118,6 +500,14 @@ pub struct SyntaxError {
     len: usize,
 }
 
+impl SyntaxError {
+    /// Gets just the error message, without the location or the
+    /// offending line, for when there's only room for one line.
+    pub fn message(&self) -> &str {
+        &self.err_msg
+    }
+}
+
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let margin = 4;
@@ -156,6 +546,161 @@ impl fmt::Display for SyntaxError {
 
 impl Error for SyntaxError {}
 
+impl Config {
+    /// Gets every line that binds exactly the given key sequence in
+    /// `mode`, or outside of any mode for None, in the order they were
+    /// defined. The first of these is the one that takes effect.
+    pub fn bindings_for(&self, keyseq: &KeySequence, mode: Option<&str>) -> Vec<&ConfigLine> {
+        self.commands
+            .iter()
+            .filter(|line| line.keyseq == *keyseq && line.mode.as_deref() == mode)
+            .collect()
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Bind {
+                commands,
+                stdin,
+                repeat,
+                ungrab,
+                env,
+                ..
+            } => {
+                write!(f, "bind ")?;
+                if *ungrab {
+                    write!(f, "ungrab ")?;
+                }
+                // Quote the words that would otherwise be split up or
+                // lost.
+                let commands: Vec<_> = commands
+                    .iter()
+                    .map(|command| {
+                        let words: Vec<_> = command
+                            .iter()
+                            .map(|word| {
+                                if word.is_empty()
+                                    || word.contains(char::is_whitespace)
+                                    || word.contains(';')
+                                {
+                                    format!("{:?}", word)
+                                } else {
+                                    word.clone()
+                                }
+                            })
+                            .collect();
+                        words.join(" ")
+                    })
+                    .collect();
+                write!(f, "{}", RepeatPrefix(*repeat))?;
+                for (name, value) in env {
+                    write!(f, "{}={} ", name, value)?;
+                }
+                write!(f, "{}", commands.join(" ; "))?;
+                if let Some(stdin) = stdin {
+                    write!(f, " <<< {:?}", stdin)?;
+                }
+                Ok(())
+            }
+            Action::Map { to, repeat, chain } => write!(
+                f,
+                "map {}{}{}",
+                if *chain { "chain " } else { "" },
+                RepeatPrefix(*repeat),
+                to
+            ),
+            Action::Passthrough => write!(f, "passthrough"),
+            Action::Clipboard { text } => write!(f, "clipboard {}", text),
+            Action::Notify { summary, body } => write!(f, "notify {:?} {:?}", summary, body),
+            Action::EnterMode { mode } => write!(f, "enter-mode {}", mode),
+            Action::ExitMode => write!(f, "exit-mode"),
+        }
+    }
+}
+
+/// Displays a `repeat N` prefix, or nothing if an action only
+/// happens once.
+struct RepeatPrefix(u32);
+
+impl fmt::Display for RepeatPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            1 => Ok(()),
+            n => write!(f, "repeat {} ", n),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file_name, self.line_num)
+    }
+}
+
+impl Settings {
+    /// Applies a change from a `set` command.
+    fn apply(&mut self, setting: Setting) {
+        match setting {
+            Setting::KeyTimeout(t) => self.key_timeout = t,
+            Setting::SequenceTimeout(t) => self.sequence_timeout = t,
+            Setting::Detach(d) => self.detach = d,
+            Setting::Watchdog(t) => self.watchdog = t,
+            Setting::SeqSeparator(c) => self.seq_separator = c,
+            Setting::DoubleTapTimeout(t) => self.double_tap_timeout = Some(t),
+            Setting::BindRepeatDelay(t) => self.bind_repeat_delay = Some(t),
+            Setting::BindRepeatInterval(t) => self.bind_repeat_interval = Some(t),
+            Setting::KeysymAlias(name, keysym) => {
+                self.keysym_aliases.insert(name, keysym);
+            }
+            Setting::StrictModifiers(b) => self.strict_modifiers = b,
+            Setting::NumLock(modifier) => self.num_lock = Some(modifier),
+            Setting::CancelKey(key) => self.cancel_key = Some(key),
+            Setting::PrefixBell(b) => self.prefix_bell = b,
+            Setting::WhichKey(b) => self.which_key = b,
+            Setting::KeyRepeat(b) => self.key_repeat = b,
+            Setting::SyncGrabs(b) => self.sync_grabs = b,
+            Setting::SyntheticEvents(b) => self.synthetic_events = b,
+            Setting::Cwd(dir) => self.cwd = Some(dir),
+            Setting::Shell(shell) => self.shell = shell,
+        }
+    }
+
+    /// Gets the modifier that Num Lock sets.
+    pub fn num_lock(&self) -> Modifier {
+        self.num_lock.unwrap_or(Modifier::Mod2)
+    }
+
+    /// Gets the key that abandons a key sequence partway through.
+    pub fn cancel_key(&self) -> Key {
+        self.cancel_key.unwrap_or_else(|| {
+            // This is a fixed, valid key name, so we can `unwrap` here.
+            Key::try_from(LineText::new("<default>", 0, DEFAULT_CANCEL_KEY)).unwrap()
+        })
+    }
+
+    /// Gets how long the user has to tap a key a second time to
+    /// trigger a `2x` binding.
+    pub fn double_tap_timeout(&self) -> Duration {
+        self.double_tap_timeout
+            .unwrap_or(DEFAULT_DOUBLE_TAP_TIMEOUT)
+    }
+
+    /// Gets how long the key of a `bind-repeat` binding has to be held
+    /// before its commands run again.
+    pub fn bind_repeat_delay(&self) -> Duration {
+        self.bind_repeat_delay.unwrap_or(DEFAULT_BIND_REPEAT_DELAY)
+    }
+
+    /// Gets how often the commands of a `bind-repeat` binding run
+    /// again after that.
+    pub fn bind_repeat_interval(&self) -> Duration {
+        self.bind_repeat_interval
+            .unwrap_or(DEFAULT_BIND_REPEAT_INTERVAL)
+    }
+}
+
 impl<'a> LineText<'a> {
     /// Creates a new LineText given the name of the source file, the
     /// line number, and the text of that line.
@@ -164,10 +709,20 @@ impl<'a> LineText<'a> {
             file_name,
             line_num,
             text,
+            shared: None,
             range: 0..text.len(),
         }
     }
 
+    /// Creates a new LineText like `new`, but whose errors share
+    /// `text` rather than copying it.
+    pub fn shared(file_name: &'a str, line_num: usize, text: &'a Rc<str>) -> Self {
+        Self {
+            shared: Some(text),
+            ..Self::new(file_name, line_num, text)
+        }
+    }
+
     /// Splits the LineText at each occurrence of a character that
     /// satisfies `pattern`. If `merge` is true, merges multiple
     /// instances of the pattern into one, thereby never emitting a
@@ -197,12 +752,22 @@ impl<'a> LineText<'a> {
             )),
 
             // Highlight the last character if an error occurs.
-            None => Err(self
-                .substr(Some(self.as_str().len()), None)
-                .to_error(err_msg.to_string())),
+            None => Err(self.end().to_error(err_msg.to_string())),
         }
     }
 
+    /// Gets the empty LineText just past the end of this one, which
+    /// is useful for reporting missing text.
+    pub fn end(&self) -> Self {
+        self.substr(Some(self.as_str().len()), None)
+    }
+
+    /// Removes leading and trailing whitespace from the LineText.
+    pub fn trim(&self) -> Self {
+        let start = self.trim_start();
+        start.substr(None, Some(start.as_str().trim_end().len()))
+    }
+
     /// Removes leading whitespace from the LineText.
     pub fn trim_start(&self) -> Self {
         let idx = self
@@ -236,17 +801,46 @@ impl<'a> LineText<'a> {
 
     /// Converts the LineText into a SyntaxError that highlights this
     /// portion of the line, with the given error message.
-    pub fn to_error(self, msg: String) -> SyntaxError {
+    pub fn to_error(&self, msg: String) -> SyntaxError {
         SyntaxError {
             err_msg: msg,
             file_name: self.file_name.to_string(),
-            line: self.text.to_string(),
+            line: match self.shared {
+                Some(shared) => Rc::clone(shared),
+                None => self.text.into(),
+            },
             line_num: self.line_num,
             col_num: self.range.start,
             len: self.as_str().len(),
         }
     }
 
+    /// Gets the text from the start of this LineText to the end of
+    /// `other`, which must come later in the same line.
+    pub fn join(&self, other: &Self) -> Self {
+        Self {
+            range: self.range.start..other.range.end,
+            ..self.clone()
+        }
+    }
+
+    /// Gets the columns the LineText covers, leaving out any
+    /// whitespace around it.
+    pub fn span(&self) -> Span {
+        Span {
+            line_num: self.line_num,
+            columns: self.trim().range,
+        }
+    }
+
+    /// Gets the location of the line the text came from.
+    pub fn location(&self) -> Location {
+        Location {
+            file_name: self.file_name.to_string(),
+            line_num: self.line_num,
+        }
+    }
+
     /// Gets the contents of the referenced section of text.
     pub fn as_str(&self) -> &'a str {
         &self.text[self.range.clone()]
@@ -297,7 +891,7 @@ impl<'a, P: Fn(char) -> bool> Iterator for LineSplit<'a, P> {
             match item {
                 None => None,
                 Some(text) => {
-                    if text.as_str().len() == 0 {
+                    if text.as_str().is_empty() {
                         // Don't return a blank substring if `merge`
                         // is true.
                         self.next()
@@ -312,30 +906,379 @@ impl<'a, P: Fn(char) -> bool> Iterator for LineSplit<'a, P> {
     }
 }
 
-/// Parses a configuration file from an input source.
+/// Parses a configuration file like `parse_config_all`, but fails with
+/// the first syntax error found. Only the tests want this at the
+/// moment.
+#[cfg(test)]
 pub fn parse_config<T: Read>(
+    config: &mut Config,
     reader: BufReader<T>,
     file_name: &str,
-) -> Result<Config, Box<dyn Error>> {
-    let mut commands = Vec::new();
+) -> Result<(), Box<dyn Error>> {
+    match parse_config_all(config, reader, file_name)?
+        .into_iter()
+        .next()
+    {
+        Some(err) => Err(Box::new(err)),
+        None => Ok(()),
+    }
+}
+
+/// Parses a configuration file from an input source, adding its
+/// contents to `config`. Parsing several files into the same Config
+/// is equivalent to parsing the concatenation of those files. Lines
+/// with syntax errors are left out of `config`, and the parse carries
+/// on past them; every error found is returned. Errors reading the
+/// file still stop the parse.
+pub fn parse_config_all<T: Read>(
+    config: &mut Config,
+    reader: BufReader<T>,
+    file_name: &str,
+) -> Result<Vec<SyntaxError>, Box<dyn Error>> {
+    let mut including = Vec::new();
+    if let Ok(path) = fs::canonicalize(file_name) {
+        including.push(path);
+    }
+    parse_config_nested(config, reader, file_name, &mut including)
+}
+
+/// Parses a configuration file like `parse_config_all`, where
+/// `including` holds the files that are partway through being
+/// parsed, the outermost first, so that an `include` of any of them
+/// can be refused rather than going round forever.
+fn parse_config_nested<T: Read>(
+    config: &mut Config,
+    reader: BufReader<T>,
+    file_name: &str,
+    including: &mut Vec<PathBuf>,
+) -> Result<Vec<SyntaxError>, Box<dyn Error>> {
+    let mut errors = Vec::new();
+
+    // The comment lines seen since the last line that wasn't a
+    // comment, which describe the next binding.
+    let mut comments = Vec::new();
+
+    // The mode block we're inside, if any, with the error to report
+    // if it's never closed.
+    let mut mode: Option<(String, SyntaxError)> = None;
+
     for (idx, line) in reader.lines().enumerate() {
         // For some reason, line numbers have always started at 1, not
         // 0, so we get to add 1 here.
         let idx = idx + 1;
         let line = line?;
+        let line = strip_windows_line(&line, idx);
+
+        if let Some(comment) = line.trim_start().strip_prefix('#') {
+            comments.push(comment.trim().to_string());
+            continue;
+        }
+
+        let lines = match expand_range(LineText::new(file_name, idx, line)) {
+            Ok(lines) => lines,
+            Err(err) => {
+                errors.push(err);
+                comments.clear();
+                continue;
+            }
+        };
+        for expanded in lines {
+            let line: Rc<str> = expanded.text.as_str().into();
+            let text = LineText::shared(file_name, idx, &line).trim();
+            let current = mode.as_ref().map(|(name, _)| name.clone());
+            match parse_command(LineText::shared(file_name, idx, &line), &config.settings) {
+                Ok(Some(Directive::Command(mut command))) => {
+                    if !comments.is_empty() {
+                        command.description = Some(comments.join(" "));
+                    }
+                    command.mode = current;
+                    command.spans = expanded.original_spans(command.spans);
+                    config.commands.push(*command);
+                }
+                Ok(Some(Directive::Set(setting))) => config.settings.apply(setting),
+                Ok(Some(Directive::Startup(action))) => config.startup.push(action),
+                Ok(Some(Directive::Unbind(keyseq, location))) => {
+                    // Inside a mode block, only that mode's bindings
+                    // are unbound; outside, only ordinary ones.
+                    let count = config.commands.len();
+                    config
+                        .commands
+                        .retain(|command| command.keyseq != keyseq || command.mode != current);
+                    if config.commands.len() == count {
+                        config.warnings.push(format!(
+                            "{}: \"{}\" isn't bound, so there's nothing to unbind",
+                            location, keyseq
+                        ));
+                    }
+                }
+                Ok(Some(Directive::Include(_))) if current.is_some() => {
+                    errors.push(
+                        text.to_error("\"include\" can't go inside a mode block".to_string()),
+                    );
+                }
+                Ok(Some(Directive::Include(path))) => {
+                    let path = include_path(&path, file_name);
+                    match include(config, &path, including, &text) {
+                        Ok(nested) => errors.extend(nested),
+                        Err(err) => errors.push(err),
+                    }
+                }
+                Ok(Some(Directive::BeginMode(name))) => match &mode {
+                    Some((open, _)) => errors.push(text.to_error(format!(
+                        "Modes can't be nested; close mode \"{}\" with \"}}\" first",
+                        open
+                    ))),
+                    None => {
+                        let unclosed =
+                            text.to_error(format!("Mode \"{}\" is never closed with \"}}\"", name));
+                        mode = Some((name, unclosed));
+                    }
+                },
+                Ok(Some(Directive::EndMode)) => {
+                    if mode.take().is_none() {
+                        errors.push(
+                            text.to_error(
+                                "\"}\" doesn't close anything; there's no \"mode\" block open"
+                                    .to_string(),
+                            ),
+                        );
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => errors.push(err),
+            }
+        }
+        comments.clear();
+    }
+
+    // A mode block has to be closed in the file it was opened in.
+    if let Some((_, unclosed)) = mode {
+        errors.push(unclosed);
+    }
+
+    Ok(errors)
+}
+
+/// Parses the file at `path` into `config` for the `include` line
+/// `text`, returning the syntax errors found in it. Failing to read
+/// the file, or finding that it's already being parsed, is an error
+/// on the `include` line itself.
+fn include(
+    config: &mut Config,
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    text: &LineText,
+) -> Result<Vec<SyntaxError>, SyntaxError> {
+    let read_error = |err: &dyn fmt::Display| {
+        text.to_error(format!("Can't read \"{}\": {}", path.display(), err))
+    };
+
+    let canonical = fs::canonicalize(path).map_err(|err| read_error(&err))?;
+    if including.contains(&canonical) {
+        return Err(text.to_error(format!(
+            "\"{}\" is already being read, so including it here would go round forever",
+            path.display()
+        )));
+    }
+    let file = File::open(path).map_err(|err| read_error(&err))?;
+
+    including.push(canonical);
+    let result = parse_config_nested(
+        config,
+        BufReader::new(file),
+        &path.to_string_lossy(),
+        including,
+    );
+    including.pop();
+    result.map_err(|err| read_error(&err))
+}
+
+/// Works out which file an `include` line in the file `file_name`
+/// means: `~` stands for the home directory, and a relative path is
+/// relative to the directory `file_name` is in.
+fn include_path(path: &str, file_name: &str) -> PathBuf {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    if path == "~" {
+        if let Some(home) = home() {
+            return home;
+        }
+    }
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = home() {
+            return home.join(rest);
+        }
+    }
+
+    let path = Path::new(path);
+    match Path::new(file_name).parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Works out where the configuration file is when none is named on
+/// the command line, given the values of `$XDG_CONFIG_HOME` and
+/// `$HOME`: `ahkd/config` in the former, or in `~/.config` if it's
+/// unset. As the XDG spec says, a relative `$XDG_CONFIG_HOME` is
+/// ignored. Returns None if there's nowhere to look.
+pub fn default_config_path(
+    xdg_config_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Option<PathBuf> {
+    let config_home = match xdg_config_home.map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(home.filter(|home| !home.is_empty())?).join(".config"),
+    };
+    Some(config_home.join("ahkd").join("config"))
+}
+
+/// Expands a numeric range like `{1..9}` in the keys of a line of
+/// the configuration file, i.e., before its first `:`, into one line
+/// per number in the range, with that number substituted for the
+/// range and for every `%N%` in the line. Ranges can count down as
+/// well as up, but can't cover more than `MAX_RANGE` numbers. Lines
+/// without a range are returned as-is; only the first range in a
+/// line is expanded.
+fn expand_range(line: LineText) -> Result<Vec<Expanded>, SyntaxError> {
+    let text = line.as_str();
+    // Braces in the command belong to the command, e.g. to awk.
+    let keys_end = text.find(':').unwrap_or(text.len());
+    let (span, from, to) = match find_range(&text[..keys_end]) {
+        Some(range) => range,
+        None => {
+            return Ok(vec![Expanded {
+                text: text.to_string(),
+                replaced: Vec::new(),
+            }])
+        }
+    };
+    if from.abs_diff(to) >= MAX_RANGE {
+        let range = line.substr(Some(span.start), Some(span.end));
+        return Err(range.to_error(format!("Ranges can cover at most {} numbers", MAX_RANGE)));
+    }
+
+    let numbers: Vec<u32> = if from <= to {
+        (from..=to).collect()
+    } else {
+        (to..=from).rev().collect()
+    };
+
+    let mut replaced: Vec<Range<usize>> = text
+        .match_indices("%N%")
+        .map(|(idx, n)| idx..idx + n.len())
+        .filter(|n| n.end <= span.start || n.start >= span.end)
+        .collect();
+    replaced.push(span);
+    replaced.sort_by_key(|range| range.start);
+
+    Ok(numbers
+        .into_iter()
+        .map(|n| {
+            let n = n.to_string();
+            let mut expanded = String::new();
+            let mut done = 0;
+            for range in &replaced {
+                expanded += &text[done..range.start];
+                expanded += &n;
+                done = range.end;
+            }
+            expanded += &text[done..];
+            Expanded {
+                text: expanded,
+                replaced: replaced
+                    .iter()
+                    .map(|range| (range.clone(), n.len()))
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+/// A line of the configuration file with a numeric range expanded,
+/// as made by `expand_range`.
+#[derive(Debug)]
+struct Expanded {
+    /// The text of the line, with a number from the range in place.
+    text: String,
+
+    /// The byte ranges of the original line that were replaced with
+    /// the number, in order, along with how long the number is.
+    replaced: Vec<(Range<usize>, usize)>,
+}
+
+impl Expanded {
+    /// Maps the spans of a binding on the expanded line back onto the
+    /// original line in the file.
+    fn original_spans(&self, spans: Spans) -> Spans {
+        let original = |span: Span| Span {
+            columns: self.original_column(span.columns.start, false)
+                ..self.original_column(span.columns.end, true),
+            ..span
+        };
+        Spans {
+            keys: original(spans.keys),
+            action: original(spans.action),
+        }
+    }
+
+    /// Maps a column of the expanded line back onto the original line.
+    /// A column partway through a number goes to the start of what it
+    /// replaced, or to the end if `end` is set.
+    fn original_column(&self, column: usize, end: bool) -> usize {
+        // How much further along the expanded line is than the
+        // original, so far.
+        let mut shift = 0;
+        for (range, len) in &self.replaced {
+            let start = (range.start as isize + shift) as usize;
+            if column <= start {
+                break;
+            }
+            if column < start + len {
+                return if end { range.end } else { range.start };
+            }
+            shift += *len as isize - range.len() as isize;
+        }
+        (column as isize - shift) as usize
+    }
+}
 
-        if let Some(command) = parse_command(LineText::new(file_name, idx, &line))? {
-            commands.push(command);
+/// Finds the first `{FROM..TO}` range in a string, returning its
+/// byte range and its bounds.
+fn find_range(text: &str) -> Option<(Range<usize>, u32, u32)> {
+    for (start, _) in text.match_indices('{') {
+        let rest = &text[start + 1..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => continue,
+        };
+        let mut bounds = rest[..end].splitn(2, "..");
+        let from = bounds.next().and_then(parse_bound);
+        let to = bounds.next().and_then(parse_bound);
+        if let (Some(from), Some(to)) = (from, to) {
+            return Some((start..start + end + 2, from, to));
         }
     }
 
-    Ok(Config { commands })
+    None
+}
+
+/// Parses one end of a `{FROM..TO}` range, which must be a plain
+/// decimal number.
+fn parse_bound(text: &str) -> Option<u32> {
+    if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
 }
 
 /// Attempts to parse the line of text as a configuration command.
 /// Returns Ok(None) if the line was blank or a comment.
-fn parse_command<'a>(line: LineText<'a>) -> Result<Option<ConfigLine>, SyntaxError> {
-    let trimmed = line.trim_start();
+fn parse_command<'a>(
+    line: LineText<'a>,
+    settings: &Settings,
+) -> Result<Option<Directive>, SyntaxError> {
+    let trimmed = strip_comment(line).trim_start();
     match trimmed.as_str().chars().next() {
         None | Some('#') => {
             // Blank line or comment.
@@ -351,8 +1294,27 @@ fn parse_command<'a>(line: LineText<'a>) -> Result<Option<ConfigLine>, SyntaxErr
     let first_word = split.next().unwrap();
 
     Ok(Some(match first_word.as_str() {
-        "bind" => parse_cmd_bind(split.rest()),
-        "map" => parse_cmd_map(split.rest()),
+        "bind" => parse_cmd_bind(split.rest(), settings).map(Directive::command),
+        "bind-repeat" => parse_cmd_bind_repeat(split.rest(), settings).map(Directive::command),
+        "map" => parse_cmd_map(split.rest(), settings).map(Directive::command),
+        "passthrough" => parse_cmd_passthrough(split.rest(), settings).map(Directive::command),
+        "clipboard" => parse_cmd_clipboard(split.rest(), settings).map(Directive::command),
+        "notify" => parse_cmd_notify(split.rest(), settings).map(Directive::command),
+        "set" => parse_cmd_set(split.rest()).map(Directive::Set),
+        "label" => parse_label(split.rest(), settings),
+        "include" => parse_cmd_include(split.rest()).map(Directive::Include),
+        "cwd" => parse_cmd_cwd(split.rest()).map(Directive::Set),
+        "shell" => parse_cmd_shell(split.rest()).map(Directive::Set),
+        "on-startup" => parse_cmd_on_startup(split.rest(), settings).map(Directive::Startup),
+        "mode" => parse_cmd_mode(split.rest()).map(Directive::BeginMode),
+        "}" => match split.rest().trim() {
+            rest if rest.as_str().is_empty() => Ok(Directive::EndMode),
+            rest => Err(rest.to_error("Expected the end of the line".to_string())),
+        },
+        "enter-mode" => parse_cmd_enter_mode(split.rest(), settings).map(Directive::command),
+        "exit-mode" => parse_cmd_exit_mode(split.rest(), settings).map(Directive::command),
+        "unbind" => parse_trigger(split.rest(), false, settings)
+            .map(|keyseq| Directive::Unbind(keyseq, trimmed.location())),
         _ => {
             let errmsg = format!("Unrecognized command \"{}\"", first_word.as_str());
             Err(first_word.to_error(errmsg))
@@ -360,33 +1322,1223 @@ fn parse_command<'a>(line: LineText<'a>) -> Result<Option<ConfigLine>, SyntaxErr
     }?))
 }
 
-fn parse_cmd_bind<'a>(args: LineText<'a>) -> Result<ConfigLine, SyntaxError> {
-    let (keys, command) = args.split1(|c| c == ':', "Expected \":\"")?;
-    Ok(ConfigLine {
-        keyseq: keys.try_into()?,
-        action: Action::Bind {
-            command: command
-                .as_str()
-                .split_ascii_whitespace()
-                .map(|s| s.to_string())
-                .collect(),
-        },
-    })
+/// Parses a `cwd` line, which sets the directory to run the commands
+/// of the `bind` lines after it in. The directory is kept as written,
+/// since `~` and environment variables in it are expanded when the
+/// commands are run.
+fn parse_cmd_cwd(args: LineText<'_>) -> Result<Setting, SyntaxError> {
+    let dir = args.trim();
+    if dir.as_str().is_empty() {
+        return Err(dir.to_error("\"cwd\" requires a directory".to_string()));
+    }
+    Ok(Setting::Cwd(dir.as_str().to_string()))
 }
 
-fn parse_cmd_map<'a>(args: LineText<'a>) -> Result<ConfigLine, SyntaxError> {
-    let (from, to) = args.split1(|c| c == ':', "Expected \":\"")?;
-    Ok(ConfigLine {
-        keyseq: from.try_into()?,
-        action: Action::Map {
-            to: to.try_into()?,
-        }
-    })
+/// Parses a `shell` line, which sets the shell to run the commands of
+/// the `bind` lines after it with, or with `none`, goes back to
+/// running them directly.
+fn parse_cmd_shell(args: LineText<'_>) -> Result<Setting, SyntaxError> {
+    let shell = args.trim();
+    match shell.as_str() {
+        "" => Err(shell.to_error("\"shell\" requires a program, or \"none\"".to_string())),
+        "none" => Ok(Setting::Shell(None)),
+        program => Ok(Setting::Shell(Some(program.to_string()))),
+    }
 }
 
-#[cfg(test)]
+/// Parses the opening line of a `mode` block, `mode NAME {`,
+/// returning the name.
+fn parse_cmd_mode(args: LineText<'_>) -> Result<String, SyntaxError> {
+    let mut words = args.split(char::is_whitespace, true);
+    let name = match words.next() {
+        Some(name) if name.as_str() != "{" => name,
+        _ => {
+            return Err(args
+                .trim_start()
+                .to_error("\"mode\" requires a name".to_string()))
+        }
+    };
+    match words.next() {
+        Some(brace) if brace.as_str() == "{" => {}
+        _ => return Err(name.end().to_error("Expected \"{\"".to_string())),
+    }
+    let rest = words.rest().trim();
+    if !rest.as_str().is_empty() {
+        return Err(rest.to_error("Expected the end of the line".to_string()));
+    }
+    Ok(name.as_str().to_string())
+}
+
+/// Parses the file name of an `include` command, which can be quoted
+/// if it has spaces in it.
+fn parse_cmd_include(args: LineText<'_>) -> Result<String, SyntaxError> {
+    let trimmed = args.trim();
+    if trimmed.as_str().starts_with(['"', '\'']) {
+        let (path, rest) = parse_quoted(trimmed)?.unwrap();
+        if !rest.as_str().trim().is_empty() {
+            return Err(rest
+                .trim()
+                .to_error("Expected the end of the line".to_string()));
+        }
+        return Ok(path);
+    }
+    if trimmed.as_str().is_empty() {
+        return Err(args
+            .end()
+            .to_error("\"include\" requires a file name".to_string()));
+    }
+    Ok(trimmed.as_str().to_string())
+}
+
+/// Parses a `label "..."` annotation, which gives the binding on the
+/// rest of the line a short name.
+fn parse_label<'a>(args: LineText<'a>, settings: &Settings) -> Result<Directive, SyntaxError> {
+    let (label, rest) = match parse_quoted(args.clone())? {
+        Some(parsed) => parsed,
+        None => {
+            return Err(args
+                .end()
+                .to_error("\"label\" requires a quoted label".to_string()))
+        }
+    };
+    match parse_command(rest.clone(), settings)? {
+        Some(Directive::Command(mut command)) => {
+            command.label = Some(label);
+            Ok(Directive::Command(command))
+        }
+        _ => Err(rest
+            .trim_start()
+            .to_error("Expected a binding after the label".to_string())),
+    }
+}
+
+/// Removes a trailing comment from a line: a `#` on its own, with
+/// whitespace before it and whitespace or the end of the line after
+/// it, starts a comment that runs to the end of the line, unless it's
+/// inside quotes or escaped as `\#`. A `#` that's part of a word, like
+/// the ones in `echo a#b`, `--color #ff0000` or the keycode `#38`, is
+/// left alone. The text left over keeps its original columns, so
+/// errors still point at the right place.
+fn strip_comment<'a>(line: LineText<'a>) -> LineText<'a> {
+    let text = line.as_str();
+    let mut quote = None;
+    let mut prev = ' ';
+    let mut chars = text.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match quote {
+            // Backslashes are taken literally in single quotes.
+            Some('\'') if c == '\'' => quote = None,
+            Some('\'') => {}
+            _ if c == '\\' => {
+                chars.next();
+            }
+            Some(_) if c == '"' => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#'
+                && prev.is_whitespace()
+                && text[idx + 1..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace) =>
+            {
+                return line.substr(None, Some(idx))
+            }
+            None => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+/// Splits an optional `sync` prefix off the key sequence of a
+/// binding, returning whether it was there and the rest of the text.
+fn parse_sync<'a>(keys: LineText<'a>) -> (bool, LineText<'a>) {
+    let mut words = keys.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "sync" => (true, words.rest()),
+        _ => (false, keys),
+    }
+}
+
+/// Splits an optional `device N` prefix, which limits a binding to
+/// the keyboard whose XInput2 ID is `N`, off the key sequence of a
+/// binding, returning the ID if it was there and the rest of the
+/// text.
+fn parse_device<'a>(keys: LineText<'a>) -> Result<(Option<u16>, LineText<'a>), SyntaxError> {
+    let mut words = keys.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "device" => {
+            let id = match words.next() {
+                Some(id) => id,
+                None => return Err(word.end().to_error("Expected a device ID".to_string())),
+            };
+            match id.as_str().parse::<u16>() {
+                Ok(id) => Ok((Some(id), words.rest())),
+                Err(_) => {
+                    Err(id.to_error("Expected a device ID, as shown by `xinput list`".to_string()))
+                }
+            }
+        }
+        _ => Ok((None, keys)),
+    }
+}
+
+/// Splits an optional `2x` prefix, which makes a binding fire when
+/// its key is tapped twice, off the key sequence of a binding,
+/// returning whether it was there and the rest of the text.
+fn parse_double_tap<'a>(keys: LineText<'a>) -> (bool, LineText<'a>) {
+    let mut words = keys.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "2x" => (true, words.rest()),
+        _ => (false, keys),
+    }
+}
+
+/// Splits an optional `release` prefix, which makes a binding fire
+/// when its key comes back up, off the key sequence of a binding,
+/// returning whether it was there and the rest of the text.
+fn parse_release<'a>(keys: LineText<'a>) -> (bool, LineText<'a>) {
+    let mut words = keys.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "release" => (true, words.rest()),
+        _ => (false, keys),
+    }
+}
+
+/// Parses the key sequence that triggers a binding, whose keys are
+/// written as `settings` says. The `<any>` wildcard can't
+/// start one, since that would mean taking over the whole keyboard.
+/// A mouse button can only start one, since we only hear about
+/// clicks of the buttons we've grabbed, and then only if the binding
+/// isn't `sync`, which `sync` says.
+fn parse_trigger(
+    keys: LineText<'_>,
+    sync: bool,
+    settings: &Settings,
+) -> Result<KeySequence, SyntaxError> {
+    let keyseq = KeySequence::parse(keys.clone(), settings)?;
+    if keyseq
+        .keys
+        .iter()
+        .skip(1)
+        .any(|key| key.main_key.button().is_some())
+    {
+        return Err(keys
+            .trim()
+            .to_error("A mouse button can only be the first key of a key sequence".to_string()));
+    }
+    match keyseq.keys.first() {
+        None => Err(keys.to_error("Expected a key sequence".to_string())),
+        Some(first) if first.is_any() => Err(keys
+            .trim_start()
+            .to_error("A key sequence can't start with <any>".to_string())),
+        Some(first) if sync && first.main_key.button().is_some() => Err(keys
+            .trim()
+            .to_error("\"sync\" doesn't work with mouse buttons".to_string())),
+        Some(_) => Ok(keyseq),
+    }
+}
+
+fn parse_cmd_bind<'a>(args: LineText<'a>, settings: &Settings) -> Result<ConfigLine, SyntaxError> {
+    let (keys, command) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let spans = Spans {
+        keys: keys.span(),
+        action: command.span(),
+    };
+    let action = parse_commands(command, "bind", settings)?;
+
+    let (sync, keys) = parse_sync(keys);
+    let (device, keys) = parse_device(keys)?;
+    let (double_tap, keys) = parse_double_tap(keys);
+    let (release, keys) = parse_release(keys);
+    let keyseq = parse_trigger(keys.clone(), sync, settings)?;
+    if double_tap && keyseq.keys.len() != 1 {
+        return Err(keys
+            .trim()
+            .to_error("\"2x\" only works with a single key".to_string()));
+    }
+    if release && keyseq.keys.len() != 1 {
+        return Err(keys
+            .trim()
+            .to_error("\"release\" only works with a single key".to_string()));
+    }
+    if release && double_tap {
+        return Err(keys
+            .trim()
+            .to_error("A binding can't be for both \"2x\" and \"release\"".to_string()));
+    }
+    if release && keyseq.keys[0].main_key.button().is_some() {
+        return Err(keys
+            .trim()
+            .to_error("Mouse buttons can't be bound to their release".to_string()));
+    }
+
+    Ok(ConfigLine {
+        keyseq,
+        action,
+        location: args.location(),
+        sync,
+        double_tap,
+        release,
+        repeat_while_held: false,
+        device,
+        description: None,
+        label: None,
+        mode: None,
+        spans,
+    })
+}
+
+/// Parses a `bind-repeat` line, which is written like a `bind` line,
+/// but makes a binding that keeps running its commands for as long as
+/// its last key is held.
+fn parse_cmd_bind_repeat(
+    args: LineText<'_>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let line = parse_cmd_bind(args.clone(), settings)?;
+    // That parsed, so there's a `:` to split at.
+    let (keys, _) = args.split1(|c| c == ':', "Expected \":\"")?;
+    if line.double_tap || line.release {
+        return Err(keys
+            .trim()
+            .to_error("\"bind-repeat\" can't be used with \"2x\" or \"release\"".to_string()));
+    }
+    if line.keyseq.keys.last().unwrap().main_key.button().is_some() {
+        return Err(keys
+            .trim()
+            .to_error("Mouse buttons can't be held to repeat a binding".to_string()));
+    }
+
+    Ok(ConfigLine {
+        repeat_while_held: true,
+        ..line
+    })
+}
+
+/// Parses the commands for a `bind` or `on-startup` line (named by
+/// `directive`, for error messages) to run, along with the `ungrab`,
+/// `repeat` and variable assignments that can go in front of them,
+/// and the here-string that can go after.
+fn parse_commands(
+    command: LineText<'_>,
+    directive: &str,
+    settings: &Settings,
+) -> Result<Action, SyntaxError> {
+    let (ungrab, command) = parse_ungrab(command);
+    let (repeat, command) = parse_repeat(command)?;
+    // A shell sees to variables itself.
+    let (env, command) = match settings.shell {
+        Some(_) => (Vec::new(), command),
+        None => parse_env(command),
+    };
+
+    // Everything after a `<<<` is a here-string to send to the
+    // command's standard input. A shell has here-strings of its own.
+    let here_string = match settings.shell {
+        Some(_) => None,
+        None => find_here_string(command.as_str()),
+    };
+    let (command, stdin) = match here_string {
+        Some(idx) => (
+            command.substr(None, Some(idx)),
+            Some(parse_here_string(command.substr(Some(idx + 3), None))?),
+        ),
+        None => (command, None),
+    };
+
+    let commands = match &settings.shell {
+        // The shell splits the command up itself, quotes, `;` and all.
+        Some(shell) => match command.trim().as_str() {
+            "" => {
+                return Err(command
+                    .substr(None, Some(0))
+                    .to_error(format!("\"{}\" requires a command to run", directive)))
+            }
+            text => vec![vec![shell.clone(), "-c".to_string(), text.to_string()]],
+        },
+        None => split_commands(command, directive)?,
+    };
+
+    Ok(Action::Bind {
+        commands,
+        stdin,
+        repeat,
+        ungrab,
+        env,
+        cwd: settings.cwd.clone(),
+        shell: settings.shell.is_some(),
+    })
+}
+
+/// Parses the arguments to an `on-startup` command: a `:`, then the
+/// commands to run, as for `bind`. There's no keyboard grab to let go
+/// of at startup, so `ungrab` isn't allowed.
+fn parse_cmd_on_startup(args: LineText<'_>, settings: &Settings) -> Result<Action, SyntaxError> {
+    let (before, command) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let before = before.trim();
+    if !before.as_str().is_empty() {
+        return Err(before.to_error("Expected \":\"".to_string()));
+    }
+    if parse_ungrab(command.clone()).0 {
+        return Err(command
+            .trim()
+            .to_error("\"ungrab\" only makes sense in a key binding".to_string()));
+    }
+    parse_commands(command, "on-startup", settings)
+}
+
+/// Splits the right-hand side of a `bind` line, or of the line named
+/// by `directive` in general, into commands at each `;` that isn't
+/// quoted or escaped, and each command into words with
+/// `split_command`. Every command must have at least one word.
+fn split_commands(text: LineText<'_>, directive: &str) -> Result<Vec<Vec<String>>, SyntaxError> {
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = text.as_str().char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(_), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, ';') => {
+                let words = split_command(text.substr(Some(start), Some(idx)))?;
+                if words.is_empty() {
+                    return Err(text
+                        .substr(Some(idx), Some(idx + 1))
+                        .to_error("Expected a command before \";\"".to_string()));
+                }
+                commands.push(words);
+                start = idx + 1;
+            }
+            (None, _) => {}
+        }
+    }
+
+    let last = split_command(text.substr(Some(start), None))?;
+    if last.is_empty() {
+        return Err(if commands.is_empty() {
+            // Point just after the colon.
+            text.substr(None, Some(0))
+                .to_error(format!("\"{}\" requires a command to run", directive))
+        } else {
+            text.substr(Some(start - 1), Some(start))
+                .to_error("Expected a command after \";\"".to_string())
+        });
+    }
+    commands.push(last);
+
+    Ok(commands)
+}
+
+/// Splits the command in a `bind` line into words the way a shell
+/// would: at whitespace, except inside single or double quotes, and
+/// with a backslash escaping the character after it. A `$` that's
+/// escaped or in single quotes is left escaped as `\$`, so that it
+/// isn't taken for an environment variable when the command is run.
+fn split_command<'a>(text: LineText<'a>) -> Result<Vec<String>, SyntaxError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Whether there's a word in progress, which might be empty if
+    // it's just a pair of quotes.
+    let mut in_word = false;
+
+    let mut chars = text.as_str().char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some((_, '$')) => word.push_str("\\$"),
+                    Some((_, escaped)) => word.push(escaped),
+                    None => word.push('\\'),
+                }
+            }
+            '\'' | '"' => {
+                in_word = true;
+                let mut closed = false;
+                while let Some((_, inner)) = chars.next() {
+                    match (c, inner) {
+                        (_, inner) if inner == c => {
+                            closed = true;
+                            break;
+                        }
+                        ('\'', '$') => word.push_str("\\$"),
+                        ('\'', '\\') => word.push('\\'),
+                        ('"', '\\') => match chars.peek() {
+                            Some(&(_, '$')) => word.push('\\'),
+                            Some(&(_, escaped @ '"')) | Some(&(_, escaped @ '\\')) => {
+                                word.push(escaped);
+                                chars.next();
+                            }
+                            _ => word.push('\\'),
+                        },
+                        (_, inner) => word.push(inner),
+                    }
+                }
+                if !closed {
+                    return Err(text
+                        .substr(Some(idx), Some(idx + 1))
+                        .to_error("Unterminated quote".to_string()));
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// Splits an optional `ungrab` prefix off the right-hand side of a
+/// `bind` command, returning whether it was there and the rest of
+/// the text.
+fn parse_ungrab<'a>(text: LineText<'a>) -> (bool, LineText<'a>) {
+    let mut words = text.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "ungrab" => (true, words.rest()),
+        _ => (false, text),
+    }
+}
+
+/// Splits any environment variable assignments, written `NAME=VALUE`
+/// as in the shell, off the start of the right-hand side of a `bind`
+/// command, returning them and the rest of the text.
+fn parse_env<'a>(text: LineText<'a>) -> (Vec<(String, String)>, LineText<'a>) {
+    let mut env = Vec::new();
+    let mut text = text;
+    loop {
+        let mut words = text.split(char::is_whitespace, true);
+        match words
+            .next()
+            .and_then(|word| parse_assignment(word.as_str()))
+        {
+            Some(assignment) => {
+                env.push(assignment);
+                text = words.rest();
+            }
+            None => return (env, text),
+        }
+    }
+}
+
+/// Parses a word as an environment variable assignment, if it is one.
+fn parse_assignment(word: &str) -> Option<(String, String)> {
+    let (name, value) = word.split_once('=')?;
+    let valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then(|| (name.to_string(), value.to_string()))
+}
+
+/// Finds the first `<<<` in the commands of a `bind` line that isn't
+/// quoted or escaped, quoting as in `split_commands`.
+fn find_here_string(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut chars = text.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(_), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, _) if text[idx..].starts_with("<<<") => return Some(idx),
+            (None, _) => {}
+        }
+    }
+
+    None
+}
+
+/// Parses the text following a `<<<` in a `bind` command. The text
+/// may optionally be wrapped in single or double quotes, which are
+/// removed; otherwise it's taken literally, minus surrounding
+/// whitespace. Unlike in Bash, no trailing newline is added.
+fn parse_here_string<'a>(text: LineText<'a>) -> Result<String, SyntaxError> {
+    let text = text.trim_start();
+    let body = text.as_str().trim_end();
+
+    match body.chars().next() {
+        None => Err(text.to_error("Expected text after \"<<<\"".to_string())),
+        Some(quote @ '"') | Some(quote @ '\'') => {
+            if body.len() >= 2 && body.ends_with(quote) {
+                Ok(body[1..body.len() - 1].to_string())
+            } else {
+                Err(text
+                    .substr(None, Some(1))
+                    .to_error("Unterminated quote".to_string()))
+            }
+        }
+        Some(_) => Ok(body.to_string()),
+    }
+}
+
+/// Splits an optional `repeat N` prefix off the right-hand side of a
+/// `bind` or `map` command, returning the count (1 if there's no
+/// prefix) and the rest of the text.
+fn parse_repeat<'a>(text: LineText<'a>) -> Result<(u32, LineText<'a>), SyntaxError> {
+    let mut words = text.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "repeat" => {
+            let count = match words.next() {
+                Some(count) => count,
+                None => return Err(word.end().to_error("Expected a repeat count".to_string())),
+            };
+            match count.as_str().parse::<u32>() {
+                Ok(n) if (1..=MAX_REPEAT).contains(&n) => Ok((n, words.rest())),
+                _ => {
+                    Err(count.to_error(format!("Expected a repeat count from 1 to {}", MAX_REPEAT)))
+                }
+            }
+        }
+        _ => Ok((1, text)),
+    }
+}
+
+/// Splits an optional `chain` prefix, which sends a `map` command's
+/// keys back through our own bindings, off the keys it sends,
+/// returning whether it was there and the rest of the text.
+fn parse_chain<'a>(text: LineText<'a>) -> (bool, LineText<'a>) {
+    let mut words = text.split(char::is_whitespace, true);
+    match words.next() {
+        Some(word) if word.as_str() == "chain" => (true, words.rest()),
+        _ => (false, text),
+    }
+}
+
+fn parse_cmd_map<'a>(args: LineText<'a>, settings: &Settings) -> Result<ConfigLine, SyntaxError> {
+    let (from, to) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let spans = Spans {
+        keys: from.span(),
+        action: to.span(),
+    };
+    let (chain, to) = parse_chain(to);
+    let (repeat, to) = parse_repeat(to)?;
+    if to.as_str().contains("<any>") {
+        return Err(to.to_error("Can't send <any> as a key".to_string()));
+    }
+    let to_keys = KeySequence::parse(to.clone(), settings)?;
+    if to_keys.keys.iter().any(|key| key.modifiers.any) {
+        return Err(to.to_error("Can't send a key with any modifiers".to_string()));
+    }
+    let (sync, from) = parse_sync(from);
+    Ok(ConfigLine {
+        keyseq: parse_trigger(from, sync, settings)?,
+        action: Action::Map {
+            to: to_keys,
+            repeat,
+            chain,
+        },
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans,
+    })
+}
+
+fn parse_cmd_passthrough<'a>(
+    args: LineText<'a>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let (sync, keys) = parse_sync(args.clone());
+    Ok(ConfigLine {
+        keyseq: parse_passthrough_trigger(keys, sync, settings)?,
+        action: Action::Passthrough,
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans: Spans {
+            keys: args.span(),
+            action: args.trim().end().span(),
+        },
+    })
+}
+
+/// Parses the key sequence of a `passthrough` command, which can't
+/// start with a mouse button, since there's no way to pass a click
+/// on.
+fn parse_passthrough_trigger(
+    keys: LineText<'_>,
+    sync: bool,
+    settings: &Settings,
+) -> Result<KeySequence, SyntaxError> {
+    let keyseq = parse_trigger(keys.clone(), sync, settings)?;
+    if keyseq.keys[0].main_key.button().is_some() {
+        return Err(keys
+            .trim()
+            .to_error("Mouse buttons can't be passed through".to_string()));
+    }
+    Ok(keyseq)
+}
+
+fn parse_cmd_clipboard<'a>(
+    args: LineText<'a>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let (keys, text) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let spans = Spans {
+        keys: keys.span(),
+        action: text.span(),
+    };
+    let (sync, keys) = parse_sync(keys);
+    let keyseq = parse_trigger(keys, sync, settings)?;
+
+    let trimmed = text.trim_start();
+    if trimmed.as_str().trim_end().is_empty() {
+        return Err(trimmed.to_error("\"clipboard\" requires some text".to_string()));
+    }
+
+    Ok(ConfigLine {
+        keyseq,
+        action: Action::Clipboard {
+            text: trimmed.as_str().trim_end().to_string(),
+        },
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans,
+    })
+}
+
+fn parse_cmd_notify<'a>(
+    args: LineText<'a>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let (keys, text) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let spans = Spans {
+        keys: keys.span(),
+        action: text.span(),
+    };
+    let (sync, keys) = parse_sync(keys);
+    let keyseq = parse_trigger(keys, sync, settings)?;
+
+    let (summary, rest) = match parse_quoted(text.clone())? {
+        Some(parsed) => parsed,
+        None => {
+            return Err(text
+                .trim()
+                .end()
+                .to_error("\"notify\" requires a quoted title".to_string()))
+        }
+    };
+    let (body, rest) = parse_quoted(rest.clone())?.unwrap_or_else(|| (String::new(), rest));
+    let rest = rest.trim();
+    if !rest.as_str().is_empty() {
+        return Err(rest.to_error("Expected a quoted string".to_string()));
+    }
+
+    Ok(ConfigLine {
+        keyseq,
+        action: Action::Notify { summary, body },
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans,
+    })
+}
+
+fn parse_cmd_enter_mode<'a>(
+    args: LineText<'a>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let (keys, name) = args.split1(|c| c == ':', "Expected \":\"")?;
+    let spans = Spans {
+        keys: keys.span(),
+        action: name.span(),
+    };
+    let (sync, keys) = parse_sync(keys);
+    let keyseq = parse_trigger(keys, sync, settings)?;
+
+    let mut words = name.split(char::is_whitespace, true);
+    let mode = match words.next() {
+        Some(mode) => mode.as_str().to_string(),
+        None => {
+            return Err(name
+                .end()
+                .to_error("\"enter-mode\" requires a mode name".to_string()))
+        }
+    };
+    let rest = words.rest().trim();
+    if !rest.as_str().is_empty() {
+        return Err(rest.to_error("Expected the end of the line".to_string()));
+    }
+
+    Ok(ConfigLine {
+        keyseq,
+        action: Action::EnterMode { mode },
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans,
+    })
+}
+
+fn parse_cmd_exit_mode<'a>(
+    args: LineText<'a>,
+    settings: &Settings,
+) -> Result<ConfigLine, SyntaxError> {
+    let (sync, keys) = parse_sync(args.clone());
+    Ok(ConfigLine {
+        keyseq: parse_trigger(keys, sync, settings)?,
+        action: Action::ExitMode,
+        location: args.location(),
+        sync,
+        double_tap: false,
+        release: false,
+        repeat_while_held: false,
+        device: None,
+        description: None,
+        label: None,
+        mode: None,
+        spans: Spans {
+            keys: args.span(),
+            action: args.trim().end().span(),
+        },
+    })
+}
+
+/// Parses a string wrapped in single or double quotes from the start
+/// of the text, returning its contents and the text after it, or
+/// None if there's nothing but whitespace left.
+fn parse_quoted<'a>(text: LineText<'a>) -> Result<Option<(String, LineText<'a>)>, SyntaxError> {
+    let text = text.trim_start();
+    match text.as_str().chars().next() {
+        None => Ok(None),
+        Some(quote @ '"') | Some(quote @ '\'') => match text.as_str()[1..].find(quote) {
+            Some(len) => Ok(Some((
+                text.as_str()[1..len + 1].to_string(),
+                text.substr(Some(len + 2), None),
+            ))),
+            None => Err(text
+                .substr(None, Some(1))
+                .to_error("Unterminated quote".to_string())),
+        },
+        Some(_) => Err(text.to_error("Expected a quoted string".to_string())),
+    }
+}
+
+fn parse_cmd_set<'a>(args: LineText<'a>) -> Result<Setting, SyntaxError> {
+    let mut words = args.split(char::is_whitespace, true);
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Err(args.end().to_error("Expected option name".to_string())),
+    };
+    // This one's value has spaces in it.
+    if name.as_str() == "keysym-alias" {
+        return parse_keysym_alias(words.rest());
+    }
+
+    let value = match words.next() {
+        Some(value) => value,
+        None => return Err(name.end().to_error("Expected option value".to_string())),
+    };
+    if let Some(extra) = words.next() {
+        return Err(extra.to_error("Unexpected text after option value".to_string()));
+    }
+
+    match name.as_str() {
+        "key-timeout" => Ok(Setting::KeyTimeout(parse_millis(value)?)),
+        "sequence-timeout" => Ok(Setting::SequenceTimeout(parse_millis(value)?)),
+        "detach" => Ok(Setting::Detach(parse_bool(value)?)),
+        "prefix-bell" => Ok(Setting::PrefixBell(parse_bool(value)?)),
+        "which-key" => Ok(Setting::WhichKey(parse_bool(value)?)),
+        "key-repeat" => Ok(Setting::KeyRepeat(parse_bool(value)?)),
+        "sync-grabs" => Ok(Setting::SyncGrabs(parse_bool(value)?)),
+        "synthetic-events" => Ok(Setting::SyntheticEvents(parse_bool(value)?)),
+        "strict-modifiers" => Ok(Setting::StrictModifiers(parse_bool(value)?)),
+        "watchdog" => Ok(Setting::Watchdog(parse_millis(value)?)),
+        "seq-separator" => Ok(Setting::SeqSeparator(parse_separator(value)?)),
+        "double-tap-timeout" => match parse_millis(value.clone())? {
+            Some(t) => Ok(Setting::DoubleTapTimeout(t)),
+            None => Err(value.to_error("The double-tap timeout can't be 0".to_string())),
+        },
+        "bind-repeat-delay" => match parse_millis(value.clone())? {
+            Some(t) => Ok(Setting::BindRepeatDelay(t)),
+            None => Err(value.to_error("The bind-repeat delay can't be 0".to_string())),
+        },
+        "bind-repeat-interval" => match parse_millis(value.clone())? {
+            Some(t) => Ok(Setting::BindRepeatInterval(t)),
+            None => Err(value.to_error("The bind-repeat interval can't be 0".to_string())),
+        },
+        "num-lock" => Ok(Setting::NumLock(parse_lock_modifier(value)?)),
+        "cancel-key" => {
+            let key = Key::try_from(value.clone())?;
+            if key.is_any() {
+                return Err(value.to_error("The cancel key can't be <any>".to_string()));
+            }
+            Ok(Setting::CancelKey(key))
+        }
+        _ => {
+            let errmsg = format!("Unknown option \"{}\"", name.as_str());
+            Err(name.to_error(errmsg))
+        }
+    }
+}
+
+/// Parses the value of `set keysym-alias`, which is written as `<name>
+/// = <keysym>`, where the keysym is a number in hexadecimal, such as
+/// `0x1008ff13`.
+fn parse_keysym_alias<'a>(args: LineText<'a>) -> Result<Setting, SyntaxError> {
+    let (name, value) = args.split1(|c| c == '=', "Expected \"=\"")?;
+    let name = name.trim();
+    if name.as_str().is_empty() {
+        return Err(name.to_error("Expected a name for the keysym".to_string()));
+    }
+    // A `-` or `+` would be taken for a modifier, and whitespace
+    // would split the name into two keys.
+    if name
+        .as_str()
+        .contains(|c: char| c == '-' || c == '+' || c.is_whitespace())
+    {
+        return Err(name.to_error("A keysym name can't contain \"-\", \"+\" or spaces".to_string()));
+    }
+
+    let value = value.trim();
+    let keysym = match value.as_str().strip_prefix("0x") {
+        // Keysym 0 is NoSymbol, which we use for `<any>`.
+        Some(hex) if !hex.is_empty() => u32::from_str_radix(hex, 16).ok().filter(|&ks| ks != 0),
+        _ => None,
+    };
+    match keysym {
+        Some(keysym) => Ok(Setting::KeysymAlias(
+            name.as_str().to_string(),
+            Keysym(keysym),
+        )),
+        None => Err(value.to_error("Expected a keysym in hexadecimal, such as 0xff0d".to_string())),
+    }
+}
+
+/// Parses a key sequence separator, which is either a single
+/// punctuation character or `space` for the default of whitespace.
+/// Characters that already mean something in a key sequence aren't
+/// allowed.
+fn parse_separator(text: LineText<'_>) -> Result<Option<char>, SyntaxError> {
+    if text.as_str() == "space" {
+        return Ok(None);
+    }
+
+    let mut chars = text.as_str().chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_punctuation() && !":-+<>_".contains(c) => Ok(Some(c)),
+        _ => Err(text.to_error(
+            "Expected a punctuation character other than \":-+<>_\", or \"space\"".to_string(),
+        )),
+    }
+}
+
+/// Parses a boolean option value.
+fn parse_bool(text: LineText<'_>) -> Result<bool, SyntaxError> {
+    match text.as_str() {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(text.to_error("Expected \"true\" or \"false\"".to_string())),
+    }
+}
+
+/// Parses the name of the modifier Num Lock sets, which is one of
+/// `mod1` to `mod5`.
+fn parse_lock_modifier(text: LineText<'_>) -> Result<Modifier, SyntaxError> {
+    match &*text.as_str().to_ascii_lowercase() {
+        "mod1" => Ok(Modifier::Mod1),
+        "mod2" => Ok(Modifier::Mod2),
+        "mod3" => Ok(Modifier::Mod3),
+        "mod4" => Ok(Modifier::Mod4),
+        "mod5" => Ok(Modifier::Mod5),
+        _ => Err(text.to_error("Expected one of mod1 to mod5".to_string())),
+    }
+}
+
+/// Parses a timeout given as a number of milliseconds, where zero
+/// means no timeout at all.
+fn parse_millis(text: LineText<'_>) -> Result<Option<Duration>, SyntaxError> {
+    match text.as_str().parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(ms) => Ok(Some(Duration::from_millis(ms))),
+        Err(_) => Err(text.to_error("Expected a number of milliseconds".to_string())),
+    }
+}
+
+/// Takes what a file written on Windows can add to a line off it: a
+/// `\r` before the `\n`, and on the first line (number 1), a byte
+/// order mark. Neither is part of the text, nor takes up a column in
+/// an editor.
+fn strip_windows_line(line: &str, line_num: usize) -> &str {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    match line_num {
+        1 => line.strip_prefix('\u{feff}').unwrap_or(line),
+        _ => line,
+    }
+}
+
+/// Parses an sxhkd configuration file like `parse_sxhkd_config_all`,
+/// but fails with the first syntax error found, as `parse_config`
+/// does for our own format.
+#[cfg(test)]
+pub fn parse_sxhkd_config<T: Read>(
+    config: &mut Config,
+    reader: BufReader<T>,
+    file_name: &str,
+) -> Result<(), Box<dyn Error>> {
+    match parse_sxhkd_config_all(config, reader, file_name)?
+        .into_iter()
+        .next()
+    {
+        Some(err) => Err(Box::new(err)),
+        None => Ok(()),
+    }
+}
+
+/// Parses a configuration file written for sxhkd, adding its
+/// bindings to `config`. In sxhkd's format, each binding is a line
+/// giving the keys, such as `super + shift + Return`, followed by an
+/// indented line giving a shell command to run. Chords in a sequence
+/// are separated by `;`, and `{a,b,c}` alternatives and `{1-9}`
+/// ranges in the keys make one binding each, paired with the
+/// corresponding alternatives in the command. As with
+/// `parse_config_all`, bindings with syntax errors are left out, and
+/// every error found is returned.
+pub fn parse_sxhkd_config_all<T: Read>(
+    config: &mut Config,
+    reader: BufReader<T>,
+    file_name: &str,
+) -> Result<Vec<SyntaxError>, Box<dyn Error>> {
+    let mut errors = Vec::new();
+
+    // The keys line we're expecting a command for, and its line
+    // number.
+    let mut keys: Option<(usize, String)> = None;
+
+    let mut lines = reader.lines().enumerate();
+    while let Some((idx, line)) = lines.next() {
+        let line_num = idx + 1;
+        let mut line = strip_windows_line(&line?, line_num).to_string();
+
+        // A backslash at the end of a line continues it on the next
+        // one.
+        while line.ends_with('\\') {
+            line.pop();
+            match lines.next() {
+                Some((idx, next)) => line.push_str(strip_windows_line(&next?, idx + 1)),
+                None => break,
+            }
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let text = LineText::new(file_name, line_num, &line);
+        if line.starts_with(char::is_whitespace) {
+            match keys.take() {
+                Some((keys_num, keys)) => {
+                    let keys = LineText::new(file_name, keys_num, &keys);
+                    match parse_sxhkd_binding(keys, text.trim_start()) {
+                        Ok(bindings) => config.commands.extend(bindings),
+                        Err(err) => errors.push(err),
+                    }
+                }
+                None => errors.push(text.to_error("Expected keys before command".to_string())),
+            }
+        } else if let Some((keys_num, keys)) = keys.replace((line_num, line.clone())) {
+            let keys = LineText::new(file_name, keys_num, &keys);
+            errors.push(
+                keys.end()
+                    .to_error("Expected an indented command on the next line".to_string()),
+            );
+        }
+    }
+
+    if let Some((keys_num, keys)) = keys {
+        let keys = LineText::new(file_name, keys_num, &keys);
+        errors.push(
+            keys.end()
+                .to_error("Expected an indented command on the next line".to_string()),
+        );
+    }
+
+    Ok(errors)
+}
+
+/// Converts a pair of sxhkd keys and command lines into bindings.
+fn parse_sxhkd_binding(
+    keys: LineText<'_>,
+    command: LineText<'_>,
+) -> Result<Vec<ConfigLine>, SyntaxError> {
+    let all_keys = expand_braces(keys.as_str()).map_err(|msg| keys.to_error(msg))?;
+    let all_commands = expand_braces(command.as_str()).map_err(|msg| command.to_error(msg))?;
+    if all_commands.len() != 1 && all_commands.len() != all_keys.len() {
+        return Err(command.to_error(format!(
+            "The keys make {} bindings, but the command has {} alternatives",
+            all_keys.len(),
+            all_commands.len()
+        )));
+    }
+
+    let spans = Spans {
+        keys: keys.span(),
+        action: command.span(),
+    };
+    let mut bindings = Vec::new();
+    for (i, keys_text) in all_keys.iter().enumerate() {
+        let keys_text = sxhkd_keys(keys_text).map_err(|msg| keys.to_error(msg))?;
+        let keyseq = parse_trigger(
+            LineText::new(keys.file_name, keys.line_num, &keys_text),
+            false,
+            &Settings::default(),
+        )?;
+        let command = &all_commands[if all_commands.len() == 1 { 0 } else { i }];
+
+        // sxhkd hands its commands to the shell.
+        bindings.push(ConfigLine {
+            keyseq,
+            action: Action::Bind {
+                commands: vec![vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    command.to_string(),
+                ]],
+                stdin: None,
+                repeat: 1,
+                ungrab: false,
+                env: Vec::new(),
+                cwd: None,
+                shell: true,
+            },
+            location: keys.location(),
+            sync: false,
+            double_tap: false,
+            release: false,
+            repeat_while_held: false,
+            device: None,
+            description: None,
+            label: None,
+            mode: None,
+            spans: spans.clone(),
+        });
+    }
+
+    Ok(bindings)
+}
+
+/// Rewrites sxhkd's notation for keys, such as `super + a ; b`, in
+/// our own, such as `super+a b`.
+fn sxhkd_keys(keys: &str) -> Result<String, String> {
+    let mut chords = Vec::new();
+    for chord in keys.split(';') {
+        let parts: Vec<&str> = chord.split('+').map(str::trim).collect();
+        if let Some(main) = parts.last() {
+            if main.starts_with('@') || main.starts_with('~') {
+                return Err(format!("sxhkd's \"{}\" prefix isn't supported", &main[..1]));
+            }
+        }
+        chords.push(parts.join("+"));
+    }
+
+    Ok(chords.join(" "))
+}
+
+/// Expands sxhkd's `{a,b,c}` alternatives in a line, producing one
+/// line for each combination of alternatives. An alternative written
+/// as `_` stands for nothing, and one written as a range of letters
+/// or digits, like `1-9`, stands for each of them in turn.
+fn expand_braces(text: &str) -> Result<Vec<String>, String> {
+    let start = match text.find('{') {
+        Some(start) => start,
+        None => return Ok(vec![text.to_string()]),
+    };
+    let end = match text[start..].find('}') {
+        Some(end) => start + end,
+        None => return Err("Unterminated \"{\"".to_string()),
+    };
+
+    let mut alternatives = Vec::new();
+    for item in text[start + 1..end].split(',') {
+        let chars: Vec<char> = item.chars().collect();
+        match chars[..] {
+            ['_'] => alternatives.push(String::new()),
+            [from, '-', to] if from.is_ascii_alphanumeric() && to.is_ascii_alphanumeric() => {
+                if from > to {
+                    return Err(format!("Invalid range \"{}\"", item));
+                }
+                alternatives.extend((from..=to).map(String::from));
+            }
+            _ => alternatives.push(item.to_string()),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for rest in expand_braces(&text[end + 1..])? {
+        for alternative in &alternatives {
+            lines.push(format!("{}{}{}", &text[..start], alternative, rest));
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryInto;
+
+    /// Parses a configuration file from a string.
+    fn parse_str(text: &str) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo")?;
+        Ok(config)
+    }
 
     #[test]
     fn split_test() {
@@ -396,6 +2548,7 @@ mod tests {
             file_name: "foo",
             line_num: 10,
             text,
+            shared: None,
             range: 0..text.len(),
         };
 
@@ -411,6 +2564,7 @@ mod tests {
             file_name: "foo",
             line_num: 10,
             text,
+            shared: None,
             range: 0..text.len(),
         };
 
@@ -421,6 +2575,16 @@ mod tests {
         assert_eq!(split, vec!["with", "whitespace"]);
     }
 
+    #[test]
+    fn shared_error_test() {
+        // Errors in a shared line shouldn't copy it.
+        let text: Rc<str> = format!("bind a : {}", "x".repeat(100_000)).into();
+        let lt = LineText::shared("foo", 1, &text);
+        let err = lt.substr(Some(5), Some(6)).to_error("Bad key".to_string());
+        assert!(Rc::ptr_eq(&err.line, &text));
+        assert!(err.to_string().starts_with("Syntax error: foo:1:5\n"));
+    }
+
     #[test]
     fn split1_test() {
         let text = "actually three words";
@@ -428,6 +2592,7 @@ mod tests {
             file_name: "foo",
             line_num: 10,
             text,
+            shared: None,
             range: 0..text.len(),
         };
 
@@ -441,4 +2606,1170 @@ mod tests {
         let failure = lt.split1(|c| c == 'x', "Expected character 'x'");
         assert!(failure.is_err());
     }
+
+    #[test]
+    fn multiple_files_test() {
+        let mut config = Config::default();
+        let base = "set key-timeout 800\nbind a : foo\n";
+        let host = "set key-timeout 0\nbind b : bar\n";
+        parse_config(&mut config, BufReader::new(base.as_bytes()), "base").unwrap();
+        parse_config(&mut config, BufReader::new(host.as_bytes()), "host").unwrap();
+
+        // Commands from both files are kept in order, and later
+        // options override earlier ones.
+        assert_eq!(config.commands.len(), 2);
+        assert_eq!(config.settings.key_timeout, None);
+
+        // Errors name the file they came from.
+        let err = parse_config(&mut config, BufReader::new("bnid".as_bytes()), "typo").unwrap_err();
+        assert!(err.to_string().contains("typo:1:0"));
+    }
+
+    #[test]
+    fn range_test() {
+        let expand_range = |text| -> Vec<String> {
+            let lines = expand_range(LineText::new("foo", 1, text)).unwrap();
+            lines.into_iter().map(|line| line.text).collect()
+        };
+        assert_eq!(
+            expand_range("bind Super-{1..3} : workspace %N% --name ws%N%"),
+            vec![
+                "bind Super-1 : workspace 1 --name ws1",
+                "bind Super-2 : workspace 2 --name ws2",
+                "bind Super-3 : workspace 3 --name ws3",
+            ]
+        );
+
+        // Ranges can count down.
+        assert_eq!(
+            expand_range("bind C-{3..1} : go %N%"),
+            vec!["bind C-3 : go 3", "bind C-2 : go 2", "bind C-1 : go 1"]
+        );
+
+        // Braces that aren't numeric ranges are left alone, and so
+        // is anything after the keys.
+        let awk = "bind C-a : awk '{print $1}' {a..b} {1..}";
+        assert_eq!(expand_range(awk), vec![awk]);
+        let seq = "bind C-s : seq {1..3}";
+        assert_eq!(expand_range(seq), vec![seq]);
+
+        let config = parse_str("bind Super-{0..9} : workspace %N%").unwrap();
+        assert_eq!(config.commands.len(), 10);
+
+        // Spans are on the line as written, not as expanded.
+        let config = parse_str("bind C-%N% C-{7..9} : go %N% now").unwrap();
+        for line in &config.commands {
+            assert_eq!(line.spans.keys.columns, 5..19);
+            assert_eq!(line.spans.action.columns, 22..32);
+        }
+
+        // Huge ranges are refused rather than expanded.
+        assert_eq!(expand_range("bind C-{1..100} : go %N%").len(), 100);
+        let err = parse_str("bind Super-{0..4294967295} : workspace %N%").unwrap_err();
+        assert!(err.to_string().contains("foo:1:11"));
+    }
+
+    #[test]
+    fn bindings_for_test() {
+        let config = parse_str(
+            "bind C-x : foo\n\nbind C-y : bar\nbind C-x : baz\nmode m {\nbind C-x : qux\n}\n",
+        )
+        .unwrap();
+        let keyseq: KeySequence = LineText::new("cmdline", 1, "C-x").try_into().unwrap();
+        let lines = |mode| -> Vec<_> {
+            config
+                .bindings_for(&keyseq, mode)
+                .iter()
+                .map(|line| (line.location.to_string(), line.action.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            lines(None),
+            vec![
+                ("foo:1".to_string(), "bind foo".to_string()),
+                ("foo:4".to_string(), "bind baz".to_string()),
+            ]
+        );
+        // A mode's binding doesn't override the ordinary ones, or
+        // get overridden by them.
+        assert_eq!(
+            lines(Some("m")),
+            vec![("foo:6".to_string(), "bind qux".to_string())]
+        );
+    }
+
+    #[test]
+    fn passthrough_test() {
+        let config = parse_str("passthrough C-c").unwrap();
+        assert!(matches!(config.commands[0].action, Action::Passthrough));
+        assert_eq!(config.commands[0].keyseq.keys.len(), 1);
+    }
+
+    #[test]
+    fn repeat_test() {
+        let config = parse_str(
+            "bind Super-plus : repeat 5 xdotool key Up\n\
+             bind Super-minus : xdotool key Down\n\
+             map C-n : repeat 3 Down",
+        )
+        .unwrap();
+        match &config.commands[0].action {
+            Action::Bind {
+                commands, repeat, ..
+            } => {
+                assert_eq!(commands, &[vec!["xdotool", "key", "Up"]]);
+                assert_eq!(*repeat, 5);
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        match &config.commands[1].action {
+            Action::Bind { repeat, .. } => assert_eq!(*repeat, 1),
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        match &config.commands[2].action {
+            Action::Map { repeat, .. } => assert_eq!(*repeat, 3),
+            action => panic!("Expected a map action, got {:?}", action),
+        }
+
+        let err = parse_str("bind a : repeat 1000 true").unwrap_err();
+        assert!(err.to_string().contains("foo:1:16"), "{}", err);
+        assert!(parse_str("bind a : repeat 0 true").is_err());
+        assert!(parse_str("bind a : repeat").is_err());
+        assert!(parse_str("bind a : repeat 2").is_err());
+    }
+
+    #[test]
+    fn any_key_test() {
+        let config = parse_str("bind Super-s <any> : screenshot %KEY%").unwrap();
+        assert!(config.commands[0].keyseq.keys[1].is_any());
+
+        assert!(parse_str("bind <any> : foo").is_err());
+        assert!(parse_str("bind  : foo").is_err());
+        assert!(parse_str("passthrough <any> C-a").is_err());
+        assert!(parse_str("map C-a : <any>").is_err());
+        assert!(parse_str("map C-a : *-b").is_err());
+    }
+
+    #[test]
+    fn cwd_env_test() {
+        let config = parse_str(
+            "bind a : foo\n\
+             cwd ~/projects\n\
+             bind b : repeat 2 EDITOR=vim LANG= emacs A=b ; bar\n",
+        )
+        .unwrap();
+        match &config.commands[0].action {
+            Action::Bind { env, cwd, .. } => {
+                assert!(env.is_empty());
+                assert_eq!(*cwd, None);
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        match &config.commands[1].action {
+            Action::Bind {
+                commands, env, cwd, ..
+            } => {
+                assert_eq!(
+                    *env,
+                    vec![
+                        ("EDITOR".to_string(), "vim".to_string()),
+                        ("LANG".to_string(), String::new())
+                    ]
+                );
+                assert_eq!(cwd.as_deref(), Some("~/projects"));
+                // Only the assignments at the start count.
+                assert_eq!(commands[0], vec!["emacs", "A=b"]);
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        assert_eq!(
+            config.commands[1].action.to_string(),
+            "bind repeat 2 EDITOR=vim LANG= emacs A=b ; bar"
+        );
+
+        // Not a variable name.
+        match &parse_str("bind a : 1X=2 foo").unwrap().commands[0].action {
+            Action::Bind { env, .. } => assert!(env.is_empty()),
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        assert!(parse_str("bind a : FOO=bar").is_err());
+        assert!(parse_str("cwd").is_err());
+    }
+
+    #[test]
+    fn shell_test() {
+        let config = parse_str(
+            "shell /bin/sh\n\
+             bind a : repeat 2 FOO=1 ls *.txt | wc -l ; cat <<< \"$FOO\"\n\
+             shell none\n\
+             bind b : ls *.txt\n",
+        )
+        .unwrap();
+        match &config.commands[0].action {
+            Action::Bind {
+                commands,
+                stdin,
+                repeat,
+                env,
+                shell,
+                ..
+            } => {
+                assert_eq!(
+                    *commands,
+                    vec![vec![
+                        "/bin/sh",
+                        "-c",
+                        "FOO=1 ls *.txt | wc -l ; cat <<< \"$FOO\""
+                    ]]
+                );
+                // The shell sees to its own here-strings.
+                assert_eq!(*stdin, None);
+                assert_eq!(*repeat, 2);
+                assert!(env.is_empty());
+                assert!(*shell);
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        match &config.commands[1].action {
+            Action::Bind {
+                commands, shell, ..
+            } => {
+                assert_eq!(*commands, vec![vec!["ls", "*.txt"]]);
+                assert!(!*shell);
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+
+        assert!(parse_str("shell").is_err());
+        assert!(parse_str("shell sh\nbind a :  ").is_err());
+    }
+
+    #[test]
+    fn ungrab_test() {
+        let config = parse_str(
+            "bind Super-l : ungrab slock\n\
+             bind Super-k : ungrab repeat 2 foo\n\
+             bind Super-j : ungrabbed",
+        )
+        .unwrap();
+        let actions: Vec<String> = config
+            .commands
+            .iter()
+            .map(|line| line.action.to_string())
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                "bind ungrab slock",
+                "bind ungrab repeat 2 foo",
+                "bind ungrabbed"
+            ]
+        );
+    }
+
+    #[test]
+    fn sync_test() {
+        let config = parse_str("bind sync C-x C-f : foo\nbind C-x k : bar").unwrap();
+        assert!(config.commands[0].sync);
+        assert_eq!(config.commands[0].keyseq.to_string(), "C-x C-f");
+        assert!(!config.commands[1].sync);
+    }
+
+    #[test]
+    fn keysym_alias_test() {
+        let config = parse_str(
+            "set keysym-alias AudioMute = 0x1008ff12\n\
+             set keysym-alias  Return=0xff8d \n\
+             bind C-AudioMute : foo\n\
+             bind Return : bar",
+        )
+        .unwrap();
+        assert_eq!(
+            config.commands[0].keyseq.keys[0].main_key,
+            Keysym(0x1008ff12)
+        );
+        assert!(config.commands[0].keyseq.keys[0].modifiers.mod_control);
+        // Aliases take precedence over the standard names.
+        assert_eq!(config.commands[1].keyseq.keys[0].main_key, Keysym(0xff8d));
+
+        // Aliases only apply after they're set.
+        assert!(parse_str("bind Foo : foo\nset keysym-alias Foo = 0x61").is_err());
+
+        assert!(parse_str("set keysym-alias Foo 0x61").is_err());
+        assert!(parse_str("set keysym-alias = 0x61").is_err());
+        assert!(parse_str("set keysym-alias Foo-Bar = 0x61").is_err());
+        assert!(parse_str("set keysym-alias Foo = 97").is_err());
+        assert!(parse_str("set keysym-alias Foo = 0x").is_err());
+        assert!(parse_str("set keysym-alias Foo = 0x0").is_err());
+    }
+
+    #[test]
+    fn double_tap_test() {
+        let config = parse_str(
+            "bind 2x Shift_L : foo\n\
+             bind sync 2x C-a : bar\n\
+             set double-tap-timeout 500\n\
+             bind Shift_L : baz",
+        )
+        .unwrap();
+        assert!(config.commands[0].double_tap);
+        assert_eq!(config.commands[0].keyseq.to_string(), "Shift_L");
+        assert!(config.commands[1].double_tap && config.commands[1].sync);
+        assert!(!config.commands[2].double_tap);
+        assert_eq!(
+            config.settings.double_tap_timeout(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            Settings::default().double_tap_timeout(),
+            DEFAULT_DOUBLE_TAP_TIMEOUT
+        );
+
+        assert!(parse_str("bind 2x C-x C-f : foo").is_err());
+        assert!(parse_str("bind 2x : foo").is_err());
+        assert!(parse_str("set double-tap-timeout 0").is_err());
+    }
+
+    #[test]
+    fn parse_all_test() {
+        let text = "bind C-x : foo\nbind : bar\nbind C-y : baz\nset nonsense 1\n";
+        let mut config = Config::default();
+        let errors = parse_config_all(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+        let lines: Vec<_> = errors.iter().map(|err| err.line_num).collect();
+        assert_eq!(lines, vec![2, 4]);
+        assert_eq!(config.commands.len(), 2);
+
+        // `parse_config` stops at the first one.
+        let err = parse_str(text).unwrap_err();
+        assert_eq!(err.downcast_ref::<SyntaxError>().unwrap().line_num, 2);
+    }
+
+    #[test]
+    fn comment_test() {
+        let config = parse_str(
+            "# A whole line.\n\
+             bind C-x : firefox  # launch browser\n\
+             bind C-y : echo a#b\n\
+             bind C-z : echo '# not a comment' \"#\" # but this is\n\
+             bind C-c : picker --color #ff0000 \\# it\\'s #not-a-comment # but this is\n\
+             bind C-v : echo \"a \\\" # b\" 'c \\' # d\n",
+        )
+        .unwrap();
+        let words: Vec<_> = config
+            .commands
+            .iter()
+            .map(|line| match &line.action {
+                Action::Bind { commands, .. } => commands[0].join(" "),
+                action => panic!("Expected a bind action, got {:?}", action),
+            })
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                "firefox",
+                "echo a#b",
+                "echo # not a comment #",
+                "picker --color #ff0000 # it's #not-a-comment",
+                "echo a \" # b c \\",
+            ]
+        );
+        match &config.commands[3].action {
+            Action::Bind { commands, .. } => assert_eq!(
+                commands[0],
+                vec![
+                    "picker",
+                    "--color",
+                    "#ff0000",
+                    "#",
+                    "it's",
+                    "#not-a-comment"
+                ]
+            ),
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+
+        // Errors after a comment is taken off still point at the
+        // right columns.
+        let err = parse_config(
+            &mut Config::default(),
+            BufReader::new("  bind C-x :  # nothing here".as_bytes()),
+            "foo",
+        )
+        .unwrap_err();
+        let err = err.downcast_ref::<SyntaxError>().unwrap();
+        assert_eq!(err.col_num, 12);
+    }
+
+    #[test]
+    fn on_startup_test() {
+        let config = parse_str(
+            "on-startup : status-bar --top\n\
+             bind a : foo\n\
+             cwd /tmp\n\
+             on-startup : repeat 2 LANG=C notify-send hi ; touch started <<< input\n",
+        )
+        .unwrap();
+        assert_eq!(config.commands.len(), 1);
+        let startup: Vec<_> = config
+            .startup
+            .iter()
+            .map(|action| match action {
+                Action::Bind {
+                    commands,
+                    stdin,
+                    repeat,
+                    env,
+                    cwd,
+                    ..
+                } => (
+                    commands.clone(),
+                    stdin.clone(),
+                    *repeat,
+                    env.clone(),
+                    cwd.clone(),
+                ),
+                action => panic!("Expected a bind action, got {:?}", action),
+            })
+            .collect();
+        assert_eq!(
+            startup,
+            vec![
+                (
+                    vec![vec!["status-bar".to_string(), "--top".to_string()]],
+                    None,
+                    1,
+                    vec![],
+                    None
+                ),
+                (
+                    vec![
+                        vec!["notify-send".to_string(), "hi".to_string()],
+                        vec!["touch".to_string(), "started".to_string()]
+                    ],
+                    Some("input".to_string()),
+                    2,
+                    vec![("LANG".to_string(), "C".to_string())],
+                    Some("/tmp".to_string())
+                ),
+            ]
+        );
+
+        assert!(parse_str("on-startup status-bar").is_err());
+        assert!(parse_str("on-startup a : status-bar").is_err());
+        assert!(parse_str("on-startup :").is_err());
+        assert!(parse_str("on-startup : ungrab status-bar").is_err());
+    }
+
+    #[test]
+    fn line_ending_test() {
+        let config = parse_str("\u{feff}bind a : foo\r\n# Bar.\r\nbind b : bar\r\n").unwrap();
+        let commands: Vec<_> = config
+            .commands
+            .iter()
+            .map(|command| format!("{} => {}", command.keyseq, command.action))
+            .collect();
+        assert_eq!(commands, vec!["a => bind foo", "b => bind bar"]);
+        assert_eq!(config.commands[1].description.as_deref(), Some("Bar."));
+
+        // Errors point at the same columns as without them.
+        for text in &["\u{feff}bind a :\r\n", "bind a :\n"] {
+            let err = parse_config(
+                &mut Config::default(),
+                BufReader::new(text.as_bytes()),
+                "foo",
+            )
+            .unwrap_err();
+            let err = err.downcast_ref::<SyntaxError>().unwrap();
+            assert_eq!((err.line_num, err.col_num), (1, 8));
+            assert_eq!(&*err.line, "bind a :");
+        }
+
+        // A byte order mark anywhere else isn't taken off.
+        assert!(parse_str("bind a : foo\n\u{feff}bind b : bar\n").is_err());
+    }
+
+    #[test]
+    fn split_command_test() {
+        let split = |text| split_command(LineText::new("foo", 1, text));
+
+        assert_eq!(
+            split("xdg-open \"/home/me/My Documents/file.pdf\"").unwrap(),
+            vec!["xdg-open", "/home/me/My Documents/file.pdf"]
+        );
+        assert_eq!(
+            split("echo 'a \"b\"'  it\\'s \"\"").unwrap(),
+            vec!["echo", "a \"b\"", "it's", ""]
+        );
+        assert_eq!(
+            split("ls My\\ Documents \"a\\\"b\\\\c\"").unwrap(),
+            vec!["ls", "My Documents", "a\"b\\c"]
+        );
+
+        // Dollar signs that shouldn't be expanded stay escaped.
+        assert_eq!(
+            split("echo $HOME \\$HOME '$HOME' \"$HOME\"").unwrap(),
+            vec!["echo", "$HOME", "\\$HOME", "\\$HOME", "$HOME"]
+        );
+
+        let err = split("echo 'unterminated").unwrap_err();
+        assert_eq!((err.col_num, err.len), (5, 1));
+
+        let config = parse_str("bind C-o : xdg-open \"My Documents\"").unwrap();
+        assert_eq!(
+            config.commands[0].action.to_string(),
+            "bind xdg-open \"My Documents\""
+        );
+    }
+
+    #[test]
+    fn map_chain_test() {
+        let config = parse_str("map C-j : chain repeat 2 C-x\nmap C-k : C-x").unwrap();
+        match &config.commands[0].action {
+            Action::Map { to, repeat, chain } => {
+                assert_eq!(to.to_string(), "C-x");
+                assert_eq!(*repeat, 2);
+                assert!(*chain);
+            }
+            action => panic!("Expected a map action, got {:?}", action),
+        }
+        assert_eq!(
+            config.commands[0].action.to_string(),
+            "map chain repeat 2 C-x"
+        );
+        assert_eq!(config.commands[1].action.to_string(), "map C-x");
+    }
+
+    #[test]
+    fn unbind_test() {
+        let config = parse_str(
+            "bind C-x C-f : foo\n\
+             bind C-x C-s : bar\n\
+             map C-x C-f : a\n\
+             unbind C-x C-f\n\
+             bind C-x C-f : baz\n",
+        )
+        .unwrap();
+        let actions: Vec<_> = config
+            .commands
+            .iter()
+            .map(|line| line.action.to_string())
+            .collect();
+        assert_eq!(actions, vec!["bind bar", "bind baz"]);
+        assert!(config.warnings.is_empty());
+
+        // Unbinding something that isn't bound only warns.
+        let config = parse_str("bind C-x C-f : foo\nunbind C-x").unwrap();
+        assert_eq!(config.commands.len(), 1);
+        assert_eq!(
+            config.warnings,
+            vec!["foo:2: \"C-x\" isn't bound, so there's nothing to unbind"]
+        );
+
+        assert!(parse_str("unbind").is_err());
+    }
+
+    #[test]
+    fn prefix_bell_test() {
+        assert!(!parse_str("bind a : foo").unwrap().settings.prefix_bell);
+        assert!(
+            parse_str("set prefix-bell on")
+                .unwrap()
+                .settings
+                .prefix_bell
+        );
+        assert!(parse_str("set prefix-bell loud").is_err());
+    }
+
+    #[test]
+    fn release_test() {
+        let config = parse_str("bind release Super_L : mic off\nbind Super_L : mic on").unwrap();
+        assert!(config.commands[0].release);
+        assert_eq!(config.commands[0].keyseq.to_string(), "Super_L");
+        assert!(!config.commands[1].release);
+
+        assert!(
+            parse_str("bind device 3 release F1 : foo")
+                .unwrap()
+                .commands[0]
+                .release
+        );
+        assert!(parse_str("bind release C-x C-f : foo").is_err());
+        assert!(parse_str("bind 2x release a : foo").is_err());
+        assert!(parse_str("bind release Button1 : foo").is_err());
+    }
+
+    #[test]
+    fn key_repeat_test() {
+        assert!(!parse_str("bind a : foo").unwrap().settings.key_repeat);
+        assert!(parse_str("set key-repeat on").unwrap().settings.key_repeat);
+        assert!(parse_str("set key-repeat sometimes").is_err());
+    }
+
+    #[test]
+    fn strict_modifiers_test() {
+        // Only the lines after the setting are strict.
+        let config =
+            parse_str("bind s-a : foo\nset strict-modifiers on\nbind super-b : bar").unwrap();
+        assert!(config.settings.strict_modifiers);
+        assert_eq!(config.commands.len(), 2);
+        assert!(parse_str("set strict-modifiers on\nbind s-a : foo").is_err());
+    }
+
+    #[test]
+    fn bind_repeat_test() {
+        let config = parse_str(
+            "bind-repeat Super-Up : amixer set Master 5%+\n\
+             set bind-repeat-delay 250\n\
+             set bind-repeat-interval 50\n\
+             bind-repeat C-x Down : amixer set Master 5%-\n\
+             bind Super-Up : foo",
+        )
+        .unwrap();
+        assert!(config.commands[0].repeat_while_held);
+        assert_eq!(config.commands[1].keyseq.to_string(), "C-x Down");
+        assert!(config.commands[1].repeat_while_held);
+        assert!(!config.commands[2].repeat_while_held);
+        assert_eq!(
+            config.settings.bind_repeat_delay(),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            config.settings.bind_repeat_interval(),
+            Duration::from_millis(50)
+        );
+        assert_eq!(
+            Settings::default().bind_repeat_delay(),
+            DEFAULT_BIND_REPEAT_DELAY
+        );
+
+        assert!(parse_str("bind-repeat release F1 : foo").is_err());
+        assert!(parse_str("bind-repeat 2x F1 : foo").is_err());
+        assert!(parse_str("bind-repeat Button4 : foo").is_err());
+        assert!(parse_str("bind-repeat F1").is_err());
+        assert!(parse_str("set bind-repeat-interval 0").is_err());
+    }
+
+    #[test]
+    fn synthetic_events_test() {
+        assert!(!parse_str("bind a : foo").unwrap().settings.synthetic_events);
+        assert!(
+            parse_str("set synthetic-events on")
+                .unwrap()
+                .settings
+                .synthetic_events
+        );
+        assert!(parse_str("set synthetic-events some").is_err());
+    }
+
+    #[test]
+    fn sync_grabs_test() {
+        assert!(!parse_str("bind a : foo").unwrap().settings.sync_grabs);
+        assert!(parse_str("set sync-grabs on").unwrap().settings.sync_grabs);
+        assert!(parse_str("set sync-grabs maybe").is_err());
+    }
+
+    #[test]
+    fn button_binding_test() {
+        let config =
+            parse_str("bind M-Button1 : foo\nbind s-Button3 x : bar\nmap C-b : Button2").unwrap();
+        assert_eq!(config.commands[0].keyseq.keys[0].main_key.button(), Some(1));
+        assert_eq!(config.commands[1].keyseq.to_string(), "s-Button3 x");
+        match &config.commands[2].action {
+            Action::Map { to, .. } => assert_eq!(to.keys[0].main_key.button(), Some(2)),
+            _ => panic!("expected a map"),
+        }
+
+        assert!(parse_str("bind x Button1 : foo").is_err());
+        assert!(parse_str("bind sync Button1 : foo").is_err());
+        assert!(parse_str("passthrough Button1").is_err());
+    }
+
+    #[test]
+    fn stdin_name_test() {
+        // `ahkd -` hands standard input to the parser under this name.
+        let input = std::io::Cursor::new("bind a : foo\nbind b\n");
+        let mut config = Config::default();
+        let errors = parse_config_all(&mut config, BufReader::new(input), "<stdin>").unwrap();
+        assert_eq!(config.commands.len(), 1);
+        assert_eq!(config.commands[0].location.to_string(), "<stdin>:1");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .to_string()
+            .starts_with("Syntax error: <stdin>:2:"));
+    }
+
+    #[test]
+    fn include_test() {
+        let dir = env::temp_dir().join(format!("ahkd-include-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let main = dir.join("main.conf");
+        fs::write(
+            &main,
+            "bind a : foo\ninclude sub/media.conf\nbind c : baz\n",
+        )
+        .unwrap();
+        fs::write(dir.join("sub/media.conf"), "bind b : bar\nbind oops\n").unwrap();
+        fs::write(dir.join("loop.conf"), "include \"loop.conf\"\n").unwrap();
+
+        let mut config = Config::default();
+        let main_name = main.to_str().unwrap();
+        let file = File::open(&main).unwrap();
+        let errors = parse_config_all(&mut config, BufReader::new(file), main_name).unwrap();
+
+        // The included bindings go where the `include` was.
+        let actions: Vec<_> = config
+            .commands
+            .iter()
+            .map(|line| line.action.to_string())
+            .collect();
+        assert_eq!(actions, vec!["bind foo", "bind bar", "bind baz"]);
+
+        // Errors in the included file are reported against it.
+        let media = dir.join("sub").join("media.conf");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file_name, media.to_str().unwrap());
+        assert_eq!(errors[0].line_num, 2);
+
+        let loop_name = dir.join("loop.conf");
+        let file = File::open(&loop_name).unwrap();
+        let errors = parse_config_all(
+            &mut Config::default(),
+            BufReader::new(file),
+            loop_name.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].err_msg.contains("already being read"));
+
+        assert!(parse_str("include nowhere/at/all.conf").is_err());
+        assert!(parse_str("include").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_path_test() {
+        assert_eq!(
+            include_path("media.conf", "/etc/ahkd/main.conf"),
+            PathBuf::from("/etc/ahkd/media.conf")
+        );
+        assert_eq!(
+            include_path("/abs/media.conf", "/etc/ahkd/main.conf"),
+            PathBuf::from("/abs/media.conf")
+        );
+        assert_eq!(
+            include_path("media.conf", "<stdin>"),
+            PathBuf::from("media.conf")
+        );
+    }
+
+    #[test]
+    fn default_config_path_test() {
+        let path = |xdg: Option<&str>, home: Option<&str>| {
+            default_config_path(xdg.map(OsString::from), home.map(OsString::from))
+        };
+        assert_eq!(
+            path(Some("/xdg"), Some("/home/me")),
+            Some(PathBuf::from("/xdg/ahkd/config"))
+        );
+        assert_eq!(
+            path(None, Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/ahkd/config"))
+        );
+        // An empty or relative $XDG_CONFIG_HOME counts as unset.
+        assert_eq!(
+            path(Some(""), Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/ahkd/config"))
+        );
+        assert_eq!(
+            path(Some("xdg"), Some("/home/me")),
+            Some(PathBuf::from("/home/me/.config/ahkd/config"))
+        );
+        assert_eq!(path(None, None), None);
+        assert_eq!(path(None, Some("")), None);
+    }
+
+    #[test]
+    fn multiple_commands_test() {
+        let config = parse_str(
+            "bind C-x : notify-send hi ; aplay ding.wav\n\
+             bind C-y : echo 'a;b' \"c ; d\" e\\;f;ls",
+        )
+        .unwrap();
+        match &config.commands[0].action {
+            Action::Bind { commands, .. } => {
+                assert_eq!(
+                    commands,
+                    &[vec!["notify-send", "hi"], vec!["aplay", "ding.wav"]]
+                )
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        match &config.commands[1].action {
+            Action::Bind { commands, .. } => {
+                assert_eq!(commands, &[vec!["echo", "a;b", "c ; d", "e;f"], vec!["ls"]])
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+        assert_eq!(
+            config.commands[1].action.to_string(),
+            "bind echo \"a;b\" \"c ; d\" \"e;f\" ; ls"
+        );
+
+        assert!(parse_str("bind a : ; foo").is_err());
+        assert!(parse_str("bind a : foo ;").is_err());
+        assert!(parse_str("bind a : foo ; ; bar").is_err());
+    }
+
+    #[test]
+    fn label_test() {
+        let config = parse_str(
+            "label \"Open a file\" bind C-x C-f : emacs\n\
+             label 'Paste' map C-y : S-Insert\n\
+             bind C-x C-s : save",
+        )
+        .unwrap();
+        assert_eq!(config.commands[0].label.as_deref(), Some("Open a file"));
+        assert_eq!(config.commands[0].keyseq.to_string(), "C-x C-f");
+        assert_eq!(config.commands[1].label.as_deref(), Some("Paste"));
+        assert_eq!(config.commands[2].label, None);
+
+        assert!(parse_str("label bind a : foo").is_err());
+        assert!(parse_str("label \"Nothing\"").is_err());
+        assert!(parse_str("label \"Nothing\" set prefix-bell on").is_err());
+    }
+
+    #[test]
+    fn mode_test() {
+        let config = parse_str(
+            "bind h : foo\n\
+             enter-mode Super-r : resize\n\
+             mode resize {\n\
+             \x20   bind h : bar\n\
+             \x20   exit-mode Escape\n\
+             \x20   unbind h\n\
+             }\n\
+             mode move {\n\
+             \x20   bind l : baz\n\
+             }\n",
+        )
+        .unwrap();
+        let lines: Vec<_> = config
+            .commands
+            .iter()
+            .map(|line| (line.mode.as_deref(), line.action.to_string()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (None, "bind foo".to_string()),
+                (None, "enter-mode resize".to_string()),
+                (Some("resize"), "exit-mode".to_string()),
+                (Some("move"), "bind baz".to_string()),
+            ]
+        );
+
+        assert!(parse_str("enter-mode a :").is_err());
+        assert!(parse_str("enter-mode a : two words").is_err());
+        assert!(parse_str("mode {\n}").is_err());
+        assert!(parse_str("mode resize\n}").is_err());
+        assert!(parse_str("mode resize { bind h : foo\n}").is_err());
+        assert!(parse_str("mode resize {\n} }").is_err());
+
+        let text = "mode a {\nmode b {\n}\n}\nmode c {\ninclude foo\n";
+        let mut config = Config::default();
+        let errors = parse_config_all(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|err| (err.line_num, err.err_msg.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    2,
+                    "Modes can't be nested; close mode \"a\" with \"}\" first"
+                ),
+                (
+                    4,
+                    "\"}\" doesn't close anything; there's no \"mode\" block open"
+                ),
+                (6, "\"include\" can't go inside a mode block"),
+                (5, "Mode \"c\" is never closed with \"}\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn num_lock_test() {
+        let config = parse_str("bind a : foo").unwrap();
+        assert_eq!(config.settings.num_lock(), Modifier::Mod2);
+        let config = parse_str("set num-lock Mod3").unwrap();
+        assert_eq!(config.settings.num_lock(), Modifier::Mod3);
+        assert!(parse_str("set num-lock shift").is_err());
+    }
+
+    #[test]
+    fn device_test() {
+        let config = parse_str("bind sync device 12 2x a : foo\nbind a : bar").unwrap();
+        let line = &config.commands[0];
+        assert_eq!(line.device, Some(12));
+        assert!(line.sync && line.double_tap);
+        assert_eq!(line.keyseq.to_string(), "a");
+        assert_eq!(config.commands[1].device, None);
+
+        assert!(parse_str("bind device : foo").is_err());
+        assert!(parse_str("bind device keyboard a : foo").is_err());
+    }
+
+    #[test]
+    fn seq_separator_test() {
+        let config = parse_str(
+            "bind a b : foo\n\
+             set seq-separator ;\n\
+             bind Super-x;Super-c : bar\n\
+             bind C-x ; C-f : baz\n\
+             map C-a : Home;End\n\
+             set seq-separator space\n\
+             bind c d : quux",
+        )
+        .unwrap();
+        let keyseqs: Vec<String> = config
+            .commands
+            .iter()
+            .map(|line| line.keyseq.to_string())
+            .collect();
+        assert_eq!(keyseqs, vec!["a b", "s-x s-c", "C-x C-f", "C-a", "c d"]);
+        match &config.commands[3].action {
+            Action::Map { to, .. } => assert_eq!(to.to_string(), "Home End"),
+            action => panic!("Expected a map action, got {:?}", action),
+        }
+
+        assert!(parse_str("set seq-separator ;\nbind a b : foo").is_err());
+        assert!(parse_str("set seq-separator ;\nbind a;;b : foo").is_err());
+        assert!(parse_str("set seq-separator -").is_err());
+        assert!(parse_str("set seq-separator ;;").is_err());
+    }
+
+    #[test]
+    fn clipboard_test() {
+        let config = parse_str("clipboard Super-c :  some  text  ").unwrap();
+        match &config.commands[0].action {
+            Action::Clipboard { text } => assert_eq!(text, "some  text"),
+            action => panic!("Expected a clipboard action, got {:?}", action),
+        }
+
+        assert!(parse_str("clipboard Super-c :   ").is_err());
+    }
+
+    #[test]
+    fn notify_test() {
+        let config = parse_str("notify Super-n : \"Hello\" 'a \"quoted\" body'").unwrap();
+        match &config.commands[0].action {
+            Action::Notify { summary, body } => {
+                assert_eq!(summary, "Hello");
+                assert_eq!(body, "a \"quoted\" body");
+            }
+            action => panic!("Expected a notify action, got {:?}", action),
+        }
+
+        let config = parse_str("notify Super-n : 'Just a title'").unwrap();
+        match &config.commands[0].action {
+            Action::Notify { summary, body } => {
+                assert_eq!(summary, "Just a title");
+                assert_eq!(body, "");
+            }
+            action => panic!("Expected a notify action, got {:?}", action),
+        }
+
+        assert!(parse_str("notify Super-n :  ").is_err());
+        assert!(parse_str("notify Super-n : unquoted").is_err());
+        assert!(parse_str("notify Super-n : \"unterminated").is_err());
+        assert!(parse_str("notify Super-n : \"a\" \"b\" \"c\"").is_err());
+    }
+
+    #[test]
+    fn here_string_test() {
+        let parse = |text| parse_cmd_bind(LineText::new("foo", 10, text), &Settings::default());
+
+        match parse("Super-p : wl-copy <<< \"fixed  text\" ")
+            .unwrap()
+            .action
+        {
+            Action::Bind {
+                commands, stdin, ..
+            } => {
+                assert_eq!(commands, vec![vec!["wl-copy"]]);
+                assert_eq!(stdin.as_deref(), Some("fixed  text"));
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+
+        match parse("Super-p : cat <<<unquoted text").unwrap().action {
+            Action::Bind { stdin, .. } => assert_eq!(stdin.as_deref(), Some("unquoted text")),
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+
+        assert!(parse("Super-p : cat <<< 'unterminated").is_err());
+        assert!(parse("Super-p : cat <<<   ").is_err());
+
+        // A quoted or escaped `<<<` is part of the command.
+        for text in [
+            "Super-p : echo \"a <<< b\"",
+            "Super-p : echo 'a <<< b'",
+            "Super-p : echo a \\<<< b",
+        ]
+        .iter()
+        {
+            match parse(text).unwrap().action {
+                Action::Bind {
+                    commands, stdin, ..
+                } => {
+                    assert_eq!(commands[0][0], "echo");
+                    assert_eq!(stdin, None);
+                }
+                action => panic!("Expected a bind action, got {:?}", action),
+            }
+        }
+        match parse("Super-p : echo \"<<<\" <<< b").unwrap().action {
+            Action::Bind {
+                commands, stdin, ..
+            } => {
+                assert_eq!(commands, vec![vec!["echo", "<<<"]]);
+                assert_eq!(stdin.as_deref(), Some("b"));
+            }
+            action => panic!("Expected a bind action, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn empty_bind_test() {
+        for text in [
+            "bind Super-t:",
+            "bind Super-t:   ",
+            "bind Super-t: <<< text",
+        ]
+        .iter()
+        {
+            let err = parse_str(text).unwrap_err().to_string();
+            assert!(err.contains("foo:1:13"));
+            assert!(err.ends_with("\"bind\" requires a command to run"));
+        }
+
+        // Other lines with commands are named for what they are.
+        for text in ["on-startup :", "on-startup : <<< text"].iter() {
+            let err = parse_str(text).unwrap_err().to_string();
+            assert!(err.contains("foo:1:12"));
+            assert!(err.ends_with("\"on-startup\" requires a command to run"));
+        }
+    }
+
+    #[test]
+    fn set_test() {
+        let text = "\
+set key-timeout 800
+set sequence-timeout 3000
+set sequence-timeout 0
+set detach on
+set watchdog 10000
+";
+        let config = parse_str(text).unwrap();
+        assert!(config.settings.detach);
+        assert_eq!(config.settings.watchdog, Some(Duration::from_secs(10)));
+        assert_eq!(
+            config.settings.key_timeout,
+            Some(Duration::from_millis(800))
+        );
+        // A timeout of zero disables the timeout.
+        assert_eq!(config.settings.sequence_timeout, None);
+
+        let errors = [
+            "set",
+            "set key-timeout",
+            "set key-timeout soon",
+            "set key-timeout 800 900",
+            "set no-such-option 1",
+            "set detach maybe",
+        ];
+        for text in errors.iter() {
+            assert!(parse_str(text).is_err());
+        }
+    }
+
+    #[test]
+    fn sxhkd_test() {
+        let text = "\
+# Terminal
+super + Return
+\tkitty --single-instance
+
+super + {_,shift + }{1-3}
+    bspc {desktop -f,node -d} '^{1-3}'
+
+ctrl + x ; ctrl + f
+\tf
+super + space
+\techo \\
+  hello
+";
+        let mut config = Config::default();
+        parse_sxhkd_config(&mut config, BufReader::new(text.as_bytes()), "sxhkdrc").unwrap();
+
+        let bindings: Vec<String> = config
+            .commands
+            .iter()
+            .map(|line| format!("{} => {}", line.keyseq, line.action))
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![
+                "s-Return => bind sh -c \"kitty --single-instance\"",
+                "s-1 => bind sh -c \"bspc desktop -f '^1'\"",
+                "S-s-1 => bind sh -c \"bspc node -d '^1'\"",
+                "s-2 => bind sh -c \"bspc desktop -f '^2'\"",
+                "S-s-2 => bind sh -c \"bspc node -d '^2'\"",
+                "s-3 => bind sh -c \"bspc desktop -f '^3'\"",
+                "S-s-3 => bind sh -c \"bspc node -d '^3'\"",
+                "C-x C-f => bind sh -c f",
+                "s-space => bind sh -c \"echo   hello\"",
+            ]
+        );
+        assert_eq!(config.commands[1].location.line_num, 5);
+
+        // Windows line endings and a byte order mark are taken off,
+        // as in our own format.
+        let mut config = Config::default();
+        let text = "\u{feff}super + a\r\n\techo \\\r\n  a\r\n";
+        parse_sxhkd_config(&mut config, BufReader::new(text.as_bytes()), "sxhkdrc").unwrap();
+        assert_eq!(
+            format!(
+                "{} => {}",
+                config.commands[0].keyseq, config.commands[0].action
+            ),
+            "s-a => bind sh -c \"echo   a\""
+        );
+
+        let errors = [
+            "super + a\nsuper + b\n\tfoo\n",
+            "\tfoo\n",
+            "super + a\n",
+            "super + {a,b}\n\t{foo,bar,baz}\n",
+            "super + @a\n\tfoo\n",
+        ];
+        for text in errors.iter() {
+            let mut config = Config::default();
+            assert!(parse_sxhkd_config(&mut config, BufReader::new(text.as_bytes()), "x").is_err());
+        }
+
+        // Every error is found in one go, and the bindings without
+        // any are kept.
+        let text = "\tfoo\nsuper + a\nsuper + @b\n\tbar\nsuper + c\n\tbaz\nsuper + d\n";
+        let mut config = Config::default();
+        let errors =
+            parse_sxhkd_config_all(&mut config, BufReader::new(text.as_bytes()), "x").unwrap();
+        let lines: Vec<_> = errors.iter().map(|err| err.line_num).collect();
+        assert_eq!(lines, vec![1, 2, 3, 7]);
+        assert_eq!(config.commands.len(), 1);
+        assert_eq!(config.commands[0].keyseq.to_string(), "s-c");
+    }
 }
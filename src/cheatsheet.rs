@@ -0,0 +1,249 @@
+// Printable references of the bindings in a configuration.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cfgfile::{Config, ConfigLine};
+use std::io::{self, Write};
+
+/// Writes a standalone HTML page listing every binding in a
+/// configuration, with the comment above it as its description.
+/// Bindings are grouped by the keys that lead up to their last key,
+/// so everything under `C-x` ends up in one table, and each mode's
+/// bindings are grouped apart from the rest.
+pub fn write_html(config: &Config, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>")?;
+    writeln!(out, "<head>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>ahkd bindings</title>")?;
+    writeln!(
+        out,
+        "<style>body {{ font-family: sans-serif; }} \
+         table {{ border-collapse: collapse; margin-bottom: 1em; }} \
+         td, th {{ border: 1px solid #999; padding: 0.2em 0.5em; text-align: left; }} \
+         kbd {{ font-family: monospace; }}</style>"
+    )?;
+    writeln!(out, "</head>")?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>ahkd bindings</h1>")?;
+
+    for (mode, prefix, lines) in groups(config) {
+        match (mode, prefix.is_empty()) {
+            (None, true) => writeln!(out, "<h2>Single keys</h2>")?,
+            (None, false) => writeln!(out, "<h2><kbd>{}</kbd></h2>", escape(&prefix))?,
+            (Some(mode), true) => writeln!(out, "<h2>Mode {}</h2>", escape(mode))?,
+            (Some(mode), false) => writeln!(
+                out,
+                "<h2>Mode {}: <kbd>{}</kbd></h2>",
+                escape(mode),
+                escape(&prefix)
+            )?,
+        }
+        writeln!(out, "<table>")?;
+        writeln!(
+            out,
+            "<tr><th>Keys</th><th>Description</th><th>Action</th></tr>"
+        )?;
+        for line in lines {
+            writeln!(
+                out,
+                "<tr><td><kbd>{}</kbd></td><td>{}</td><td><code>{}</code></td></tr>",
+                escape(&keys(line)),
+                escape(line.description.as_deref().unwrap_or("")),
+                escape(&line.action.to_string())
+            )?;
+        }
+        writeln!(out, "</table>")?;
+    }
+
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")
+}
+
+/// Writes a plain text table of every binding in a configuration:
+/// its keys, its action, and where it was defined, with the columns
+/// lined up. Bindings are grouped the same way as in `write_html`.
+pub fn write_text(config: &Config, out: &mut dyn Write) -> io::Result<()> {
+    let rows: Vec<(String, Vec<[String; 3]>)> = groups(config)
+        .into_iter()
+        .map(|(mode, prefix, lines)| {
+            let rows = lines
+                .into_iter()
+                .map(|line| {
+                    [
+                        keys(line),
+                        line.action.to_string(),
+                        line.location.to_string(),
+                    ]
+                })
+                .collect();
+            let heading = match (mode, prefix.is_empty()) {
+                (None, true) => "Single keys".to_string(),
+                (None, false) => prefix,
+                (Some(mode), true) => format!("Mode {}", mode),
+                (Some(mode), false) => format!("Mode {}, {}", mode, prefix),
+            };
+            (heading, rows)
+        })
+        .collect();
+
+    // Line the columns up across every group, not just within each.
+    let width = |col: usize| {
+        rows.iter()
+            .flat_map(|(_, rows)| rows.iter())
+            .map(|row| row[col].chars().count())
+            .max()
+            .unwrap_or(0)
+    };
+    let (keys_width, action_width) = (width(0), width(1));
+
+    for (idx, (heading, rows)) in rows.iter().enumerate() {
+        if idx > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "{}:", heading)?;
+        for [keys, action, location] in rows {
+            writeln!(
+                out,
+                "  {:keys_width$}  {:action_width$}  {}",
+                keys,
+                action,
+                location,
+                keys_width = keys_width,
+                action_width = action_width
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the keys of a binding as they're shown in a cheat sheet.
+fn keys(line: &ConfigLine) -> String {
+    if line.double_tap {
+        format!("2x {}", line.keyseq)
+    } else if line.release {
+        format!("release {}", line.keyseq)
+    } else if line.repeat_while_held {
+        format!("held {}", line.keyseq)
+    } else {
+        line.keyseq.to_string()
+    }
+}
+
+/// A group of bindings in a cheat sheet: the mode they're in, if
+/// any, the keys they all start with, and the bindings.
+type Group<'a> = (Option<&'a str>, String, Vec<&'a ConfigLine>);
+
+/// Groups the bindings in a configuration by their mode and every key
+/// but their last, in the order each group first appears in the file.
+fn groups(config: &Config) -> Vec<Group<'_>> {
+    let mut groups: Vec<Group> = Vec::new();
+    for line in &config.commands {
+        let keys = &line.keyseq.keys;
+        let prefix = keys[..keys.len().saturating_sub(1)]
+            .iter()
+            .map(|key| key.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mode = line.mode.as_deref();
+        match groups
+            .iter_mut()
+            .find(|(m, p, _)| *m == mode && *p == prefix)
+        {
+            Some((_, _, lines)) => lines.push(line),
+            None => groups.push((mode, prefix, vec![line])),
+        }
+    }
+    groups
+}
+
+/// Escapes a string for use in HTML text.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::parse_config;
+    use std::io::BufReader;
+
+    #[test]
+    fn groups_test() {
+        let text = "# Open a file.\n\
+                    bind C-x C-f : emacs\n\
+                    \n\
+                    passthrough C-a\n\
+                    # Save it.\n\
+                    bind C-x C-s : save\n";
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+
+        let groups = groups(&config);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].1, "C-x");
+        assert_eq!(groups[0].2.len(), 2);
+        assert_eq!(groups[0].2[0].description.as_deref(), Some("Open a file."));
+        assert_eq!(groups[0].2[1].description.as_deref(), Some("Save it."));
+        assert_eq!(groups[1].1, "");
+        assert_eq!(groups[1].2[0].description, None);
+    }
+
+    #[test]
+    fn write_text_test() {
+        let text = "bind C-x C-f : emacs\n\
+                    passthrough C-a\n\
+                    map C-x b : C-x C-b\n\
+                    mode resize {\n\
+                    bind h : shrink\n\
+                    }\n";
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+
+        let mut out = Vec::new();
+        write_text(&config, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "C-x:\n\
+             \x20 C-x C-f  bind emacs   foo:1\n\
+             \x20 C-x b    map C-x C-b  foo:3\n\
+             \n\
+             Single keys:\n\
+             \x20 C-a      passthrough  foo:2\n\
+             \n\
+             Mode resize:\n\
+             \x20 h        bind shrink  foo:5\n"
+        );
+    }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!(escape("a <b> & \"c\""), "a &lt;b&gt; &amp; &quot;c&quot;");
+    }
+}
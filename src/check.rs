@@ -0,0 +1,501 @@
+// Configuration sanity checks.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cfgfile::{Action, Config, ConfigLine};
+use crate::keyseq::{Key, Keysym, Modifier};
+use crate::matcher::is_modifier_tap;
+use std::fmt;
+
+/// A problem with a configuration that doesn't stop it from being
+/// used, but that probably isn't what the user meant.
+#[derive(Debug)]
+pub enum Warning<'a> {
+    /// One binding's key sequence is a prefix of another's, so the
+    /// longer one can never be typed: the shorter one fires as soon
+    /// as its last key is pressed.
+    Unreachable {
+        /// The binding with the shorter key sequence.
+        prefix: &'a ConfigLine,
+
+        /// The binding that can't be reached.
+        shadowed: &'a ConfigLine,
+    },
+
+    /// The same key sequence is bound twice, so the second binding
+    /// never does anything: the first one always takes effect.
+    Duplicate {
+        /// The binding that takes effect.
+        first: &'a ConfigLine,
+
+        /// The binding that's ignored.
+        duplicate: &'a ConfigLine,
+    },
+
+    /// A binding uses a keysym that the keyboard layout only produces
+    /// with a modifier held, such as `at` on a US layout, where it's
+    /// Shift-2. Keys are always read as their unshifted keysym, so
+    /// the binding never matches.
+    Shifted {
+        /// The binding using the keysym.
+        line: &'a ConfigLine,
+
+        /// The key in the binding that can't be typed.
+        key: Key,
+
+        /// The level the keysym is at (1 for shifted, and higher for
+        /// levels reached with other modifiers).
+        level: usize,
+
+        /// The keysym the same key produces when unshifted.
+        base: Keysym,
+    },
+
+    /// A binding holds the modifier that AltGr sets on the keyboard
+    /// layout, such as `mod5-e` where AltGr is Mod5. Pressing AltGr is
+    /// always read as AltGr, so the binding never matches.
+    HoldsAltGr {
+        /// The binding holding the modifier.
+        line: &'a ConfigLine,
+
+        /// The key in the binding that holds it.
+        key: Key,
+
+        /// The modifier AltGr sets.
+        altgr: Modifier,
+    },
+
+    /// An `enter-mode` binding names a mode that no `mode` block
+    /// defines, so it can't do anything.
+    UnknownMode {
+        /// The `enter-mode` binding.
+        line: &'a ConfigLine,
+
+        /// The mode it names.
+        mode: &'a str,
+    },
+}
+
+impl fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Unreachable { prefix, shadowed } => write!(
+                f,
+                "{}: \"{}\" can never be typed, because \"{}\" is bound at {} \
+                 and fires as soon as it's pressed",
+                shadowed.location, shadowed.keyseq, prefix.keyseq, prefix.location
+            ),
+            Warning::Duplicate { first, duplicate } => write!(
+                f,
+                "{}: \"{}\" is already bound at {}, so this binding is ignored",
+                duplicate.location, duplicate.keyseq, first.location
+            ),
+            Warning::Shifted {
+                line,
+                key,
+                level: 1,
+                base,
+            } => {
+                let mut suggestion = Key {
+                    main_key: *base,
+                    ..*key
+                };
+                suggestion.modifiers.mod_shift = true;
+                write!(
+                    f,
+                    "{}: \"{}\" needs Shift on this keyboard layout, so it will never \
+                     match; try \"{}\" instead",
+                    line.location, key, suggestion
+                )
+            }
+            // The symbols on a key reached with AltGr come after the
+            // ones for the second keyboard group.
+            Warning::Shifted {
+                line,
+                key,
+                level: level @ (4 | 5),
+                base,
+            } => {
+                let mut suggestion = Key {
+                    main_key: *base,
+                    ..*key
+                };
+                suggestion.modifiers.mod_altgr = true;
+                suggestion.modifiers.mod_shift |= *level == 5;
+                write!(
+                    f,
+                    "{}: \"{}\" needs AltGr on this keyboard layout, so it will never \
+                     match; try \"{}\" instead",
+                    line.location, key, suggestion
+                )
+            }
+            Warning::Shifted { line, key, .. } => write!(
+                f,
+                "{}: \"{}\" can't be typed on this keyboard layout without extra \
+                 modifiers, so it will never match",
+                line.location, key
+            ),
+            Warning::HoldsAltGr { line, key, altgr } => {
+                let suggestion = Key {
+                    modifiers: key.modifiers.with_altgr(*altgr),
+                    ..*key
+                };
+                write!(
+                    f,
+                    "{}: \"{}\" holds \"{}\", which AltGr sets on this keyboard layout, so \
+                     it will never match; try \"{}\" instead",
+                    line.location, key, altgr, suggestion
+                )
+            }
+            Warning::UnknownMode { line, mode } => write!(
+                f,
+                "{}: there are no bindings in mode \"{}\", so this binding does nothing",
+                line.location, mode
+            ),
+        }
+    }
+}
+
+/// Looks for likely mistakes in a configuration.
+pub fn check(config: &Config) -> Vec<Warning<'_>> {
+    let num_lock = config.settings.num_lock();
+    let mut warnings = Vec::new();
+    for (i, duplicate) in config.commands.iter().enumerate() {
+        // Only compare against the earliest matching binding, so that
+        // a key sequence bound three times gives two warnings, not
+        // three.
+        if let Some(first) = config.commands[..i]
+            .iter()
+            .find(|first| is_duplicate(first, duplicate, num_lock))
+        {
+            warnings.push(Warning::Duplicate { first, duplicate });
+        }
+    }
+
+    for prefix in &config.commands {
+        for shadowed in &config.commands {
+            if is_strict_prefix(prefix, shadowed, num_lock) {
+                warnings.push(Warning::Unreachable { prefix, shadowed });
+            }
+        }
+    }
+
+    for line in &config.commands {
+        if let Action::EnterMode { mode } = &line.action {
+            if !config
+                .commands
+                .iter()
+                .any(|c| c.mode.as_ref() == Some(mode))
+            {
+                warnings.push(Warning::UnknownMode { line, mode });
+            }
+        }
+    }
+
+    warnings
+}
+
+/// Looks for keys in a configuration that can't be typed as written
+/// on the current keyboard layout. `keysym_level` gives the level a
+/// keysym is at and the unshifted keysym on the same key, as
+/// `X11Conn::keysym_level` does, and `altgr` is the modifier AltGr
+/// sets.
+pub fn check_layout<'a>(
+    config: &'a Config,
+    keysym_level: impl Fn(Keysym) -> Option<(usize, Keysym)>,
+    altgr: Modifier,
+) -> Vec<Warning<'a>> {
+    let mut warnings = Vec::new();
+    for line in &config.commands {
+        for key in &line.keyseq.keys {
+            if key.is_any() {
+                continue;
+            }
+            if key.canonical_modifier_order().contains(&altgr) {
+                warnings.push(Warning::HoldsAltGr {
+                    line,
+                    key: *key,
+                    altgr,
+                });
+            }
+            if let Some((level, base)) = keysym_level(key.main_key) {
+                // Shifted letters are fine: they're matched by the
+                // letter typed with Shift.
+                let shifted_letter = level == 1
+                    && key.modifiers.mod_shift
+                    && base.to_uppercase() == Some(key.main_key);
+                if level > 0 && base != key.main_key && !shifted_letter {
+                    warnings.push(Warning::Shifted {
+                        line,
+                        key: *key,
+                        level,
+                        base,
+                    });
+                }
+            }
+        }
+    }
+
+    warnings
+}
+
+/// Checks whether two bindings are for typing the same keys in the
+/// same way, on the same keyboard, in the same mode.
+fn is_duplicate(first: &ConfigLine, line: &ConfigLine, num_lock: Modifier) -> bool {
+    first.device == line.device
+        && first.mode == line.mode
+        && first.double_tap == line.double_tap
+        && first.release == line.release
+        && first.keyseq.keys.len() == line.keyseq.keys.len()
+        && first
+            .keyseq
+            .keys
+            .iter()
+            .zip(line.keyseq.keys.iter())
+            .all(|(a, b)| a.without_locks(num_lock) == b.without_locks(num_lock))
+}
+
+/// Checks whether the key sequence of `prefix` is a prefix of, but
+/// not the same as, the key sequence of `line`, where both can be
+/// typed on the same keyboard in the same mode, and `prefix` fires
+/// when its key is pressed. A `2x` binding only fires on a second
+/// tap, and a lone modifier only once it's released untouched, so
+/// neither gets in the way of longer sequences.
+fn is_strict_prefix(prefix: &ConfigLine, line: &ConfigLine, num_lock: Modifier) -> bool {
+    if prefix.release
+        || prefix.double_tap
+        || is_modifier_tap(prefix)
+        || prefix.mode != line.mode
+        || prefix.device.is_some() && prefix.device != line.device
+    {
+        return false;
+    }
+
+    let prefix = &prefix.keyseq.keys;
+    let keys = &line.keyseq.keys;
+    prefix.len() < keys.len()
+        && prefix
+            .iter()
+            .zip(keys.iter())
+            .all(|(a, b)| a.without_locks(num_lock) == b.without_locks(num_lock))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::parse_config;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+
+    /// Parses a configuration file from a string.
+    fn parse_str(text: &str) -> Config {
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+        config
+    }
+
+    #[test]
+    fn prefix_conflict_test() {
+        let config = parse_str(
+            "\
+bind a b : foo
+bind a : bar
+bind C-a b : baz
+",
+        );
+        let warnings: Vec<_> = check(&config).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "foo:1: \"a b\" can never be typed, because \"a\" is bound at foo:2 \
+                 and fires as soon as it's pressed"
+            ]
+        );
+
+        // No conflicts here.
+        let config = parse_str("bind a b : foo\nbind a c : bar\nbind b : baz\n");
+        assert!(check(&config).is_empty());
+
+        // Nor here, since the keys are on different keyboards.
+        let config = parse_str("bind device 2 a : foo\nbind device 3 a b : bar\nbind a c : baz");
+        assert!(check(&config).is_empty());
+
+        // Nor here, since a double tap only fires on the second tap.
+        let config = parse_str("bind 2x a : foo\nbind a b : bar\n");
+        assert!(check(&config).is_empty());
+
+        // Nor here, since a lone modifier only fires if it's released
+        // without another key pressed.
+        let config = parse_str("bind Super_L : foo\nbind Super_L a : bar\n");
+        assert!(check(&config).is_empty());
+    }
+
+    #[test]
+    fn shadowed_and_duplicate_test() {
+        let config = parse_str("bind C-x : a\nbind C-x C-f : b\nbind C-x : c\n");
+        let warnings: Vec<_> = check(&config).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "foo:3: \"C-x\" is already bound at foo:1, so this binding is ignored",
+                "foo:2: \"C-x C-f\" can never be typed, because \"C-x\" is bound at foo:1 \
+                 and fires as soon as it's pressed",
+                "foo:2: \"C-x C-f\" can never be typed, because \"C-x\" is bound at foo:3 \
+                 and fires as soon as it's pressed",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_test() {
+        let config = parse_str(
+            "\
+bind C-x : foo
+bind C-x : bar
+bind device 2 C-x : baz
+bind 2x C-x : quux
+map C-x : a
+",
+        );
+        let warnings: Vec<_> = check(&config).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "foo:2: \"C-x\" is already bound at foo:1, so this binding is ignored",
+                "foo:5: \"C-x\" is already bound at foo:1, so this binding is ignored",
+            ]
+        );
+
+        // Only the modifier Num Lock sets is left out of the
+        // comparison.
+        let config = parse_str("bind mod2-x : foo\nbind x : bar\n");
+        assert_eq!(check(&config).len(), 1);
+        let config = parse_str("set num-lock mod3\nbind mod2-x : foo\nbind x : bar\n");
+        assert!(check(&config).is_empty());
+    }
+
+    #[test]
+    fn included_location_test() {
+        let dir = env::temp_dir().join(format!("ahkd-check-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.conf");
+        let media = dir.join("media.conf");
+        fs::write(&main, "bind C-x : foo\ninclude media.conf\n").unwrap();
+        fs::write(&media, "\nbind C-x : bar\nbind C-x C-f : baz\n").unwrap();
+
+        let mut config = Config::default();
+        let main_name = main.to_str().unwrap();
+        let file = File::open(&main).unwrap();
+        parse_config(&mut config, BufReader::new(file), main_name).unwrap();
+
+        // Each binding is reported against the file it's in.
+        let media_name = media.to_str().unwrap();
+        let warnings: Vec<_> = check(&config).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                format!(
+                    "{}:2: \"C-x\" is already bound at {}:1, so this binding is ignored",
+                    media_name, main_name
+                ),
+                format!(
+                    "{}:3: \"C-x C-f\" can never be typed, because \"C-x\" is bound at \
+                     {}:1 and fires as soon as it's pressed",
+                    media_name, main_name
+                ),
+                format!(
+                    "{}:3: \"C-x C-f\" can never be typed, because \"C-x\" is bound at \
+                     {}:2 and fires as soon as it's pressed",
+                    media_name, media_name
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mode_test() {
+        let config = parse_str(
+            "\
+bind h : foo
+enter-mode C-r : resize
+enter-mode C-m : move
+mode resize {
+    bind h : bar
+    bind h l : baz
+}
+",
+        );
+        let warnings: Vec<_> = check(&config).iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "foo:6: \"h l\" can never be typed, because \"h\" is bound at foo:5 \
+                 and fires as soon as it's pressed",
+                "foo:3: there are no bindings in mode \"move\", so this binding does nothing",
+            ]
+        );
+    }
+
+    #[test]
+    fn shifted_test() {
+        // A US-style layout with "2" and "@" on one key, and "a", "A"
+        // and "ä" on another, the latter at level 2 (with AltGr).
+        let keysym_level = |keysym: Keysym| match keysym.0 {
+            0x32 => Some((0, Keysym(0x32))),
+            0x40 => Some((1, Keysym(0x32))),
+            0x41 => Some((1, Keysym(0x61))),
+            0x61 => Some((0, Keysym(0x61))),
+            0xe4 => Some((2, Keysym(0x61))),
+            // And "e", with "€" and "¢" reached with AltGr.
+            0x65 => Some((0, Keysym(0x65))),
+            0x20ac => Some((4, Keysym(0x65))),
+            0xa2 => Some((5, Keysym(0x65))),
+            _ => None,
+        };
+
+        let config = parse_str(
+            "bind C-at : foo\nbind a adiaeresis : bar\nbind 2 A : baz\n\
+             bind M-EuroSign : qux\nbind cent : quux\nbind AltGr-e : quuux\n\
+             bind mod5-e : quuuux\n",
+        );
+        let warnings: Vec<_> = check_layout(&config, keysym_level, Modifier::Mod5)
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "foo:1: \"C-at\" needs Shift on this keyboard layout, so it will never \
+                 match; try \"C-S-2\" instead",
+                "foo:2: \"adiaeresis\" can't be typed on this keyboard layout without \
+                 extra modifiers, so it will never match",
+                "foo:4: \"M-EuroSign\" needs AltGr on this keyboard layout, so it will \
+                 never match; try \"M-AltGr-e\" instead",
+                "foo:5: \"cent\" needs AltGr on this keyboard layout, so it will never \
+                 match; try \"S-AltGr-e\" instead",
+                "foo:7: \"mod5-e\" holds \"mod5\", which AltGr sets on this keyboard \
+                 layout, so it will never match; try \"AltGr-e\" instead",
+            ]
+        );
+
+        // Elsewhere, Mod5 is just another modifier.
+        assert_eq!(check_layout(&config, keysym_level, Modifier::Mod3).len(), 4);
+    }
+}
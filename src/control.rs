@@ -0,0 +1,240 @@
+// A socket for controlling a running daemon.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long a client gets to send its request, and to take our
+/// response, before we give up on it. Keys aren't being listened for
+/// in the meantime, so this has to be short.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest request we'll read, in bytes, including the newline.
+const MAX_REQUEST: u64 = 4096;
+
+/// A request sent over the control socket. Each is a single line of
+/// text: a command, then its argument, if it has one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    /// `reload`: read the configuration again, as on SIGHUP.
+    Reload,
+
+    /// `list`: send back the table of bindings `--list` prints.
+    List,
+
+    /// `trigger KEYS`: act as if the key sequence had been typed.
+    Trigger(String),
+}
+
+impl Request {
+    /// Parses a line of a request, without its newline. Fails with a
+    /// message for the client if it isn't a request we understand.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "" => Err("Expected a command".to_string()),
+            "reload" | "list" if !arg.is_empty() => {
+                Err(format!("\"{}\" doesn't take any arguments", command))
+            }
+            "reload" => Ok(Request::Reload),
+            "list" => Ok(Request::List),
+            "trigger" if arg.is_empty() => Err("\"trigger\" requires a key sequence".to_string()),
+            "trigger" => Ok(Request::Trigger(arg.to_string())),
+            _ => Err(format!("Unrecognized command \"{}\"", command)),
+        }
+    }
+}
+
+/// A Unix socket that clients connect to in order to send the daemon
+/// requests. The socket file is removed when this is dropped.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Starts listening at `path`. A socket left behind there by a
+    /// daemon that's no longer running is replaced, but one that's
+    /// still in use is not, and nor is anything at `path` that isn't a
+    /// socket, such as a file given by mistake.
+    pub fn bind(path: &str) -> io::Result<Self> {
+        let listener = match UnixListener::bind(path) {
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() =>
+            {
+                if !fs::symlink_metadata(path)?.file_type().is_socket() {
+                    return Err(err);
+                }
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            result => result?,
+        };
+        // We only look for clients when the socket is readable, but
+        // one could give up between then and our accepting it.
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.into(),
+        })
+    }
+
+    /// Gets the socket's file descriptor, which becomes readable when
+    /// a client connects.
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Accepts a client that's waiting to connect, if there is one.
+    pub fn accept(&self) -> io::Result<Option<Client>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+                Ok(Some(Client { stream }))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        // There's nothing to be done if this fails, and we're on our
+        // way out anyway.
+        let _ignored = fs::remove_file(&self.path);
+    }
+}
+
+/// A client connected to the control socket. Each client gets to
+/// make one request, and is disconnected once it's been answered.
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Reads the client's request. Fails with a message for the
+    /// client if the request is malformed, and with an I/O error if
+    /// the client can't be read from, such as if it takes too long to
+    /// send anything.
+    pub fn request(&mut self) -> io::Result<Result<Request, String>> {
+        let mut line = Vec::new();
+        BufReader::new((&self.stream).take(MAX_REQUEST)).read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            return Ok(Err(if line.len() as u64 == MAX_REQUEST {
+                format!("Request is longer than {} bytes", MAX_REQUEST)
+            } else {
+                "Request doesn't end with a newline".to_string()
+            }));
+        }
+        line.pop();
+        Ok(match String::from_utf8(line) {
+            Ok(line) => Request::parse(&line),
+            Err(_) => Err("Request isn't valid UTF-8".to_string()),
+        })
+    }
+
+    /// Sends the response to the client's request: `ok` and a newline,
+    /// then any output, if the request succeeded, or `error`, a space,
+    /// the message and a newline if it failed. The connection is
+    /// closed afterwards, which marks the end of the output.
+    pub fn respond(mut self, response: Result<String, String>) -> io::Result<()> {
+        match response {
+            Ok(output) => write!(self.stream, "ok\n{}", output),
+            Err(msg) => writeln!(self.stream, "error {}", msg.replace('\n', " ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Request::parse("reload"), Ok(Request::Reload));
+        assert_eq!(Request::parse("  list "), Ok(Request::List));
+        assert_eq!(
+            Request::parse("trigger  C-x C-f"),
+            Ok(Request::Trigger("C-x C-f".to_string()))
+        );
+
+        assert_eq!(Request::parse(""), Err("Expected a command".to_string()));
+        assert_eq!(
+            Request::parse("reload now"),
+            Err("\"reload\" doesn't take any arguments".to_string())
+        );
+        assert_eq!(
+            Request::parse("trigger"),
+            Err("\"trigger\" requires a key sequence".to_string())
+        );
+        assert_eq!(
+            Request::parse("quit"),
+            Err("Unrecognized command \"quit\"".to_string())
+        );
+    }
+
+    #[test]
+    fn socket_test() {
+        let path = env::temp_dir().join(format!("ahkd-control-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let socket = ControlSocket::bind(path).unwrap();
+        assert!(socket.accept().unwrap().is_none());
+
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(b"list\n").unwrap();
+        let mut client = socket.accept().unwrap().unwrap();
+        assert_eq!(client.request().unwrap(), Ok(Request::List));
+        client.respond(Ok("C-a\n".to_string())).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "ok\nC-a\n");
+
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream.write_all(b"list").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut client = socket.accept().unwrap().unwrap();
+        let request = client.request().unwrap();
+        client.respond(request.map(|_| String::new())).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "error Request doesn't end with a newline\n");
+
+        drop(socket);
+        assert!(!std::path::Path::new(path).exists());
+
+        // Something that isn't a socket is left alone.
+        fs::write(path, "notes").unwrap();
+        assert!(ControlSocket::bind(path).is_err());
+        assert_eq!(fs::read_to_string(path).unwrap(), "notes");
+        fs::remove_file(path).unwrap();
+    }
+}
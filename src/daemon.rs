@@ -16,141 +16,2000 @@
 // You should have received a copy of the GNU General Public License
 // along with ahkd. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::cfgfile::{Action, Config, ConfigLine};
-use crate::keyseq::{Key, KeySequence};
-use crate::x11::X11Conn;
-use std::convert::Infallible;
+use crate::cfgfile::{Action, Config, ConfigLine, LineText, Settings};
+use crate::cheatsheet::write_text;
+use crate::check::check_layout;
+use crate::control::{ControlSocket, Request};
+use crate::keyseq::{Key, KeySequence, Keysym, Modifier};
+use crate::log::{self, debug, verbose};
+use crate::matcher::{is_modifier_tap, MatchResult, Matcher};
+use crate::notify::notify;
+use crate::popup;
+use crate::reaper::{Job, Reaper};
+use crate::watchdog::Watchdog;
+use crate::x11::{Grab, GrabTarget, Held, KeySource, Pointer, X11Conn};
+use crate::AhkdError;
+use nix::fcntl::OFlag;
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{self, getpid, setsid};
+use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
-use std::process::Command;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+use x11rb::errors::{ConnectError, ConnectionError, ReplyError};
+
+/// What the daemon should do when it loses its connection to the X
+/// server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDisconnect {
+    /// Give up, returning `AhkdError::Disconnected`.
+    Exit,
+
+    /// Keep trying to connect to the display again, then carry on
+    /// where we left off.
+    Reconnect,
+}
+
+/// How long to wait between attempts to reconnect to the X server.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait before the first retry when the X server isn't
+/// ready at startup; the wait doubles after each attempt, up to
+/// `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The longest to wait between retries at startup.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(4);
+
+/// Options controlling how the daemon runs, as opposed to what it
+/// does, which is up to the configuration file.
+#[derive(Debug, Clone, Copy)]
+pub struct DaemonOptions<'a> {
+    /// The X11 display to connect to, or None for the default.
+    pub display_name: Option<&'a str>,
+
+    /// Where to grab keys.
+    pub target: GrabTarget,
+
+    /// What to do if the connection to the X server is lost.
+    pub on_disconnect: OnDisconnect,
+
+    /// How many more times to try connecting to the X server at
+    /// startup, if it isn't ready yet.
+    pub retries: u32,
+
+    /// Whether to print how long we take to act on each key
+    /// sequence.
+    pub timing: bool,
+
+    /// Whether to show the keys typed so far, and the bindings they
+    /// could lead to, in the terminal as they're typed.
+    pub tui: bool,
+
+    /// If set, how long to wait for the user to press `CONFIRM_KEY`
+    /// before grabbing any keys, exiting if they don't.
+    pub safe_mode: Option<Duration>,
+
+    /// Whether to fork into the background once we've connected to
+    /// the X server and checked the configuration against it.
+    pub background: bool,
+
+    /// Where to write our process ID, if anywhere. The file is removed
+    /// again when we exit.
+    pub pid_file: Option<&'a str>,
+
+    /// Where to listen for requests from other programs, if anywhere
+    /// (see `control`). The socket is removed again when we exit.
+    pub socket: Option<&'a str>,
+}
+
+/// The key to press to have `--safe-mode` go ahead.
+const CONFIRM_KEY: &str = "Return";
+
+/// Set by the SIGHUP handler to ask for the configuration to be
+/// reloaded.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Set by the SIGTERM and SIGINT handler to ask us to exit.
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+/// Set by any of the signal handlers, to stop us waiting for keys so
+/// that we can see to the signal.
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// The write end of the pipe the signal handlers write to after
+/// setting `INTERRUPT`, to wake us if we're waiting for keys; see
+/// `X11Conn::with_interrupt`.
+static INTERRUPT_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_signal(signal: c_int) {
+    if signal == Signal::SIGTERM as c_int || signal == Signal::SIGINT as c_int {
+        TERMINATE.store(true, Ordering::SeqCst);
+    } else {
+        RELOAD.store(true, Ordering::SeqCst);
+    }
+    INTERRUPT.store(true, Ordering::SeqCst);
+    // This can only fail if the pipe is full, in which case we'll be
+    // woken anyway.
+    let _ = unistd::write(INTERRUPT_PIPE.load(Ordering::SeqCst), &[0]);
+}
+
+/// Runs the daemon with the given configuration and options, until
+/// something goes wrong or we get SIGTERM or SIGINT, which is when
+/// this lets go of every grab and returns Ok. On SIGHUP, `reload` is
+/// called to read the configuration again, and if it succeeds, the
+/// new configuration replaces the old one.
+pub fn daemon(
+    cfg: Config,
+    options: DaemonOptions,
+    reload: &dyn Fn() -> Result<Config, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let DaemonOptions {
+        display_name,
+        target,
+        on_disconnect,
+        retries,
+        timing,
+        tui,
+        safe_mode,
+        background,
+        pid_file,
+        socket,
+    } = options;
+    let mut cfg = cfg;
+    let mut num_lock = cfg.settings.num_lock();
+    let conn = connect(display_name, target, retries)?
+        .with_num_lock(num_lock)
+        .with_raw_keycodes(raw_keycodes(&cfg))
+        .with_synthetic_events(cfg.settings.synthetic_events);
+    conn.listen_for_raw_keys(wants_raw_keys(&cfg))?;
+    if let Some(timeout) = safe_mode {
+        confirm(&conn, timeout)?;
+    }
+
+    for warning in check_layout(&cfg, |keysym| conn.keysym_level(keysym), conn.altgr()) {
+        eprintln!("Warning: {}", warning);
+    }
+    // Check this again whenever we connect, since a new X server may
+    // have a different keyboard layout.
+    check_available(&cfg, |keysym| conn.keysym_level(keysym).is_some())?;
+
+    // Nobody else can be using the socket once we're in the
+    // background, but that's too late to hear about it.
+    let control = match socket {
+        Some(path) => Some(ControlSocket::bind(path)?),
+        None => None,
+    };
+
+    // Anything that's wrong at startup has been reported by now, while
+    // the user can still see it. This has to happen before any
+    // threads are started, since only the forking thread survives.
+    // We stay in the same directory, so that reloading can find the
+    // configuration files, and keep our output going to the same
+    // place.
+    if background {
+        unistd::daemon(true, true)?;
+    }
+    // Dropping this removes the file, so it's gone on any exit other
+    // than being killed outright.
+    let _pid_file = match pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+
+    // Don't take over these signals until we're ready to handle them,
+    // so that before then they still have their usual effect.
+    let action = SigAction::new(
+        SigHandler::Handler(handle_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // Neither end may block: the handler mustn't, and we read until
+    // the pipe is empty.
+    let (interrupt_pipe, pipe_write) = unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+    INTERRUPT_PIPE.store(pipe_write, Ordering::SeqCst);
+    // Safety: the handler only stores to atomics and writes to a
+    // pipe, which are async-signal-safe.
+    unsafe {
+        sigaction(Signal::SIGHUP, &action)?;
+        sigaction(Signal::SIGTERM, &action)?;
+        sigaction(Signal::SIGINT, &action)?;
+    }
+    let conn = conn.with_interrupt(&INTERRUPT, interrupt_pipe);
+
+    // Stop waiting for keys when a client connects to the socket, so
+    // that we can answer it.
+    let wake = |conn: X11Conn| match &control {
+        Some(control) => conn.with_wake_fd(control.fd()),
+        None => conn,
+    };
+
+    let watchdog = match cfg.settings.watchdog {
+        Some(timeout) => Some(Watchdog::spawn(conn.display_name(), timeout)?),
+        None => None,
+    };
+    let watch = |conn: X11Conn| match &watchdog {
+        Some(watchdog) => conn.with_watchdog(Arc::clone(watchdog)),
+        None => conn,
+    };
+
+    // Waits for the commands bindings run, for as long as we run.
+    let reaper = Reaper::spawn();
+
+    let mut conn = watch(wake(conn));
+
+    // Start whatever the configuration wants running alongside us,
+    // now that we're ready for keys. This only happens once, not on
+    // every reload or reconnection.
+    for action in &cfg.startup {
+        verbose!("on-startup: {}", action);
+        run_commands(&conn, &cfg.settings, action, &[], &reaper);
+    }
 
-/// Runs the daemon with the given configuration and on the given X11
-/// display (or the default display if none is specified).
-pub fn daemon(cfg: Config, display_name: Option<&str>) -> Result<Infallible, Box<dyn Error>> {
-    let conn = X11Conn::new(display_name)?;
-    let init_keys = get_init_keys(&cfg);
     loop {
-        let mut seen_keys = vec![conn.next_key(&init_keys)?];
-        loop {
-            match get_prefixes(&cfg, &seen_keys) {
-                PrefixState::Prefix => {
-                    seen_keys.push(conn.next_key_kbd()?);
+        let init_keys = get_init_keys(&cfg);
+        for grab in &init_keys {
+            verbose!(
+                "Grabbing {}{}",
+                grab.key,
+                if grab.sync { " synchronously" } else { "" }
+            );
+        }
+        let err = match serve(
+            &conn,
+            &cfg,
+            &init_keys,
+            control.as_ref(),
+            &reaper,
+            timing,
+            tui,
+        ) {
+            Ok(()) => {
+                INTERRUPT.store(false, Ordering::SeqCst);
+                if TERMINATE.load(Ordering::SeqCst) {
+                    release(&conn)?;
+                    return Ok(());
                 }
-                PrefixState::None => {
-                    break;
+                if !RELOAD.swap(false, Ordering::SeqCst) {
+                    continue;
+                }
+                let new_cfg = reload().and_then(|new_cfg| {
+                    check_available(&new_cfg, |keysym| conn.keysym_level(keysym).is_some())?;
+                    Ok(new_cfg)
+                });
+                match new_cfg {
+                    Ok(new_cfg) => {
+                        cfg = new_cfg;
+                        for warning in
+                            check_layout(&cfg, |keysym| conn.keysym_level(keysym), conn.altgr())
+                        {
+                            eprintln!("Warning: {}", warning);
+                        }
+                        // Keys are grabbed along with Num Lock's mask,
+                        // so if that's moved, every grab has to be
+                        // made afresh; otherwise only the keys that
+                        // changed are, once `serve` gets going.
+                        if cfg.settings.num_lock() != num_lock {
+                            conn.hold_grabs(&[])?;
+                        }
+                        num_lock = cfg.settings.num_lock();
+                        conn = conn
+                            .with_num_lock(num_lock)
+                            .with_raw_keycodes(raw_keycodes(&cfg))
+                            .with_synthetic_events(cfg.settings.synthetic_events);
+                        conn.listen_for_raw_keys(wants_raw_keys(&cfg))?;
+                        eprintln!("Reloaded the configuration");
+                    }
+                    Err(err) => {
+                        eprintln!("Error reloading the configuration: {}", err);
+                        eprintln!("Keeping the old configuration");
+                    }
+                }
+                continue;
+            }
+            Err(err) => err,
+        };
+        if !is_disconnect(err.as_ref()) {
+            return Err(err);
+        }
+
+        match on_disconnect {
+            OnDisconnect::Exit => return Err(Box::new(AhkdError::Disconnected)),
+            OnDisconnect::Reconnect => {
+                eprintln!("Lost the connection to the X server: {}", err);
+                conn = watch(wake(
+                    reconnect(display_name, target)
+                        .with_num_lock(num_lock)
+                        .with_raw_keycodes(raw_keycodes(&cfg))
+                        .with_synthetic_events(cfg.settings.synthetic_events)
+                        .with_interrupt(&INTERRUPT, interrupt_pipe),
+                ));
+                eprintln!("Reconnected to the X server");
+                conn.listen_for_raw_keys(wants_raw_keys(&cfg))?;
+                check_available(&cfg, |keysym| conn.keysym_level(keysym).is_some())?;
+            }
+        }
+    }
+}
+
+/// A file holding our process ID, which is removed when this is
+/// dropped.
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes our process ID to the file at `path`, replacing
+    /// anything that's already there.
+    fn create(path: &str) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", getpid()))?;
+        Ok(Self { path: path.into() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // There's nothing to be done if this fails, and we're on our
+        // way out anyway.
+        let _ignored = fs::remove_file(&self.path);
+    }
+}
+
+/// Waits for the user to press `CONFIRM_KEY`, failing if they don't
+/// within `timeout`. Nothing else is grabbed in the meantime, so if
+/// the user can't get to the keyboard (say, because they're logged
+/// in remotely), a configuration that grabs keys they need can't lock
+/// them out.
+fn confirm(conn: &X11Conn, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let grab = Grab {
+        key: Key::try_from(LineText::new("<safe mode>", 1, CONFIRM_KEY))?,
+        sync: false,
+    };
+
+    eprintln!(
+        "Press {} within {} seconds to start ahkd",
+        CONFIRM_KEY,
+        timeout.as_secs()
+    );
+    match conn.next_key_until(&[grab], Instant::now() + timeout)? {
+        Some(_) => Ok(()),
+        None => Err(Box::new(AhkdError::NotConfirmed)),
+    }
+}
+
+/// Keeps trying to connect to the X server until it works.
+fn reconnect(display_name: Option<&str>, target: GrabTarget) -> X11Conn {
+    loop {
+        thread::sleep(RECONNECT_DELAY);
+        if let Ok(conn) = X11Conn::new_for(display_name, target) {
+            return conn;
+        }
+    }
+}
+
+/// Connects to the X server, trying again up to `retries` times, a
+/// little longer apart each time, if it isn't ready yet; e.g. because
+/// we've been started along with it by a display manager.
+fn connect(
+    display_name: Option<&str>,
+    target: GrabTarget,
+    retries: u32,
+) -> Result<X11Conn, Box<dyn Error>> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match X11Conn::new_for(display_name, target) {
+            Err(err) if attempt < retries && is_not_ready(err.as_ref()) => {
+                attempt += 1;
+                verbose!(
+                    "Can't connect to the X server yet ({}); trying again in {} ms \
+                     (retry {} of {})",
+                    err,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Determines whether an error connecting to the X server means that
+/// it isn't there yet, as opposed to it refusing us.
+fn is_not_ready(err: &(dyn Error + 'static)) -> bool {
+    // With no display to connect to yet, there's no display name
+    // to parse. A name that doesn't parse, though, never will.
+    if let Some(AhkdError::NoDisplay(name)) = err.downcast_ref::<AhkdError>() {
+        return name.is_none();
+    }
+    match err.downcast_ref::<ConnectError>() {
+        Some(ConnectError::IOError(err)) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+        ),
+        _ => false,
+    }
+}
+
+/// Listens for key sequences on an X11 connection and acts on them,
+/// until something goes wrong or a signal interrupts us, which is
+/// when this returns Ok. Requests on `control` are answered in
+/// between key sequences, and commands are left to `reaper` to wait
+/// for. If `timing` is set, prints how long it took to act on each
+/// one, counting from when we got its last key. If `tui` is set,
+/// shows each key sequence in the terminal as it's typed.
+fn serve(
+    conn: &X11Conn,
+    cfg: &Config,
+    init_keys: &[Grab],
+    control: Option<&ControlSocket>,
+    reaper: &Reaper,
+    timing: bool,
+    tui: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut matcher = Matcher::new(cfg);
+    // The modes entered so far, the innermost last. A reload, which
+    // comes back through here, starts over with none.
+    let mut modes: Vec<&str> = Vec::new();
+    // A key pressed while we were waiting for a lone modifier to be
+    // tapped, which starts the next key sequence.
+    let mut chord = None;
+    if tui {
+        show_progress(&matcher, Some(&MatchResult::None));
+    }
+    loop {
+        let key = if let Some(key) = chord.take() {
+            key
+        } else if modes.is_empty() {
+            match conn.next_key(init_keys)? {
+                Some(key) => key,
+                None if INTERRUPT.load(Ordering::SeqCst) => return Ok(()),
+                None => {
+                    // A client has connected to the control socket.
+                    if let Some(control) = control {
+                        handle_control(conn, cfg, control, reaper, &mut modes);
+                        matcher.set_mode(modes.last().copied());
+                    }
+                    continue;
+                }
+            }
+        } else {
+            // A mode has the whole keyboard. Let go of it every so
+            // often, so that the watchdog doesn't mistake waiting in
+            // a mode for being stuck.
+            let deadline = cfg.settings.watchdog.map(|t| Instant::now() + t / 2);
+            // Holding a key down in a mode repeats its binding, as
+            // it does outside of one. Clients are answered in a mode
+            // too, since there's no telling how long it'll last.
+            match conn.next_key_kbd(deadline, true, false, true)? {
+                Some(key) => key,
+                None if INTERRUPT.load(Ordering::SeqCst) => return Ok(()),
+                None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => continue,
+                None if control.is_some_and(|control| {
+                    handle_control(conn, cfg, control, reaper, &mut modes)
+                }) =>
+                {
+                    matcher.set_mode(modes.last().copied());
+                    continue;
+                }
+                None => {
+                    // Some other program wants the keyboard, such as
+                    // a screen locker; don't fight it for the
+                    // keyboard once it's done.
+                    verbose!("Leaving every mode, since the focus changed");
+                    modes.clear();
+                    matcher.set_mode(None);
+                    continue;
+                }
+            }
+        };
+        let mut received = timing.then(Instant::now);
+        let mut result = matcher.feed_press(key, conn.last_press());
+        log_result(&matcher, &result);
+        let seq_deadline = cfg.settings.sequence_timeout.map(|t| Instant::now() + t);
+        while let MatchResult::Prefix = result {
+            if tui {
+                show_progress(&matcher, Some(&result));
+            }
+            if cfg.settings.prefix_bell {
+                if let Err(err) = conn.bell() {
+                    eprintln!("Error ringing the bell: {}", err);
+                }
+            }
+            if cfg.settings.which_key {
+                if let Err(err) = conn.show_popup(popup::continuations(&matcher)) {
+                    eprintln!("Error showing the popup: {}", err);
+                }
+            }
+            let key_deadline = cfg.settings.key_timeout.map(|t| Instant::now() + t);
+            let skip_repeats = !cfg.settings.key_repeat;
+            let deadline = earliest(seq_deadline, key_deadline);
+            let next = loop {
+                // Let go of the keyboard every so often, as in a
+                // mode, so that the watchdog doesn't mistake a pause
+                // partway through a sequence for being stuck.
+                let check_in = cfg.settings.watchdog.map(|t| Instant::now() + t / 2);
+                // Clients wait until the sequence is over, rather
+                // than having a `trigger` land in the middle of it.
+                match conn.next_key_kbd(earliest(deadline, check_in), true, skip_repeats, false)? {
+                    None if !INTERRUPT.load(Ordering::SeqCst)
+                        && check_in.is_some_and(|check_in| Instant::now() >= check_in)
+                        && deadline.is_none_or(|deadline| Instant::now() < deadline) =>
+                    {
+                        continue
+                    }
+                    next => break next,
+                }
+            };
+            match next {
+                Some(key) => {
+                    received = timing.then(Instant::now);
+                    result = matcher.feed_press(key, conn.last_press());
+                    log_result(&matcher, &result);
                 }
-                PrefixState::Match(line) => {
-                    do_action(&line.action);
+                None => {
+                    debug!("Gave up waiting for the next key");
+                    // The user took too long; give up on the
+                    // sequence.
+                    if tui {
+                        show_progress(&matcher, None);
+                    }
+                    matcher.reset();
                     break;
                 }
             }
         }
+        if let Err(err) = conn.hide_popup() {
+            eprintln!("Error hiding the popup: {}", err);
+        }
+        if tui && !matcher.keys().is_empty() {
+            show_progress(&matcher, Some(&result));
+        }
+
+        let replay = replay_for(
+            &result,
+            !modes.is_empty(),
+            is_sync(init_keys, matcher.keys(), cfg.settings.num_lock()),
+            matcher.keys().len(),
+        );
+        if replay == Replay::Frozen {
+            conn.replay_frozen()?;
+        }
+        conn.thaw()?;
+        // A signal cut the key sequence short.
+        if INTERRUPT.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        if matches!(result, MatchResult::None)
+            && !modes.is_empty()
+            && cfg
+                .settings
+                .cancel_key()
+                .matches(&key, cfg.settings.num_lock())
+        {
+            // The cancel key on its own, if the mode doesn't bind it,
+            // is the way out of every mode at once.
+            verbose!("Leaving every mode");
+            modes.clear();
+            matcher.set_mode(None);
+        }
+
+        if replay == Replay::Synthetic {
+            // The user typed something after a synchronous key that
+            // doesn't go with it, so it was probably meant for some
+            // other program; pass it all on.
+            if let Err(err) = conn.replay_keys(matcher.keys()) {
+                eprintln!("Error passing keys through: {}", err);
+            }
+        }
+
+        if let MatchResult::Match(line) = result {
+            let matched = timing.then(Instant::now);
+            do_action(conn, cfg, line, matcher.keys(), reaper, &mut modes);
+            matcher.set_mode(modes.last().copied());
+            if let (Some(received), Some(matched)) = (received, matched) {
+                eprintln!(
+                    "{}: matched after {} µs, acted after {} µs",
+                    line.keyseq,
+                    (matched - received).as_micros(),
+                    received.elapsed().as_micros()
+                );
+            }
+            if line.repeat_while_held {
+                repeat_while_held(conn, &cfg.settings, line, matcher.keys(), reaper)?;
+            }
+        }
+
+        if let Some(line) = matcher.release_binding() {
+            debug!("{}: waiting for it to be released", line.keyseq);
+            // A lone modifier with some other key pressed while it's
+            // down was held for a chord, not tapped.
+            match conn.wait_while_held(None, is_modifier_tap(line))? {
+                Held::Released => {
+                    do_action(conn, cfg, line, matcher.keys(), reaper, &mut modes);
+                    matcher.set_mode(modes.last().copied());
+                }
+                Held::Pressed(key) => {
+                    debug!("{}: held for a chord, so not tapped", line.keyseq);
+                    chord = Some(key);
+                }
+                Held::StillDown | Held::Interrupted => return Ok(()),
+            }
+        }
+    }
+}
+
+/// When a `bind-repeat` binding whose key is still held should run
+/// again: `delay` after it first ran, then every `interval`. If a run
+/// is late, e.g. because starting the commands took a while, the runs
+/// it held up are skipped rather than made up for in a burst.
+struct RepeatTimer {
+    next: Instant,
+    interval: Duration,
+}
+
+impl RepeatTimer {
+    /// Starts the timer for a binding that first ran at `start`.
+    fn new(start: Instant, delay: Duration, interval: Duration) -> Self {
+        Self {
+            next: start + delay,
+            interval,
+        }
+    }
+
+    /// Gets when the binding should next run.
+    fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// Moves on to the run after the one just made at `now`.
+    fn advance(&mut self, now: Instant) {
+        // The interval is never 0, so this always ends.
+        while self.next <= now {
+            self.next += self.interval;
+        }
+    }
+}
+
+/// Runs the commands of a `bind-repeat` binding again and again, as
+/// the settings say, for as long as the last key of `keys` is held
+/// down, stopping once it's released or some other key is pressed.
+fn repeat_while_held(
+    conn: &X11Conn,
+    settings: &Settings,
+    line: &ConfigLine,
+    keys: &[Key],
+    reaper: &Reaper,
+) -> Result<(), Box<dyn Error>> {
+    let mut timer = RepeatTimer::new(
+        Instant::now(),
+        settings.bind_repeat_delay(),
+        settings.bind_repeat_interval(),
+    );
+    while conn.wait_while_held(Some(timer.deadline()), true)? == Held::StillDown {
+        debug!("{}: still held, so running it again", line.keyseq);
+        run_commands(conn, settings, &line.action, keys, reaper);
+        timer.advance(Instant::now());
+    }
+
+    Ok(())
+}
+
+/// Answers a client waiting on the control socket, if there is one,
+/// and returns whether there was. Anything that goes wrong is
+/// reported and otherwise ignored, since it's only the one client
+/// that's affected.
+fn handle_control<'a>(
+    conn: &X11Conn,
+    cfg: &'a Config,
+    control: &ControlSocket,
+    reaper: &Reaper,
+    modes: &mut Vec<&'a str>,
+) -> bool {
+    let mut client = match control.accept() {
+        Ok(Some(client)) => client,
+        // Nobody's there, or it gave up before we got to it.
+        Ok(None) => return false,
+        Err(err) => {
+            eprintln!("Error accepting a control connection: {}", err);
+            return true;
+        }
+    };
+    let response = match client.request() {
+        Ok(request) => {
+            request.and_then(|request| handle_request(conn, cfg, request, reaper, modes))
+        }
+        Err(err) => {
+            eprintln!("Error reading a control request: {}", err);
+            return true;
+        }
+    };
+    if let Err(err) = client.respond(response) {
+        eprintln!("Error answering a control request: {}", err);
+    }
+    true
+}
+
+/// Carries out a request from the control socket, returning the
+/// output to send back, or the error message if it can't be done.
+/// `reaper` and `modes` are as for `do_action`.
+fn handle_request<'a>(
+    conn: &X11Conn,
+    cfg: &'a Config,
+    request: Request,
+    reaper: &Reaper,
+    modes: &mut Vec<&'a str>,
+) -> Result<String, String> {
+    verbose!("Control request: {:?}", request);
+    match request {
+        Request::Reload => {
+            // The reload happens once we're back out in `daemon`, just
+            // as for SIGHUP; how it goes is reported there.
+            RELOAD.store(true, Ordering::SeqCst);
+            INTERRUPT.store(true, Ordering::SeqCst);
+            Ok(String::new())
+        }
+        Request::List => {
+            let mut out = Vec::new();
+            write_text(cfg, &mut out).map_err(|err| err.to_string())?;
+            Ok(String::from_utf8_lossy(&out).into_owned())
+        }
+        Request::Trigger(keys) => {
+            let keyseq = KeySequence::parse(LineText::new("<control>", 1, &keys), &cfg.settings)
+                .map_err(|err| err.message().to_string())?;
+            if keyseq.keys.iter().any(Key::is_any) {
+                return Err("Can't trigger <any>; name a key instead".to_string());
+            }
+            match chained_binding(cfg, &keyseq, modes.last().copied()) {
+                Some(line) => do_action(conn, cfg, line, &keyseq.keys, reaper, modes),
+                // Nothing would have caught the keys, so they'd have
+                // gone to whatever has the focus.
+                None => conn
+                    .send_keyseq(&keyseq)
+                    .map_err(|err| format!("Couldn't send keys: {}", err))?,
+            }
+            Ok(String::new())
+        }
+    }
+}
+
+/// Logs what the keys typed so far have led to, for `-vv`.
+fn log_result(matcher: &Matcher, result: &MatchResult) {
+    if !log::enabled(2) {
+        return;
+    }
+    let typed: Vec<_> = matcher.keys().iter().map(Key::to_string).collect();
+    let typed = typed.join(" ");
+    match result {
+        MatchResult::Prefix => debug!(
+            "{}: waiting for more keys, with {} binding(s) left",
+            typed,
+            matcher.candidates().len()
+        ),
+        MatchResult::Match(line) => debug!("{}: matched the binding at {}", typed, line.location),
+        MatchResult::None => debug!("{}: not bound", typed),
+        MatchResult::Cancelled => debug!("{}: cancelled", typed),
+    }
+}
+
+/// Redraws the terminal to show the keys typed so far, and either the
+/// bindings they could still lead to or what they finally did.
+/// `result` is what typing them led to, or None if the user took too
+/// long to finish.
+fn show_progress(matcher: &Matcher, result: Option<&MatchResult>) {
+    // Clear the screen, and go back to the top left.
+    let mut out = String::from("\x1b[2J\x1b[H");
+
+    let typed: Vec<_> = matcher.keys().iter().map(|key| key.to_string()).collect();
+    if let Some(mode) = matcher.mode() {
+        out += &format!("Mode: {}\n", mode);
+    }
+    out += &format!("Typed: {}\n\n", typed.join(" "));
+    match result {
+        Some(MatchResult::Prefix) => {
+            for line in matcher.candidates() {
+                out += &format!("  {} : {}\n", line.keyseq, line.action);
+            }
+        }
+        Some(MatchResult::Match(line)) => {
+            out += &format!("  {} ({})\n", line.action, line.location);
+        }
+        Some(MatchResult::None) if typed.is_empty() => out += "  Waiting for a key...\n",
+        Some(MatchResult::None) => out += "  Not bound\n",
+        Some(MatchResult::Cancelled) => out += "  Cancelled\n",
+        None => out += "  Timed out\n",
+    }
+
+    print!("{}", out);
+    let _ = io::stdout().flush();
+}
+
+/// How the keys of a key sequence get passed on to the focused window
+/// once it's clear they don't make up any binding.
+#[derive(Debug, PartialEq, Eq)]
+enum Replay {
+    /// They don't; they're swallowed.
+    Nothing,
+
+    /// The X server is still holding the keyboard frozen on the one
+    /// key typed, so it can deliver that key for real.
+    Frozen,
+
+    /// The keys are sent as synthetic events, like `passthrough`.
+    Synthetic,
+}
+
+/// Works out how to pass on the keys of a key sequence that's over,
+/// given how it ended, whether it was typed in a mode, whether its
+/// first key was grabbed synchronously, and how many keys were typed.
+/// Only keys that start with a synchronous key, outside of any mode,
+/// are passed on, since only then could they have been meant for some
+/// other program.
+fn replay_for(result: &MatchResult, in_mode: bool, sync: bool, typed: usize) -> Replay {
+    match result {
+        MatchResult::None if sync && !in_mode && typed == 1 => Replay::Frozen,
+        MatchResult::None if sync && !in_mode => Replay::Synthetic,
+        _ => Replay::Nothing,
+    }
+}
+
+/// Checks whether a key sequence the user typed was started by a key
+/// we grabbed synchronously, with Num Lock setting `num_lock`.
+fn is_sync(init_keys: &[Grab], keys: &[Key], num_lock: Modifier) -> bool {
+    match keys.first() {
+        Some(first) => init_keys
+            .iter()
+            .any(|grab| grab.sync && grab.key.matches(first, num_lock)),
+        None => false,
     }
 }
 
-/// Performs the action indicated by the Action structure.
-fn do_action(action: &Action) {
-    match action {
-        Action::Bind { command } => {
-            match Command::new(&command[0]).args(command[1..].iter()).spawn() {
-                Ok(mut handle) => {
-                    // Need to call `wait()` at some point because
-                    // Unix.
-                    thread::spawn(move || {
-                        // Ignore errors here. We don't care about the
-                        // return status of whatever the user had us
-                        // invoke, and dealing with errors there is
-                        // their problem.
-                        let _ignored = handle.wait();
-                    });
+/// Determines whether an error means we've lost the connection to
+/// the X server, as opposed to the server merely refusing a request.
+fn is_disconnect(err: &(dyn Error + 'static)) -> bool {
+    if err.is::<ConnectionError>() {
+        return true;
+    }
+    matches!(
+        err.downcast_ref::<ReplyError>(),
+        Some(ReplyError::ConnectionError(_))
+    )
+}
+
+/// Gets the earlier of two optional deadlines.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Performs the action indicated by a configuration line, whose key
+/// sequence the user typed as `keys`. Commands run in the background
+/// are handed to `reaper` to wait for. `modes` is the stack of modes
+/// entered so far, which `enter-mode` and `exit-mode` change.
+fn do_action<'a>(
+    conn: &X11Conn,
+    cfg: &'a Config,
+    line: &'a ConfigLine,
+    keys: &[Key],
+    reaper: &Reaper,
+    modes: &mut Vec<&'a str>,
+) {
+    do_chained_action(conn, cfg, line, keys, reaper, modes, &mut Vec::new());
+}
+
+/// Performs the action indicated by a configuration line like
+/// `do_action`, where `chain` is the `map chain` bindings that led to
+/// it, if any. A binding that comes up twice in the chain would go
+/// on forever, so is reported and abandoned.
+fn do_chained_action<'a>(
+    conn: &X11Conn,
+    cfg: &'a Config,
+    line: &'a ConfigLine,
+    keys: &[Key],
+    reaper: &Reaper,
+    modes: &mut Vec<&'a str>,
+    chain: &mut Vec<&'a ConfigLine>,
+) {
+    let settings = &cfg.settings;
+    verbose!("{}: {}", line.keyseq, line.action);
+    let target = match chain_step(cfg, line, modes.last().copied(), chain) {
+        Ok(target) => target,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+    match &line.action {
+        Action::Bind { .. } => run_commands(conn, settings, &line.action, keys, reaper),
+        Action::Map { to, repeat, .. } => match target {
+            Some(target) => {
+                chain.push(line);
+                for _ in 0..*repeat {
+                    do_chained_action(conn, cfg, target, &to.keys, reaper, modes, chain);
                 }
-                Err(err) => {
-                    println!("Error launching \"{}\": {}", &command[0], err);
+                chain.pop();
+            }
+            None => {
+                for _ in 0..*repeat {
+                    if let Err(err) = conn.send_keyseq(to) {
+                        eprintln!("Error sending keys: {}", err);
+                        return;
+                    }
                 }
             }
+        },
+        Action::Passthrough => {
+            if let Err(err) = conn.replay_keys(keys) {
+                eprintln!("Error passing keys through: {}", err);
+            }
         }
-        Action::Map { to: _ } => {
-            todo!();
+        Action::Notify { summary, body } => {
+            // Talking to the session bus can take a moment, so don't
+            // hold up the next key for it.
+            let (summary, body) = (summary.clone(), body.clone());
+            thread::spawn(move || {
+                if let Err(err) = notify(&summary, &body) {
+                    eprintln!("Error showing notification: {}", err);
+                }
+            });
+        }
+        Action::Clipboard { text } => {
+            if let Err(err) = conn.set_clipboard(text) {
+                eprintln!("Error setting the clipboard: {}", err);
+            }
+        }
+        Action::EnterMode { mode } => enter_mode(cfg, line, mode, modes),
+        Action::ExitMode => {
+            if let Some(mode) = modes.pop() {
+                verbose!("Leaving mode {}", mode);
+            }
         }
     }
 }
 
-/// The state of the keybinding manager at a particular point in time.
-enum PrefixState<'a> {
-    /// The user has typed something that can't possibly match any key
-    /// sequence we're listening for. We should therefore discard any
-    /// keys we might have saved.
-    None,
+/// Enters `mode` for the binding `line`, pushing it onto the stack of
+/// modes entered so far. Entering a mode that's already on the stack
+/// goes back to it instead, leaving the modes entered from it, so
+/// that modes which enter each other don't pile up forever.
+fn enter_mode<'a>(cfg: &'a Config, line: &ConfigLine, mode: &'a str, modes: &mut Vec<&'a str>) {
+    if !cfg.commands.iter().any(|c| c.mode.as_deref() == Some(mode)) {
+        // There'd be no way out but the cancel key.
+        eprintln!(
+            "Error: {} enters mode \"{}\", which has no bindings",
+            line.location, mode
+        );
+        return;
+    }
 
-    /// The user has typed something that matches one or more key
-    /// sequences we're listening for.
-    Prefix,
+    match modes.iter().position(|&entered| entered == mode) {
+        Some(idx) => modes.truncate(idx + 1),
+        None => modes.push(mode),
+    }
+    verbose!("Entering mode {}", mode);
+}
 
-    /// The user has typed something that perfectly matches a key
-    /// binding we're listening for, with this associated
-    /// configuration line.
-    Match(&'a ConfigLine),
+/// Finds the binding that typing exactly the given key sequence
+/// in `mode` would trigger, for `map chain`. Double-tap bindings are
+/// left out, since the keys are only typed once.
+fn chained_binding<'a>(
+    cfg: &'a Config,
+    keyseq: &KeySequence,
+    mode: Option<&str>,
+) -> Option<&'a ConfigLine> {
+    cfg.bindings_for(keyseq, mode)
+        .into_iter()
+        .find(|line| !line.double_tap)
 }
 
-/// The result of matching a key sequence with a set of prefix keys.
-enum SeqMatch {
-    /// The key sequence does not match the prefix.
-    None,
+/// Finds the binding that `line` leads to in `mode`, if it's a `map
+/// chain` binding whose keys are bound, where `chain` is the `map
+/// chain` bindings that led to `line`. Following a binding that's
+/// already in the chain would go round forever, so that's an error.
+fn chain_step<'a>(
+    cfg: &'a Config,
+    line: &ConfigLine,
+    mode: Option<&str>,
+    chain: &[&ConfigLine],
+) -> Result<Option<&'a ConfigLine>, String> {
+    let target = match &line.action {
+        Action::Map {
+            to, chain: true, ..
+        } => chained_binding(cfg, to, mode),
+        _ => None,
+    };
+    if target.is_some() && chain.iter().any(|seen| std::ptr::eq(*seen, line)) {
+        return Err(format!(
+            "{} maps \"{}\" back to itself by way of other bindings",
+            line.location, line.keyseq
+        ));
+    }
 
-    /// The key sequence partially matches the prefix, and this is the
-    /// next key that would be required for a full match.
-    Partial,
+    Ok(target)
+}
 
-    /// The key sequence prefectly matches the prefix, i.e., the user
-    /// has typed this key sequence to completion..
-    Full,
+/// Runs the commands of a `bind` action, whose key sequence the user
+/// typed as `keys`, if any. Commands run in the background are handed
+/// to `reaper` to wait for. Does nothing for any other action.
+fn run_commands(
+    conn: &X11Conn,
+    settings: &Settings,
+    action: &Action,
+    keys: &[Key],
+    reaper: &Reaper,
+) {
+    let (commands, stdin, repeat, ungrab, vars, cwd, shell) = match action {
+        Action::Bind {
+            commands,
+            stdin,
+            repeat,
+            ungrab,
+            env,
+            cwd,
+            shell,
+        } => (commands, stdin, *repeat, *ungrab, env, cwd, *shell),
+        _ => return,
+    };
+
+    // The parser never produces empty commands, but there's no
+    // sense in crashing over one.
+    if commands.is_empty() || commands.iter().any(Vec::is_empty) {
+        return;
+    }
+
+    let lookup = |name: &str| {
+        let value = env::var(name).ok();
+        if value.is_none() {
+            eprintln!("Warning: ${} isn't set; using an empty string", name);
+        }
+        value
+    };
+    let mut commands = substitute_all(commands, keys, shell, lookup);
+    if commands
+        .iter()
+        .flatten()
+        .any(|word| has_pointer_placeholder(word))
+    {
+        match conn.query_pointer() {
+            Ok(pointer) => {
+                commands = commands
+                    .iter()
+                    .map(|command| substitute_pointer(command, pointer))
+                    .collect()
+            }
+            Err(err) => eprintln!("Error finding the pointer: {}", err),
+        }
+    }
+    let vars: Vec<_> = vars
+        .iter()
+        .map(|(name, value)| (name.clone(), expand_env(value, &lookup)))
+        .collect();
+    let cwd = cwd.as_deref().map(|dir| expand_dir(dir, &lookup));
+
+    // Only the last command gets the here-string.
+    let last = commands.len() - 1;
+    let mut cmds: Vec<_> = commands
+        .iter()
+        .enumerate()
+        .map(|(idx, command)| {
+            verbose!("Running {:?}", command);
+            let mut cmd = make_command(command, cwd.as_deref(), &vars, settings);
+            if idx == last && stdin.is_some() {
+                cmd.stdin(Stdio::piped());
+            }
+            (cmd, command[0].clone())
+        })
+        .collect();
+
+    // Start the first run right away, rather than leaving it to
+    // the reaper, so that it isn't held up by the reaper's
+    // thread being scheduled.
+    let first = match cmds[0].0.spawn() {
+        Ok(handle) => handle,
+        Err(err) => {
+            eprintln!("Error launching \"{}\": {}", cmds[0].1, err);
+            return;
+        }
+    };
+
+    let job = Job::new(cmds, first, stdin.clone(), repeat);
+    if ungrab {
+        // The key that triggered us may still be held down,
+        // in which case the server still has the keyboard
+        // grabbed on our behalf. Let go of it, and wait here
+        // until the commands are done before grabbing any
+        // keys again.
+        if let Err(err) = conn.ungrab_kbd() {
+            eprintln!("Error releasing the keyboard: {}", err);
+        }
+        job.run();
+    } else {
+        // Need to call `wait()` at some point because Unix.
+        reaper.add(job);
+    }
 }
 
-// TODO: this description is a little unclear for my taste.
-/// Gets the set of all keys that should be grabbed initially, given
-/// the configuration.
-fn get_init_keys(config: &Config) -> Vec<Key> {
-    config
-        .commands
+/// Fills in `%KEY%` in each of a binding's commands, and expands
+/// their environment variables unless they're run by a `shell` (as
+/// sxhkd's always are), since the shell expands variables itself,
+/// and doing it first would undo any quoting meant to stop it.
+fn substitute_all(
+    commands: &[Vec<String>],
+    keys: &[Key],
+    shell: bool,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Vec<Vec<String>> {
+    commands
+        .iter()
+        .map(|command| {
+            let command = substitute_key(command, keys);
+            if shell {
+                command
+            } else {
+                substitute_env(&command, &lookup)
+            }
+        })
+        .collect()
+}
+
+/// Replaces `%KEY%` in each word of a command with the name of the
+/// last key typed, which is how a binding ending in `<any>` finds out
+/// what was pressed.
+fn substitute_key(command: &[String], keys: &[Key]) -> Vec<String> {
+    let name = match keys.last() {
+        Some(key) => key.to_string(),
+        None => return command.to_vec(),
+    };
+
+    command
         .iter()
-        .map(|cmd| cmd.keyseq.keys[0])
+        .map(|word| word.replace("%KEY%", &name))
         .collect()
 }
 
-/// Attempts to determine what the user meant, given that they've
-/// typed the given set of keys `seen_keys' and we're listening for
-/// `bindings'.
-fn get_prefixes<'a>(config: &'a Config, seen_keys: &[Key]) -> PrefixState<'a> {
-    let mut partial = false;
-    for command in &config.commands {
-        match match_keyseq(&command.keyseq, seen_keys) {
-            SeqMatch::None => {}
-            SeqMatch::Partial => {
-                partial = true;
+/// Expands environment variables, written as `$NAME` or `${NAME}`,
+/// in each word of a command, looking their values up with `lookup`.
+/// Variables that aren't set expand to nothing, and `\$` is a literal
+/// dollar sign. A `$` that isn't followed by a name is left alone.
+fn substitute_env(command: &[String], lookup: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    command
+        .iter()
+        .map(|word| expand_env(word, &lookup))
+        .collect()
+}
+
+/// Expands the environment variables in a single word, for
+/// `substitute_env`.
+fn expand_env(word: &str, lookup: &impl Fn(&str) -> Option<String>) -> String {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut expanded = String::new();
+    let mut rest = word;
+    while let Some(idx) = rest.find(['$', '\\']) {
+        expanded.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        if rest[idx..].starts_with("\\$") {
+            expanded.push('$');
+            rest = &after[1..];
+            continue;
+        }
+        if rest[idx..].starts_with('\\') {
+            expanded.push('\\');
+            rest = after;
+            continue;
+        }
+
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", after),
+            },
+            None => {
+                let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+                (&after[..end], &after[end..])
             }
-            SeqMatch::Full => return PrefixState::Match(&command),
         };
+        let valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+            && name.chars().all(is_name_char);
+        if valid {
+            expanded.push_str(&lookup(name).unwrap_or_default());
+            rest = next;
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
     }
+    expanded.push_str(rest);
+    expanded
+}
 
-    if partial {
-        PrefixState::Prefix
-    } else {
-        PrefixState::None
+/// Expands the directory given in a `cwd` line: `~` at the start
+/// stands for the home directory, and environment variables are
+/// expanded as in commands, looking them up with `lookup`.
+fn expand_dir(dir: &str, lookup: &impl Fn(&str) -> Option<String>) -> String {
+    match dir.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            expand_env(&format!("${{HOME}}{}", rest), lookup)
+        }
+        _ => expand_env(dir, lookup),
     }
 }
 
-/// Matches a key sequence with a set of keys we've seen from the
-/// user.
-fn match_keyseq(seq: &KeySequence, seen_keys: &[Key]) -> SeqMatch {
-    for (i, key) in seen_keys.iter().enumerate() {
-        if seq.keys[i] != *key {
-            return SeqMatch::None;
+/// The placeholders `substitute_pointer` fills in.
+const POINTER_PLACEHOLDERS: &[&str] = &["%X%", "%Y%", "%SCREEN%"];
+
+/// Checks whether a word of a command mentions the pointer.
+fn has_pointer_placeholder(word: &str) -> bool {
+    POINTER_PLACEHOLDERS.iter().any(|p| word.contains(p))
+}
+
+/// Replaces `%X%` and `%Y%` in each word of a command with the
+/// pointer's coordinates, and `%SCREEN%` with the number of the
+/// screen it's on, which the coordinates are relative to.
+fn substitute_pointer(command: &[String], pointer: Pointer) -> Vec<String> {
+    command
+        .iter()
+        .map(|word| {
+            word.replace("%X%", &pointer.x.to_string())
+                .replace("%Y%", &pointer.y.to_string())
+                .replace("%SCREEN%", &pointer.screen.to_string())
+        })
+        .collect()
+}
+
+/// Sets up a Command to run the given program and arguments, in the
+/// directory `cwd` if that's given, with the environment variables
+/// `vars` set on top of our own.
+fn make_command(
+    command: &[String],
+    cwd: Option<&str>,
+    vars: &[(String, String)],
+    settings: &Settings,
+) -> Command {
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(command[1..].iter());
+    if let Some(dir) = cwd {
+        cmd.current_dir(dir);
+    }
+    cmd.envs(vars.iter().map(|(name, value)| (name, value)));
+
+    if settings.detach {
+        // Safety: `setsid` is async-signal-safe, and so is building
+        // an `io::Error` from `errno`, so this closure is fine to run
+        // between `fork` and `exec`.
+        unsafe {
+            cmd.pre_exec(|| match setsid() {
+                Ok(_) => Ok(()),
+                Err(_) => Err(io::Error::last_os_error()),
+            });
         }
     }
 
-    if seq.keys.len() == seen_keys.len() {
-        SeqMatch::Full
-    } else {
-        SeqMatch::Partial
+    cmd
+}
+
+/// Gets the set of keys that should be grabbed while no key sequence
+/// is in progress, i.e., the first key of every binding, each listed
+/// only once. A key is grabbed synchronously if any binding starting
+/// with it asks for that, or `sync-grabs` is set and it isn't a mouse
+/// button. Bindings in modes are left out, since a mode grabs the
+/// whole keyboard.
+pub fn get_init_keys(config: &Config) -> Vec<Grab> {
+    let mut grabs: Vec<Grab> = Vec::new();
+    for cmd in config.commands.iter().filter(|cmd| cmd.mode.is_none()) {
+        let key = cmd.keyseq.keys[0];
+        let sync = cmd.sync || (config.settings.sync_grabs && key.main_key.button().is_none());
+        match grabs.iter_mut().find(|grab| grab.key == key) {
+            Some(grab) => grab.sync |= sync,
+            None => grabs.push(Grab { key, sync }),
+        }
+    }
+
+    grabs
+}
+
+/// Checks whether a configuration needs to hear about every key
+/// pressed, for bindings limited to one keyboard, or for double taps;
+/// see `X11Conn::listen_for_raw_keys`.
+fn wants_raw_keys(config: &Config) -> bool {
+    config
+        .commands
+        .iter()
+        .any(|line| line.device.is_some() || line.double_tap)
+}
+
+/// Gets the keycodes that a configuration's bindings refer to
+/// directly, rather than by keysym, each listed once.
+fn raw_keycodes(config: &Config) -> Vec<u8> {
+    let mut keycodes = Vec::new();
+    for line in &config.commands {
+        for key in &line.keyseq.keys {
+            if let Some(keycode) = key.main_key.keycode() {
+                if !keycodes.contains(&keycode) {
+                    keycodes.push(keycode);
+                }
+            }
+        }
+    }
+    keycodes
+}
+
+/// Makes sure every key in a configuration, including the keys that
+/// `map` bindings type, is on the current keyboard layout, so that a
+/// missing one is reported now rather than when it's first grabbed.
+/// `has_keysym` tells whether any key produces a keysym.
+fn check_available(config: &Config, has_keysym: impl Fn(Keysym) -> bool) -> Result<(), AhkdError> {
+    for line in &config.commands {
+        let mapped = match &line.action {
+            Action::Map { to, .. } => &to.keys[..],
+            _ => &[],
+        };
+        for key in line.keyseq.keys.iter().chain(mapped) {
+            if !key.is_any() && !has_keysym(key.main_key) {
+                return Err(AhkdError::UnavailableKey(
+                    key.main_key,
+                    Some(line.location.clone()),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets go of every key we're holding a grab on, and of the keyboard,
+/// on our way out, so that none of our grabs outlive us. The X server
+/// would see to this itself once we disconnect, but there's no telling
+/// how soon that is if, say, we've been forked off and something holds
+/// on to our connection.
+fn release(source: &impl KeySource) -> Result<(), Box<dyn Error>> {
+    verbose!("Releasing every grab");
+    source.hold_grabs(&[])?;
+    source.release_keyboard()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::{parse_config, parse_sxhkd_config};
+    use crate::x11::RecordingKeySource;
+    use std::io::BufReader;
+
+    /// Parses a configuration file from a string.
+    fn parse_str(text: &str) -> Config {
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+        config
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let lost: Box<dyn Error> = Box::new(ConnectionError::UnknownError);
+        assert!(is_disconnect(lost.as_ref()));
+
+        let lost: Box<dyn Error> =
+            Box::new(ReplyError::ConnectionError(ConnectionError::UnknownError));
+        assert!(is_disconnect(lost.as_ref()));
+
+        let other: Box<dyn Error> = Box::new(AhkdError::KeyboardGrabError);
+        assert!(!is_disconnect(other.as_ref()));
+    }
+
+    #[test]
+    fn not_ready_test() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let refused: Box<dyn Error> = Box::new(ConnectError::IOError(refused));
+        assert!(is_not_ready(refused.as_ref()));
+
+        let no_display: Box<dyn Error> = Box::new(AhkdError::NoDisplay(None));
+        assert!(is_not_ready(no_display.as_ref()));
+        let bad_name: Box<dyn Error> = Box::new(AhkdError::NoDisplay(Some("foo".to_string())));
+        assert!(!is_not_ready(bad_name.as_ref()));
+
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let denied: Box<dyn Error> = Box::new(ConnectError::IOError(denied));
+        assert!(!is_not_ready(denied.as_ref()));
+
+        let other: Box<dyn Error> = Box::new(ConnectError::InvalidScreen);
+        assert!(!is_not_ready(other.as_ref()));
+    }
+
+    #[test]
+    fn substitute_key_test() {
+        let config = parse_str("bind a C-Return : foo");
+        let command = vec!["screenshot".to_string(), "--key=%KEY%".to_string()];
+        assert_eq!(
+            substitute_key(&command, &config.commands[0].keyseq.keys),
+            vec!["screenshot", "--key=C-Return"]
+        );
+    }
+
+    #[test]
+    fn substitute_env_test() {
+        let lookup = |name: &str| match name {
+            "TERMINAL" => Some("xterm".to_string()),
+            "HOME" => Some("/home/me".to_string()),
+            _ => None,
+        };
+        let expand = |words: &[&str]| {
+            let command: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+            substitute_env(&command, lookup)
+        };
+
+        assert_eq!(
+            expand(&["$TERMINAL", "-e", "htop"]),
+            vec!["xterm", "-e", "htop"]
+        );
+        assert_eq!(
+            expand(&["${HOME}/bin/x", "$HOME/y"]),
+            vec!["/home/me/bin/x", "/home/me/y"]
+        );
+        assert_eq!(expand(&["a${UNSET}b", "$UNSET"]), vec!["ab", ""]);
+
+        // The shell gets sxhkd's commands as written, quoting and all.
+        let mut config = Config::default();
+        let text = "super + a\n\techo \"$HOME\" '$HOME'\n";
+        parse_sxhkd_config(&mut config, BufReader::new(text.as_bytes()), "sxhkdrc").unwrap();
+        let line = &config.commands[0];
+        let (commands, shell) = match &line.action {
+            Action::Bind {
+                commands, shell, ..
+            } => (commands, *shell),
+            action => panic!("Expected a bind action, got {:?}", action),
+        };
+        assert_eq!(
+            substitute_all(commands, &line.keyseq.keys, shell, lookup),
+            vec![vec!["sh", "-c", "echo \"$HOME\" '$HOME'"]]
+        );
+        assert_eq!(expand(&["\\$HOME", "a\\b"]), vec!["$HOME", "a\\b"]);
+        assert_eq!(
+            expand(&["$", "5$", "$1", "${", "${}"]),
+            vec!["$", "5$", "$1", "${", "${}"]
+        );
+    }
+
+    #[test]
+    fn expand_dir_test() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "PROJECT" => Some("ahkd".to_string()),
+            _ => None,
+        };
+        assert_eq!(expand_dir("~", &lookup), "/home/me");
+        assert_eq!(expand_dir("~/src/$PROJECT", &lookup), "/home/me/src/ahkd");
+        assert_eq!(expand_dir("/tmp/~x", &lookup), "/tmp/~x");
+        assert_eq!(expand_dir("~other", &lookup), "~other");
+    }
+
+    #[test]
+    fn cwd_env_test() {
+        let dir = env::temp_dir().join(format!("ahkd-cwd-test-{}", getpid()));
+        fs::create_dir(&dir).unwrap();
+        let config = parse_str(&format!(
+            "cwd {}\nbind a : GREETING=hello sh -c 'pwd > out; printenv GREETING >> out'",
+            dir.to_str().unwrap()
+        ));
+        let (commands, vars, cwd) = match &config.commands[0].action {
+            Action::Bind {
+                commands, env, cwd, ..
+            } => (commands, env, cwd),
+            action => panic!("Expected a bind action, got {:?}", action),
+        };
+
+        make_command(&commands[0], cwd.as_deref(), vars, &config.settings)
+            .status()
+            .unwrap();
+        // The file is written relative to the configured directory,
+        // and the command sees it as its working directory.
+        let out = fs::read_to_string(dir.join("out")).unwrap();
+        let pwd = dir.canonicalize().unwrap();
+        assert_eq!(out, format!("{}\nhello\n", pwd.to_str().unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shell_test() {
+        let path = env::temp_dir().join(format!("ahkd-shell-test-{}.out", getpid()));
+        let config = parse_str(&format!(
+            "shell sh\nbind a : printf 'b\\na\\n' | sort > {}",
+            path.to_str().unwrap()
+        ));
+        let commands = match &config.commands[0].action {
+            Action::Bind { commands, .. } => commands,
+            action => panic!("Expected a bind action, got {:?}", action),
+        };
+
+        make_command(&commands[0], None, &[], &config.settings)
+            .status()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn substitute_pointer_test() {
+        let command = vec![
+            "menu".to_string(),
+            "--at=%X%,%Y%".to_string(),
+            "%SCREEN%".to_string(),
+        ];
+        assert!(command.iter().any(|word| has_pointer_placeholder(word)));
+        let pointer = Pointer {
+            x: 120,
+            y: -4,
+            screen: 1,
+        };
+        assert_eq!(
+            substitute_pointer(&command, pointer),
+            vec!["menu", "--at=120,-4", "1"]
+        );
+
+        assert!(!has_pointer_placeholder("%KEY%"));
+    }
+
+    #[test]
+    fn detach_test() {
+        use nix::unistd::{getpgid, Pid};
+
+        let command = vec!["sleep".to_string(), "10".to_string()];
+        let mut settings = Settings::default();
+
+        let mut attached = make_command(&command, None, &[], &settings)
+            .spawn()
+            .unwrap();
+        settings.detach = true;
+        let mut detached = make_command(&command, None, &[], &settings)
+            .spawn()
+            .unwrap();
+
+        let our_group = getpgid(None).unwrap();
+        let attached_group = getpgid(Some(Pid::from_raw(attached.id() as i32))).unwrap();
+        let detached_group = getpgid(Some(Pid::from_raw(detached.id() as i32))).unwrap();
+
+        attached.kill().unwrap();
+        detached.kill().unwrap();
+        attached.wait().unwrap();
+        detached.wait().unwrap();
+
+        assert_eq!(attached_group, our_group);
+        assert_ne!(detached_group, our_group);
+    }
+
+    #[test]
+    fn init_keys_grabbed_once_test() {
+        let config = parse_str(
+            "\
+bind C-x C-f : foo
+bind sync C-x C-s : bar
+bind M-x : baz
+bind C-x k : quux
+",
+        );
+        let source = RecordingKeySource::default();
+        source.grab_keys(&get_init_keys(&config)).unwrap();
+
+        // 0x78 is the keysym for `x`; 0x4 and 0x8 are the masks for
+        // Control and Mod1. `C-x` is grabbed synchronously, because
+        // one of the bindings starting with it asks for that.
+        assert_eq!(
+            *source.grabbed.borrow(),
+            vec![(Keysym(0x78), 0x4, true), (Keysym(0x78), 0x8, false)]
+        );
+
+        source.ungrab_keys(&get_init_keys(&config)).unwrap();
+        assert_eq!(
+            *source.ungrabbed.borrow(),
+            vec![(Keysym(0x78), 0x4), (Keysym(0x78), 0x8)]
+        );
+    }
+
+    #[test]
+    fn repeat_timer_test() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut timer = RepeatTimer::new(start, ms(400), ms(100));
+        assert_eq!(timer.deadline(), start + ms(400));
+
+        // On time, the runs come every interval.
+        timer.advance(start + ms(400));
+        assert_eq!(timer.deadline(), start + ms(500));
+        timer.advance(start + ms(510));
+        assert_eq!(timer.deadline(), start + ms(600));
+
+        // A late run skips the ones it held up.
+        timer.advance(start + ms(850));
+        assert_eq!(timer.deadline(), start + ms(900));
+    }
+
+    #[test]
+    fn sync_grabs_test() {
+        let config = parse_str("set sync-grabs on\nbind C-x C-f : foo\nbind M-Button1 : bar\n");
+        let grabs = get_init_keys(&config);
+        assert!(grabs[0].sync);
+        // Buttons can't be grabbed synchronously.
+        assert!(!grabs[1].sync);
+    }
+
+    #[test]
+    fn replay_test() {
+        let config = parse_str("bind C-x C-f : foo\n");
+        let matched = MatchResult::Match(&config.commands[0]);
+
+        // A lone synchronous key that starts nothing is still frozen.
+        assert_eq!(
+            replay_for(&MatchResult::None, false, true, 1),
+            Replay::Frozen
+        );
+        // Once more keys have been typed, it's too late for that.
+        assert_eq!(
+            replay_for(&MatchResult::None, false, true, 2),
+            Replay::Synthetic
+        );
+        assert_eq!(
+            replay_for(&MatchResult::None, false, false, 2),
+            Replay::Nothing
+        );
+        assert_eq!(
+            replay_for(&MatchResult::None, true, true, 1),
+            Replay::Nothing
+        );
+        assert_eq!(
+            replay_for(&MatchResult::Cancelled, false, true, 2),
+            Replay::Nothing
+        );
+        assert_eq!(replay_for(&matched, false, true, 2), Replay::Nothing);
+    }
+
+    #[test]
+    fn release_test() {
+        let config = parse_str("bind C-x C-f : foo\nbind M-x : bar\nmode m {\nbind a : baz\n}\n");
+        let source = RecordingKeySource::default();
+        source.hold_grabs(&get_init_keys(&config)).unwrap();
+        release(&source).unwrap();
+
+        // Every key we were holding, which is every key we grab
+        // outside of a mode, and the keyboard.
+        assert_eq!(
+            *source.ungrabbed.borrow(),
+            vec![(Keysym(0x78), 0x4), (Keysym(0x78), 0x8)]
+        );
+        assert!(source.held.borrow().is_empty());
+        assert!(source.released.get());
+
+        // Once they're let go of, there's nothing left to ungrab.
+        release(&source).unwrap();
+        assert_eq!(source.ungrabbed.borrow().len(), 2);
+    }
+
+    #[test]
+    fn conflict_test() {
+        let config = parse_str("bind C-x C-f : foo\nbind M-x : bar\n");
+        let source = RecordingKeySource {
+            num_lock: Some(0x20),
+            // Only with Caps Lock on, as a window manager might.
+            taken: vec![(Keysym(0x78), 0x6)],
+            ..Default::default()
+        };
+        source.grab_keys(&get_init_keys(&config)).unwrap();
+
+        // The rest of C-x's lock masks, and M-x, are still grabbed.
+        assert_eq!(source.grabbed.borrow().len(), 7);
+        let conflicts: Vec<_> = source
+            .conflicts
+            .borrow()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                "C-x is already grabbed by another program, so bindings starting with it \
+                 won't work; it may conflict with a binding of the window manager's"
+            ]
+        );
+    }
+
+    #[test]
+    fn regrab_test() {
+        let config = parse_str("bind C-x : foo\nbind M-y : bar\n");
+        let grabs = get_init_keys(&config);
+        let source = RecordingKeySource::default();
+        source.grab_keys(&grabs).unwrap();
+
+        // The new layout has no `y`.
+        source
+            .regrab_keys(&grabs, || {
+                source.missing.borrow_mut().push(Keysym(0x79));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            *source.ungrabbed.borrow(),
+            vec![(Keysym(0x78), 0x4), (Keysym(0x79), 0x8)]
+        );
+        let grabbed: Vec<_> = source
+            .grabbed
+            .borrow()
+            .iter()
+            .map(|&(keysym, mask, _)| (keysym, mask))
+            .collect();
+        assert_eq!(
+            grabbed,
+            vec![
+                (Keysym(0x78), 0x4),
+                (Keysym(0x79), 0x8),
+                (Keysym(0x78), 0x4)
+            ]
+        );
+        let conflicts: Vec<_> = source
+            .conflicts
+            .borrow()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec!["Key 'y' is not available on the current keyboard layout"]
+        );
+
+        // It isn't grabbed, so isn't ungrabbed either.
+        source.ungrab_keys(&grabs).unwrap();
+        assert_eq!(source.ungrabbed.borrow().len(), 3);
+    }
+
+    #[test]
+    fn hold_grabs_test() {
+        let source = RecordingKeySource::default();
+        let old = get_init_keys(&parse_str("bind C-x : foo\nbind M-x : bar\n"));
+        source.hold_grabs(&old).unwrap();
+        source.grabbed.borrow_mut().clear();
+
+        // Switching to new keys leaves C-x grabbed, and grabs M-x
+        // again since it's now synchronous.
+        let new = get_init_keys(&parse_str(
+            "bind C-x : foo\nbind sync M-x : bar\nbind C-y : baz\n",
+        ));
+        source.hold_grabs(&new).unwrap();
+        assert_eq!(*source.ungrabbed.borrow(), vec![(Keysym(0x78), 0x8)]);
+        assert_eq!(
+            *source.grabbed.borrow(),
+            vec![(Keysym(0x78), 0x8, true), (Keysym(0x79), 0x4, false)]
+        );
+        assert_eq!(*source.held.borrow(), new);
+
+        // Holding the same keys again changes nothing.
+        source.hold_grabs(&new).unwrap();
+        assert_eq!(source.grabbed.borrow().len(), 2);
+        assert_eq!(source.ungrabbed.borrow().len(), 1);
+    }
+
+    #[test]
+    fn lock_masks_test() {
+        let config = parse_str("bind C-x : foo");
+        let source = RecordingKeySource {
+            num_lock: Some(0x20),
+            ..Default::default()
+        };
+        source.grab_keys(&get_init_keys(&config)).unwrap();
+        source.ungrab_keys(&get_init_keys(&config)).unwrap();
+
+        // Control, with nothing, Caps Lock (0x2), Num Lock on Mod3
+        // (0x20), and both.
+        let expected = vec![
+            (Keysym(0x78), 0x4),
+            (Keysym(0x78), 0x6),
+            (Keysym(0x78), 0x24),
+            (Keysym(0x78), 0x26),
+        ];
+        let grabbed: Vec<_> = source
+            .grabbed
+            .borrow()
+            .iter()
+            .map(|&(keysym, mask, _)| (keysym, mask))
+            .collect();
+        assert_eq!(grabbed, expected);
+        assert_eq!(*source.ungrabbed.borrow(), expected);
+    }
+
+    #[test]
+    fn check_available_test() {
+        // Only `a` and `b` are on the keyboard.
+        let has_keysym = |keysym: Keysym| keysym == Keysym(0x61) || keysym == Keysym(0x62);
+
+        let config = parse_str("bind a : foo\nmap b : a");
+        assert!(check_available(&config, has_keysym).is_ok());
+
+        let config = parse_str("bind a : foo\nbind b c : bar");
+        match check_available(&config, has_keysym) {
+            Err(AhkdError::UnavailableKey(keysym, Some(location))) => {
+                assert_eq!(keysym, Keysym(0x63));
+                assert_eq!(location.line_num, 2);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let config = parse_str("map a : c");
+        assert!(check_available(&config, has_keysym).is_err());
+    }
+
+    #[test]
+    fn raw_keycode_test() {
+        let config = parse_str("bind keycode 38 : foo\nbind C-#100 #38 : bar\nbind a : baz");
+        assert_eq!(raw_keycodes(&config), vec![38, 100]);
+
+        // A press of a raw keycode matches the bindings for it.
+        let mut matcher = Matcher::new(&config);
+        let parse_key = |text| Key::try_from(LineText::new("test", 1, text)).unwrap();
+        match matcher.feed(parse_key("#38"), None) {
+            MatchResult::Match(line) => assert_eq!(line.action.to_string(), "bind foo"),
+            _ => panic!("#38 didn't match"),
+        }
+        assert!(matches!(
+            matcher.feed(parse_key("C-#100"), None),
+            MatchResult::Prefix
+        ));
+        match matcher.feed(parse_key("#38"), None) {
+            MatchResult::Match(line) => assert_eq!(line.action.to_string(), "bind bar"),
+            _ => panic!("C-#100 #38 didn't match"),
+        }
+
+        // The keysym the key produces is a different key.
+        assert!(matches!(
+            matcher.feed(parse_key("a"), None),
+            MatchResult::Match(line) if line.action.to_string() == "bind baz"
+        ));
+    }
+
+    #[test]
+    fn wants_raw_keys_test() {
+        assert!(!wants_raw_keys(&parse_str(
+            "bind C-x : foo\nbind release F1 : bar"
+        )));
+        assert!(wants_raw_keys(&parse_str(
+            "bind C-x : foo\nbind device 12 a : bar"
+        )));
+        assert!(wants_raw_keys(&parse_str("bind 2x Shift_L : foo")));
+    }
+
+    #[test]
+    fn pid_file_test() {
+        let path = env::temp_dir().join(format!("ahkd-test-{}.pid", getpid()));
+        let pid_file = PidFile::create(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", getpid())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn run_repeatedly_test() {
+        let path = env::temp_dir().join(format!("ahkd-test-{}.out", getpid()));
+        let path_str = path.to_str().unwrap();
+        let config = parse_str(&format!(
+            "bind a : repeat 2 sh -c 'echo one >> {0}' ; sh -c 'cat >> {0}; echo >> {0}' <<< two",
+            path_str
+        ));
+        let (commands, stdin) = match &config.commands[0].action {
+            Action::Bind {
+                commands, stdin, ..
+            } => (commands, stdin.clone()),
+            action => panic!("Expected a bind action, got {:?}", action),
+        };
+
+        let mut cmds: Vec<_> = commands
+            .iter()
+            .enumerate()
+            .map(|(idx, command)| {
+                let mut cmd = make_command(command, None, &[], &config.settings);
+                if idx == 1 {
+                    cmd.stdin(Stdio::piped());
+                }
+                (cmd, command[0].clone())
+            })
+            .collect();
+        let first = cmds[0].0.spawn().unwrap();
+        Job::new(cmds, first, stdin, 2).run();
+
+        // Each command waits for the one before it.
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\none\ntwo\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn enter_mode_test() {
+        let config = parse_str(
+            "\
+enter-mode Super-r : resize
+enter-mode Super-m : move
+enter-mode Super-e : empty
+mode resize {
+    enter-mode m : move
+}
+mode move {
+    enter-mode r : resize
+}
+bind C-x : foo
+",
+        );
+        fn enter<'a>(config: &'a Config, idx: usize, modes: &mut Vec<&'a str>) {
+            let line = &config.commands[idx];
+            match &line.action {
+                Action::EnterMode { mode } => enter_mode(config, line, mode, modes),
+                action => panic!("Expected an enter-mode action, got {:?}", action),
+            }
+        }
+
+        let mut modes = Vec::new();
+        enter(&config, 0, &mut modes);
+        enter(&config, 3, &mut modes);
+        assert_eq!(modes, vec!["resize", "move"]);
+
+        // Going back into a mode on the stack leaves the ones above it.
+        enter(&config, 4, &mut modes);
+        assert_eq!(modes, vec!["resize"]);
+
+        // A mode with no bindings can't be entered.
+        enter(&config, 2, &mut modes);
+        assert_eq!(modes, vec!["resize"]);
+
+        // Only the ordinary bindings are grabbed up front.
+        let keys: Vec<_> = get_init_keys(&config)
+            .iter()
+            .map(|grab| grab.key.to_string())
+            .collect();
+        assert_eq!(keys, vec!["s-r", "s-m", "s-e", "C-x"]);
+    }
+
+    #[test]
+    fn chained_binding_test() {
+        let config = parse_str("map C-j : chain C-x\nbind 2x C-x : foo\nbind C-x : bar");
+        let to = match &config.commands[0].action {
+            Action::Map { to, .. } => to,
+            action => panic!("Expected a map action, got {:?}", action),
+        };
+        let target = chained_binding(&config, to, None).unwrap();
+        assert_eq!(target.action.to_string(), "bind bar");
+
+        let keyseq = KeySequence::try_from(LineText::new("test", 1, "C-y")).unwrap();
+        assert!(chained_binding(&config, &keyseq, None).is_none());
+
+        // Two bindings that chain to each other are followed round
+        // once, then given up on.
+        let config = parse_str("map C-j : chain C-k\nmap C-k : chain C-j\n");
+        let mut chain = Vec::new();
+        let mut line = &config.commands[0];
+        let err = loop {
+            assert!(chain.len() <= 2, "the chain went round more than once");
+            match chain_step(&config, line, None, &chain) {
+                Ok(Some(target)) => {
+                    chain.push(line);
+                    line = target;
+                }
+                Ok(None) => panic!("the chain came to an end"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(chain.len(), 2);
+        assert_eq!(
+            err,
+            "foo:1 maps \"C-j\" back to itself by way of other bindings"
+        );
     }
 }
@@ -16,10 +16,14 @@
 // You should have received a copy of the GNU General Public License
 // along with ahkd. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::cfgfile::{LineText, SyntaxError};
+use crate::cfgfile::{LineText, Settings, SyntaxError};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use x11_keysymdef::{lookup_by_codepoint, lookup_by_name};
+use std::sync::OnceLock;
+use x11_keysymdef::{lookup_by_codepoint, lookup_by_keysym, lookup_by_name};
 
 /// A sequence of keys that might be pressed. This type represents the
 /// selector of the `map` and `bind` commands, and the target of the
@@ -56,18 +60,475 @@ pub struct ModField {
     pub mod3: bool, // Unused
     pub mod4: bool, // Super, Hyper
     pub mod5: bool, // Unused
+
+    /// AltGr, or ISO_Level3_Shift, which sets whichever of Mod1 to
+    /// Mod5 the keyboard layout puts it on; see `ModField::with_altgr`.
+    pub mod_altgr: bool,
+
+    /// Set for a key written as `*-x`, which matches the key whatever
+    /// modifiers are held; the other fields are all unset then.
+    pub any: bool,
+}
+
+/// Standard keysym names that `x11_keysymdef` leaves out because
+/// they're synonyms for other names, along with the keysyms they
+/// stand for.
+const KEYSYM_SYNONYMS: &[(&str, u32)] = &[
+    ("Page_Up", 0xff55),
+    ("Page_Down", 0xff56),
+    ("KP_Page_Up", 0xff9a),
+    ("KP_Page_Down", 0xff9b),
+];
+
+/// The XF86 "internet keyboard" keysyms for media and other special
+/// keys, which `x11_keysymdef` doesn't know about, along with some
+/// friendlier aliases for each of them. Each entry is the keysym's
+/// official name, its number, and its aliases.
+pub const MEDIA_KEYS: &[(&str, u32, &[&str])] = &[
+    ("XF86MonBrightnessUp", 0x1008ff02, &["brightnessup"]),
+    ("XF86MonBrightnessDown", 0x1008ff03, &["brightnessdown"]),
+    ("XF86KbdBrightnessUp", 0x1008ff05, &["kbdbrightnessup"]),
+    ("XF86KbdBrightnessDown", 0x1008ff06, &["kbdbrightnessdown"]),
+    (
+        "XF86AudioLowerVolume",
+        0x1008ff11,
+        &["volumedown", "voldown"],
+    ),
+    ("XF86AudioMute", 0x1008ff12, &["mute"]),
+    ("XF86AudioRaiseVolume", 0x1008ff13, &["volumeup", "volup"]),
+    ("XF86AudioPlay", 0x1008ff14, &["playpause", "play"]),
+    ("XF86AudioStop", 0x1008ff15, &["stop"]),
+    ("XF86AudioPrev", 0x1008ff16, &["prevtrack"]),
+    ("XF86AudioNext", 0x1008ff17, &["nexttrack"]),
+    ("XF86HomePage", 0x1008ff18, &["homepage"]),
+    ("XF86Mail", 0x1008ff19, &["mail"]),
+    ("XF86Search", 0x1008ff1b, &["search"]),
+    ("XF86Calculator", 0x1008ff1d, &["calculator"]),
+    ("XF86Eject", 0x1008ff2c, &["eject"]),
+    ("XF86WWW", 0x1008ff2e, &["browser"]),
+    ("XF86Sleep", 0x1008ff2f, &["sleep"]),
+    ("XF86AudioPause", 0x1008ff31, &["pausetrack"]),
+    ("XF86Display", 0x1008ff59, &["display"]),
+    ("XF86Explorer", 0x1008ff5d, &["explorer"]),
+    ("XF86WLAN", 0x1008ff95, &["wlan", "wifi"]),
+    ("XF86TouchpadToggle", 0x1008ffa9, &["touchpad"]),
+    ("XF86AudioMicMute", 0x1008ffb2, &["micmute"]),
+];
+
+/// One of the modifiers in a ModField.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Modifier {
+    Control,
+    Shift,
+    Mod1,
+    Mod2,
+    Mod3,
+    Mod4,
+    Mod5,
+    AltGr,
 }
 
 /// The number corresponding to a symbol on a specific key.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct Keysym(pub u32);
 
+impl Keysym {
+    /// The keysym that stands for the `<any>` wildcard in a binding.
+    /// This is NoSymbol, which no real key ever produces.
+    pub const ANY: Keysym = Keysym(0);
+
+    /// The highest a real keysym can go.
+    const MAX_KEYSYM: u32 = 0x1fff_ffff;
+
+    /// Real keysyms never go above `MAX_KEYSYM`, so a key written as
+    /// a raw keycode, like `#38`, is stored as the keycode with this
+    /// bit set, and stands for whatever that key produces.
+    const KEYCODE_FLAG: u32 = 0x8000_0000;
+
+    /// Gets the keysym that stands for a raw keycode.
+    pub fn from_keycode(keycode: u8) -> Self {
+        Self(Self::KEYCODE_FLAG | keycode as u32)
+    }
+
+    /// Gets the keycode this keysym stands for, if it was written as
+    /// a raw keycode.
+    pub fn keycode(&self) -> Option<u8> {
+        if self.0 & Self::KEYCODE_FLAG != 0 {
+            Some(self.0 as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Likewise, a mouse button, like `Button1`, is stored as the
+    /// button number with this bit set.
+    const BUTTON_FLAG: u32 = 0x4000_0000;
+
+    /// The highest mouse button that can be bound.
+    const MAX_BUTTON: u8 = 5;
+
+    /// Gets the keysym that stands for a mouse button.
+    pub fn from_button(button: u8) -> Self {
+        Self(Self::BUTTON_FLAG | button as u32)
+    }
+
+    /// Gets the mouse button this keysym stands for, if it's one.
+    pub fn button(&self) -> Option<u8> {
+        if self.0 & Self::BUTTON_FLAG != 0 {
+            Some(self.0 as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Gets the uppercase form of a lowercase letter, or None if the
+    /// keysym isn't one.
+    pub fn to_uppercase(self) -> Option<Keysym> {
+        let c = lookup_by_keysym(self.0)?.unicode;
+        if !c.is_lowercase() {
+            return None;
+        }
+        Self::single_char(c.to_uppercase())
+    }
+
+    /// Gets the lowercase form of an uppercase letter, or None if the
+    /// keysym isn't one.
+    pub fn to_lowercase(self) -> Option<Keysym> {
+        let c = lookup_by_keysym(self.0)?.unicode;
+        if !c.is_uppercase() {
+            return None;
+        }
+        Self::single_char(c.to_lowercase())
+    }
+
+    /// Gets the keysym for the result of changing a letter's case,
+    /// if that's a single character with a keysym of its own.
+    fn single_char(mut chars: impl Iterator<Item = char>) -> Option<Keysym> {
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => lookup_by_codepoint(c).map(|record| Keysym(record.keysym)),
+            _ => None,
+        }
+    }
+}
+
+impl Key {
+    /// Gets the modifiers applied to the key, in the order they
+    /// should be written or pressed: Control, Shift, Alt (Mod1),
+    /// Super (Mod4), AltGr, and then the rarely-used Mod2, Mod3 and
+    /// Mod5.
+    /// Anything that displays or synthesizes a key should go through
+    /// here, so that the same key always comes out the same way no
+    /// matter how it was written in the configuration file.
+    pub fn canonical_modifier_order(&self) -> Vec<Modifier> {
+        self.modifiers.canonical_order()
+    }
+}
+
+impl ModField {
+    /// Lists the modifiers that are held, in the order they're
+    /// written and pressed in; see `Key::canonical_modifier_order`.
+    pub fn canonical_order(&self) -> Vec<Modifier> {
+        let mods = self;
+        [
+            (mods.mod_control, Modifier::Control),
+            (mods.mod_shift, Modifier::Shift),
+            (mods.mod1, Modifier::Mod1),
+            (mods.mod4, Modifier::Mod4),
+            (mods.mod_altgr, Modifier::AltGr),
+            (mods.mod2, Modifier::Mod2),
+            (mods.mod3, Modifier::Mod3),
+            (mods.mod5, Modifier::Mod5),
+        ]
+        .iter()
+        .filter(|(held, _)| *held)
+        .map(|(_, modifier)| *modifier)
+        .collect()
+    }
+
+    /// Puts modifiers read from the X server into the form used to
+    /// compare them, where `altgr`, whichever of Mod1 to Mod5 AltGr
+    /// sets on the keyboard layout, is written as AltGr. This makes
+    /// pressing AltGr-e on a layout with AltGr on Mod5 match
+    /// `AltGr-e`.
+    pub fn with_altgr(self, altgr: Modifier) -> ModField {
+        let mut mods = self;
+        let held = match altgr {
+            Modifier::Mod1 => &mut mods.mod1,
+            Modifier::Mod2 => &mut mods.mod2,
+            Modifier::Mod3 => &mut mods.mod3,
+            Modifier::Mod4 => &mut mods.mod4,
+            _ => &mut mods.mod5,
+        };
+        if *held {
+            *held = false;
+            mods.mod_altgr = true;
+        }
+        mods
+    }
+}
+
+impl Key {
+    /// Gets the key with Num Lock, which sets the modifier
+    /// `num_lock`, removed from its modifiers, for comparing keys the
+    /// user typed against keys in bindings: the state of the lock keys
+    /// is almost never something the user means to bind to. (Caps
+    /// Lock isn't represented in a ModField at all, so it's ignored
+    /// already.)
+    pub fn without_locks(&self, num_lock: Modifier) -> Key {
+        let mut modifiers = self.modifiers;
+        *match num_lock {
+            Modifier::Shift => &mut modifiers.mod_shift,
+            Modifier::Control => &mut modifiers.mod_control,
+            Modifier::Mod1 => &mut modifiers.mod1,
+            Modifier::Mod2 => &mut modifiers.mod2,
+            Modifier::Mod3 => &mut modifiers.mod3,
+            Modifier::Mod4 => &mut modifiers.mod4,
+            Modifier::Mod5 => &mut modifiers.mod5,
+            Modifier::AltGr => &mut modifiers.mod_altgr,
+        } = false;
+        Key { modifiers, ..*self }
+    }
+
+    /// Checks whether this is the `<any>` wildcard.
+    pub fn is_any(&self) -> bool {
+        self.main_key == Keysym::ANY
+    }
+
+    /// Puts a key into the form used to compare keys, where a letter
+    /// with Shift held is always written as the uppercase letter,
+    /// still with Shift among its modifiers. This makes `S-x` and `X`
+    /// the same key, whichever way it's written or typed.
+    pub fn with_shifted_case(self) -> Key {
+        let mut key = self;
+        if key.modifiers.mod_shift {
+            if let Some(upper) = key.main_key.to_uppercase() {
+                key.main_key = upper;
+            }
+        } else if key.main_key.to_lowercase().is_some() {
+            key.modifiers.mod_shift = true;
+        }
+        key
+    }
+
+    /// Checks whether the user typing `typed` counts as typing this
+    /// key from a binding: either they're the same key, lock keys
+    /// aside (with Num Lock setting the modifier `num_lock`), or this
+    /// is the `<any>` wildcard, or this matches any modifiers and
+    /// `typed` is the same key with some held.
+    pub fn matches(&self, typed: &Key, num_lock: Modifier) -> bool {
+        if self.modifiers.any {
+            // Shift may have made a letter uppercase, but the binding
+            // is written in lowercase.
+            let main_key = typed.main_key.to_lowercase().unwrap_or(typed.main_key);
+            return self.main_key == main_key;
+        }
+        self.is_any() || self.without_locks(num_lock) == typed.without_locks(num_lock)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_any() {
+            return write!(f, "<any>");
+        }
+
+        write!(f, "{}", self.modifiers)?;
+        // Shifted letters are written as `S-x`, since the Shift is
+        // already there.
+        match self.main_key.to_lowercase() {
+            Some(lower) if self.modifiers.mod_shift => write!(f, "{}", lower),
+            _ => write!(f, "{}", self.main_key),
+        }
+    }
+}
+
+impl fmt::Display for KeySequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ModField {
+    /// Writes each modifier followed by a `-`, ready to be put in
+    /// front of a key.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.any {
+            write!(f, "*-")?;
+        }
+        for modifier in self.canonical_order() {
+            write!(f, "{}-", modifier)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Use the short names where there are any, since they're what
+        // people usually write.
+        let name = match self {
+            Modifier::Control => "C",
+            Modifier::Shift => "S",
+            Modifier::Mod1 => "M",
+            Modifier::Mod2 => "mod2",
+            Modifier::Mod3 => "mod3",
+            Modifier::Mod4 => "s",
+            Modifier::Mod5 => "mod5",
+            Modifier::AltGr => "AltGr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Keysym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(keycode) = self.keycode() {
+            return write!(f, "#{}", keycode);
+        }
+        if let Some(button) = self.button() {
+            return write!(f, "Button{}", button);
+        }
+        if let Some((name, _, _)) = MEDIA_KEYS.iter().find(|(_, ks, _)| *ks == self.0) {
+            return write!(f, "{}", name);
+        }
+
+        // Keysyms without names are written as numbers, which
+        // `Keysym::parse` also accepts.
+        match lookup_by_keysym(self.0) {
+            Some(record) => write!(f, "{}", record.names[0]),
+            None => write!(f, "0x{:x}", self.0),
+        }
+    }
+}
+
+/// Looks up a media key by its official XF86 name or one of its
+/// aliases.
+fn lookup_media_key(name: &str) -> Option<Keysym> {
+    MEDIA_KEYS
+        .iter()
+        .find(|(official, _, aliases)| *official == name || aliases.contains(&name))
+        .map(|(_, keysym, _)| Keysym(*keysym))
+}
+
+/// Looks up a keysym by name, ignoring case and underscores. This is
+/// a fallback for names that aren't spelled quite right, such as
+/// `pageup` or `ESCAPE`; names that are ambiguous without their
+/// capitalization, like `AACUTE` (which could be `Aacute` or
+/// `aacute`), aren't found.
+fn lookup_normalized(name: &str) -> Option<Keysym> {
+    static INDEX: OnceLock<HashMap<String, Option<u32>>> = OnceLock::new();
+
+    let index = INDEX.get_or_init(|| {
+        let mut index = HashMap::new();
+        let mut add = |name: &str, keysym: u32| match index.entry(normalize_name(name)) {
+            Entry::Vacant(entry) => {
+                entry.insert(Some(keysym));
+            }
+            Entry::Occupied(mut entry) => {
+                if *entry.get() != Some(keysym) {
+                    entry.insert(None);
+                }
+            }
+        };
+
+        // x11-keysymdef can't list its names, so look up every keysym
+        // it knows about instead; they're all in the first 16 bits,
+        // apart from `VoidSymbol`.
+        for keysym in (0..=0xffff).chain(Some(0xffffff)) {
+            if let Some(record) = lookup_by_keysym(keysym) {
+                for name in record.names {
+                    add(name, record.keysym);
+                }
+            }
+        }
+        for (name, keysym) in KEYSYM_SYNONYMS {
+            add(name, *keysym);
+        }
+        for (name, keysym, _) in MEDIA_KEYS {
+            add(name, *keysym);
+        }
+
+        index
+    });
+
+    index
+        .get(&normalize_name(name))
+        .copied()
+        .flatten()
+        .map(Keysym)
+}
+
+/// Reduces a keysym name to lowercase letters and digits, for
+/// `lookup_normalized`.
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|&c| c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl KeySequence {
+    /// Parses a key sequence written as `settings` says: with its keys
+    /// separated by `settings.seq_separator`, or by whitespace if
+    /// that's None, with `settings.keysym_aliases` available as key
+    /// names, and with only the short modifier names
+    /// `settings.strict_modifiers` allows. Whitespace around a
+    /// separator is ignored.
+    pub fn parse(text: LineText<'_>, settings: &Settings) -> Result<Self, SyntaxError> {
+        let sep = match settings.seq_separator {
+            Some(sep) if !text.as_str().trim().is_empty() => sep,
+            _ => {
+                let keys = split_keys(text)
+                    .into_iter()
+                    .map(|word| Key::parse(word, settings))
+                    .collect::<Result<_, _>>()?;
+                return Ok(Self { keys });
+            }
+        };
+
+        let mut keys = Vec::new();
+        for part in text.split(|c| c == sep, false) {
+            let key = part.trim();
+            if key.as_str().is_empty() {
+                return Err(key.to_error("Expected a key".to_string()));
+            }
+            keys.push(Key::parse(key, settings)?);
+        }
+
+        Ok(Self { keys })
+    }
+}
+
+/// Splits a key sequence into its keys at whitespace, except that a
+/// key written as `keycode N` keeps its number.
+fn split_keys(text: LineText<'_>) -> Vec<LineText<'_>> {
+    let mut keys = Vec::new();
+    let mut words = text.split(char::is_whitespace, true);
+    while let Some(word) = words.next() {
+        let main_key = word.as_str().rsplit(['-', '+']).next();
+        if main_key == Some("keycode") {
+            if let Some(number) = words.next() {
+                keys.push(word.join(&number));
+                continue;
+            }
+        }
+        keys.push(word);
+    }
+    keys
+}
+
 impl<'a> TryFrom<LineText<'a>> for KeySequence {
     type Error = SyntaxError;
 
     fn try_from(value: LineText<'a>) -> Result<Self, Self::Error> {
         let mut keys = Vec::new();
-        for word in value.split(char::is_whitespace, true) {
+        for word in split_keys(value) {
             keys.push(word.try_into()?);
         }
 
@@ -79,6 +540,14 @@ impl<'a> TryFrom<LineText<'a>> for Key {
     type Error = SyntaxError;
 
     fn try_from(text: LineText<'a>) -> Result<Self, Self::Error> {
+        Self::parse(text, &Settings::default())
+    }
+}
+
+impl Key {
+    /// Parses a key, such as `C-x`, as `KeySequence::parse` does.
+    fn parse(text: LineText<'_>, settings: &Settings) -> Result<Self, SyntaxError> {
+        let aliases = &settings.keysym_aliases;
         let mut subkeys: Vec<_> = text.split(|c| c == '-' || c == '+', false).collect();
         let mut modifiers = ModField {
             mod_shift: false,
@@ -88,34 +557,84 @@ impl<'a> TryFrom<LineText<'a>> for Key {
             mod3: false,
             mod4: false,
             mod5: false,
+            mod_altgr: false,
+            any: false,
         };
 
+        // The wildcard matches keys with any modifiers, so it doesn't
+        // take any of its own.
+        if text.as_str() == "<any>" {
+            return Ok(Self {
+                main_key: Keysym::ANY,
+                modifiers,
+            });
+        }
+
         // This can only fail if `text` is of length 0, which is
         // impossible because we're only called from
         // KeySequence::try_from, which uses LineText::split with
         // `merge = true`, which never emits blank LineTexts.
         let last = subkeys.pop().unwrap();
+        if last.as_str().is_empty() {
+            // Point just past the last separator, where the key
+            // should have been.
+            return Err(last.to_error("Expected a key after modifiers".to_string()));
+        }
+
+        let any = subkeys
+            .iter()
+            .find(|m| is_any_modifier(m.as_str()))
+            .cloned();
         for modifier in subkeys.into_iter() {
-            modifiers.add(modifier)?;
+            modifiers.add(modifier, settings.strict_modifiers)?;
+        }
+
+        if let Some(any) = any {
+            if modifiers.canonical_order().is_empty() {
+                // Shift makes no difference, so leave it out of the
+                // letter too.
+                let main_key = Keysym::parse(last, aliases)?;
+                return Ok(Self {
+                    main_key: main_key.to_lowercase().unwrap_or(main_key),
+                    modifiers,
+                });
+            }
+            let errmsg = format!(
+                "\"{}\" matches any modifiers, so it can't go with others",
+                any.as_str()
+            );
+            return Err(any.to_error(errmsg));
         }
 
         Ok(Self {
-            main_key: last.try_into()?,
+            main_key: Keysym::parse(last, aliases)?,
             modifiers,
-        })
+        }
+        .with_shifted_case())
     }
 }
 
 impl ModField {
-    /// Attempts to add a modifier key with the given name.
-    fn add(&mut self, modifier: LineText<'_>) -> Result<(), SyntaxError> {
+    /// Attempts to add a modifier key with the given name. If `strict`
+    /// is set, the single-letter names that are easily mistaken for
+    /// others are rejected.
+    fn add(&mut self, modifier: LineText<'_>, strict: bool) -> Result<(), SyntaxError> {
         let text = modifier.as_str();
+        if let Some(long) = terse_modifier(text).filter(|_| strict) {
+            let errmsg = format!(
+                "\"{}\" is short for {}, but only C (control), M (alt) and S (shift) \
+                 can be written short with strict-modifiers set; write \"{}\" instead",
+                text, long, long
+            );
+            return Err(modifier.to_error(errmsg));
+        }
         match text {
             // Case-sensitive short names.
             "C" => self.mod_control = true,
             "S" => self.mod_shift = true,
             "A" | "M" => self.mod1 = true,
             "s" | "h" => self.mod4 = true,
+            _ if is_any_modifier(text) => self.any = true,
 
             // Non-case-sensitive long names.
             _ => match &*text.to_ascii_lowercase() {
@@ -129,6 +648,7 @@ impl ModField {
                 // all keyboards or not...
                 "mod4" | "super" | "windows" | "command" | "hyper" => self.mod4 = true,
                 "mod5" => self.mod5 = true,
+                "altgr" | "iso_level3_shift" => self.mod_altgr = true,
                 _ => {
                     let errmsg = format!("Invalid modifier \"{}\"", text);
                     return Err(modifier.to_error(errmsg));
@@ -140,10 +660,147 @@ impl ModField {
     }
 }
 
+/// Gets the long name of a modifier written with one of the
+/// single-letter names that `strict-modifiers` rejects, since they're
+/// easily confused: `s` looks like it could be Shift, for one.
+fn terse_modifier(name: &str) -> Option<&'static str> {
+    match name {
+        "A" => Some("alt"),
+        "s" => Some("super"),
+        "h" => Some("hyper"),
+        _ => None,
+    }
+}
+
+/// Parses a number written in hexadecimal, without any sign.
+fn parse_hex(text: &str) -> Option<u32> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// Checks whether a modifier name is `*` or `Any`, which stand for
+/// whatever modifiers are held.
+fn is_any_modifier(name: &str) -> bool {
+    name == "*" || name.eq_ignore_ascii_case("any")
+}
+
+impl Keysym {
+    /// Checks whether the keysym names a modifier key, such as
+    /// `Shift_L` or `Super_R`, rather than an ordinary key.
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.0,
+            // Shift_L through Hyper_R.
+            0xffe1..=0xffee
+            // ISO_Lock through ISO_Last_Group_Lock, which includes
+            // ISO_Level3_Shift.
+            | 0xfe01..=0xfe0f
+            // Mode_switch.
+            | 0xff7e
+            // Num_Lock.
+            | 0xff7f
+        )
+    }
+}
+
 impl<'a> TryFrom<LineText<'a>> for Keysym {
     type Error = SyntaxError;
 
     fn try_from(text: LineText<'a>) -> Result<Self, Self::Error> {
+        Self::parse(text, &HashMap::new())
+    }
+}
+
+impl Keysym {
+    /// Gets the keysym that types a Unicode character. That's the
+    /// keysym X has always had for it, if there is one, and otherwise,
+    /// by X's convention for the rest of Unicode, the code point with
+    /// 0x1000000 added. Control characters have no keysym.
+    fn from_char(c: char) -> Option<Self> {
+        if let Some(record) = lookup_by_codepoint(c) {
+            return Some(Self(record.keysym));
+        }
+        match u32::from(c) {
+            0..=0x1f | 0x7f..=0x9f => None,
+            codepoint => Some(Self(0x0100_0000 | codepoint)),
+        }
+    }
+
+    /// Parses a keysym name, looking in `aliases` before the standard
+    /// names. A keysym can also be written as a hexadecimal number
+    /// starting with `0x`, as `xev` prints them, or as the Unicode
+    /// character it types, like `U+20AC`; a raw keycode as `#N` or
+    /// `keycode N`; and a mouse button as `Button1` through
+    /// `Button5`.
+    fn parse(text: LineText<'_>, aliases: &HashMap<String, Keysym>) -> Result<Self, SyntaxError> {
+        if let Some(&keysym) = aliases.get(text.as_str()) {
+            return Ok(keysym);
+        }
+
+        let keycode = match text.as_str().strip_prefix("keycode") {
+            Some(number) if number.is_empty() || number.starts_with(char::is_whitespace) => {
+                Some(number.trim_start())
+            }
+            _ => text
+                .as_str()
+                .strip_prefix('#')
+                .filter(|number| !number.is_empty()),
+        };
+        if let Some(number) = keycode {
+            // X11 keycodes start at 8.
+            return match number.parse::<u8>() {
+                Ok(keycode) if keycode >= 8 => Ok(Self::from_keycode(keycode)),
+                _ => Err(text.to_error("Expected a keycode from 8 to 255".to_string())),
+            };
+        }
+
+        if let Some(number) = text.as_str().strip_prefix("Button") {
+            return match number.parse::<u8>() {
+                Ok(button) if (1..=Self::MAX_BUTTON).contains(&button) => {
+                    Ok(Self::from_button(button))
+                }
+                _ => Err(text.to_error(format!(
+                    "Expected a mouse button from Button1 to Button{}",
+                    Self::MAX_BUTTON
+                ))),
+            };
+        }
+
+        if let Some(hex) = text.as_str().strip_prefix("0x") {
+            return match parse_hex(hex) {
+                Some(keysym) if keysym != Keysym::ANY.0 && keysym <= Self::MAX_KEYSYM => {
+                    Ok(Self(keysym))
+                }
+                _ => {
+                    let errmsg = format!("Invalid keysym number \"{}\"", text.as_str());
+                    Err(text.to_error(errmsg))
+                }
+            };
+        }
+
+        if let Some(hex) = text.as_str().strip_prefix("U+") {
+            return match parse_hex(hex)
+                .and_then(char::from_u32)
+                .and_then(Self::from_char)
+            {
+                Some(keysym) => Ok(keysym),
+                None => {
+                    let errmsg = format!("Invalid Unicode character \"{}\"", text.as_str());
+                    Err(text.to_error(errmsg))
+                }
+            };
+        }
+
+        if let Some(keysym) = lookup_media_key(text.as_str()) {
+            return Ok(keysym);
+        }
+
+        if let Some((_, keysym)) = KEYSYM_SYNONYMS.iter().find(|(n, _)| *n == text.as_str()) {
+            return Ok(Self(*keysym));
+        }
+
         let record = match (text.as_str()).len() {
             // len is 1, so we must have a zeroth character, so unwrap
             // is OK here.
@@ -153,6 +810,13 @@ impl<'a> TryFrom<LineText<'a>> for Keysym {
 
         match record {
             Some(record) => Ok(Self(record.keysym)),
+            None if text.as_str().len() > 1 => match lookup_normalized(text.as_str()) {
+                Some(keysym) => Ok(keysym),
+                None => {
+                    let errmsg = format!("Invalid keysym \"{}\"", text.as_str());
+                    Err(text.to_error(errmsg))
+                }
+            },
             None => {
                 let errmsg = format!("Invalid keysym \"{}\"", text.as_str());
                 Err(text.to_error(errmsg))
@@ -181,6 +845,139 @@ mod tests {
         assert_eq!(from_name, Keysym(0x5b));
     }
 
+    #[test]
+    fn modifier_keysym_test() {
+        // Modifier keys are valid main keys, but are recognized as
+        // modifiers.
+        let super_l: Keysym = mk_lt("Super_L").try_into().unwrap();
+        assert!(super_l.is_modifier());
+
+        let control_r: Keysym = mk_lt("Control_R").try_into().unwrap();
+        assert!(control_r.is_modifier());
+
+        let x: Keysym = mk_lt("x").try_into().unwrap();
+        assert!(!x.is_modifier());
+    }
+
+    #[test]
+    fn media_key_test() {
+        let volup: Keysym = mk_lt("volup").try_into().unwrap();
+        let volumeup: Keysym = mk_lt("volumeup").try_into().unwrap();
+        let official: Keysym = mk_lt("XF86AudioRaiseVolume").try_into().unwrap();
+        assert_eq!(volup, Keysym(0x1008ff13));
+        assert_eq!(volumeup, volup);
+        assert_eq!(official, volup);
+        assert_eq!(volup.to_string(), "XF86AudioRaiseVolume");
+
+        let mute: Keysym = mk_lt("mute").try_into().unwrap();
+        assert_eq!(mute, Keysym(0x1008ff12));
+
+        // None of the aliases should hide an ordinary keysym name.
+        for (_, _, aliases) in MEDIA_KEYS {
+            for alias in aliases.iter() {
+                assert!(lookup_by_name(alias).is_none(), "{} is a keysym", alias);
+            }
+        }
+    }
+
+    #[test]
+    fn forgiving_keysym_test() {
+        let parse = |name| Keysym::try_from(mk_lt(name));
+
+        let page_up = parse("Prior").unwrap();
+        assert_eq!(parse("Page_Up").unwrap(), page_up);
+        assert_eq!(parse("pageup").unwrap(), page_up);
+        assert_eq!(parse("page_up").unwrap(), page_up);
+        assert_eq!(parse("PRIOR").unwrap(), page_up);
+        assert_eq!(parse("ESCAPE").unwrap(), parse("Escape").unwrap());
+        assert_eq!(parse("backspace").unwrap(), parse("BackSpace").unwrap());
+        assert_eq!(parse("kp_enter").unwrap(), parse("KP_Enter").unwrap());
+        assert_eq!(
+            parse("xf86audiomute").unwrap(),
+            parse("XF86AudioMute").unwrap()
+        );
+
+        // Exact spellings still come first, and names that differ only
+        // by case are too ambiguous to guess at.
+        assert_eq!(parse("Aacute").unwrap(), Keysym(0xc1));
+        assert_eq!(parse("aacute").unwrap(), Keysym(0xe1));
+        assert!(parse("AACUTE").is_err());
+        assert!(parse("pagedownn").is_err());
+    }
+
+    #[test]
+    fn any_key_test() {
+        let any = Key::try_from(mk_lt("<any>")).unwrap();
+        assert!(any.is_any());
+        assert_eq!(any.to_string(), "<any>");
+        assert!(any.matches(&Key::try_from(mk_lt("C-M-Return")).unwrap(), Modifier::Mod2));
+        assert!(Key::try_from(mk_lt("C-<any>")).is_err());
+
+        let a = Key::try_from(mk_lt("a")).unwrap();
+        assert!(a.matches(&a, Modifier::Mod2));
+        assert!(!a.matches(&any, Modifier::Mod2));
+    }
+
+    #[test]
+    fn any_modifier_test() {
+        let key = |text| Key::try_from(mk_lt(text)).unwrap();
+        let any_x = key("*-x");
+        assert!(any_x.modifiers.any);
+        assert_eq!(any_x.modifiers.canonical_order(), vec![]);
+        assert_eq!(any_x, key("Any-x"));
+        assert_eq!(any_x, key("any+X"));
+        assert_eq!(any_x.to_string(), "*-x");
+
+        assert!(any_x.matches(&key("x"), Modifier::Mod2));
+        assert!(any_x.matches(&key("C-M-x"), Modifier::Mod2));
+        assert!(any_x.matches(&key("S-x"), Modifier::Mod2));
+        assert!(!any_x.matches(&key("C-y"), Modifier::Mod2));
+        assert!(!key("x").matches(&key("C-x"), Modifier::Mod2));
+
+        // It wouldn't mean anything alongside other modifiers.
+        assert!(Key::try_from(mk_lt("C-*-x")).is_err());
+        assert!(Key::try_from(mk_lt("*-S-x")).is_err());
+        // A key can still be called `*`.
+        assert!(!key("C-*").modifiers.any);
+    }
+
+    #[test]
+    fn altgr_test() {
+        let key = |text| Key::try_from(mk_lt(text)).unwrap();
+        let altgr_e = key("AltGr-e");
+        assert!(altgr_e.modifiers.mod_altgr);
+        assert_eq!(altgr_e, key("iso_level3_shift-e"));
+        assert_eq!(altgr_e, key("ALTGR+e"));
+        assert_eq!(key("C-S-AltGr-e").to_string(), "C-S-AltGr-e");
+        assert_eq!(
+            key("M-altgr-e").modifiers.canonical_order(),
+            vec![Modifier::Mod1, Modifier::AltGr]
+        );
+
+        // Pressing AltGr sets whichever modifier it's mapped to on
+        // the layout, which is read as AltGr.
+        let pressed = |mods| Key {
+            modifiers: key("mod5-e").modifiers.with_altgr(mods),
+            ..altgr_e
+        };
+        assert!(altgr_e.matches(&pressed(Modifier::Mod5), Modifier::Mod2));
+        assert_eq!(pressed(Modifier::Mod5).to_string(), "AltGr-e");
+        assert!(!altgr_e.matches(&pressed(Modifier::Mod3), Modifier::Mod2));
+        assert!(!altgr_e.matches(&key("e"), Modifier::Mod2));
+    }
+
+    #[test]
+    fn num_lock_test() {
+        let key = |text| Key::try_from(mk_lt(text)).unwrap();
+        assert!(key("x").matches(&key("mod2-x"), Modifier::Mod2));
+        assert_eq!(key("mod2-x").without_locks(Modifier::Mod2), key("x"));
+
+        // Only the modifier Num Lock sets is ignored.
+        assert!(key("x").matches(&key("mod3-x"), Modifier::Mod3));
+        assert!(!key("x").matches(&key("mod2-x"), Modifier::Mod3));
+        assert_ne!(key("mod2-x").without_locks(Modifier::Mod3), key("x"));
+    }
+
     #[test]
     fn key_parse_test() {
         // Basic Emacs-style keybinding.
@@ -197,28 +994,155 @@ mod tests {
                     mod3: false,
                     mod4: false,
                     mod5: false,
+                    mod_altgr: false,
+                    any: false,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn display_round_trip_test() {
+        let modifiers = ["", "C-", "S-", "M-s-", "C-S-M-s-mod2-mod3-mod5-"];
+        let keysyms = (1..=0xffff)
+            .filter(|&keysym| lookup_by_keysym(keysym).is_some())
+            .chain(MEDIA_KEYS.iter().map(|(_, keysym, _)| *keysym))
+            // Some keysyms that don't have names.
+            .chain(vec![0x1008ff00, 0x12345678]);
+        for keysym in keysyms {
+            for modifiers in modifiers.iter() {
+                let key = Key {
+                    main_key: Keysym(keysym),
+                    modifiers: Key::try_from(mk_lt(&format!("{}x", modifiers)))
+                        .unwrap()
+                        .modifiers,
                 }
+                .with_shifted_case();
+                let text = key.to_string();
+                assert_eq!(Key::try_from(mk_lt(&text)).unwrap(), key, "{}", text);
             }
+        }
+
+        let keyseq = KeySequence::try_from(mk_lt("C-x  S-a 0x1008ff00 <any>")).unwrap();
+        assert_eq!(keyseq.to_string(), "C-x S-a 0x1008ff00 <any>");
+        assert_eq!(
+            KeySequence::try_from(mk_lt(&keyseq.to_string())).unwrap(),
+            keyseq
         );
+        assert!(Keysym::try_from(mk_lt("0xzz")).is_err());
+        assert!(Keysym::try_from(mk_lt("0x0")).is_err());
+        // Too big for a keysym, or not a plain number.
+        assert!(Keysym::try_from(mk_lt("0x+61")).is_err());
+        assert!(Keysym::try_from(mk_lt("0x")).is_err());
+        assert!(Keysym::try_from(mk_lt("0x20000000")).is_err());
+
+        // Keycodes and buttons are kept above the highest keysym, so
+        // can only be written their own way, and come back that way.
+        for text in ["#38", "Button1"].iter() {
+            let keysym = Keysym::try_from(mk_lt(text)).unwrap();
+            assert_eq!(keysym.to_string(), *text);
+            let number = format!("0x{:x}", keysym.0);
+            assert!(Keysym::try_from(mk_lt(&number)).is_err(), "{}", number);
+        }
+    }
+
+    #[test]
+    fn keysym_number_test() {
+        let parse = |text| Keysym::try_from(mk_lt(text));
+        assert_eq!(parse("0x1008FF11").unwrap(), Keysym(0x1008ff11));
+        assert_eq!(parse("0x1fffffff").unwrap(), Keysym(0x1fff_ffff));
+
+        // Unicode characters beyond the ones X has always had keysyms
+        // for are the code point plus 0x1000000.
+        assert_eq!(parse("U+1F600").unwrap(), Keysym(0x0101_f600));
+        // The ones it has keep those keysyms.
+        assert_eq!(parse("U+0061").unwrap(), parse("a").unwrap());
+        assert_eq!(parse("U+20AC").unwrap(), parse("EuroSign").unwrap());
+        assert!(parse("U+").is_err());
+        assert!(parse("U+D800").is_err());
+        assert!(parse("U+110000").is_err());
+        assert!(parse("U+0007").is_err());
+    }
+
+    #[test]
+    fn keycode_parse_test() {
+        let key = Key::try_from(mk_lt("C-#38")).unwrap();
+        assert_eq!(key.main_key.keycode(), Some(38));
+        assert!(key.modifiers.mod_control);
+        assert_eq!(key.to_string(), "C-#38");
+
+        let keyseq = KeySequence::try_from(mk_lt("C-keycode 38 x keycode 255")).unwrap();
+        assert_eq!(keyseq.to_string(), "C-#38 x #255");
+
+        // `#` on its own is still the number sign.
+        let hash = Keysym::try_from(mk_lt("#")).unwrap();
+        assert_eq!(hash, Keysym(0x23));
+        assert_eq!(hash.keycode(), None);
+
+        assert!(Key::try_from(mk_lt("#7")).is_err());
+        assert!(Key::try_from(mk_lt("#256")).is_err());
+        assert!(KeySequence::try_from(mk_lt("keycode")).is_err());
+        assert!(KeySequence::try_from(mk_lt("keycode x")).is_err());
+    }
+
+    #[test]
+    fn button_parse_test() {
+        let key = Key::try_from(mk_lt("M-Button1")).unwrap();
+        assert_eq!(key.main_key.button(), Some(1));
+        assert_eq!(key.main_key.keycode(), None);
+        assert!(key.modifiers.mod1);
+        assert_eq!(key.to_string(), "M-Button1");
+
+        let keyseq = KeySequence::try_from(mk_lt("super+Button3 x")).unwrap();
+        assert_eq!(keyseq.to_string(), "s-Button3 x");
+        assert_eq!(Keysym::try_from(mk_lt("x")).unwrap().button(), None);
+
+        assert!(Key::try_from(mk_lt("Button0")).is_err());
+        assert!(Key::try_from(mk_lt("Button6")).is_err());
+        assert!(Key::try_from(mk_lt("M-Button")).is_err());
+    }
+
+    #[test]
+    fn canonical_modifier_order_test() {
+        // The order the modifiers are written in doesn't matter.
+        let k1: Key = mk_lt("super-shift-alt-control-x").try_into().unwrap();
+        let k2: Key = mk_lt("C-S-M-s-x").try_into().unwrap();
+        assert_eq!(k1, k2);
+        assert_eq!(
+            k1.canonical_modifier_order(),
+            vec![
+                Modifier::Control,
+                Modifier::Shift,
+                Modifier::Mod1,
+                Modifier::Mod4
+            ]
+        );
+        assert_eq!(k1.to_string(), "C-S-M-s-x");
+
+        let bare: Key = mk_lt("Return").try_into().unwrap();
+        assert_eq!(bare.canonical_modifier_order(), vec![]);
+        assert_eq!(bare.to_string(), "Return");
     }
 
     #[test]
     fn ambiguous_modifier_parse_test() {
         // Could be interpreted as "control", but should be
-        // interpreted as just a capital C.
+        // interpreted as just a capital C, which is typed with Shift.
         let key: Key = mk_lt("C").try_into().unwrap();
         assert_eq!(
             key,
             Key {
                 main_key: mk_lt("C").try_into().unwrap(),
                 modifiers: ModField {
-                    mod_shift: false,
+                    mod_shift: true,
                     mod_control: false,
                     mod1: false,
                     mod2: false,
                     mod3: false,
                     mod4: false,
                     mod5: false,
+                    mod_altgr: false,
+                    any: false,
                 }
             }
         );
@@ -228,7 +1152,11 @@ mod tests {
     fn missing_main_key_parse_test() {
         // A keybinding without a main key.
         let invalid = Key::try_from(mk_lt("C-M-"));
-        assert!(invalid.is_err());
+        let msg = invalid.unwrap_err().to_string();
+        assert!(msg.ends_with("Expected a key after modifiers"));
+
+        // The error should point just past the trailing separator.
+        assert!(msg.contains("foo.txt:10:4"));
     }
 
     #[test]
@@ -240,7 +1168,7 @@ mod tests {
         assert_eq!(
             key,
             Key {
-                main_key: mk_lt("z").try_into().unwrap(),
+                main_key: mk_lt("Z").try_into().unwrap(),
                 modifiers: ModField {
                     mod_shift: true,
                     mod_control: true,
@@ -249,6 +1177,8 @@ mod tests {
                     mod3: false,
                     mod4: true,
                     mod5: false,
+                    mod_altgr: false,
+                    any: false,
                 }
             }
         );
@@ -273,12 +1203,45 @@ mod tests {
                     mod3: false,
                     mod4: false,
                     mod5: false,
+                    mod_altgr: false,
+                    any: false,
                 }
             }
         );
         assert!(invalid.is_err());
     }
 
+    #[test]
+    fn strict_modifiers_test() {
+        let lenient = Settings::default();
+        let strict = Settings {
+            strict_modifiers: true,
+            ..Settings::default()
+        };
+        let parse = |text: &str, settings| KeySequence::parse(mk_lt(text), settings);
+
+        // The names that are allowed either way.
+        for text in ["C-x", "M-x", "S-x", "super-x", "Hyper-x", "alt-x", "mod4-x"] {
+            assert!(parse(text, &lenient).is_ok(), "{}", text);
+            assert!(parse(text, &strict).is_ok(), "{}", text);
+        }
+
+        // And the ones only allowed when not strict.
+        for (text, long) in [("s-x", "super"), ("h-x", "hyper"), ("A-x", "alt")] {
+            assert_eq!(
+                parse(text, &lenient).unwrap(),
+                parse(&format!("{}-x", long), &lenient).unwrap()
+            );
+            let msg = parse(text, &strict).unwrap_err().to_string();
+            assert!(msg.contains("foo.txt:10:0"), "{}", msg);
+            assert!(
+                msg.ends_with(&format!("write \"{}\" instead", long)),
+                "{}",
+                msg
+            );
+        }
+    }
+
     #[test]
     fn long_non_case_sensitive_parse_test() {
         // Long key names should not be case-sensitive.
@@ -295,17 +1258,15 @@ mod tests {
                     mod3: false,
                     mod4: true,
                     mod5: false,
+                    mod_altgr: false,
+                    any: false,
                 }
             }
         );
     }
 
     #[test]
-    #[ignore]
     fn shift_test() {
-        // This feature is not implemented yet, and may never be
-        // implemented.
-
         // The "shift" modifier should capitalize the main key.
         let k1: Key = mk_lt("x").try_into().unwrap();
         let k2: Key = mk_lt("shift+x").try_into().unwrap();
@@ -313,6 +1274,13 @@ mod tests {
 
         assert_ne!(k1.main_key, k2.main_key);
         assert_eq!(k2, k3);
+        assert_eq!(k3.to_string(), "S-x");
+
+        // Keys without case are left alone.
+        let k4: Key = mk_lt("S-2").try_into().unwrap();
+        assert_eq!(k4.to_string(), "S-2");
+        let k5: Key = mk_lt("S-X").try_into().unwrap();
+        assert_eq!(k5, k3);
     }
 
     #[test]
@@ -334,6 +1302,8 @@ mod tests {
                             mod3: false,
                             mod4: false,
                             mod5: false,
+                            mod_altgr: false,
+                            any: false,
                         },
                     },
                     Key {
@@ -346,6 +1316,8 @@ mod tests {
                             mod3: false,
                             mod4: false,
                             mod5: false,
+                            mod_altgr: false,
+                            any: false,
                         },
                     },
                     Key {
@@ -358,6 +1330,8 @@ mod tests {
                             mod3: false,
                             mod4: false,
                             mod5: false,
+                            mod_altgr: false,
+                            any: false,
                         },
                     },
                 ],
@@ -0,0 +1,56 @@
+// Optional logging of what the daemon is doing.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much to log: nothing at 0, what we grab and do at 1, and
+/// every key and how it was matched at 2 or more.
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Sets how much to log, as the number of times `-v` was given.
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Checks whether messages at the given level should be logged.
+pub fn enabled(level: u8) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+/// Logs a message, formatted like `eprintln!`, if we were given
+/// `-v`. This is for what we grab and what we do in response.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled(1) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Logs a message, formatted like `eprintln!`, if we were given
+/// `-vv`. This is for every key and how it was matched.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled(2) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use debug;
+pub(crate) use verbose;
@@ -16,36 +16,81 @@
 // You should have received a copy of the GNU General Public License
 // along with ahkd. If not, see <https://www.gnu.org/licenses/>.
 
-use clap::{App, Arg};
-use std::convert::Infallible;
+use clap::{App, Arg, ArgGroup, ArgMatches};
+use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader, Read};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
 
+mod ast;
 mod cfgfile;
+mod cheatsheet;
+mod check;
+mod control;
 mod daemon;
 mod keyseq;
+mod log;
+mod matcher;
+mod notify;
+mod popup;
+mod reaper;
+mod watchdog;
 mod x11;
 
-use cfgfile::parse_config;
-use daemon::daemon;
+use ast::write_ast;
+use cfgfile::{
+    default_config_path, parse_config_all, parse_sxhkd_config_all, Config, LineText, Location,
+    SyntaxError,
+};
+use cheatsheet::{write_html, write_text};
+use check::{check, check_layout};
+use daemon::{daemon, get_init_keys, DaemonOptions, OnDisconnect};
+use keyseq::{Key, KeySequence, Keysym};
+use matcher::{simulate, MatchResult};
+use x11::{GrabTarget, KeySource, X11Conn};
+
+/// The exit status when we lose the connection to the X server and
+/// have been told not to reconnect, so that supervisors can tell it
+/// apart from other failures.
+const DISCONNECTED_STATUS: i32 = 2;
+
+/// How many times to retry connecting to the X server at startup,
+/// unless `--retry` says otherwise.
+const DEFAULT_RETRIES: &str = "5";
+
+/// How many seconds `--safe-mode` waits for confirmation, unless
+/// `--safe-timeout` says otherwise.
+const DEFAULT_SAFE_TIMEOUT: u64 = 30;
 
 fn main() {
-    std::process::exit(if let Err(e) = run() {
+    if let Err(e) = run() {
         println!("{}", e);
-        1
-    } else {
-        0
-    })
+        match e.downcast_ref::<AhkdError>() {
+            Some(AhkdError::Disconnected) => std::process::exit(DISCONNECTED_STATUS),
+            _ => std::process::exit(1),
+        }
+    }
 }
 
-fn run() -> Result<Infallible, Box<dyn Error>> {
+fn run() -> Result<(), Box<dyn Error>> {
     let matches = App::new("ahkd")
         .version("0.1.0")
         .author("A. Bethel")
         .about("Hotkey manager for X11")
-        .arg(Arg::with_name("config-file").required(true).index(1))
+        .arg(
+            Arg::with_name("config-file")
+                .multiple(true)
+                .index(1)
+                .help(
+                    "Configuration files to read, in order, or - for standard input \
+                     [default: $XDG_CONFIG_HOME/ahkd/config]",
+                ),
+        )
         .arg(
             Arg::with_name("display")
                 .short("d")
@@ -54,18 +99,502 @@ fn run() -> Result<Infallible, Box<dyn Error>> {
                 .help("Selects the X11 display to connect to")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("window")
+                .short("w")
+                .long("window")
+                .value_name("WINDOW")
+                .help(
+                    "Only listens for keys while the given window, or one inside it, has the focus",
+                )
+                .validator(|id| match parse_window(&id) {
+                    Some(_) => Ok(()),
+                    None => Err(format!("\"{}\" is not a window ID", id)),
+                }),
+        )
+        .arg(
+            Arg::with_name("screen")
+                .long("screen")
+                .value_name("N")
+                .help("Only grabs keys on the given screen, rather than on every screen")
+                .conflicts_with("window")
+                .validator(|n| match n.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("\"{}\" is not a screen number", n)),
+                }),
+        )
+        .arg(
+            Arg::with_name("syntax")
+                .long("syntax")
+                .value_name("SYNTAX")
+                .help("Selects the syntax the configuration files are written in")
+                .possible_values(&["ahkd", "sxhkd"])
+                .default_value("ahkd"),
+        )
+        .arg(
+            Arg::with_name("on-disconnect")
+                .long("on-disconnect")
+                .value_name("ACTION")
+                .help("Whether to exit or reconnect when the X server goes away")
+                .possible_values(&["exit", "reconnect"])
+                .default_value("exit"),
+        )
+        .arg(
+            Arg::with_name("retry")
+                .long("retry")
+                .value_name("N")
+                .help("How many times to retry connecting if the X server isn't ready yet")
+                .default_value(DEFAULT_RETRIES)
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Expected a number of retries".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("key-timeout")
+                .long("key-timeout")
+                .value_name("MILLISECONDS")
+                .help("Overrides the key-timeout option in the configuration files")
+                .validator(|millis| match millis.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("Expected a number of milliseconds".to_string()),
+                }),
+        )
+        .arg(
+            Arg::with_name("timing")
+                .long("timing")
+                .help("Prints how long it takes to act on each key sequence"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Logs what keys are grabbed and what's done with them; give twice to log every key"),
+        )
+        .arg(
+            Arg::with_name("tui")
+                .long("tui")
+                .help("Shows each key sequence, and the bindings it could lead to, as it's typed"),
+        )
+        .arg(
+            Arg::with_name("safe-mode").long("safe-mode").help(
+                "Waits for Return to be pressed before grabbing any keys, and exits if it isn't",
+            ),
+        )
+        .arg(
+            Arg::with_name("safe-timeout")
+                .long("safe-timeout")
+                .value_name("SECONDS")
+                .help("How long --safe-mode waits for Return to be pressed [default: 30]")
+                .requires("safe-mode")
+                .validator(|secs| match secs.parse::<u64>() {
+                    Ok(0) | Err(_) => Err("Expected a number of seconds".to_string()),
+                    Ok(_) => Ok(()),
+                }),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Runs in the background once connected to the X server"),
+        )
+        .arg(
+            Arg::with_name("pidfile")
+                .long("pidfile")
+                .value_name("PATH")
+                .help("Writes the process ID to the given file, removing it on exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .value_name("PATH")
+                .help("Listens for commands on a Unix socket at the given path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Checks the configuration for mistakes, then exits"),
+        )
+        .arg(
+            Arg::with_name("check-layout")
+                .long("check-layout")
+                .help("With --check, also checks the keys against the X server's keyboard layout")
+                .requires("check"),
+        )
+        .arg(
+            Arg::with_name("ast")
+                .long("ast")
+                .help("Prints the parsed configuration as JSON, then exits"),
+        )
+        .arg(
+            Arg::with_name("cheatsheet")
+                .long("cheatsheet")
+                .value_name("FORMAT")
+                .help("Prints a reference of every binding, then exits")
+                .takes_value(true)
+                .possible_values(&["html"]),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .help("Prints a table of every binding, then exits"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name("KEYS")
+                .help("Shows where the binding for a key sequence is defined, then exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .value_name("KEYS")
+                .help("Shows what typing a key sequence would do, then exits")
+                .takes_value(true),
+        )
+        .group(ArgGroup::with_name("lookup").args(&["explain", "simulate"]))
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("NAME")
+                .help("With --explain or --simulate, uses the bindings of a mode")
+                .takes_value(true)
+                .requires("lookup"),
+        )
+        .arg(
+            Arg::with_name("print-keysym")
+                .long("print-keysym")
+                .help("Prints the name of the next key pressed, then exits"),
+        )
         .get_matches();
 
-    // "config" is a required argument, so we can `unwrap` here.
-    let config_name = matches.value_of("config-file").unwrap();
-    let config_file = File::open(config_name)?;
-    let config_buf = BufReader::new(config_file);
-    let config = parse_config(config_buf, &config_name)?;
+    log::set_verbosity(u8::try_from(matches.occurrences_of("verbose")).unwrap_or(u8::MAX));
+
+    // This doesn't need a configuration, so it doesn't go looking
+    // for one.
+    if matches.is_present("print-keysym") {
+        return print_keysym(matches.value_of("display"));
+    }
+
+    let (config, errors) = load_config(&matches)?;
+
+    if matches.is_present("check-layout") {
+        // The other checks happen whenever the configuration is
+        // loaded, but this one needs the X server, so is only done
+        // when asked for.
+        let conn = X11Conn::new(matches.value_of("display"))?;
+        for warning in check_layout(&config, |keysym| conn.keysym_level(keysym), conn.altgr()) {
+            eprintln!("Warning: {}", warning);
+        }
+        // The daemon warns about these as it grabs them, but by then
+        // it's too late to pick other keys.
+        for grab in get_init_keys(&config) {
+            if conn.has_key(grab.key.main_key) && !conn.can_grab(&grab.key)? {
+                eprintln!("Warning: {}", AhkdError::AlreadyGrabbed(grab.key));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Box::new(AhkdError::InvalidConfig(errors.len())));
+    }
+    if matches.is_present("check") {
+        return Ok(());
+    }
+
+    if matches.is_present("ast") {
+        write_ast(&config, &mut std::io::stdout())?;
+        return Ok(());
+    }
 
-    let display_name = matches.value_of("display");
+    // clap checks "cheatsheet" is one of the possible values, and
+    // there's only one so far.
+    if matches.is_present("cheatsheet") {
+        write_html(&config, &mut std::io::stdout())?;
+        return Ok(());
+    }
 
-    daemon(config, display_name)?;
-    todo!()
+    if matches.is_present("list") {
+        write_text(&config, &mut std::io::stdout())?;
+        return Ok(());
+    }
+
+    let mode = matches.value_of("mode");
+    if let Some(mode) = mode {
+        if !config
+            .commands
+            .iter()
+            .any(|line| line.mode.as_deref() == Some(mode))
+        {
+            return Err(Box::new(AhkdError::NoSuchMode(mode.to_string())));
+        }
+    }
+
+    if let Some(keys) = matches.value_of("explain") {
+        explain(&config, keys, mode)?;
+        return Ok(());
+    }
+
+    if let Some(keys) = matches.value_of("simulate") {
+        simulate_keys(&config, keys, mode)?;
+        return Ok(());
+    }
+
+    // "on-disconnect" has a default value, and clap checks it's one
+    // of the possible values.
+    let on_disconnect = match matches.value_of("on-disconnect").unwrap() {
+        "reconnect" => OnDisconnect::Reconnect,
+        _ => OnDisconnect::Exit,
+    };
+
+    let options = DaemonOptions {
+        display_name: matches.value_of("display"),
+        // clap has already checked that the window ID and screen
+        // number parse.
+        target: match (matches.value_of("window"), matches.value_of("screen")) {
+            (Some(id), _) => GrabTarget::Window(parse_window(id).unwrap()),
+            (None, Some(n)) => GrabTarget::Screen(n.parse().unwrap()),
+            (None, None) => GrabTarget::AllScreens,
+        },
+        on_disconnect,
+        // "retry" has a default value, and clap has checked that it
+        // parses.
+        retries: matches.value_of("retry").unwrap().parse().unwrap(),
+        timing: matches.is_present("timing"),
+        tui: matches.is_present("tui"),
+        // clap has already checked that the timeout parses.
+        safe_mode: if matches.is_present("safe-mode") {
+            let secs = matches
+                .value_of("safe-timeout")
+                .map_or(DEFAULT_SAFE_TIMEOUT, |secs| secs.parse().unwrap());
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        },
+        background: matches.is_present("daemon"),
+        pid_file: matches.value_of("pidfile"),
+        socket: matches.value_of("socket"),
+    };
+
+    // Reloading reads the same files again, keeping the old
+    // configuration if the new one has any errors.
+    let reload = || -> Result<Config, Box<dyn Error>> {
+        let (config, errors) = load_config(&matches)?;
+        if !errors.is_empty() {
+            return Err(Box::new(AhkdError::InvalidConfig(errors.len())));
+        }
+        Ok(config)
+    };
+
+    // The daemon only ever returns successfully if it's told to exit.
+    daemon(config, options, &reload)
+}
+
+/// Gets the configuration files named on the command line, or the
+/// default one if there aren't any. Fails if there aren't any and the
+/// default one doesn't exist.
+fn config_files(matches: &ArgMatches) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if let Some(names) = matches.values_of_os("config-file") {
+        return Ok(names.map(PathBuf::from).collect());
+    }
+    match default_config_path(env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        Some(path) if path.is_file() => Ok(vec![path]),
+        path => Err(Box::new(AhkdError::NoConfigFile(path))),
+    }
+}
+
+/// Gets the configuration given on standard input. This is read all
+/// at once the first time it's needed, and kept for reloads, since
+/// by then standard input has nothing left to give.
+fn stdin_config() -> io::Result<&'static str> {
+    static TEXT: OnceLock<String> = OnceLock::new();
+    if let Some(text) = TEXT.get() {
+        return Ok(text);
+    }
+    let mut text = String::new();
+    io::stdin().read_to_string(&mut text)?;
+    Ok(TEXT.get_or_init(|| text))
+}
+
+/// Reads every configuration file named on the command line, and
+/// applies any settings the command line overrides. Prints every
+/// syntax error and warning found, the warnings on standard error so
+/// that they stay out of what `--ast` and the like print, and returns
+/// the configuration along with the errors.
+fn load_config(matches: &ArgMatches) -> Result<(Config, Vec<SyntaxError>), Box<dyn Error>> {
+    // "syntax" has a default value, so we can `unwrap` here.
+    let sxhkd = matches.value_of("syntax").unwrap() == "sxhkd";
+    let mut config = Config::default();
+    let mut errors = Vec::new();
+    for path in config_files(matches)? {
+        let config_name = path.to_string_lossy();
+        let (config_buf, config_name): (BufReader<Box<dyn Read>>, _) = if config_name == "-" {
+            (
+                BufReader::new(Box::new(stdin_config()?.as_bytes())),
+                "<stdin>",
+            )
+        } else {
+            (BufReader::new(Box::new(File::open(&path)?)), &*config_name)
+        };
+        // Report every error at once, rather than making the user fix
+        // them one at a time.
+        if sxhkd {
+            errors.extend(parse_sxhkd_config_all(
+                &mut config,
+                config_buf,
+                config_name,
+            )?);
+        } else {
+            errors.extend(parse_config_all(&mut config, config_buf, config_name)?);
+        }
+    }
+
+    for err in &errors {
+        eprintln!("{}", err);
+    }
+    for warning in &config.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    for warning in check(&config) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    // clap has already checked that the timeout parses.
+    if let Some(millis) = matches.value_of("key-timeout") {
+        config.settings.key_timeout = match millis.parse().unwrap() {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        };
+    }
+
+    Ok((config, errors))
+}
+
+/// Parses a window ID, written in decimal or, as `xwininfo` and
+/// `xdotool` print them, in hexadecimal with a `0x` prefix.
+fn parse_window(id: &str) -> Option<u32> {
+    match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => id.parse().ok(),
+    }
+}
+
+/// Prints where the binding for a key sequence, given as text, was
+/// defined in `mode`, or outside of any mode for None, and what it
+/// does. Bindings of the same keys in other modes are listed after
+/// it, since they don't override it.
+fn explain(config: &Config, keys: &str, mode: Option<&str>) -> Result<(), Box<dyn Error>> {
+    // Read the keys the way the end of the configuration would.
+    let keyseq = KeySequence::parse(LineText::new("<command line>", 1, keys), &config.settings)?;
+    let in_mode = |mode: Option<&str>| match mode {
+        Some(mode) => format!(" in mode {}", mode),
+        None => String::new(),
+    };
+
+    let lines = config.bindings_for(&keyseq, mode);
+    match lines.split_first() {
+        None => println!("{} is not bound{}", keyseq, in_mode(mode)),
+        Some((active, shadowed)) => {
+            println!(
+                "{} is bound{} at {}: {}",
+                keyseq,
+                in_mode(mode),
+                active.location,
+                active.action
+            );
+            for line in shadowed {
+                println!(
+                    "  (also bound at {}: {}, which has no effect)",
+                    line.location, line.action
+                );
+            }
+        }
+    }
+
+    for line in config
+        .commands
+        .iter()
+        .filter(|line| line.keyseq == keyseq && line.mode.as_deref() != mode)
+    {
+        let other = match &line.mode {
+            Some(mode) => format!("in mode {}", mode),
+            None => "outside of any mode".to_string(),
+        };
+        println!(
+            "  (also bound {} at {}: {})",
+            other, line.location, line.action
+        );
+    }
+
+    Ok(())
+}
+
+/// Waits for a key to be pressed anywhere, and prints it as it'd be
+/// written in a configuration file, along with its keysym as a
+/// number. The keyboard is grabbed only while waiting. Modifier keys
+/// count, so that the names of keys like `Super_L` and
+/// `ISO_Level3_Shift` can be looked up too.
+fn print_keysym(display: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let conn = X11Conn::new(display)?;
+    println!("Press a key...");
+    // No deadline, but the wait can still be cut short, e.g. by the
+    // focus changing; just wait again.
+    let key = loop {
+        if let Some(key) = conn.next_key_kbd(None, false, true, false)? {
+            break key;
+        }
+    };
+    println!("{}  {:#06x}", key, key.main_key.0);
+    Ok(())
+}
+
+/// Prints what typing a key sequence, given as text, in `mode` would
+/// do: which binding it would trigger, if any, or what it could go on
+/// to if it's unfinished.
+fn simulate_keys(config: &Config, keys: &str, mode: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let keyseq = KeySequence::parse(LineText::new("<command line>", 1, keys), &config.settings)?;
+    let (matcher, result) = simulate(config, &keyseq.keys, mode);
+    let typed = KeySequence {
+        keys: matcher.keys().to_vec(),
+    };
+
+    match result {
+        MatchResult::Match(line) => println!(
+            "{} matches the binding at {}: {}",
+            typed, line.location, line.action
+        ),
+        MatchResult::Prefix => {
+            println!("{} is unfinished; it could go on to:", typed);
+            for line in matcher.candidates() {
+                println!("  {} at {}: {}", line.keyseq, line.location, line.action);
+            }
+        }
+        MatchResult::Cancelled => {
+            println!("{} is cancelled by {}", typed, config.settings.cancel_key())
+        }
+        MatchResult::None => match matcher.release_binding() {
+            Some(line) => println!(
+                "{} matches the binding at {} once it's released: {}",
+                typed, line.location, line.action
+            ),
+            None => println!("{} doesn't match any binding", typed),
+        },
+    }
+
+    let rest: Vec<_> = keyseq.keys[matcher.keys().len()..]
+        .iter()
+        .map(Key::to_string)
+        .collect();
+    if !rest.is_empty() {
+        println!(
+            "  (the rest, {}, would start a new key sequence)",
+            rest.join(" ")
+        );
+    }
+
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -74,6 +603,14 @@ pub enum AhkdError {
     X11Error(String),
     NoKeysError,
     KeyboardGrabError,
+    Disconnected,
+    NotConfirmed,
+    InvalidConfig(usize),
+    UnavailableKey(Keysym, Option<Location>),
+    AlreadyGrabbed(Key),
+    NoConfigFile(Option<PathBuf>),
+    NoDisplay(Option<String>),
+    NoSuchMode(String),
 }
 
 impl fmt::Display for AhkdError {
@@ -96,6 +633,63 @@ impl fmt::Display for AhkdError {
                 KeyboardGrabError => {
                     "Unable to grab keyboard".to_string()
                 }
+                Disconnected => {
+                    "Lost the connection to the X server".to_string()
+                }
+                NotConfirmed => {
+                    "Startup wasn't confirmed in time".to_string()
+                }
+                InvalidConfig(1) => {
+                    "Found 1 syntax error".to_string()
+                }
+                InvalidConfig(count) => {
+                    format!("Found {} syntax errors", count)
+                }
+                UnavailableKey(keysym, Some(location)) => {
+                    format!(
+                        "{}: key '{}' is not available on the current keyboard layout",
+                        location, keysym
+                    )
+                }
+                UnavailableKey(keysym, None) => {
+                    format!(
+                        "Key '{}' is not available on the current keyboard layout",
+                        keysym
+                    )
+                }
+                AlreadyGrabbed(key) => {
+                    format!(
+                        "{} is already grabbed by another program, so bindings starting with it \
+                         won't work; it may conflict with a binding of the window manager's",
+                        key
+                    )
+                }
+                NoConfigFile(Some(path)) => {
+                    format!(
+                        "No configuration file given, and there isn't one at {}\n\
+                         Create one there, or give the path to one on the command line.",
+                        path.display()
+                    )
+                }
+                NoDisplay(Some(name)) => {
+                    format!(
+                        "Cannot connect to X server: \"{}\" is not a valid display name",
+                        name
+                    )
+                }
+                NoDisplay(None) => {
+                    "Cannot connect to X server: DISPLAY is not set \
+                     (are you in a graphical session?)"
+                        .to_string()
+                }
+                NoSuchMode(mode) => {
+                    format!("There's no mode called \"{}\" with any bindings", mode)
+                }
+                NoConfigFile(None) => {
+                    "No configuration file given, and neither $XDG_CONFIG_HOME nor $HOME is set \
+                     to find the default one\nGive the path to one on the command line."
+                        .to_string()
+                }
             }
         )
     }
@@ -0,0 +1,901 @@
+// Matching typed keys against the bindings in a configuration.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cfgfile::{Config, ConfigLine};
+use crate::keyseq::{Key, KeySequence, Modifier};
+
+/// Keeps track of the keys the user has typed so far, and works out
+/// which binding in a configuration, if any, they've typed. This
+/// holds all of the matching logic, without any of the X11 side of
+/// things, so it can be driven by hand.
+#[derive(Debug)]
+pub struct Matcher<'a> {
+    /// The configuration whose bindings we're matching.
+    config: &'a Config,
+
+    /// The keys typed so far in the current key sequence, or in the
+    /// one just finished.
+    seen_keys: Vec<Key>,
+
+    /// The keyboards each of `seen_keys` was typed on, where known.
+    seen_devices: Vec<Option<u16>>,
+
+    /// Whether `seen_keys` is a finished sequence, to be cleared
+    /// before the next key is added.
+    finished: bool,
+
+    /// The key that started the last key sequence, and how it was
+    /// pressed, so that we can tell when a key is tapped twice. This
+    /// is forgotten as soon as any other key is pressed.
+    last_tap: Option<(Key, Press)>,
+
+    /// The mode whose bindings are active, or None for the ordinary
+    /// bindings.
+    mode: Option<&'a str>,
+}
+
+/// What's known about how a key was pressed, besides which key it
+/// was, for telling when a key is tapped twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Press {
+    /// The XInput2 ID of the keyboard the key was typed on, if known.
+    pub device: Option<u16>,
+
+    /// The X server's timestamp for the press, in milliseconds, if
+    /// known.
+    pub time: Option<u32>,
+
+    /// Whether this was the key auto-repeating, rather than being
+    /// pressed afresh.
+    pub repeat: bool,
+
+    /// Whether some other key, that the Matcher wasn't fed, was
+    /// pressed between this key and the one fed before it, e.g. a key
+    /// typed into some other program.
+    pub interrupted: bool,
+}
+
+/// What the user has typed so far, as reported by `Matcher::feed`.
+#[derive(Debug)]
+pub enum MatchResult<'a> {
+    /// The user has typed something that can't possibly match any key
+    /// sequence we're listening for. The keys typed so far have been
+    /// discarded.
+    None,
+
+    /// The user has typed the start of one or more key sequences
+    /// we're listening for, and we need more keys to tell which.
+    Prefix,
+
+    /// The user has typed a complete key sequence, bound by this
+    /// configuration line. The keys typed so far have been
+    /// discarded, ready for the next sequence.
+    Match(&'a ConfigLine),
+
+    /// The user typed the cancel key partway through a key sequence,
+    /// and no binding continues with it, so they've changed their
+    /// mind. The keys typed so far have been discarded.
+    Cancelled,
+}
+
+impl PartialEq for MatchResult<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (MatchResult::None, MatchResult::None) => true,
+            (MatchResult::Prefix, MatchResult::Prefix) => true,
+            (MatchResult::Cancelled, MatchResult::Cancelled) => true,
+            // Two matches are the same if they're for the very same
+            // line of the configuration.
+            (MatchResult::Match(a), MatchResult::Match(b)) => std::ptr::eq(*a, *b),
+            _ => false,
+        }
+    }
+}
+
+/// The result of matching a key sequence with a set of prefix keys.
+enum SeqMatch {
+    /// The key sequence does not match the prefix.
+    None,
+
+    /// The key sequence partially matches the prefix, i.e., the user
+    /// has typed the start of it.
+    Partial,
+
+    /// The key sequence prefectly matches the prefix, i.e., the user
+    /// has typed this key sequence to completion.
+    Full,
+}
+
+impl<'a> Matcher<'a> {
+    /// Creates a Matcher for the bindings in the given
+    /// configuration, with no keys typed yet.
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            seen_keys: Vec::new(),
+            seen_devices: Vec::new(),
+            finished: false,
+            last_tap: None,
+            mode: None,
+        }
+    }
+
+    /// Switches to the bindings of the given mode, or back to the
+    /// ordinary bindings for None. Changing modes forgets any keys
+    /// typed so far.
+    pub fn set_mode(&mut self, mode: Option<&'a str>) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.reset();
+            self.last_tap = None;
+        }
+    }
+
+    /// Gets the mode whose bindings are active, if any.
+    pub fn mode(&self) -> Option<&'a str> {
+        self.mode
+    }
+
+    /// Records that the user has typed a key, on the keyboard with the
+    /// given XInput2 ID if we know it, and reports what that means.
+    /// Once this returns anything other than `MatchResult::Prefix`,
+    /// the Matcher starts over with the next key. Without knowing
+    /// when the key was pressed, it can't be the second tap of a
+    /// double tap; see `feed_press`.
+    pub fn feed(&mut self, key: Key, device: Option<u16>) -> MatchResult<'a> {
+        self.feed_press(
+            key,
+            Press {
+                device,
+                ..Press::default()
+            },
+        )
+    }
+
+    /// Records that the user has typed a key like `feed`, along with
+    /// everything known about how it was pressed.
+    pub fn feed_press(&mut self, key: Key, press: Press) -> MatchResult<'a> {
+        if self.finished {
+            self.reset();
+        }
+
+        let device = press.device;
+        if self.seen_keys.is_empty() {
+            if let Some(line) = self.double_tap(key, press) {
+                // Don't let a third tap count as a second one.
+                self.last_tap = None;
+                self.seen_keys.push(key);
+                self.seen_devices.push(device);
+                self.finished = true;
+                return MatchResult::Match(line);
+            }
+            // A key that's being held down isn't being tapped.
+            self.last_tap = Some((key, press)).filter(|_| !press.repeat);
+        } else {
+            self.last_tap = None;
+        }
+        self.seen_keys.push(key);
+        self.seen_devices.push(device);
+
+        let result = match self.state() {
+            // Bindings that carry on with the cancel key take
+            // precedence over cancelling.
+            MatchResult::None
+                if self.seen_keys.len() > 1
+                    && self
+                        .config
+                        .settings
+                        .cancel_key()
+                        .matches(&key, self.config.settings.num_lock()) =>
+            {
+                MatchResult::Cancelled
+            }
+            result => result,
+        };
+        self.finished = !matches!(result, MatchResult::Prefix);
+        result
+    }
+
+    /// Checks whether a key starting a new key sequence is a second
+    /// tap of the one that started the last sequence, with no other
+    /// key pressed in between, and if so finds the `2x` binding for
+    /// it.
+    fn double_tap(&self, key: Key, press: Press) -> Option<&'a ConfigLine> {
+        let (last, last_press) = self.last_tap?;
+        // The server's clock wraps around every 49 days or so.
+        let elapsed = press.time?.wrapping_sub(last_press.time?);
+        if press.repeat
+            || press.interrupted
+            || last != key
+            || last_press.device != press.device
+            || u128::from(elapsed) > self.config.settings.double_tap_timeout().as_millis()
+        {
+            return None;
+        }
+        let device = press.device;
+
+        self.config.commands.iter().find(|command| {
+            command.double_tap
+                && self.in_mode(command)
+                && command.device.is_none_or(|wanted| device == Some(wanted))
+                && command.keyseq.keys[0].matches(&key, self.config.settings.num_lock())
+        })
+    }
+
+    /// Checks whether every key typed so far came from the keyboard a
+    /// binding is limited to, if any.
+    fn right_device(&self, command: &ConfigLine) -> bool {
+        match command.device {
+            Some(wanted) => self
+                .seen_devices
+                .iter()
+                .all(|&device| device == Some(wanted)),
+            None => true,
+        }
+    }
+
+    /// Checks whether a binding belongs to the active mode.
+    fn in_mode(&self, command: &ConfigLine) -> bool {
+        command.mode.as_deref() == self.mode
+    }
+
+    /// Forgets about any keys typed so far, e.g. because the user
+    /// took too long to finish a key sequence.
+    pub fn reset(&mut self) {
+        self.seen_keys.clear();
+        self.seen_devices.clear();
+        self.finished = false;
+    }
+
+    /// Gets the keys the user actually typed for the key sequence in
+    /// progress, or for the one just matched; these can differ from
+    /// the binding's own keys when it uses `<any>`.
+    pub fn keys(&self) -> &[Key] {
+        &self.seen_keys
+    }
+
+    /// Gets the `release` binding for the key just typed, if that key
+    /// made up a key sequence on its own, so that it should fire once
+    /// the key comes back up. Bindings of a lone modifier key count
+    /// as `release` bindings; see `is_modifier_tap`.
+    pub fn release_binding(&self) -> Option<&'a ConfigLine> {
+        let key = match self.seen_keys.as_slice() {
+            [key] => key,
+            _ => return None,
+        };
+        self.config.commands.iter().find(|command| {
+            (command.release || is_modifier_tap(command))
+                && self.in_mode(command)
+                && self.right_device(command)
+                && command.keyseq.keys[0].matches(key, self.config.settings.num_lock())
+        })
+    }
+
+    /// Gets the bindings that the key sequence in progress could
+    /// still turn into, in the order they were defined. This is
+    /// empty if no sequence is in progress.
+    pub fn candidates(&self) -> Vec<&'a ConfigLine> {
+        if self.finished {
+            return Vec::new();
+        }
+
+        self.config
+            .commands
+            .iter()
+            .filter(|command| {
+                self.in_mode(command)
+                    && self.right_device(command)
+                    && matches!(
+                        match_keyseq(
+                            &command.keyseq,
+                            &self.seen_keys,
+                            self.config.settings.num_lock()
+                        ),
+                        SeqMatch::Partial
+                    )
+            })
+            .collect()
+    }
+
+    /// Attempts to determine what the user meant, given the keys
+    /// they've typed so far. Bindings that spell out their modifiers
+    /// take precedence over ones written with `*-`, which are only
+    /// considered if none of the others match, even partly; so with
+    /// `C-x C-f` and `*-x` both bound, `C-x` is a prefix, but `M-x`
+    /// triggers `*-x`.
+    fn state(&self) -> MatchResult<'a> {
+        // `2x` bindings are only ever matched by `double_tap`, and
+        // `release` ones and lone modifiers by `release_binding`.
+        let (wildcards, specific): (Vec<_>, Vec<_>) = self
+            .config
+            .commands
+            .iter()
+            .filter(|c| {
+                !c.double_tap
+                    && !c.release
+                    && !is_modifier_tap(c)
+                    && self.in_mode(c)
+                    && self.right_device(c)
+            })
+            .partition(|c| c.keyseq.keys.iter().any(|key| key.modifiers.any));
+        match self.state_among(specific) {
+            MatchResult::None => self.state_among(wildcards),
+            result => result,
+        }
+    }
+
+    /// Works out what the keys typed so far mean among just the given
+    /// bindings, in the order they were defined.
+    fn state_among(&self, commands: Vec<&'a ConfigLine>) -> MatchResult<'a> {
+        let mut partial = false;
+        for command in commands {
+            match match_keyseq(
+                &command.keyseq,
+                &self.seen_keys,
+                self.config.settings.num_lock(),
+            ) {
+                SeqMatch::None => {}
+                SeqMatch::Partial => {
+                    partial = true;
+                }
+                SeqMatch::Full => return MatchResult::Match(command),
+            };
+        }
+
+        if partial {
+            MatchResult::Prefix
+        } else {
+            MatchResult::None
+        }
+    }
+}
+
+/// Works out what typing `keys` would do with the bindings in
+/// `config`, in `mode` or outside of any mode for None, without an X
+/// server. Keys are fed in until one finishes the key sequence or
+/// leaves it unfinished at the end; the Matcher is returned along
+/// with what happened, so that the caller can see which keys made up
+/// the sequence, and what an unfinished one could go on to. The keys
+/// are taken to be typed in quick succession, but not so quickly as
+/// to count as double taps.
+pub fn simulate<'a>(
+    config: &'a Config,
+    keys: &[Key],
+    mode: Option<&'a str>,
+) -> (Matcher<'a>, MatchResult<'a>) {
+    let mut matcher = Matcher::new(config);
+    matcher.set_mode(mode);
+    let mut result = MatchResult::None;
+    for &key in keys {
+        result = matcher.feed(key, None);
+        if result != MatchResult::Prefix {
+            break;
+        }
+    }
+    (matcher, result)
+}
+
+/// Checks whether a binding is for a modifier key on its own, like
+/// `bind Super_L`. Since the same key is held down for chords like
+/// `Super-e`, such a binding only fires if the key is tapped: released
+/// without any other key being pressed while it was down.
+pub fn is_modifier_tap(command: &ConfigLine) -> bool {
+    !command.release
+        && !command.double_tap
+        && matches!(command.keyseq.keys.as_slice(), [key] if key.main_key.is_modifier())
+}
+
+/// Matches a key sequence with a set of keys we've seen from the
+/// user.
+fn match_keyseq(seq: &KeySequence, seen_keys: &[Key], num_lock: Modifier) -> SeqMatch {
+    if seen_keys.len() > seq.keys.len() {
+        return SeqMatch::None;
+    }
+
+    for (expected, key) in seq.keys.iter().zip(seen_keys) {
+        if !expected.matches(key, num_lock) {
+            return SeqMatch::None;
+        }
+    }
+
+    if seq.keys.len() == seen_keys.len() {
+        SeqMatch::Full
+    } else {
+        SeqMatch::Partial
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::{parse_config, LineText};
+    use std::convert::TryFrom;
+    use std::io::BufReader;
+
+    /// Parses a configuration file from a string.
+    fn parse_str(text: &str) -> Config {
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+        config
+    }
+
+    /// Parses a single key.
+    fn key(text: &str) -> Key {
+        Key::try_from(LineText::new("foo", 1, text)).unwrap()
+    }
+
+    #[test]
+    fn feed_test() {
+        let config = parse_str(
+            "\
+bind C-x C-f : foo
+bind C-x k : bar
+bind M-x : baz
+",
+        );
+        let mut matcher = Matcher::new(&config);
+
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("k"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+
+        // The match starts the next sequence afresh.
+        assert_eq!(
+            matcher.feed(key("M-x"), None),
+            MatchResult::Match(&config.commands[2])
+        );
+
+        // Only bindings starting with the keys typed so far are still
+        // in the running.
+        assert!(matcher.candidates().is_empty());
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        let candidates: Vec<_> = matcher
+            .candidates()
+            .iter()
+            .map(|line| line.location.to_string())
+            .collect();
+        assert_eq!(candidates, vec!["foo:1", "foo:2"]);
+        assert_eq!(
+            matcher.feed(key("k"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert!(matcher.candidates().is_empty());
+
+        // A wrong key abandons the sequence.
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(matcher.feed(key("C-g"), None), MatchResult::None);
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-f"), None),
+            MatchResult::Match(&config.commands[0])
+        );
+
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        matcher.reset();
+        assert_eq!(matcher.feed(key("k"), None), MatchResult::None);
+    }
+
+    #[test]
+    fn any_key_test() {
+        let config = parse_str("bind Super-s <any> : screenshot %KEY%");
+        let mut matcher = Matcher::new(&config);
+
+        assert_eq!(matcher.feed(key("Super-s"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-w"), None),
+            MatchResult::Match(&config.commands[0])
+        );
+        assert_eq!(matcher.keys(), &[key("Super-s"), key("C-w")]);
+    }
+
+    #[test]
+    fn any_modifier_test() {
+        let config = parse_str(
+            "bind *-x : any\n\
+             bind C-x C-f : find\n\
+             bind M-x : run\n",
+        );
+        let mut matcher = Matcher::new(&config);
+
+        // The bindings that name their modifiers come first, wherever
+        // they are in the file.
+        assert_eq!(
+            matcher.feed(key("M-x"), None),
+            MatchResult::Match(&config.commands[2])
+        );
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-f"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+
+        // Anything else goes to the wildcard.
+        for typed in &["x", "S-x", "C-s-x"] {
+            assert_eq!(
+                matcher.feed(key(typed), None),
+                MatchResult::Match(&config.commands[0])
+            );
+        }
+        assert_eq!(matcher.feed(key("C-y"), None), MatchResult::None);
+    }
+
+    #[test]
+    fn simulate_test() {
+        let config = parse_str(
+            "bind C-x C-f : find\n\
+             bind C-x 4 f : other\n\
+             bind C-y : yank\n\
+             mode m {\n\
+             bind C-y : other-yank\n\
+             }\n",
+        );
+        let keys = |text: &str| -> Vec<Key> { text.split(' ').map(key).collect() };
+
+        let (matcher, result) = simulate(&config, &keys("C-x C-f"), None);
+        assert_eq!(result, MatchResult::Match(&config.commands[0]));
+        assert_eq!(matcher.keys(), keys("C-x C-f").as_slice());
+
+        let (matcher, result) = simulate(&config, &keys("C-x"), None);
+        assert_eq!(result, MatchResult::Prefix);
+        assert_eq!(matcher.candidates().len(), 2);
+
+        let (_, result) = simulate(&config, &keys("C-x C-g"), None);
+        assert_eq!(result, MatchResult::None);
+
+        // Keys after a finished sequence aren't fed in.
+        let (matcher, result) = simulate(&config, &keys("C-y C-y"), None);
+        assert_eq!(result, MatchResult::Match(&config.commands[2]));
+        assert_eq!(matcher.keys(), keys("C-y").as_slice());
+
+        // A mode only has its own bindings.
+        let (_, result) = simulate(&config, &keys("C-y"), Some("m"));
+        assert_eq!(result, MatchResult::Match(&config.commands[3]));
+        let (_, result) = simulate(&config, &keys("C-x C-f"), Some("m"));
+        assert_eq!(result, MatchResult::None);
+    }
+
+    #[test]
+    fn num_lock_match_test() {
+        let config = parse_str("bind KP_1 : foo");
+        let mut typed = config.commands[0].keyseq.keys[0];
+
+        // The event for a keypad key comes with Num Lock's modifier,
+        // Mod2, set.
+        typed.modifiers.mod2 = true;
+        assert!(matches!(
+            match_keyseq(&config.commands[0].keyseq, &[typed], Modifier::Mod2),
+            SeqMatch::Full
+        ));
+    }
+
+    #[test]
+    fn double_tap_test() {
+        let config = parse_str(
+            "\
+bind 2x Shift_L : foo
+bind a : bar
+bind 2x a : baz
+bind C-x C-f : quux
+bind 2x C-x : xyzzy
+",
+        );
+        let mut matcher = Matcher::new(&config);
+        let at = |ms| Press {
+            time: Some(ms),
+            ..Press::default()
+        };
+
+        // A single tap does nothing, but a second one soon after
+        // triggers the binding.
+        assert_eq!(matcher.feed_press(key("Shift_L"), at(0)), MatchResult::None);
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(200)),
+            MatchResult::Match(&config.commands[0])
+        );
+
+        // A third tap starts over.
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(250)),
+            MatchResult::None
+        );
+
+        // Too slow.
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(1000)),
+            MatchResult::None
+        );
+
+        // A key with an ordinary binding too fires that on the first
+        // tap, and the double-tap binding on the second.
+        assert_eq!(
+            matcher.feed_press(key("a"), at(1100)),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert_eq!(
+            matcher.feed_press(key("a"), at(1200)),
+            MatchResult::Match(&config.commands[2])
+        );
+
+        // Another key in between breaks it up.
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(1300)),
+            MatchResult::None
+        );
+        assert_eq!(
+            matcher.feed_press(key("a"), at(1350)),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(1400)),
+            MatchResult::None
+        );
+
+        // So does a key we never see, e.g. because it was typed into
+        // some other program.
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(2000)),
+            MatchResult::None
+        );
+        let interrupted = Press {
+            interrupted: true,
+            ..at(2100)
+        };
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), interrupted),
+            MatchResult::None
+        );
+
+        // And so does the rest of a key sequence.
+        assert_eq!(
+            matcher.feed_press(key("C-x"), at(3000)),
+            MatchResult::Prefix
+        );
+        assert_eq!(
+            matcher.feed_press(key("C-f"), at(3050)),
+            MatchResult::Match(&config.commands[3])
+        );
+        assert_eq!(
+            matcher.feed_press(key("C-x"), at(3100)),
+            MatchResult::Prefix
+        );
+        matcher.reset();
+
+        // Holding a key down isn't tapping it, and neither are its
+        // repeats.
+        let repeat = |ms| Press {
+            repeat: true,
+            ..at(ms)
+        };
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(4000)),
+            MatchResult::None
+        );
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), repeat(4100)),
+            MatchResult::None
+        );
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), repeat(4150)),
+            MatchResult::None
+        );
+
+        // The server's clock can wrap around between taps.
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(u32::MAX - 50)),
+            MatchResult::None
+        );
+        assert_eq!(
+            matcher.feed_press(key("Shift_L"), at(50)),
+            MatchResult::Match(&config.commands[0])
+        );
+
+        // Without the time, a key can't be a second tap.
+        assert_eq!(matcher.feed(key("Shift_L"), None), MatchResult::None);
+        assert_eq!(matcher.feed(key("Shift_L"), None), MatchResult::None);
+    }
+
+    #[test]
+    fn device_test() {
+        let config = parse_str(
+            "\
+bind device 12 C-x C-f : foo
+bind C-x C-f : bar
+bind device 12 2x a : baz
+",
+        );
+        let mut matcher = Matcher::new(&config);
+
+        assert_eq!(matcher.feed(key("C-x"), Some(12)), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-f"), Some(12)),
+            MatchResult::Match(&config.commands[0])
+        );
+
+        // Any key from another keyboard rules the binding out.
+        assert_eq!(matcher.feed(key("C-x"), Some(12)), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-f"), Some(13)),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("C-f"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+
+        let on = |device| Press {
+            device: Some(device),
+            time: Some(0),
+            ..Press::default()
+        };
+        assert_eq!(matcher.feed_press(key("a"), on(13)), MatchResult::None);
+        assert_eq!(matcher.feed_press(key("a"), on(13)), MatchResult::None);
+        assert_eq!(matcher.feed_press(key("a"), on(12)), MatchResult::None);
+        assert_eq!(
+            matcher.feed_press(key("a"), on(12)),
+            MatchResult::Match(&config.commands[2])
+        );
+    }
+
+    #[test]
+    fn cancel_test() {
+        let config = parse_str("bind C-x C-f : foo\nbind C-c Escape : bar");
+        let mut matcher = Matcher::new(&config);
+        let escape = Key::try_from(LineText::new("foo", 1, "Escape")).unwrap();
+        let c_x = config.commands[0].keyseq.keys[0];
+        let c_c = config.commands[1].keyseq.keys[0];
+
+        assert_eq!(matcher.feed(c_x, None), MatchResult::Prefix);
+        assert_eq!(matcher.feed(escape, None), MatchResult::Cancelled);
+        assert!(matcher.candidates().is_empty());
+
+        // Escape on its own isn't cancelling anything.
+        assert_eq!(matcher.feed(escape, None), MatchResult::None);
+
+        // A binding that uses the cancel key still works.
+        assert_eq!(matcher.feed(c_c, None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(escape, None),
+            MatchResult::Match(&config.commands[1])
+        );
+
+        let config = parse_str("set cancel-key C-g\nbind C-x C-f : foo");
+        let mut matcher = Matcher::new(&config);
+        let c_g = Key::try_from(LineText::new("foo", 1, "C-g")).unwrap();
+        assert_eq!(matcher.feed(c_x, None), MatchResult::Prefix);
+        assert_eq!(matcher.feed(escape, None), MatchResult::None);
+        assert_eq!(matcher.feed(c_x, None), MatchResult::Prefix);
+        assert_eq!(matcher.feed(c_g, None), MatchResult::Cancelled);
+    }
+
+    #[test]
+    fn mode_test() {
+        let config = parse_str(
+            "\
+bind h : foo
+mode resize {
+    bind h : bar
+    bind C-x h : baz
+}
+",
+        );
+        let mut matcher = Matcher::new(&config);
+
+        assert_eq!(
+            matcher.feed(key("h"), None),
+            MatchResult::Match(&config.commands[0])
+        );
+
+        matcher.set_mode(Some("resize"));
+        assert_eq!(
+            matcher.feed(key("h"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(matcher.candidates().len(), 1);
+        assert_eq!(matcher.candidates()[0].location.to_string(), "foo:4");
+
+        // Switching modes drops the sequence in progress.
+        matcher.set_mode(None);
+        assert_eq!(
+            matcher.feed(key("h"), None),
+            MatchResult::Match(&config.commands[0])
+        );
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::None);
+    }
+
+    #[test]
+    fn release_test() {
+        let config = parse_str(
+            "\
+bind F2 : foo
+bind release F2 : bar
+bind release F1 : baz
+bind C-x F1 : quux
+",
+        );
+        let mut matcher = Matcher::new(&config);
+
+        // The press fires its own binding, and the release binding is
+        // there for when the key comes up.
+        assert_eq!(
+            matcher.feed(key("F2"), None),
+            MatchResult::Match(&config.commands[0])
+        );
+        assert_eq!(
+            matcher.release_binding().unwrap().location.to_string(),
+            "foo:2"
+        );
+
+        // A key bound only on release does nothing when pressed.
+        assert_eq!(matcher.feed(key("F1"), None), MatchResult::None);
+        assert_eq!(
+            matcher.release_binding().unwrap().location.to_string(),
+            "foo:3"
+        );
+
+        // Only a key on its own counts.
+        assert_eq!(matcher.feed(key("C-x"), None), MatchResult::Prefix);
+        assert_eq!(
+            matcher.feed(key("F1"), None),
+            MatchResult::Match(&config.commands[3])
+        );
+        assert!(matcher.release_binding().is_none());
+    }
+
+    #[test]
+    fn modifier_tap_test() {
+        let config = parse_str(
+            "\
+bind Super_L : launcher
+bind Super-e : editor
+",
+        );
+        let mut matcher = Matcher::new(&config);
+        assert!(is_modifier_tap(&config.commands[0]));
+        assert!(!is_modifier_tap(&config.commands[1]));
+
+        // Pressing the modifier does nothing yet; it waits to see
+        // whether the key is tapped.
+        assert_eq!(matcher.feed(key("Super_L"), None), MatchResult::None);
+        assert_eq!(
+            matcher.release_binding().unwrap().location.to_string(),
+            "foo:1"
+        );
+
+        // If it's held for a chord instead, the chord is a key
+        // sequence of its own, and the tap binding doesn't apply.
+        assert_eq!(
+            matcher.feed(key("Super-e"), None),
+            MatchResult::Match(&config.commands[1])
+        );
+        assert!(matcher.release_binding().is_none());
+    }
+
+    #[test]
+    fn longer_than_binding_test() {
+        let config = parse_str("bind C-x : foo");
+        let seq = &config.commands[0].keyseq;
+        assert!(matches!(
+            match_keyseq(seq, &[key("C-x"), key("C-x")], Modifier::Mod2),
+            SeqMatch::None
+        ));
+    }
+}
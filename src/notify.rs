@@ -0,0 +1,65 @@
+// Desktop notifications.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+
+/// The well-known bus name, object path, and interface of the
+/// desktop notification service.
+#[cfg(feature = "notify")]
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+#[cfg(feature = "notify")]
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// Shows a desktop notification with the given summary and body, by
+/// way of the notification daemon on the session bus.
+#[cfg(feature = "notify")]
+pub fn notify(summary: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    use std::collections::HashMap;
+    use zbus::zvariant::Value;
+
+    let conn = zbus::blocking::Connection::session()?;
+    let actions: Vec<&str> = Vec::new();
+    let hints: HashMap<&str, Value> = HashMap::new();
+    let reply = conn.call_method(
+        Some(NOTIFICATIONS),
+        NOTIFICATIONS_PATH,
+        Some(NOTIFICATIONS),
+        "Notify",
+        // The app name, the ID of a notification to replace (none),
+        // the icon, the summary and body, the actions, the hints,
+        // and the timeout (the server's default).
+        &("ahkd", 0u32, "", summary, body, actions, hints, -1i32),
+    );
+
+    match reply {
+        Ok(_) => Ok(()),
+        Err(zbus::Error::MethodError(name, _, _))
+            if name.as_str() == "org.freedesktop.DBus.Error.ServiceUnknown" =>
+        {
+            Err("no notification daemon is running".into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Stands in for the real `notify` when ahkd is built without D-Bus
+/// support.
+#[cfg(not(feature = "notify"))]
+pub fn notify(_summary: &str, _body: &str) -> Result<(), Box<dyn Error>> {
+    Err("ahkd was built without the \"notify\" feature".into())
+}
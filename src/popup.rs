@@ -0,0 +1,282 @@
+// A window listing the keys that can follow a prefix.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::matcher::Matcher;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::error::Error;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    CloseFontRequest, ConfigureWindowAux, ConfigureWindowRequest, CreateGCAux, CreateGCRequest,
+    CreateWindowAux, CreateWindowRequest, DestroyWindowRequest, EventMask, Font, FreeGCRequest,
+    Gcontext, ImageText8Request, MapWindowRequest, OpenFontRequest, QueryFontRequest, Screen,
+    StackMode, Window, WindowClass,
+};
+use x11rb::rust_connection::RustConnection;
+
+/// The core X font the popup is drawn in. Every X server has it.
+const FONT: &str = "fixed";
+
+/// The space, in pixels, between the text and the edge of the popup.
+const PADDING: i16 = 6;
+
+/// How far from the pointer, in pixels, the popup's corner goes, so
+/// that the pointer doesn't cover the first line.
+const POINTER_OFFSET: i16 = 12;
+
+/// A small window, shown near the pointer while a key sequence is in
+/// progress, that lists the keys that could come next and what each
+/// would do.
+pub struct Popup {
+    /// The popup window itself.
+    window: Window,
+
+    /// The graphics context the text is drawn with.
+    gc: Gcontext,
+
+    /// The font the text is drawn in.
+    font: Font,
+
+    /// The lines of text shown in the popup.
+    lines: Vec<String>,
+
+    /// The height of a line of text, in pixels.
+    line_height: i16,
+
+    /// How far above the baseline the font reaches, in pixels.
+    ascent: i16,
+}
+
+impl Popup {
+    /// Creates a popup showing the given lines and puts it up on
+    /// `screen`, with its top left corner near the point (`x`, `y`),
+    /// but moved as needed to keep it all on the screen.
+    pub fn show(
+        display: &RustConnection,
+        screen: &Screen,
+        x: i16,
+        y: i16,
+        lines: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let font = display.generate_id()?;
+        OpenFontRequest {
+            fid: font,
+            name: Cow::Borrowed(FONT.as_bytes()),
+        }
+        .send(display)?
+        .check()?;
+        let metrics = QueryFontRequest { font }.send(display)?.reply()?;
+        let ascent = metrics.font_ascent;
+        let line_height = metrics.font_ascent + metrics.font_descent;
+        let char_width = metrics.max_bounds.character_width;
+
+        let longest = lines.iter().map(|line| line.chars().count()).max();
+        let width = (longest.unwrap_or(0) as i16)
+            .saturating_mul(char_width)
+            .saturating_add(2 * PADDING)
+            .max(1);
+        let height = (lines.len() as i16)
+            .saturating_mul(line_height)
+            .saturating_add(2 * PADDING)
+            .max(1);
+        let x = place(x, width, screen.width_in_pixels);
+        let y = place(y, height, screen.height_in_pixels);
+
+        let window = display.generate_id()?;
+        CreateWindowRequest {
+            depth: 0,
+            wid: window,
+            parent: screen.root,
+            x,
+            y,
+            width: width as u16,
+            height: height as u16,
+            border_width: 1,
+            class: WindowClass::INPUT_OUTPUT,
+            visual: 0,
+            // Override-redirect keeps the window manager from
+            // decorating the popup, or giving it the focus.
+            value_list: Cow::Owned(
+                CreateWindowAux::new()
+                    .background_pixel(screen.white_pixel)
+                    .border_pixel(screen.black_pixel)
+                    .override_redirect(1)
+                    .event_mask(EventMask::EXPOSURE),
+            ),
+        }
+        .send(display)?
+        .check()?;
+
+        let gc = display.generate_id()?;
+        CreateGCRequest {
+            cid: gc,
+            drawable: window,
+            value_list: Cow::Owned(
+                CreateGCAux::new()
+                    .foreground(screen.black_pixel)
+                    .background(screen.white_pixel)
+                    .font(font),
+            ),
+        }
+        .send(display)?
+        .check()?;
+
+        MapWindowRequest { window }.send(display)?.check()?;
+        // Make sure it's not hidden behind some other override-redirect
+        // window, like a panel.
+        ConfigureWindowRequest {
+            window,
+            value_list: Cow::Owned(ConfigureWindowAux::new().stack_mode(StackMode::ABOVE)),
+        }
+        .send(display)?
+        .check()?;
+
+        // The text gets drawn when the window is exposed.
+        Ok(Self {
+            window,
+            gc,
+            font,
+            lines,
+            line_height,
+            ascent,
+        })
+    }
+
+    /// Gets the popup window.
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Draws the text in the popup, e.g. because it's just been
+    /// shown, or uncovered.
+    pub fn draw(&self, display: &RustConnection) -> Result<(), Box<dyn Error>> {
+        for (idx, line) in self.lines.iter().enumerate() {
+            let y = PADDING + self.ascent + idx as i16 * self.line_height;
+            // ImageText8 only takes up to 255 characters.
+            let text: Vec<u8> = latin1(line).into_iter().take(255).collect();
+            ImageText8Request {
+                drawable: self.window,
+                gc: self.gc,
+                x: PADDING,
+                y,
+                string: Cow::Owned(text),
+            }
+            .send(display)?;
+        }
+        display.flush()?;
+        Ok(())
+    }
+
+    /// Takes the popup down, and frees everything it was using.
+    pub fn close(self, display: &RustConnection) -> Result<(), Box<dyn Error>> {
+        DestroyWindowRequest {
+            window: self.window,
+        }
+        .send(display)?;
+        FreeGCRequest { gc: self.gc }.send(display)?;
+        CloseFontRequest { font: self.font }.send(display)?;
+        display.flush()?;
+        Ok(())
+    }
+}
+
+/// Works out where along one axis of the screen to put an edge of
+/// the popup, which is `size` pixels long, so that it starts just
+/// past `pointer` but doesn't run off the end of the screen, which is
+/// `screen` pixels long.
+fn place(pointer: i16, size: i16, screen: u16) -> i16 {
+    let screen = screen.min(i16::MAX as u16) as i16;
+    // Leave room for the border.
+    let last = screen.saturating_sub(size).saturating_sub(2);
+    pointer.saturating_add(POINTER_OFFSET).min(last).max(0)
+}
+
+/// Converts text to Latin-1, the encoding core X fonts use,
+/// replacing anything it doesn't cover with `?`.
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+        .collect()
+}
+
+/// Lists the ways the key sequence in progress could carry on, one
+/// line for each binding it could still turn into: the rest of the
+/// binding's keys, then its label, or its action if it doesn't have
+/// one.
+pub fn continuations(matcher: &Matcher) -> Vec<String> {
+    let typed = matcher.keys().len();
+    let rows: Vec<(String, String)> = matcher
+        .candidates()
+        .into_iter()
+        .map(|line| {
+            let keys: Vec<_> = line.keyseq.keys[typed..]
+                .iter()
+                .map(|key| key.to_string())
+                .collect();
+            let what = match &line.label {
+                Some(label) => label.clone(),
+                None => line.action.to_string(),
+            };
+            (keys.join(" "), what)
+        })
+        .collect();
+
+    let width = rows.iter().map(|(keys, _)| keys.chars().count()).max();
+    rows.into_iter()
+        .map(|(keys, what)| format!("{:width$}  {}", keys, what, width = width.unwrap_or(0)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::{parse_config, Config, LineText};
+    use crate::keyseq::Key;
+    use std::io::BufReader;
+
+    #[test]
+    fn continuations_test() {
+        let text = "label \"Open a file\" bind C-x C-f : emacs\n\
+                    bind C-x 4 f : emacs --other-window\n\
+                    bind C-y : yank\n";
+        let mut config = Config::default();
+        parse_config(&mut config, BufReader::new(text.as_bytes()), "foo").unwrap();
+
+        let mut matcher = Matcher::new(&config);
+        matcher.feed(Key::try_from(LineText::new("foo", 1, "C-x")).unwrap(), None);
+        assert_eq!(
+            continuations(&matcher),
+            vec!["C-f  Open a file", "4 f  bind emacs --other-window"]
+        );
+    }
+
+    #[test]
+    fn place_test() {
+        // Just past the pointer, if there's room.
+        assert_eq!(place(100, 50, 1000), 112);
+        // Otherwise up against the far edge.
+        assert_eq!(place(980, 50, 1000), 948);
+        // And never off the near edge.
+        assert_eq!(place(10, 2000, 1000), 0);
+    }
+
+    #[test]
+    fn latin1_test() {
+        assert_eq!(latin1("café ✓"), b"caf\xe9 ?".to_vec());
+    }
+}
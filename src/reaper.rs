@@ -0,0 +1,303 @@
+// Waiting on the commands bindings run.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use std::io::Write;
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often to check whether a command has finished, when there's
+/// another command to start once it has. This is how long, at most,
+/// e.g. a series of volume steps waits between steps.
+const STEP_POLL: Duration = Duration::from_millis(10);
+
+/// How often to check whether a command has finished, when it's the
+/// last one its binding runs; all that's left to do then is collect
+/// its exit status, so that it doesn't linger as a zombie.
+const REAP_POLL: Duration = Duration::from_secs(1);
+
+/// A binding's commands, run one after another, waiting for each to
+/// finish before starting the next, and then all again, until they've
+/// been run `repeat` times in all. Waiting means that e.g. a series of
+/// volume steps happen in order.
+pub struct Job {
+    /// The commands, each with the name to report it by.
+    cmds: Vec<(Command, String)>,
+
+    /// The text to feed the last command.
+    stdin: Option<String>,
+
+    /// How many times to run the commands.
+    repeat: u32,
+
+    /// The command that's running.
+    child: Child,
+
+    /// Which of `cmds` is running, and in which run of them all,
+    /// starting from 1.
+    idx: usize,
+    round: u32,
+}
+
+impl Job {
+    /// Sets up a job for the given commands, the first of which has
+    /// already been started as `first`. `stdin` is fed to the last
+    /// command, which should have been given a pipe for it.
+    pub fn new(
+        cmds: Vec<(Command, String)>,
+        first: Child,
+        stdin: Option<String>,
+        repeat: u32,
+    ) -> Self {
+        let mut job = Self {
+            cmds,
+            stdin,
+            repeat,
+            child: first,
+            idx: 0,
+            round: 1,
+        };
+        job.feed_stdin();
+        job
+    }
+
+    /// Runs the rest of the job here and now, returning once the last
+    /// command has finished.
+    pub fn run(mut self) {
+        loop {
+            // Ignore errors here. We don't care about the return
+            // status of whatever the user had us invoke, and dealing
+            // with errors there is their problem.
+            let _ignored = self.child.wait();
+            if !self.start_next() {
+                return;
+            }
+        }
+    }
+
+    /// Checks whether the running command is the last one to run.
+    fn is_last(&self) -> bool {
+        self.idx + 1 == self.cmds.len() && self.round >= self.repeat
+    }
+
+    /// Starts the next command, once the one running has finished.
+    /// Returns false if there's nothing left to run, or if the next
+    /// command can't be started, in which case the rest are given up
+    /// on.
+    fn start_next(&mut self) -> bool {
+        if self.is_last() {
+            return false;
+        }
+        self.idx += 1;
+        if self.idx == self.cmds.len() {
+            self.idx = 0;
+            self.round += 1;
+        }
+
+        let (cmd, name) = &mut self.cmds[self.idx];
+        match cmd.spawn() {
+            Ok(child) => {
+                self.child = child;
+                self.feed_stdin();
+                true
+            }
+            Err(err) => {
+                eprintln!("Error launching \"{}\": {}", name, err);
+                false
+            }
+        }
+    }
+
+    /// Writes `stdin` to the running command, if it has a pipe for it;
+    /// only the last command does. The writing is done on a thread of
+    /// its own, since a command that doesn't read its input straight
+    /// away would otherwise hold up every other job once the pipe
+    /// fills up.
+    fn feed_stdin(&mut self) {
+        if let (Some(text), Some(mut pipe)) = (&self.stdin, self.child.stdin.take()) {
+            let text = text.clone();
+            thread::spawn(move || {
+                // If the command doesn't read all of its input,
+                // that's its own business too.
+                let _ignored = pipe.write_all(text.as_bytes());
+            });
+        }
+    }
+
+    /// Checks whether the running command has finished, and if so,
+    /// starts the next one. Returns false once the job is done.
+    fn poll(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(None) => true,
+            // An error means there's no child to wait for, which is as
+            // good as it having finished.
+            Ok(Some(_)) | Err(_) => self.start_next(),
+        }
+    }
+}
+
+/// A thread that sees jobs through to the end in the background, so
+/// that every command gets waited for without needing a thread of
+/// its own. The thread is stopped when this is dropped; any commands
+/// still running carry on without us.
+pub struct Reaper {
+    /// Where to send jobs for the thread to take over.
+    jobs: Option<Sender<Job>>,
+
+    /// The thread itself.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    /// Starts the reaper thread.
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            jobs: Some(sender),
+            thread: Some(thread::spawn(move || reap(receiver))),
+        }
+    }
+
+    /// Hands a job over to the reaper thread, to run the rest of it in
+    /// the background.
+    pub fn add(&self, job: Job) {
+        // The sender is only taken away when we're dropped.
+        if let Err(mpsc::SendError(job)) = self.jobs.as_ref().unwrap().send(job) {
+            // The thread has died, which shouldn't happen; make sure
+            // the job still gets run.
+            eprintln!("Error: the thread that waits for commands has stopped");
+            thread::spawn(move || job.run());
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // Hanging up tells the thread to stop.
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ignored = thread.join();
+        }
+    }
+}
+
+/// Runs the reaper thread, taking jobs from `jobs` until the sending
+/// end hangs up.
+fn reap(jobs: Receiver<Job>) {
+    let mut running: Vec<Job> = Vec::new();
+    loop {
+        // Only check up on commands as often as there's a need to.
+        let received = if running.is_empty() {
+            jobs.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else if running.iter().all(Job::is_last) {
+            jobs.recv_timeout(REAP_POLL)
+        } else {
+            jobs.recv_timeout(STEP_POLL)
+        };
+        match received {
+            Ok(job) => running.push(job),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        running.extend(jobs.try_iter());
+        running.retain_mut(Job::poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process::{self, Stdio};
+    use std::time::Instant;
+
+    /// Makes a job appending each of `lines` to the file at `path`,
+    /// one command per line, run `repeat` times.
+    fn echo_job(path: &str, lines: &[&str], repeat: u32) -> Job {
+        let mut cmds: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg(format!("sleep 0.05; echo {} >> {}", line, path));
+                (cmd, "sh".to_string())
+            })
+            .collect();
+        let first = cmds[0].0.spawn().unwrap();
+        Job::new(cmds, first, None, repeat)
+    }
+
+    #[test]
+    fn reaper_test() {
+        let path = env::temp_dir().join(format!("ahkd-reaper-test-{}.out", process::id()));
+        let path_str = path.to_str().unwrap();
+
+        let reaper = Reaper::spawn();
+        reaper.add(echo_job(path_str, &["one", "two"], 2));
+
+        // The commands still run in order, in the background.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let expected = "one\ntwo\none\ntwo\n";
+        while fs::read_to_string(&path).unwrap_or_default() != expected {
+            assert!(Instant::now() < deadline, "commands didn't all run");
+            thread::sleep(STEP_POLL);
+        }
+
+        fs::remove_file(&path).unwrap();
+
+        // A command that doesn't read its input yet, given more than
+        // fits in a pipe, holds up neither starting it nor the
+        // commands after it.
+        let mut slow = Command::new("sh");
+        slow.arg("-c")
+            .arg(format!(
+                "sleep 0.5; cat > /dev/null; echo done >> {}",
+                path_str
+            ))
+            .stdin(Stdio::piped());
+        let first = slow.spawn().unwrap();
+        let starting = Instant::now();
+        let job = Job::new(
+            vec![(slow, "sh".to_string())],
+            first,
+            Some("x".repeat(1 << 20)),
+            1,
+        );
+        assert!(starting.elapsed() < Duration::from_millis(400));
+        reaper.add(job);
+        reaper.add(echo_job(path_str, &["after"], 1));
+        let expected = "after\ndone\n";
+        while fs::read_to_string(&path).unwrap_or_default() != expected {
+            assert!(Instant::now() < deadline, "commands didn't all run");
+            thread::sleep(STEP_POLL);
+        }
+
+        fs::remove_file(&path).unwrap();
+
+        // Stopping the reaper doesn't wait for anything still running.
+        let mut sleep = Command::new("sleep");
+        sleep.arg("5");
+        let first = sleep.spawn().unwrap();
+        reaper.add(Job::new(vec![(sleep, "sleep".to_string())], first, None, 1));
+        let stopping = Instant::now();
+        drop(reaper);
+        assert!(stopping.elapsed() < REAP_POLL);
+    }
+}
@@ -0,0 +1,148 @@
+// Watchdog for releasing a stuck keyboard grab.
+// Copyright (C) 2021 by Alexander Bethel.
+
+// This file is part of ahkd.
+
+// ahkd is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// ahkd is distributed in the hope that it will be useful, but WITHOUT
+// ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+// or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public
+// License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with ahkd. If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{KillClientRequest, Window};
+use x11rb::rust_connection::RustConnection;
+
+/// Keeps track of whether the main thread is holding a grab on the
+/// whole keyboard, so that a separate thread can step in if it holds
+/// on to the grab for too long. While we've got the keyboard, nobody
+/// else can type anything, so if we were to hang or crash at the
+/// wrong moment the user would be locked out of their own computer.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// How long a grab may be held before we release it by force.
+    timeout: Duration,
+
+    /// If a grab is being held, a resource belonging to the
+    /// connection holding it, and when the grab started.
+    grab: Mutex<Option<(Window, Instant)>>,
+}
+
+impl Watchdog {
+    /// Starts a watchdog thread on its own connection to the given
+    /// display, which releases any keyboard grab held for longer
+    /// than `timeout`.
+    pub fn spawn(
+        display_name: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
+        // Connect now rather than in the thread, so that the user
+        // hears about it if we can't.
+        let display = RustConnection::connect(display_name)?.0;
+
+        let watchdog = Arc::new(Self {
+            timeout,
+            grab: Mutex::new(None),
+        });
+        let shared = Arc::clone(&watchdog);
+        thread::spawn(move || shared.run(display));
+
+        Ok(watchdog)
+    }
+
+    /// Records that the connection owning `resource` has just
+    /// grabbed the keyboard.
+    pub fn grabbed(&self, resource: Window) {
+        *self.lock() = Some((resource, Instant::now()));
+    }
+
+    /// Records that the keyboard grab has been released normally.
+    pub fn released(&self) {
+        *self.lock() = None;
+    }
+
+    /// Gets the resource whose connection has been holding the
+    /// keyboard for too long, if any, and forgets about the grab.
+    fn expired(&self) -> Option<Window> {
+        let mut grab = self.lock();
+        match *grab {
+            Some((resource, since)) if since.elapsed() >= self.timeout => {
+                *grab = None;
+                Some(resource)
+            }
+            _ => None,
+        }
+    }
+
+    /// Locks the grab state. The state is always valid, so it's fine
+    /// to carry on with it even if some thread panicked while holding
+    /// the lock; indeed, that's when we're needed most.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(Window, Instant)>> {
+        self.grab
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Body of the watchdog thread.
+    fn run(&self, display: RustConnection) {
+        // Check a few times per timeout period, so we're never too
+        // late.
+        let interval = (self.timeout / 4).max(Duration::from_millis(10));
+        loop {
+            thread::sleep(interval);
+            if let Some(resource) = self.expired() {
+                eprintln!(
+                    "The keyboard has been grabbed for over {} ms; releasing it",
+                    self.timeout.as_millis()
+                );
+
+                // Clients can only release their own grabs, so the
+                // only way to release the main connection's grab
+                // from here is to have the server disconnect it.
+                let result = KillClientRequest { resource }
+                    .send(&display)
+                    .and_then(|_| display.flush());
+                if let Err(err) = result {
+                    eprintln!("Error releasing the keyboard: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_test() {
+        let watchdog = Watchdog {
+            timeout: Duration::from_millis(20),
+            grab: Mutex::new(None),
+        };
+        assert_eq!(watchdog.expired(), None);
+
+        watchdog.grabbed(42);
+        assert_eq!(watchdog.expired(), None);
+        watchdog.released();
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(watchdog.expired(), None);
+
+        watchdog.grabbed(42);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(watchdog.expired(), Some(42));
+        // It only fires once per grab.
+        assert_eq!(watchdog.expired(), None);
+    }
+}
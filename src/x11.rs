@@ -16,251 +16,2204 @@
 // You should have received a copy of the GNU General Public License
 // along with ahkd. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::keyseq::{Key, Keysym, ModField};
+use crate::keyseq::{Key, KeySequence, Keysym, ModField, Modifier};
+use crate::log::{debug, verbose};
+use crate::matcher::Press;
+use crate::popup::Popup;
+use crate::watchdog::Watchdog;
 use crate::AhkdError;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::env;
 use std::error::Error;
-use x11_keysymdef::lookup_by_keysym;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use x11rb::connection::Connection;
-use x11rb::errors::ConnectionError;
+use x11rb::errors::{ConnectError, ConnectionError, ReplyError};
 use x11rb::protocol::{
+    xinput::{
+        self, Device, KeyEventFlags, XIEventMask, XIQueryVersionRequest, XISelectEventsRequest,
+    },
     xproto::{
-        GetKeyboardMappingReply, GetKeyboardMappingRequest, GrabKeyRequest, GrabKeyboardRequest,
-        GrabMode, GrabStatus, ModMask, UngrabKeyRequest, UngrabKeyboardRequest, Window,
+        Allow, AllowEventsRequest, Atom, AtomEnum, BellRequest, ChangePropertyRequest,
+        ChangeWindowAttributesAux, ChangeWindowAttributesRequest, CreateWindowAux,
+        CreateWindowRequest, EventMask, GetInputFocusRequest, GetKeyboardMappingReply,
+        GetKeyboardMappingRequest, GetModifierMappingReply, GetModifierMappingRequest,
+        GetSelectionOwnerRequest, GetWindowAttributesRequest, GrabButtonRequest, GrabKeyRequest,
+        GrabKeyboardRequest, GrabMode, GrabStatus, InternAtomRequest, KeyPressEvent, Mapping,
+        ModMask, NotifyMode, PropMode, QueryKeymapRequest, QueryPointerRequest,
+        SelectionNotifyEvent, SelectionRequestEvent, SendEventRequest, SetSelectionOwnerRequest,
+        UngrabButtonRequest, UngrabKeyRequest, UngrabKeyboardRequest, Window, WindowClass,
+        BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, KEY_PRESS_EVENT, KEY_RELEASE_EVENT,
+        SELECTION_NOTIFY_EVENT,
     },
-    Event,
+    xtest::FakeInputRequest,
+    ErrorKind, Event,
 };
 use x11rb::rust_connection::RustConnection;
 use x11rb::CURRENT_TIME;
 
+/// How often `wait_while_held` checks whether the key is still down.
+const RELEASE_POLL: Duration = Duration::from_millis(100);
+
+/// A key going down or coming back up, as reported by `get_event`.
+#[derive(Debug, PartialEq)]
+enum KeyEvent {
+    /// A key, or a grabbed mouse button, was pressed.
+    Press(Key),
+
+    /// The key with this keycode came back up.
+    Release(u8),
+}
+
+/// What became of a held key while `wait_while_held` was waiting on
+/// it.
+#[derive(Debug, PartialEq)]
+pub enum Held {
+    /// It came back up.
+    Released,
+
+    /// It's still down, and the deadline has passed.
+    StillDown,
+
+    /// Some other key was pressed while it was held.
+    Pressed(Key),
+
+    /// Our interrupt flag got set, or there was no key to wait on.
+    Interrupted,
+}
+
+/// What a key release event means; see `classify_release`.
+#[derive(Debug, PartialEq)]
+enum ReleaseKind {
+    /// The key came back up.
+    Release,
+
+    /// The key is still held down, and auto-repeating.
+    Repeat,
+}
+
+/// The keysym X uses to denote the absence of a symbol.
+const NO_SYMBOL: u32 = 0;
+
+/// The special `SendEvent` destination meaning whichever window has
+/// the input focus.
+const DEST_INPUT_FOCUS: Window = 1;
+
 /// A structure for sending and receiving X11 events.
 pub struct X11Conn {
     /// The display we're connected to.
     display: RustConnection,
 
-    /// The root window of that display.
+    /// The name of that display, or None if we used the default
+    /// from `$DISPLAY`.
+    display_name: Option<String>,
+
+    /// The root window of the display's default screen.
     root_window: Window,
 
-    /// The keyboard mapping.
-    keymap: KeyMap,
+    /// The windows we grab keys on. These are normally the root
+    /// windows of every screen, making our bindings global, but can
+    /// be some other window to limit them to when it or one of its
+    /// descendants has the focus.
+    grab_windows: Vec<Window>,
+
+    /// The keyboard mapping. This changes whenever the user switches
+    /// keyboard layouts.
+    keymap: RefCell<KeyMap>,
+
+    /// A hidden window of our own, which owns the clipboard when
+    /// we've set it.
+    window: Window,
+
+    /// The atoms needed to serve the clipboard.
+    atoms: Atoms,
+
+    /// The text we're serving as the contents of the clipboard, if
+    /// we own it.
+    clipboard: RefCell<Option<String>>,
+
+    /// The watchdog to tell about keyboard grabs, if any.
+    watchdog: Option<Arc<Watchdog>>,
+
+    /// Whether a key grabbed with `sync` has frozen the keyboard.
+    frozen: Cell<bool>,
+
+    /// The XInput2 ID of the keyboard the last key was pressed on, if
+    /// we know it.
+    last_device: Cell<Option<u16>>,
+
+    /// How the last key we returned was pressed; see `last_press`.
+    last_press: Cell<Press>,
+
+    /// How many fresh key presses XInput2 has told us about since the
+    /// last ordinary key press event. Each of those comes just after
+    /// its raw event, so more than one means that some key went to
+    /// another program in between.
+    raw_presses: Cell<u32>,
+
+    /// Whether a key press came to us since the last key we returned
+    /// that we didn't return, like a modifier on its own.
+    missed_press: Cell<bool>,
+
+    /// Whether the next key press event is an auto-repeat; see
+    /// `classify_release`.
+    repeat_next: Cell<bool>,
+
+    /// The keycode of the last key pressed, or None if it was a mouse
+    /// button.
+    last_keycode: Cell<Option<u8>>,
+
+    /// The modifier that Num Lock sets.
+    num_lock: Modifier,
+
+    /// A flag that, once set, makes us stop waiting for keys, if any,
+    /// and the read end of a pipe that's written to when it's set.
+    interrupt: Option<(&'static AtomicBool, RawFd)>,
+
+    /// A file descriptor that, once there's something to read from
+    /// it, makes `next_key` stop waiting, if any.
+    wake_fd: Option<RawFd>,
+
+    /// The keycodes that bindings refer to directly, rather than by
+    /// keysym. Presses of these keys are reported as the keycode.
+    raw_keycodes: Vec<u8>,
+
+    /// Whether to take notice of key presses and clicks that were
+    /// sent with SendEvent; see `is_synthetic`.
+    synthetic_events: bool,
+
+    /// The popup of keys that can follow a prefix, if it's showing.
+    popup: RefCell<Option<Popup>>,
+
+    /// An event read ahead of time, while checking whether a key
+    /// release was part of an auto-repeat, that's yet to be handled.
+    pending: RefCell<Option<Event>>,
+
+    /// The keys we keep grabbed in between key sequences; see
+    /// `hold_grabs`.
+    held: RefCell<Vec<Grab>>,
+
+    /// The warnings passed to `conflict` so far, about keys some
+    /// other program had already grabbed or that the layout lacks, so
+    /// that each is only given once.
+    conflicts: RefCell<Vec<String>>,
+}
+
+/// The atoms used in the clipboard protocol.
+struct Atoms {
+    clipboard: Atom,
+    targets: Atom,
+    text: Atom,
+    utf8_string: Atom,
+}
+
+/// Where to grab keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrabTarget {
+    /// The root windows of every screen, so that bindings work
+    /// everywhere.
+    AllScreens,
+
+    /// The root window of the screen with this number only.
+    Screen(usize),
+
+    /// This window, so that bindings only work when it or one of its
+    /// descendants has the focus.
+    Window(Window),
+}
+
+/// Something that individual keys can be grabbed from. This is
+/// implemented by `X11Conn`, and exists as a separate trait so that
+/// the grabbing logic can be tested without an X server.
+pub trait KeySource {
+    /// Grabs a single key, given as a keysym and an X11 modifier
+    /// mask. If `sync` is set, the keyboard is frozen when
+    /// the key is pressed, until we thaw it. Returns false if some
+    /// other program has already grabbed the key, so we can't, on
+    /// every window we grab keys on.
+    fn grab_key(&self, keysym: Keysym, modifiers: u16, sync: bool) -> Result<bool, Box<dyn Error>>;
+
+    /// Ungrabs a single key previously grabbed with `grab_key`.
+    fn ungrab_key(&self, keysym: Keysym, modifiers: u16) -> Result<(), Box<dyn Error>>;
+
+    /// Gets the combinations of lock modifiers that a key producing
+    /// `keysym` is grabbed with, on top of its own modifiers. A grab
+    /// only fires when the modifiers match exactly, so without these,
+    /// a binding wouldn't work with Num Lock or Caps Lock on.
+    fn lock_masks(&self, keysym: Keysym) -> Vec<u16>;
+
+    /// Tells whether any key on the current layout produces `keysym`,
+    /// so it can be grabbed.
+    fn has_key(&self, keysym: Keysym) -> bool;
+
+    /// Gets which of Mod1 to Mod5 AltGr sets on the current layout.
+    fn altgr(&self) -> Modifier;
+
+    /// Tells whether two keysyms are on the same key, or are the same
+    /// mouse button, so that grabbing one grabs the other.
+    fn same_key(&self, a: Keysym, b: Keysym) -> bool;
+
+    /// Lets go of the whole keyboard, if we've grabbed it.
+    fn release_keyboard(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Deals with finding that some other program has already grabbed
+    /// a key, given as `AhkdError::AlreadyGrabbed`.
+    fn conflict(&self, err: AhkdError);
+
+    /// Gets the keys grabbed with `hold_grabs`.
+    fn held(&self) -> &RefCell<Vec<Grab>>;
+
+    /// Grabs the given set of keys. Any that some other program has
+    /// already grabbed, or that aren't on the current layout, are
+    /// passed to `conflict`, and the rest are still grabbed.
+    fn grab_keys(&self, grabs: &[Grab]) -> Result<(), Box<dyn Error>> {
+        for grab in grabs {
+            let key = &grab.key;
+            if !self.has_key(key.main_key) {
+                self.conflict(AhkdError::UnavailableKey(key.main_key, None));
+                continue;
+            }
+            let locks = self.lock_masks(key.main_key);
+            let modifiers = modifier_mask(&key.modifiers, self.altgr());
+            let mut grabbed = true;
+            for lock in any_locks(key, &locks) {
+                grabbed &= self.grab_key(key.main_key, modifiers | lock, grab.sync)?;
+            }
+            if !grabbed {
+                self.conflict(AhkdError::AlreadyGrabbed(*key));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ungrabs the given set of keys. Keys that aren't on the current
+    /// layout were never grabbed, so are skipped.
+    fn ungrab_keys(&self, grabs: &[Grab]) -> Result<(), Box<dyn Error>> {
+        for grab in grabs {
+            let key = &grab.key;
+            if !self.has_key(key.main_key) {
+                continue;
+            }
+            let locks = self.lock_masks(key.main_key);
+            let modifiers = modifier_mask(&key.modifiers, self.altgr());
+            for lock in any_locks(key, &locks) {
+                self.ungrab_key(key.main_key, modifiers | lock)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the keys grabbed from `old` to `new`, leaving the keys
+    /// that are in both alone, so that they're never ungrabbed even
+    /// for a moment.
+    fn change_grabs(&self, old: &[Grab], new: &[Grab]) -> Result<(), Box<dyn Error>> {
+        let removed: Vec<_> = old
+            .iter()
+            .filter(|grab| !new.contains(grab))
+            .copied()
+            .collect();
+        let added: Vec<_> = new
+            .iter()
+            .filter(|grab| !old.contains(grab))
+            .copied()
+            .collect();
+        self.ungrab_keys(&removed)?;
+        self.grab_keys(&added)
+    }
+
+    /// Makes `grabs` the keys we hold grabs on, until the next call,
+    /// changing only the grabs that differ from the last call's.
+    fn hold_grabs(&self, grabs: &[Grab]) -> Result<(), Box<dyn Error>> {
+        let old = self.held().replace(grabs.to_vec());
+        self.change_grabs(&old, grabs)
+    }
+
+    /// Moves the given grabs over to a new keyboard layout, which
+    /// `relayout` switches to. They're let go of first, while the old
+    /// layout still says which keycodes they're on.
+    fn regrab_keys(
+        &self,
+        grabs: &[Grab],
+        relayout: impl FnOnce() -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        Self: Sized,
+    {
+        self.ungrab_keys(grabs)?;
+        relayout()?;
+        self.grab_keys(grabs)
+    }
+
+    /// Runs `send`, which presses `keys` in a way that our own grabs
+    /// could catch, with the held grabs on those keys let go of in
+    /// the meantime, so that they don't set off our bindings again.
+    /// Grabs on any other keys are left alone, so their bindings keep
+    /// working throughout.
+    fn without_grabs_on(
+        &self,
+        keys: &[Keysym],
+        send: impl FnOnce() -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        Self: Sized,
+    {
+        let grabs: Vec<_> = self
+            .held()
+            .borrow()
+            .iter()
+            .filter(|grab| {
+                keys.iter()
+                    .any(|&key| self.same_key(grab.key.main_key, key))
+            })
+            .copied()
+            .collect();
+        self.ungrab_keys(&grabs)?;
+        let sent = send();
+        self.grab_keys(&grabs)?;
+        sent
+    }
+}
+
+/// Gets the lock masks to grab `key` with, out of `locks`. A key
+/// that matches any modifiers is grabbed with AnyModifier, which
+/// covers the locks already, and can't be combined with them.
+fn any_locks<'a>(key: &Key, locks: &'a [u16]) -> &'a [u16] {
+    if key.modifiers.any {
+        &[0]
+    } else {
+        locks
+    }
+}
+
+/// Where the mouse pointer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    /// The pointer's horizontal coordinate, relative to the root
+    /// window of the screen it's on.
+    pub x: i16,
+
+    /// The pointer's vertical coordinate, likewise.
+    pub y: i16,
+
+    /// The number of the screen the pointer is on.
+    pub screen: usize,
+}
+
+/// A key to grab, and how to grab it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grab {
+    /// The key to grab.
+    pub key: Key,
+
+    /// Whether to freeze the keyboard when the key is pressed, so
+    /// that any keys typed after it are held back until we're ready
+    /// for them, rather than going to whichever window has the
+    /// focus.
+    pub sync: bool,
+}
+
+/// A KeySource that doesn't grab anything, but keeps a record of
+/// every grab and ungrab it's asked to do, for testing.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingKeySource {
+    /// Every `grab_key` call, in order.
+    pub grabbed: std::cell::RefCell<Vec<(Keysym, u16, bool)>>,
+
+    /// Every `ungrab_key` call, in order.
+    pub ungrabbed: std::cell::RefCell<Vec<(Keysym, u16)>>,
+
+    /// The mask for Num Lock, if keys should be grabbed with the lock
+    /// modifiers as `X11Conn` does.
+    pub num_lock: Option<u16>,
+
+    /// Keys to refuse to grab, as if some other program had grabbed
+    /// them already.
+    pub taken: Vec<(Keysym, u16)>,
+
+    /// Every error passed to `conflict`, in order.
+    pub conflicts: std::cell::RefCell<Vec<AhkdError>>,
+
+    /// Keysyms that no key produces, as if the layout lacked them.
+    pub missing: std::cell::RefCell<Vec<Keysym>>,
+
+    /// The keys grabbed with `hold_grabs`.
+    pub held: std::cell::RefCell<Vec<Grab>>,
+
+    /// Whether `release_keyboard` has been called.
+    pub released: Cell<bool>,
 }
 
 /// A converter between keycodes and keysyms.
 struct KeyMap {
-    /// The mapping from keysyms to keycodes.
-    ks_to_kc: HashMap<u32, u8>,
+    /// The mapping from keysyms to keycodes, along with the level
+    /// (0 for unshifted, 1 for shifted, and so on) at which each
+    /// keycode produces the keysym.
+    ks_to_kc: HashMap<u32, (u8, usize)>,
+
+    /// The mapping from keycodes to the keysyms they produce at each
+    /// level.
+    kc_to_ks: HashMap<u8, Vec<u32>>,
 
-    /// The mapping from keycodes to keysyms.
-    kc_to_ks: HashMap<u8, u32>,
+    /// Which of Mod1 to Mod5 AltGr sets. Until we've asked the server,
+    /// this is Mod5, where most layouts put it.
+    altgr: Modifier,
 }
 
 impl X11Conn {
-    /// Connects to the X11 display.
+    /// Connects to the X11 display. If no display is given and
+    /// `$DISPLAY` isn't set, picks the lowest-numbered local display
+    /// that has a socket in `/tmp/.X11-unix`.
     pub fn new(display_name: Option<&str>) -> Result<Self, Box<dyn Error>> {
-        let display = RustConnection::connect(display_name)?.0;
+        Self::new_for(display_name, GrabTarget::AllScreens)
+    }
+
+    /// Connects to the X11 display like `new`, but grabs keys on the
+    /// given target rather than on every screen.
+    pub fn new_for(display_name: Option<&str>, target: GrabTarget) -> Result<Self, Box<dyn Error>> {
+        let given = display_name;
+        let probed;
+        let display_name = match display_name {
+            Some(name) => Some(name),
+            None if env::var_os("DISPLAY").is_none_or(|d| d.is_empty()) => {
+                probed = probe_display();
+                if let Some(name) = &probed {
+                    eprintln!("$DISPLAY is not set; using display {}", name);
+                }
+                probed.as_deref()
+            }
+            None => None,
+        };
+        let (display, default_screen) = match RustConnection::connect(display_name) {
+            Ok(connected) => connected,
+            Err(ConnectError::DisplayParsingError) => {
+                return Err(Box::new(no_display_error(given, env::var_os("DISPLAY"))))
+            }
+            Err(err) => return Err(Box::new(err)),
+        };
 
         let setup = display.setup();
-        // TODO: what if there's more than one screen?
-        let root_window = setup.roots[0].root;
-        let min_keycode = setup.min_keycode;
-        let max_keycode = setup.max_keycode;
-        let keymap_pkt = GetKeyboardMappingRequest {
-            first_keycode: min_keycode,
-            count: max_keycode - min_keycode,
+        let roots: Vec<Window> = setup.roots.iter().map(|screen| screen.root).collect();
+        let root_window = roots[default_screen];
+        let keymap = RefCell::new(KeyMap::fetch(&display)?);
+
+        let grab_windows = match target {
+            GrabTarget::AllScreens => roots.clone(),
+            GrabTarget::Screen(screen) => match roots.get(screen) {
+                Some(&root) => vec![root],
+                None => {
+                    return Err(Box::new(AhkdError::X11Error(format!(
+                        "no screen {}; the display only has {}",
+                        screen,
+                        roots.len()
+                    ))))
+                }
+            },
+            GrabTarget::Window(window) => {
+                // Make sure the window exists now, rather than failing
+                // on the first grab.
+                let request = GetWindowAttributesRequest { window };
+                if request.send(&display)?.reply().is_err() {
+                    return Err(Box::new(AhkdError::X11Error(format!(
+                        "no such window {:#x}",
+                        window
+                    ))));
+                }
+                vec![window]
+            }
+        };
+
+        // Ask to hear about focus changes, so that we can tell if some
+        // other program takes the focus while we've got the whole
+        // keyboard grabbed.
+        for &root in &roots {
+            ChangeWindowAttributesRequest {
+                window: root,
+                value_list: Cow::Owned(
+                    ChangeWindowAttributesAux::new().event_mask(EventMask::FOCUS_CHANGE),
+                ),
+            }
+            .send(&display)?
+            .check()?;
+        }
+
+        let window = display.generate_id()?;
+        CreateWindowRequest {
+            depth: 0,
+            wid: window,
+            parent: root_window,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            border_width: 0,
+            class: WindowClass::INPUT_ONLY,
+            visual: 0,
+            value_list: Cow::Owned(CreateWindowAux::new()),
         }
         .send(&display)?
-        .reply()?;
+        .check()?;
 
-        let keymap = KeyMap::new(min_keycode, keymap_pkt);
+        let atoms = Atoms {
+            clipboard: intern_atom(&display, "CLIPBOARD")?,
+            targets: intern_atom(&display, "TARGETS")?,
+            text: intern_atom(&display, "TEXT")?,
+            utf8_string: intern_atom(&display, "UTF8_STRING")?,
+        };
 
         Ok(Self {
             display,
+            display_name: display_name.map(str::to_string),
             root_window,
+            grab_windows,
             keymap,
+            window,
+            atoms,
+            clipboard: RefCell::new(None),
+            watchdog: None,
+            frozen: Cell::new(false),
+            last_device: Cell::new(None),
+            last_press: Cell::new(Press::default()),
+            raw_presses: Cell::new(0),
+            missed_press: Cell::new(false),
+            repeat_next: Cell::new(false),
+            last_keycode: Cell::new(None),
+            num_lock: Modifier::Mod2,
+            interrupt: None,
+            wake_fd: None,
+            raw_keycodes: Vec::new(),
+            synthetic_events: false,
+            popup: RefCell::new(None),
+            pending: RefCell::new(None),
+            held: RefCell::new(Vec::new()),
+            conflicts: RefCell::new(Vec::new()),
         })
     }
 
     /// Listens for the given set of keys, and returns the first key
-    /// pressed.
-    pub fn next_key(&self, keys: &[Key]) -> Result<Key, Box<dyn Error>> {
-        self.grab_keys(&keys)?;
-        let k = self.get_key()?;
-        self.ungrab_keys(&keys)?;
+    /// pressed, or None if our interrupt flag gets set or our wake
+    /// file descriptor becomes readable first. If that key was grabbed
+    /// with `sync` set, the keyboard stays frozen until the next call
+    /// to `next_key_kbd` or `thaw`. The keys stay grabbed afterwards,
+    /// so that the next call, or a reload that changes only some of
+    /// them, doesn't have to grab them all again.
+    pub fn next_key(&self, grabs: &[Grab]) -> Result<Option<Key>, Box<dyn Error>> {
+        self.hold_grabs(grabs)?;
+        let k = self.get_key(false, false, false, true, None)?;
+
+        if let Some(k) = &k {
+            if grabs
+                .iter()
+                .any(|grab| grab.sync && grab.key.matches(k, self.num_lock))
+            {
+                self.frozen.set(true);
+            }
+        }
 
         Ok(k)
     }
 
+    /// Listens for the given set of keys like `next_key`, but gives up
+    /// and returns None if `deadline` passes first. Keys grabbed with
+    /// `sync` aren't treated specially, and none of the keys stay
+    /// grabbed afterwards.
+    pub fn next_key_until(
+        &self,
+        grabs: &[Grab],
+        deadline: Instant,
+    ) -> Result<Option<Key>, Box<dyn Error>> {
+        self.hold_grabs(grabs)?;
+        let k = self.get_key(false, false, false, false, Some(deadline))?;
+        self.hold_grabs(&[])?;
+        Ok(k)
+    }
+
+    /// Lets the keyboard carry on after a key grabbed with `sync`
+    /// froze it, delivering any keys typed in the meantime. Does
+    /// nothing if the keyboard isn't frozen.
+    pub fn thaw(&self) -> Result<(), Box<dyn Error>> {
+        if self.frozen.replace(false) {
+            AllowEventsRequest {
+                mode: Allow::ASYNC_KEYBOARD,
+                time: CURRENT_TIME,
+            }
+            .send(&self.display)?
+            .check()?;
+        }
+
+        Ok(())
+    }
+
+    /// Lets go of the key that froze the keyboard after it was grabbed
+    /// with `sync`, and has the X server deliver it again as though we
+    /// had never grabbed it: to the focused window, as a real event
+    /// rather than a synthetic one. Does nothing if the keyboard isn't
+    /// frozen.
+    pub fn replay_frozen(&self) -> Result<(), Box<dyn Error>> {
+        if self.frozen.replace(false) {
+            AllowEventsRequest {
+                mode: Allow::REPLAY_KEYBOARD,
+                time: CURRENT_TIME,
+            }
+            .send(&self.display)?
+            .check()?;
+        }
+
+        Ok(())
+    }
+
     /// Listens for any keypress on the entire keyboard, and returns
-    /// the first key pressed.
-    pub fn next_key_kbd(&self) -> Result<Key, Box<dyn Error>> {
+    /// the first key pressed, or None if `deadline` passes first, the
+    /// input focus changes while we're waiting, our interrupt flag
+    /// gets set, or `wake` is set and our wake file descriptor becomes
+    /// readable. If `skip_modifiers` is set, presses of modifier keys
+    /// on their own are skipped, since they're almost always the user
+    /// getting ready to type the next chord. If `skip_repeats` is set,
+    /// so are the presses a held key auto-repeats.
+    pub fn next_key_kbd(
+        &self,
+        deadline: Option<Instant>,
+        skip_modifiers: bool,
+        skip_repeats: bool,
+        wake: bool,
+    ) -> Result<Option<Key>, Box<dyn Error>> {
         self.grab_kbd()?;
-        let k = self.get_key()?;
+        // Now that we've got the whole keyboard, any keys held back
+        // by a sync grab will come to us.
+        self.thaw()?;
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.grabbed(self.window);
+        }
+        let k = self.get_key(true, skip_modifiers, skip_repeats, wake, deadline)?;
         self.ungrab_kbd()?;
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.released();
+        }
 
         Ok(k)
     }
 
-    /// Globally grabs the given set of keys from the keybaord.
-    fn grab_keys(&self, keys: &[Key]) -> Result<(), Box<dyn Error>> {
+    /// Sends the given keys to the window that has the input focus,
+    /// as though the user had typed them there. The events are
+    /// delivered directly to the window, bypassing any grabs
+    /// (including ours), but they're marked as synthetic and some
+    /// applications choose to ignore them.
+    pub fn replay_keys(&self, keys: &[Key]) -> Result<(), Box<dyn Error>> {
+        let focus = GetInputFocusRequest.send(&self.display)?.reply()?.focus;
+
+        // If no particular window has the focus (i.e., the focus is
+        // None or PointerRoot), let the server work out where the keys
+        // should go.
+        let (destination, event_window) = match focus {
+            0 | 1 => (DEST_INPUT_FOCUS, self.root_window),
+            _ => (focus, focus),
+        };
+
         for key in keys {
-            // TODO: why am I making a Keysym here?
-            let keycode = self.keymap.keysym_to_keycode(Keysym(key.main_key.0));
+            let keycode = self.keymap.borrow().keysym_to_keycode(key.main_key)?;
+            for &(response_type, mask) in [
+                (KEY_PRESS_EVENT, EventMask::KEY_PRESS),
+                (KEY_RELEASE_EVENT, EventMask::KEY_RELEASE),
+            ]
+            .iter()
+            {
+                let event = KeyPressEvent {
+                    response_type,
+                    detail: keycode,
+                    sequence: 0,
+                    time: CURRENT_TIME,
+                    root: self.root_window,
+                    event: event_window,
+                    child: x11rb::NONE,
+                    root_x: 0,
+                    root_y: 0,
+                    event_x: 0,
+                    event_y: 0,
+                    state: modifier_mask(&key.modifiers, self.altgr()),
+                    same_screen: true,
+                };
 
-            GrabKeyRequest {
-                owner_events: false,
-                grab_window: self.root_window,
-                modifiers: (&key.modifiers).into(),
-                key: keycode,
-                pointer_mode: GrabMode::ASYNC,
-                keyboard_mode: GrabMode::ASYNC,
+                SendEventRequest {
+                    propagate: true,
+                    destination,
+                    event_mask: mask.into(),
+                    event: Cow::Owned(event.into()),
+                }
+                .send(&self.display)?
+                .check()?;
             }
-            .send(&self.display)?
-            .check()?;
         }
 
         Ok(())
     }
 
-    /// Globally grabs the entire keyboard.
-    fn grab_kbd(&self) -> Result<(), Box<dyn Error>> {
-        let reply = GrabKeyboardRequest {
-            owner_events: false,
-            grab_window: self.root_window,
+    /// Types the given keys in whichever window has the input focus,
+    /// by faking key events with the XTEST extension: for each key,
+    /// its modifiers are pressed, then the key is pressed and
+    /// released, then the modifiers are released. Unlike
+    /// `replay_keys`, the events are indistinguishable from real
+    /// typing. Modifier keys the user is holding down, such as those
+    /// of the binding that led here, are let go of while the keys are
+    /// typed, and pressed again afterwards.
+    pub fn send_keyseq(&self, seq: &KeySequence) -> Result<(), Box<dyn Error>> {
+        // The server keeps the keyboard grabbed for us while the key
+        // that triggered the action is held down, which would send
+        // the fake events straight back to us.
+        self.ungrab_kbd()?;
+
+        let modifiers = GetModifierMappingRequest.send(&self.display)?.reply()?;
+        // Otherwise they'd be added to every key we type.
+        let keys = QueryKeymapRequest.send(&self.display)?.reply()?.keys;
+        let held = held_modifiers(&modifiers, &keys);
+
+        // So would our grabs on any of the keys we're about to press.
+        // They only take effect again once the server has seen to the
+        // fake events, since requests are handled in order.
+        let mut pressed: Vec<_> = held.iter().map(|&kc| Keysym::from_keycode(kc)).collect();
+        for key in &seq.keys {
+            pressed.push(key.main_key);
+            for modifier in key.canonical_modifier_order() {
+                let keycode = modifier_keycode(&modifiers, modifier, self.altgr())?;
+                pressed.push(Keysym::from_keycode(keycode));
+            }
+        }
+        self.without_grabs_on(&pressed, || self.fake_keyseq(seq, &modifiers, &held))
+    }
+
+    /// Fakes the events for `send_keyseq`, given the modifier mapping
+    /// and the modifier keys being held down, once nothing of ours is
+    /// grabbed that could catch them.
+    fn fake_keyseq(
+        &self,
+        seq: &KeySequence,
+        modifiers: &GetModifierMappingReply,
+        held: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        for &mod_keycode in held {
+            self.fake_key(KEY_RELEASE_EVENT, mod_keycode)?;
+        }
+
+        for key in &seq.keys {
+            // A mouse button is clicked rather than typed.
+            let (press, release, detail) = match key.main_key.button() {
+                Some(button) => (BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, button),
+                None => (
+                    KEY_PRESS_EVENT,
+                    KEY_RELEASE_EVENT,
+                    self.keymap.borrow().keysym_to_keycode(key.main_key)?,
+                ),
+            };
+            let mod_keycodes = key
+                .canonical_modifier_order()
+                .into_iter()
+                .map(|modifier| modifier_keycode(modifiers, modifier, self.altgr()))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for &mod_keycode in &mod_keycodes {
+                self.fake_key(KEY_PRESS_EVENT, mod_keycode)?;
+            }
+            self.fake_key(press, detail)?;
+            self.fake_key(release, detail)?;
+            for &mod_keycode in mod_keycodes.iter().rev() {
+                self.fake_key(KEY_RELEASE_EVENT, mod_keycode)?;
+            }
+        }
+
+        for &mod_keycode in held.iter().rev() {
+            self.fake_key(KEY_PRESS_EVENT, mod_keycode)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fakes a single key or button press or release (according to
+    /// `event_type`) with the XTEST extension.
+    fn fake_key(&self, event_type: u8, detail: u8) -> Result<(), Box<dyn Error>> {
+        FakeInputRequest {
+            type_: event_type,
+            detail,
             time: CURRENT_TIME,
-            pointer_mode: GrabMode::ASYNC,
-            keyboard_mode: GrabMode::ASYNC,
+            root: self.root_window,
+            root_x: 0,
+            root_y: 0,
+            deviceid: 0,
         }
         .send(&self.display)?
-        .reply()?;
+        .check()?;
 
-        if reply.status != GrabStatus::SUCCESS {
-            Err(Box::new(AhkdError::KeyboardGrabError))
-        } else {
-            Ok(())
+        Ok(())
+    }
+
+    /// Makes the given text the contents of the clipboard. We keep
+    /// serving it to other programs until some other program takes
+    /// over the clipboard.
+    pub fn set_clipboard(&self, text: &str) -> Result<(), Box<dyn Error>> {
+        SetSelectionOwnerRequest {
+            owner: self.window,
+            selection: self.atoms.clipboard,
+            time: CURRENT_TIME,
+        }
+        .send(&self.display)?
+        .check()?;
+
+        let owner = GetSelectionOwnerRequest {
+            selection: self.atoms.clipboard,
+        }
+        .send(&self.display)?
+        .reply()?
+        .owner;
+        if owner != self.window {
+            return Err(Box::new(AhkdError::X11Error(
+                "Unable to take ownership of the clipboard".to_string(),
+            )));
         }
+
+        self.clipboard.replace(Some(text.to_string()));
+        Ok(())
     }
 
-    /// Ungrabs the given set of keys.
-    fn ungrab_keys(&self, keys: &[Key]) -> Result<(), Box<dyn Error>> {
-        for key in keys {
-            UngrabKeyRequest {
-                key: self.keymap.keysym_to_keycode(Keysym(key.main_key.0)),
-                grab_window: self.root_window,
-                modifiers: (&key.modifiers).into(),
+    /// Answers another program's request for the contents of the
+    /// clipboard.
+    fn serve_selection(&self, request: SelectionRequestEvent) -> Result<(), Box<dyn Error>> {
+        // Obsolete clients leave out the property, meaning to use the
+        // name of the target instead.
+        let property = if request.property == x11rb::NONE {
+            request.target
+        } else {
+            request.property
+        };
+
+        let clipboard = self.clipboard.borrow();
+        let text = match clipboard.as_deref() {
+            Some(text) if request.selection == self.atoms.clipboard => Some(text),
+            _ => None,
+        };
+        let string = AtomEnum::STRING.into();
+
+        let served = match text {
+            Some(_) if request.target == self.atoms.targets => {
+                // The client wants to know what formats we can give
+                // it the text in.
+                let targets = [
+                    self.atoms.targets,
+                    self.atoms.utf8_string,
+                    self.atoms.text,
+                    string,
+                ];
+                let data: Vec<u8> = targets.iter().flat_map(|t| t.to_ne_bytes()).collect();
+                self.set_property(
+                    request.requestor,
+                    property,
+                    AtomEnum::ATOM.into(),
+                    32,
+                    targets.len() as u32,
+                    &data,
+                )?;
+                true
             }
-            .send(&self.display)?
-            .check()?;
+            Some(text)
+                if request.target == self.atoms.utf8_string
+                    || request.target == self.atoms.text
+                    || request.target == string =>
+            {
+                // Strictly, STRING should be Latin-1, but nobody asks
+                // for it these days except to get plain ASCII.
+                let type_ = if request.target == string {
+                    string
+                } else {
+                    self.atoms.utf8_string
+                };
+                self.set_property(
+                    request.requestor,
+                    property,
+                    type_,
+                    8,
+                    text.len() as u32,
+                    text.as_bytes(),
+                )?;
+                true
+            }
+            _ => false,
+        };
+
+        let notify = SelectionNotifyEvent {
+            response_type: SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: request.time,
+            requestor: request.requestor,
+            selection: request.selection,
+            target: request.target,
+            property: if served { property } else { x11rb::NONE },
+        };
+        SendEventRequest {
+            propagate: false,
+            destination: request.requestor,
+            event_mask: 0,
+            event: Cow::Owned(notify.into()),
         }
+        .send(&self.display)?
+        .check()?;
 
         Ok(())
     }
 
-    /// Ungrabs the keyboard.
-    fn ungrab_kbd(&self) -> Result<(), Box<dyn Error>> {
-        UngrabKeyboardRequest { time: CURRENT_TIME }
-            .send(&self.display)?
-            .check()?;
+    /// Sets a property on another program's window.
+    fn set_property(
+        &self,
+        window: Window,
+        property: Atom,
+        type_: Atom,
+        format: u8,
+        data_len: u32,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        ChangePropertyRequest {
+            mode: PropMode::REPLACE,
+            window,
+            property,
+            type_,
+            format,
+            data_len,
+            data: Cow::Borrowed(data),
+        }
+        .send(&self.display)?
+        .check()?;
+
         Ok(())
     }
 
-    /// Waits for and returns a keypress event from the X11 server.
-    /// For this function to ever return successfully, it is necessary
-    /// to have informed the X11 server in advance that we wish to
-    /// receive some set of keypresses, using either grab_keys() or
-    /// grab_kbd().
-    fn get_key(&self) -> Result<Key, ConnectionError> {
-        loop {
-            if let Some(key) = self.event_to_key(self.display.wait_for_event()?) {
-                return Ok(key);
+    /// Gets the name of the display we're connected to, or None if
+    /// it's the default one from `$DISPLAY`. This is the display we
+    /// found by looking around, if the caller didn't specify one.
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    /// Checks whether a key is free to be grabbed on every grab
+    /// window, with every combination of lock modifiers `grab_keys`
+    /// would grab it with, or whether some other program (usually the
+    /// window manager) has grabbed it already. This works by briefly
+    /// grabbing the key, so it mustn't be used on keys we're holding a
+    /// grab on ourselves: they'd be reported as available, and then
+    /// released.
+    pub fn can_grab(&self, key: &Key) -> Result<bool, Box<dyn Error>> {
+        let locks = self.lock_masks(key.main_key);
+        let modifiers = modifier_mask(&key.modifiers, self.altgr());
+        let mut free = true;
+        if let Some(button) = key.main_key.button() {
+            for lock in any_locks(key, &locks) {
+                // The button may have been grabbed on some windows
+                // but not others, and ungrabbing it on those it
+                // wasn't grabbed on does nothing.
+                free &= self.grab_button(button, modifiers | lock)?;
+                self.ungrab_button(button, modifiers | lock)?;
             }
+            return Ok(free);
         }
-    }
 
-    /// Determines what keypress the event corresponds to, if any.
-    fn event_to_key(&self, ev: Event) -> Option<Key> {
-        if let Event::KeyPress(e) = ev {
-            let keycode = e.detail;
-            let modifiers = e.state.into();
-            let keysym = self.keymap.keycode_to_keysym(keycode);
+        let keycode = self.keymap.borrow().keysym_to_keycode(key.main_key)?;
+        for lock in any_locks(key, &locks) {
+            for &grab_window in &self.grab_windows {
+                let result = GrabKeyRequest {
+                    owner_events: false,
+                    grab_window,
+                    modifiers: modifiers | lock,
+                    key: keycode,
+                    pointer_mode: GrabMode::ASYNC,
+                    keyboard_mode: GrabMode::ASYNC,
+                }
+                .send(&self.display)?
+                .check();
 
-            // We received the keysym from the X11 server, so it must
-            // be a valid keysym number, so we can `unwrap` here.
-            if lookup_by_keysym(keysym.0).unwrap().unicode != 0 as char {
-                return Some(Key {
-                    modifiers,
-                    main_key: keysym,
-                });
+                match result {
+                    Ok(()) => {
+                        UngrabKeyRequest {
+                            key: keycode,
+                            grab_window,
+                            modifiers: modifiers | lock,
+                        }
+                        .send(&self.display)?
+                        .check()?;
+                    }
+                    Err(err) if is_access_error(&err) => free = false,
+                    Err(err) => return Err(Box::new(err)),
+                }
             }
         }
-        None
+
+        Ok(free)
     }
-}
 
-impl KeyMap {
-    /// Sets up the mappings between keysyms and keycodes.
-    pub fn new(min_keycode: u8, packet: GetKeyboardMappingReply) -> Self {
-        Self {
-            ks_to_kc: packet
-                .keysyms
-                .iter()
-                .enumerate()
-                .map(|(keycode, keysym)| {
-                    (
-                        *keysym,
-                        (keycode / packet.keysyms_per_keycode as usize) as u8 + min_keycode,
-                    )
-                })
-                .collect(),
-            kc_to_ks: packet
-                .keysyms
-                .iter()
-                .step_by(packet.keysyms_per_keycode as _)
-                .enumerate()
-                .map(|(keycode, keysym)| (keycode as u8 + min_keycode, *keysym))
-                .collect(),
+    /// Finds out where the mouse pointer is.
+    pub fn query_pointer(&self) -> Result<Pointer, Box<dyn Error>> {
+        let reply = QueryPointerRequest {
+            window: self.root_window,
         }
-    }
+        .send(&self.display)?
+        .reply()?;
 
-    /// Gets the lowest keycode corresponding to a keysym.
-    fn keysym_to_keycode(&self, keysym: Keysym) -> u8 {
-        // TODO: deal with missing keysyms.
-        *self.ks_to_kc.get(&keysym.0).unwrap_or_else(|| todo!())
-    }
+        // If the pointer isn't on our screen, `root` is the root
+        // window of the screen it is on.
+        let screen = self
+            .display
+            .setup()
+            .roots
+            .iter()
+            .position(|screen| screen.root == reply.root)
+            .unwrap_or(0);
 
-    /// Gets the first keysym corresponding to a keycode.
-    fn keycode_to_keysym(&self, keycode: u8) -> Keysym {
-        // All valid keycodes must have at least one associated
-        // keysym, so we can `unwrap` here.
-        Keysym(*self.kc_to_ks.get(&keycode).unwrap())
+        Ok(Pointer {
+            x: reply.root_x,
+            y: reply.root_y,
+            screen,
+        })
     }
-}
 
-impl From<&ModField> for u16 {
-    fn from(mods: &ModField) -> Self {
-        // I wonder if we can use some bitfield crate or something to
-        // make this a little more elegant?
-        let mut n = 0;
+    /// Finds where a keysym is on the current keyboard layout: the
+    /// level it's at (0 for unshifted, 1 for shifted, and so on), and
+    /// the keysym its key produces when unshifted. Returns None if no
+    /// key produces the keysym.
+    pub fn keysym_level(&self, keysym: Keysym) -> Option<(usize, Keysym)> {
+        self.keymap.borrow().keysym_level(keysym)
+    }
 
-        let mut mask_if = |flag, mask| {
-            if flag {
-                n |= mask;
-            }
-        };
+    /// Gets how the last key we got was pressed: on which keyboard,
+    /// if we know, when, by the X server's clock, whether it was an
+    /// auto-repeat, and whether any other key was pressed since the
+    /// key we got before it. Keys that go to other programs are only
+    /// noticed if the X server supports XInput2.
+    pub fn last_press(&self) -> Press {
+        self.last_press.get()
+    }
 
-        mask_if(mods.mod_shift, ModMask::SHIFT);
-        mask_if(mods.mod_control, ModMask::CONTROL);
-        mask_if(mods.mod1, ModMask::M1);
-        mask_if(mods.mod2, ModMask::M2);
-        mask_if(mods.mod3, ModMask::M3);
-        mask_if(mods.mod4, ModMask::M4);
-        mask_if(mods.mod5, ModMask::M5);
+    /// Asks to hear about every key pressed, on any keyboard and
+    /// whichever program it goes to, if `wanted` is set, or stops
+    /// asking if it isn't. This tells us which keyboard each key comes
+    /// from, and whether some other key was pressed between two taps
+    /// of a key, so is only worth it for configurations with bindings
+    /// for particular keyboards or double taps.
+    pub fn listen_for_raw_keys(&self, wanted: bool) -> Result<(), Box<dyn Error>> {
+        if !select_raw_keys(&self.display, self.root_window, wanted)? && wanted {
+            eprintln!("Warning: the X server doesn't support XInput2, so bindings for particular keyboards won't work");
+        }
+        Ok(())
+    }
 
-        n
+    /// Has the given watchdog keep an eye on our keyboard grabs, and
+    /// release them by force if we hold on to one for too long.
+    pub fn with_watchdog(self, watchdog: Arc<Watchdog>) -> Self {
+        Self {
+            watchdog: Some(watchdog),
+            ..self
+        }
     }
-}
 
-impl From<u16> for ModField {
-    fn from(n: u16) -> Self {
+    /// Has us stop waiting for keys as soon as the given flag is set,
+    /// as though the user had taken too long to type them. The flag
+    /// is meant to be set by a signal handler, which then writes to
+    /// the pipe whose (non-blocking) read end is `pipe`: that wakes us
+    /// even if the signal comes just as we're going to sleep.
+    pub fn with_interrupt(self, interrupt: &'static AtomicBool, pipe: RawFd) -> Self {
+        Self {
+            interrupt: Some((interrupt, pipe)),
+            ..self
+        }
+    }
+
+    /// Makes `next_key`, and `next_key_kbd` if asked to, stop waiting
+    /// and return None when there's something to read from the given
+    /// file descriptor, such as a client connecting to a listening
+    /// socket.
+    pub fn with_wake_fd(self, fd: RawFd) -> Self {
+        Self {
+            wake_fd: Some(fd),
+            ..self
+        }
+    }
+
+    /// Reports presses of the given keycodes as the keycodes
+    /// themselves (see `Keysym::from_keycode`), rather than as the
+    /// keysyms they produce, so that they match bindings written with
+    /// raw keycodes.
+    pub fn with_raw_keycodes(self, raw_keycodes: Vec<u8>) -> Self {
+        Self {
+            raw_keycodes,
+            ..self
+        }
+    }
+
+    /// Takes notice of key presses and clicks sent with SendEvent, if
+    /// `synthetic_events` is set, rather than ignoring them.
+    pub fn with_synthetic_events(self, synthetic_events: bool) -> Self {
+        Self {
+            synthetic_events,
+            ..self
+        }
+    }
+
+    /// Uses the given modifier as Num Lock, rather than Mod2.
+    pub fn with_num_lock(self, num_lock: Modifier) -> Self {
+        Self { num_lock, ..self }
+    }
+
+    /// Gets the modifier mask that Num Lock sets.
+    fn num_lock_mask(&self) -> u16 {
+        modifier_bit(self.num_lock, self.altgr())
+    }
+
+    /// Grabs the entire keyboard.
+    fn grab_kbd(&self) -> Result<(), Box<dyn Error>> {
+        // The keyboard can only be grabbed once, and which window it's
+        // grabbed on only matters for where the events are reported,
+        // so any of our windows will do.
+        let reply = GrabKeyboardRequest {
+            owner_events: false,
+            grab_window: self.grab_windows[0],
+            time: CURRENT_TIME,
+            pointer_mode: GrabMode::ASYNC,
+            keyboard_mode: GrabMode::ASYNC,
+        }
+        .send(&self.display)?
+        .reply()?;
+
+        if reply.status != GrabStatus::SUCCESS {
+            Err(Box::new(AhkdError::KeyboardGrabError))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Grabs a mouse button, with the given X11 modifier mask, on each
+    /// of our grab windows. Unlike keys, buttons are never grabbed
+    /// synchronously. Returns false if some other program has already
+    /// grabbed it on any of them, but still grabs it on the rest.
+    fn grab_button(&self, button: u8, modifiers: u16) -> Result<bool, Box<dyn Error>> {
+        let mut grabbed = true;
+        for &grab_window in &self.grab_windows {
+            let result = GrabButtonRequest {
+                owner_events: false,
+                grab_window,
+                event_mask: u32::from(EventMask::BUTTON_PRESS) as u16,
+                pointer_mode: GrabMode::ASYNC,
+                keyboard_mode: GrabMode::ASYNC,
+                confine_to: x11rb::NONE,
+                cursor: x11rb::NONE,
+                button: button.into(),
+                modifiers,
+            }
+            .send(&self.display)?
+            .check();
+
+            match result {
+                Ok(()) => {}
+                Err(err) if is_access_error(&err) => grabbed = false,
+                Err(err) => return Err(Box::new(err)),
+            }
+        }
+
+        Ok(grabbed)
+    }
+
+    /// Ungrabs a mouse button previously grabbed with `grab_button`.
+    fn ungrab_button(&self, button: u8, modifiers: u16) -> Result<(), Box<dyn Error>> {
+        for &grab_window in &self.grab_windows {
+            UngrabButtonRequest {
+                button: button.into(),
+                grab_window,
+                modifiers,
+            }
+            .send(&self.display)?
+            .check()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rings the bell, at the volume the user has set for it.
+    pub fn bell(&self) -> Result<(), Box<dyn Error>> {
+        BellRequest { percent: 0 }.send(&self.display)?.check()?;
+        Ok(())
+    }
+
+    /// Shows a popup with the given lines of text near the mouse
+    /// pointer, in place of any popup already showing.
+    pub fn show_popup(&self, lines: Vec<String>) -> Result<(), Box<dyn Error>> {
+        self.hide_popup()?;
+        let pointer = self.query_pointer()?;
+        let screen = &self.display.setup().roots[pointer.screen];
+        let popup = Popup::show(&self.display, screen, pointer.x, pointer.y, lines)?;
+        self.popup.replace(Some(popup));
+        Ok(())
+    }
+
+    /// Takes down the popup shown by `show_popup`, if it's showing.
+    pub fn hide_popup(&self) -> Result<(), Box<dyn Error>> {
+        match self.popup.replace(None) {
+            Some(popup) => popup.close(&self.display),
+            None => Ok(()),
+        }
+    }
+
+    /// Ungrabs the keyboard, including the grab the X server makes
+    /// for us while a key we've grabbed is held down.
+    pub fn ungrab_kbd(&self) -> Result<(), Box<dyn Error>> {
+        UngrabKeyboardRequest { time: CURRENT_TIME }
+            .send(&self.display)?
+            .check()?;
+        // Releasing a grab also thaws the keyboard.
+        self.frozen.set(false);
+        Ok(())
+    }
+
+    /// Waits for and returns a keypress event from the X11 server.
+    /// For this function to ever return successfully, it is necessary
+    /// to have informed the X11 server in advance that we wish to
+    /// receive some set of keypresses, using either grab_keys() or
+    /// grab_kbd(). Returns None if `deadline` passes or our interrupt
+    /// flag gets set before a key is pressed. The arguments are as for
+    /// `get_event`.
+    fn get_key(
+        &self,
+        kbd_grabbed: bool,
+        skip_modifiers: bool,
+        skip_repeats: bool,
+        wake: bool,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Key>, Box<dyn Error>> {
+        loop {
+            match self.get_event(kbd_grabbed, skip_modifiers, skip_repeats, wake, deadline)? {
+                Some(KeyEvent::Press(key)) => return Ok(Some(key)),
+                Some(KeyEvent::Release(_)) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Waits for a key to be pressed or released, seeing to any other
+    /// events that come in the meantime. Returns None if `deadline`
+    /// passes or our interrupt flag gets set first, or if `wake` is
+    /// set and our wake file descriptor becomes readable. If the
+    /// keyboard mapping changes, the keys we hold grabs on are grabbed
+    /// again.
+    ///
+    /// If `kbd_grabbed` is set, we're holding a grab on the whole
+    /// keyboard, so we give up and return None if some other program
+    /// changes the input focus: that usually means a screen locker or
+    /// an urgent dialog wants the keyboard, and holding onto it would
+    /// lock the user out. If `skip_modifiers` is set too, presses of
+    /// modifier keys such as `Control_L` are ignored. (With only our
+    /// own keys grabbed, we never see those anyway.)
+    ///
+    /// Holding a key down until it auto-repeats never produces a
+    /// release, since the key hasn't come up; see `classify_release`.
+    /// If `skip_repeats` is set, the repeated presses are ignored too.
+    /// (XKB's "detectable auto-repeat" would leave out the releases
+    /// instead, but we'd still have to tell repeats apart from real
+    /// presses ourselves.)
+    fn get_event(
+        &self,
+        kbd_grabbed: bool,
+        skip_modifiers: bool,
+        skip_repeats: bool,
+        wake: bool,
+        deadline: Option<Instant>,
+    ) -> Result<Option<KeyEvent>, Box<dyn Error>> {
+        while let Some(event) = self.next_event(deadline, wake)? {
+            match event {
+                Event::FocusIn(e) | Event::FocusOut(e)
+                    if kbd_grabbed && e.mode == NotifyMode::WHILE_GRABBED =>
+                {
+                    return Ok(None);
+                }
+                Event::MappingNotify(e) if e.request != Mapping::POINTER => {
+                    // The keyboard layout changed, so our idea of
+                    // which keycode is which key is now wrong, and so
+                    // are the keycodes we've grabbed. Keys the new
+                    // layout lacks are warned about, not fatal.
+                    let held = self.held.borrow().clone();
+                    self.regrab_keys(&held, || {
+                        self.keymap.replace(KeyMap::fetch(&self.display)?);
+                        Ok(())
+                    })?;
+                }
+                Event::SelectionRequest(e) => {
+                    if let Err(err) = self.serve_selection(e) {
+                        eprintln!("Error serving the clipboard: {}", err);
+                    }
+                }
+                Event::XinputRawKeyPress(e) => {
+                    // This comes just before the ordinary event for the
+                    // same key press, if there is one for us.
+                    self.last_device.set(Some(e.sourceid));
+                    if e.flags & u32::from(KeyEventFlags::KEY_REPEAT) == 0 {
+                        self.raw_presses.set(self.raw_presses.get() + 1);
+                    }
+                }
+                Event::Expose(e) if e.count == 0 => {
+                    if let Some(popup) = &*self.popup.borrow() {
+                        if popup.window() == e.window {
+                            popup.draw(&self.display)?;
+                        }
+                    }
+                }
+                Event::SelectionClear(_) => {
+                    // Somebody else owns the clipboard now.
+                    self.clipboard.replace(None);
+                }
+                Event::KeyRelease(release) => {
+                    // Peek at the next event, if it's already here,
+                    // to see whether this is half of an auto-repeat.
+                    let next = self.display.poll_for_event()?;
+                    match classify_release(&release, next.as_ref()) {
+                        ReleaseKind::Repeat if skip_repeats => {
+                            debug!("Skipping an auto-repeat of keycode {}", release.detail);
+                        }
+                        ReleaseKind::Repeat => {
+                            self.repeat_next.set(true);
+                            self.pending.replace(next);
+                        }
+                        ReleaseKind::Release => {
+                            self.pending.replace(next);
+                            return Ok(Some(KeyEvent::Release(release.detail)));
+                        }
+                    }
+                }
+                Event::Error(e) => {
+                    // Errors from requests we check are reported
+                    // where they're made, so this is one we didn't
+                    // ask to hear about, e.g. from a request we sent
+                    // without checking; it's worth mentioning with
+                    // -v, but not worth dying over.
+                    verbose!("Unexpected X11 error: {:?}", e);
+                }
+                event => {
+                    if let Some(key) = self.event_to_key(event) {
+                        if kbd_grabbed && skip_modifiers && key.main_key.is_modifier() {
+                            self.missed_press.set(true);
+                            continue;
+                        }
+                        return Ok(Some(KeyEvent::Press(key)));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Waits for the key last returned by `next_key` or
+    /// `next_key_kbd` to be released, giving up once `deadline`
+    /// passes, if there is one, and if `stop_on_press` is set, as soon
+    /// as some other key is pressed, returning that key. Otherwise,
+    /// other keys pressed in the meantime are ignored. Presses of the
+    /// held key itself, which it makes as it auto-repeats, never stop
+    /// the wait. There's nothing to wait for if the last key was a
+    /// mouse button.
+    pub fn wait_while_held(
+        &self,
+        deadline: Option<Instant>,
+        stop_on_press: bool,
+    ) -> Result<Held, Box<dyn Error>> {
+        let keycode = match self.last_keycode.get() {
+            Some(keycode) => keycode,
+            None => return Ok(Held::Interrupted),
+        };
+
+        // A key grabbed with grab_keys() stays grabbed until it's
+        // released, even once the grab itself is gone, so the release
+        // normally comes to us. It won't if something has let go of
+        // the keyboard since, or if it was never grabbed, as in a
+        // mode; so every so often, ask the server whether the key is
+        // still down.
+        loop {
+            let poll = Instant::now() + RELEASE_POLL;
+            let wait = deadline.map_or(poll, |deadline| deadline.min(poll));
+            match self.get_event(false, false, true, false, Some(wait))? {
+                Some(KeyEvent::Release(released)) if released == keycode => {
+                    // A repeat whose press hasn't reached us yet
+                    // looks like a release, but the key's still down.
+                    if !self.key_is_down(keycode)? {
+                        return Ok(Held::Released);
+                    }
+                }
+                // The held key repeating without a release before it,
+                // which can happen if the release was lost, is still
+                // the same key being held.
+                Some(KeyEvent::Press(_)) if self.last_keycode.get() == Some(keycode) => {}
+                Some(KeyEvent::Press(key)) if stop_on_press => return Ok(Held::Pressed(key)),
+                Some(KeyEvent::Press(_)) => {
+                    // We're still waiting on the held key, not the one
+                    // we're ignoring.
+                    self.last_keycode.set(Some(keycode));
+                    self.missed_press.set(true);
+                }
+                Some(_) => {}
+                None if self.interrupted() => return Ok(Held::Interrupted),
+                None => {
+                    if !self.key_is_down(keycode)? {
+                        return Ok(Held::Released);
+                    }
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Ok(Held::StillDown);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Asks the server whether the key with the given keycode is
+    /// being held down.
+    fn key_is_down(&self, keycode: u8) -> Result<bool, Box<dyn Error>> {
+        let keys = QueryKeymapRequest.send(&self.display)?.reply()?.keys;
+        Ok(is_down(&keys, keycode))
+    }
+
+    /// Checks whether our interrupt flag, if any, is set.
+    fn interrupted(&self) -> bool {
+        self.interrupt
+            .is_some_and(|(interrupt, _)| interrupt.load(Ordering::SeqCst))
+    }
+
+    /// Waits for the next event from the X11 server. Returns None if
+    /// `deadline` passes or our interrupt flag gets set before an
+    /// event arrives, or if `wake` is set and our wake file descriptor
+    /// becomes readable; if there's no deadline, waits forever.
+    fn next_event(
+        &self,
+        deadline: Option<Instant>,
+        wake: bool,
+    ) -> Result<Option<Event>, ConnectionError> {
+        if let Some(event) = self.pending.take() {
+            return Ok(Some(event));
+        }
+        self.display.flush()?;
+        loop {
+            if let Some(event) = self.display.poll_for_event()? {
+                return Ok(Some(event));
+            }
+
+            if self.interrupted() {
+                return Ok(None);
+            }
+
+            // Sleep until either the server sends us something, we
+            // run out of time, or a signal interrupts us. A signal
+            // that arrives just between checking the flag and going
+            // to sleep has still written to the interrupt pipe, so
+            // doesn't leave us asleep.
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    (deadline - now).as_millis().try_into().unwrap_or(i32::MAX)
+                }
+                None => -1,
+            };
+            let pipe = self.interrupt.map(|(_, pipe)| pipe);
+            let wake_fd = self.wake_fd.filter(|_| wake);
+            let mut fds = vec![PollFd::new(
+                self.display.stream().as_raw_fd(),
+                PollFlags::POLLIN,
+            )];
+            for &fd in pipe.iter().chain(wake_fd.iter()) {
+                fds.push(PollFd::new(fd, PollFlags::POLLIN));
+            }
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(err) => return Err(ConnectionError::IOError(io::Error::other(err))),
+            }
+            let readable =
+                |fd: &PollFd| fd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN));
+            let mut others = fds[1..].iter();
+            if let Some(pipe) = pipe {
+                if others.next().is_some_and(readable) {
+                    // Empty the pipe, so that it only wakes us again
+                    // for the next signal; the flag itself is checked
+                    // next time around.
+                    let mut buf = [0; 64];
+                    while matches!(nix::unistd::read(pipe, &mut buf), Ok(n) if n > 0) {}
+                }
+            }
+            if wake_fd.is_some() && others.next().is_some_and(readable) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Determines what keypress, or click of a grabbed mouse button,
+    /// the event corresponds to, if any.
+    fn event_to_key(&self, ev: Event) -> Option<Key> {
+        if !self.synthetic_events && is_synthetic(&ev) {
+            debug!("Ignoring a synthetic event: {:?}", ev);
+            return None;
+        }
+
+        if let Event::ButtonPress(e) = ev {
+            // XInput2 only tells us which keyboard keys come from.
+            self.last_device.set(None);
+            self.last_keycode.set(None);
+            // Clicks have no raw key event of their own.
+            let interrupted = self.raw_presses.replace(0) > 0;
+            self.last_press.set(Press {
+                device: None,
+                time: Some(e.time),
+                repeat: false,
+                interrupted: self.missed_press.replace(false) || interrupted,
+            });
+            let key = Key {
+                modifiers: ModField::from(e.state & !self.num_lock_mask())
+                    .with_altgr(self.keymap.borrow().altgr),
+                main_key: Keysym::from_button(e.detail),
+            };
+            debug!(
+                "Button press: button {}, state {:#x}, read as {}",
+                e.detail, e.state, key
+            );
+            return Some(key);
+        }
+
+        if let Event::KeyPress(e) = ev {
+            let keycode = e.detail;
+            self.last_keycode.set(Some(keycode));
+            let interrupted = self.raw_presses.replace(0) > 1;
+            self.last_press.set(Press {
+                device: self.last_device.get(),
+                time: Some(e.time),
+                repeat: self.repeat_next.replace(false),
+                interrupted: self.missed_press.replace(false) || interrupted,
+            });
+            // Like Caps Lock, Num Lock isn't part of the key. Like
+            // Shift, AltGr is, and the key is still read as the symbol
+            // on it without any modifiers.
+            let modifiers = ModField::from(e.state & !self.num_lock_mask())
+                .with_altgr(self.keymap.borrow().altgr);
+            let num_lock = e.state & self.num_lock_mask() != 0;
+            let keysym = if self.raw_keycodes.contains(&keycode) {
+                Keysym::from_keycode(keycode)
+            } else {
+                self.keymap.borrow().keycode_to_keysym(keycode, num_lock)
+            };
+
+            // Keycodes with no symbols at all on the current layout
+            // come through as NoSymbol, which can't be bound to.
+            if keysym.0 != NO_SYMBOL {
+                let key = Key {
+                    modifiers,
+                    main_key: keysym,
+                }
+                .with_shifted_case();
+                debug!(
+                    "Key press: keycode {}, state {:#x}, read as {}",
+                    keycode, e.state, key
+                );
+                return Some(key);
+            }
+            self.missed_press.set(true);
+        }
+        None
+    }
+}
+
+/// Gets the atom with the given name, creating it if necessary.
+fn intern_atom(display: &RustConnection, name: &str) -> Result<Atom, Box<dyn Error>> {
+    Ok(InternAtomRequest {
+        only_if_exists: false,
+        name: Cow::Borrowed(name.as_bytes()),
+    }
+    .send(display)?
+    .reply()?
+    .atom)
+}
+
+/// Checks whether an error is the X server refusing a request
+/// because some other client got there first, e.g. by grabbing a
+/// key we asked to grab.
+fn is_access_error(err: &ReplyError) -> bool {
+    matches!(err, ReplyError::X11Error(e) if e.error_kind == ErrorKind::Access)
+}
+
+/// Looks for local X11 displays, returning the name of the one with
+/// the lowest number, if any.
+fn probe_display() -> Option<String> {
+    let sockets = fs::read_dir("/tmp/.X11-unix").ok()?;
+    lowest_display(sockets.filter_map(|entry| entry.ok()?.file_name().into_string().ok()))
+}
+
+/// Explains why there's no display to connect to, given the display
+/// name passed on the command line, if any, and the value of
+/// `$DISPLAY`: either the one that was used can't be parsed, or
+/// neither was set (and there wasn't a local display to fall back on).
+fn no_display_error(given: Option<&str>, env_display: Option<OsString>) -> AhkdError {
+    let name = match given {
+        Some(name) => Some(name.to_string()),
+        None => env_display.map(|name| name.to_string_lossy().into_owned()),
+    };
+    AhkdError::NoDisplay(name.filter(|name| !name.is_empty()))
+}
+
+/// Given the names of the sockets in `/tmp/.X11-unix`, picks the
+/// display with the lowest number, and returns its name.
+fn lowest_display<I: Iterator<Item = String>>(sockets: I) -> Option<String> {
+    sockets
+        .filter_map(|name| name.strip_prefix('X')?.parse::<u32>().ok())
+        .min()
+        .map(|num| format!(":{}", num))
+}
+
+/// Finds a keycode that sets a modifier, given the server's modifier
+/// mapping. AltGr is looked up as the modifier `altgr`.
+fn modifier_keycode(
+    mapping: &GetModifierMappingReply,
+    modifier: Modifier,
+    altgr: Modifier,
+) -> Result<u8, Box<dyn Error>> {
+    // The mapping lists the keycodes for Shift, Lock, Control, and
+    // Mod1 through Mod5, in the same order as the bits of a modifier
+    // mask, with the same number for each and zeros to fill in the
+    // gaps.
+    let row = modifier_bit(modifier, altgr).trailing_zeros() as usize;
+    let per_modifier = mapping.keycodes.len() / 8;
+    mapping
+        .keycodes
+        .iter()
+        .skip(row * per_modifier)
+        .take(per_modifier)
+        .find(|&&keycode| keycode != 0)
+        .copied()
+        .ok_or_else(|| {
+            Box::new(AhkdError::X11Error(format!(
+                "no key is mapped to the {:?} modifier",
+                modifier
+            ))) as Box<dyn Error>
+        })
+}
+
+/// Finds the keycodes of the modifier keys that are held down, given
+/// the server's modifier mapping and which keys are down. Caps Lock
+/// is left out: pressing it again would turn the lock back off.
+fn held_modifiers(mapping: &GetModifierMappingReply, keys: &[u8; 32]) -> Vec<u8> {
+    let per_modifier = mapping.keycodes.len() / 8;
+    let mut held = Vec::new();
+    for (idx, &keycode) in mapping.keycodes.iter().enumerate() {
+        let lock = idx / per_modifier == 1;
+        if keycode != 0 && !lock && is_down(keys, keycode) && !held.contains(&keycode) {
+            held.push(keycode);
+        }
+    }
+    held
+}
+
+/// The keysyms of the keys that are usually AltGr.
+const ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+const MODE_SWITCH: u32 = 0xff7e;
+
+/// Looks up which of Mod1 to Mod5 AltGr sets on the current keyboard
+/// layout, falling back to Mod5 if no key is AltGr.
+fn find_altgr(display: &RustConnection, keymap: &KeyMap) -> Result<Modifier, Box<dyn Error>> {
+    let mapping = GetModifierMappingRequest.send(display)?.reply()?;
+    let altgr = altgr_modifier(&mapping, keymap).unwrap_or(Modifier::Mod5);
+    debug!("AltGr is {:?}", altgr);
+    Ok(altgr)
+}
+
+/// Works out which of Mod1 to Mod5 AltGr sets, given the server's
+/// modifier mapping: the one that a key producing `ISO_Level3_Shift`
+/// or `Mode_switch` is mapped to. Returns None if there isn't one.
+fn altgr_modifier(mapping: &GetModifierMappingReply, keymap: &KeyMap) -> Option<Modifier> {
+    // The rows are laid out as for `modifier_keycode`.
+    let per_modifier = mapping.keycodes.len() / 8;
+    if per_modifier == 0 {
+        return None;
+    }
+    let mods = [
+        Modifier::Mod1,
+        Modifier::Mod2,
+        Modifier::Mod3,
+        Modifier::Mod4,
+        Modifier::Mod5,
+    ];
+    let is_altgr = |keycode: &u8| {
+        keymap.kc_to_ks.get(keycode).is_some_and(|keysyms| {
+            keysyms.contains(&ISO_LEVEL3_SHIFT) || keysyms.contains(&MODE_SWITCH)
+        })
+    };
+    mapping
+        .keycodes
+        .chunks(per_modifier)
+        .skip(3)
+        .zip(mods.iter())
+        .find(|(keycodes, _)| keycodes.iter().any(is_altgr))
+        .map(|(_, &modifier)| modifier)
+}
+
+/// Gets every combination of Caps Lock and the given Num Lock mask.
+fn lock_masks(num_lock: u16) -> [u16; 4] {
+    let caps_lock = u16::from(ModMask::LOCK);
+    [0, caps_lock, num_lock, caps_lock | num_lock]
+}
+
+/// Gets the lock masks to grab a key with, given the Num Lock mask
+/// and whether the key's keysym needs Num Lock on or off, as
+/// `KeyMap::num_lock_needed` says. A keypad key bound by the symbol
+/// it types with Num Lock on is only grabbed with Num Lock on, and
+/// vice versa, so that the other symbol still goes to whichever
+/// window has the focus, rather than being swallowed by a grab that
+/// never matches.
+fn keypad_lock_masks(num_lock: u16, needed: Option<bool>) -> Vec<u16> {
+    let locks = lock_masks(num_lock);
+    match needed {
+        Some(on) => locks
+            .iter()
+            .copied()
+            .filter(|&mask| (mask & num_lock != 0) == on)
+            .collect(),
+        None => locks.to_vec(),
+    }
+}
+
+/// Checks whether a keysym is on the numeric keypad, i.e., is between
+/// `KP_Space` and `KP_Equal`.
+fn is_keypad(keysym: u32) -> bool {
+    (0xff80..=0xffbd).contains(&keysym)
+}
+
+/// Asks to hear about every key pressed on any keyboard through
+/// XInput2's raw events, which, unlike the core protocol's, say which
+/// keyboard each key came from, and come to us even for keys that go
+/// to other programs; or if `wanted` isn't set, stops asking. Returns
+/// false if the server doesn't support XInput2.
+fn select_raw_keys(
+    display: &RustConnection,
+    root: Window,
+    wanted: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let request = XIQueryVersionRequest {
+        major_version: 2,
+        minor_version: 0,
+    };
+    let version = match request.send(display) {
+        Ok(cookie) => cookie.reply()?,
+        Err(ConnectionError::UnsupportedExtension) => return Ok(false),
+        Err(err) => return Err(Box::new(err)),
+    };
+    if version.major_version < 2 {
+        return Ok(false);
+    }
+
+    // An empty mask stops the events.
+    let mask = if wanted {
+        vec![XIEventMask::RAW_KEY_PRESS.into()]
+    } else {
+        Vec::new()
+    };
+    XISelectEventsRequest {
+        window: root,
+        masks: Cow::Owned(vec![xinput::EventMask {
+            deviceid: Device::ALL_MASTER.into(),
+            mask,
+        }]),
+    }
+    .send(display)?
+    .check()?;
+
+    Ok(true)
+}
+
+/// Checks whether a key or button event was sent by some program with
+/// SendEvent, as our own `replay_keys` does, rather than coming from
+/// the keyboard or mouse. The server marks these by setting the top
+/// bit of the event's type. Events faked with XTEST, as `send_keyseq`
+/// and `xdotool` do, aren't marked, and can't be told apart from real
+/// ones.
+fn is_synthetic(ev: &Event) -> bool {
+    let response_type = match ev {
+        Event::KeyPress(e) | Event::KeyRelease(e) => e.response_type,
+        Event::ButtonPress(e) | Event::ButtonRelease(e) => e.response_type,
+        _ => return false,
+    };
+    response_type & 0x80 != 0
+}
+
+/// Works out whether a key release really means the key came up, or
+/// is half of an auto-repeat, given the event that came straight
+/// after it, if there was one already. X sends each repeat of a held
+/// key as a release followed by a press of the same key with the same
+/// timestamp.
+fn classify_release(release: &KeyPressEvent, next: Option<&Event>) -> ReleaseKind {
+    match next {
+        Some(Event::KeyPress(press))
+            if press.detail == release.detail && press.time == release.time =>
+        {
+            ReleaseKind::Repeat
+        }
+        _ => ReleaseKind::Release,
+    }
+}
+
+/// Checks whether a key is down in the bitmap of keys `QueryKeymap`
+/// returns, which has a bit for each keycode.
+fn is_down(keys: &[u8; 32], keycode: u8) -> bool {
+    keys[usize::from(keycode / 8)] & (1 << (keycode % 8)) != 0
+}
+
+impl KeySource for X11Conn {
+    fn grab_key(&self, keysym: Keysym, modifiers: u16, sync: bool) -> Result<bool, Box<dyn Error>> {
+        if let Some(button) = keysym.button() {
+            return self.grab_button(button, modifiers);
+        }
+
+        // Another program's grab on one screen doesn't stop us
+        // grabbing the key on the others.
+        let mut grabbed = true;
+        for &grab_window in &self.grab_windows {
+            let result = GrabKeyRequest {
+                owner_events: false,
+                grab_window,
+                modifiers,
+                key: self.keymap.borrow().keysym_to_keycode(keysym)?,
+                pointer_mode: GrabMode::ASYNC,
+                keyboard_mode: if sync {
+                    GrabMode::SYNC
+                } else {
+                    GrabMode::ASYNC
+                },
+            }
+            .send(&self.display)?
+            .check();
+
+            match result {
+                Ok(()) => {}
+                Err(err) if is_access_error(&err) => grabbed = false,
+                Err(err) => return Err(Box::new(err)),
+            }
+        }
+
+        Ok(grabbed)
+    }
+
+    fn ungrab_key(&self, keysym: Keysym, modifiers: u16) -> Result<(), Box<dyn Error>> {
+        if let Some(button) = keysym.button() {
+            return self.ungrab_button(button, modifiers);
+        }
+
+        for &grab_window in &self.grab_windows {
+            UngrabKeyRequest {
+                key: self.keymap.borrow().keysym_to_keycode(keysym)?,
+                grab_window,
+                modifiers,
+            }
+            .send(&self.display)?
+            .check()?;
+        }
+
+        Ok(())
+    }
+
+    fn lock_masks(&self, keysym: Keysym) -> Vec<u16> {
+        let needed = self.keymap.borrow().num_lock_needed(keysym);
+        keypad_lock_masks(self.num_lock_mask(), needed)
+    }
+
+    fn has_key(&self, keysym: Keysym) -> bool {
+        keysym.button().is_some() || self.keymap.borrow().keysym_to_keycode(keysym).is_ok()
+    }
+
+    fn altgr(&self) -> Modifier {
+        self.keymap.borrow().altgr
+    }
+
+    fn same_key(&self, a: Keysym, b: Keysym) -> bool {
+        if a.button().is_some() || b.button().is_some() {
+            return a == b;
+        }
+        let keymap = self.keymap.borrow();
+        match (keymap.keysym_to_keycode(a), keymap.keysym_to_keycode(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn release_keyboard(&self) -> Result<(), Box<dyn Error>> {
+        self.ungrab_kbd()
+    }
+
+    fn conflict(&self, err: AhkdError) {
+        let warning = err.to_string();
+        let mut conflicts = self.conflicts.borrow_mut();
+        if conflicts.contains(&warning) {
+            return;
+        }
+        eprintln!("Warning: {}", warning);
+        conflicts.push(warning);
+    }
+
+    fn held(&self) -> &RefCell<Vec<Grab>> {
+        &self.held
+    }
+}
+
+#[cfg(test)]
+impl KeySource for RecordingKeySource {
+    fn grab_key(&self, keysym: Keysym, modifiers: u16, sync: bool) -> Result<bool, Box<dyn Error>> {
+        if self.taken.contains(&(keysym, modifiers)) {
+            return Ok(false);
+        }
+        self.grabbed.borrow_mut().push((keysym, modifiers, sync));
+        Ok(true)
+    }
+
+    fn ungrab_key(&self, keysym: Keysym, modifiers: u16) -> Result<(), Box<dyn Error>> {
+        self.ungrabbed.borrow_mut().push((keysym, modifiers));
+        Ok(())
+    }
+
+    fn lock_masks(&self, _keysym: Keysym) -> Vec<u16> {
+        match self.num_lock {
+            Some(num_lock) => lock_masks(num_lock).to_vec(),
+            None => vec![0],
+        }
+    }
+
+    fn has_key(&self, keysym: Keysym) -> bool {
+        !self.missing.borrow().contains(&keysym)
+    }
+
+    fn altgr(&self) -> Modifier {
+        Modifier::Mod5
+    }
+
+    fn same_key(&self, a: Keysym, b: Keysym) -> bool {
+        a == b
+    }
+
+    fn release_keyboard(&self) -> Result<(), Box<dyn Error>> {
+        self.released.set(true);
+        Ok(())
+    }
+
+    fn conflict(&self, err: AhkdError) {
+        self.conflicts.borrow_mut().push(err);
+    }
+
+    fn held(&self) -> &RefCell<Vec<Grab>> {
+        &self.held
+    }
+}
+
+impl KeyMap {
+    /// Asks the X11 server for the current keyboard mapping.
+    fn fetch(display: &RustConnection) -> Result<Self, Box<dyn Error>> {
+        let setup = display.setup();
+        let min_keycode = setup.min_keycode;
+        let max_keycode = setup.max_keycode;
+        // The range of keycodes is inclusive at both ends.
+        let count = max_keycode - min_keycode + 1;
+        let keymap_pkt = GetKeyboardMappingRequest {
+            first_keycode: min_keycode,
+            count,
+        }
+        .send(display)?
+        .reply()?;
+
+        let mut keymap = Self::new(min_keycode, count, keymap_pkt)?;
+        keymap.altgr = find_altgr(display, &keymap)?;
+        Ok(keymap)
+    }
+
+    /// Sets up the mappings between keysyms and keycodes, given the
+    /// server's reply to a request for the mapping of `count`
+    /// keycodes starting at `min_keycode`.
+    pub fn new(
+        min_keycode: u8,
+        count: u8,
+        packet: GetKeyboardMappingReply,
+    ) -> Result<Self, Box<dyn Error>> {
+        let per_keycode = packet.keysyms_per_keycode as usize;
+
+        // Anything other than exactly `per_keycode` keysyms for each
+        // keycode would leave keycodes missing from the map, or
+        // keysyms attached to the wrong keycodes.
+        let expected = count as usize * per_keycode;
+        if per_keycode == 0 || packet.keysyms.len() != expected {
+            return Err(Box::new(AhkdError::X11Error(format!(
+                "malformed keyboard mapping: expected {} keysyms for each of {} keycodes, \
+                 but got {} in total",
+                per_keycode,
+                count,
+                packet.keysyms.len()
+            ))));
+        }
+
+        // Prefer keycodes that produce the keysym at the lowest
+        // level, and then the lowest such keycode; many layouts
+        // repeat modifier keysyms like `Super_L` on phantom keycodes
+        // at higher levels, which are useless to grab.
+        let mut ks_to_kc = HashMap::new();
+        for level in 0..per_keycode {
+            for (keycode, keysym) in packet
+                .keysyms
+                .iter()
+                .skip(level)
+                .step_by(per_keycode)
+                .enumerate()
+            {
+                ks_to_kc
+                    .entry(*keysym)
+                    .or_insert((keycode as u8 + min_keycode, level));
+            }
+        }
+
+        Ok(Self {
+            ks_to_kc,
+            kc_to_ks: packet
+                .keysyms
+                .chunks(per_keycode)
+                .enumerate()
+                .map(|(keycode, keysyms)| (keycode as u8 + min_keycode, keysyms.to_vec()))
+                .collect(),
+            altgr: Modifier::Mod5,
+        })
+    }
+
+    /// Gets the lowest keycode corresponding to a keysym, failing if
+    /// no key on the current layout produces it.
+    fn keysym_to_keycode(&self, keysym: Keysym) -> Result<u8, AhkdError> {
+        if let Some(keycode) = keysym.keycode() {
+            if !self.kc_to_ks.contains_key(&keycode) {
+                return Err(AhkdError::UnavailableKey(keysym, None));
+            }
+            return Ok(keycode);
+        }
+
+        match self.ks_to_kc.get(&keysym.0) {
+            Some(&(keycode, _)) => Ok(keycode),
+            None => Err(AhkdError::UnavailableKey(keysym, None)),
+        }
+    }
+
+    /// Gets the level at which the lowest keycode corresponding to a
+    /// keysym produces it, and the keysym that keycode produces at
+    /// level 0. Returns None if no keycode produces the keysym. A
+    /// keypad keysym typed with Num Lock on, such as `KP_1`, counts
+    /// as level 0, since Num Lock isn't a modifier held with it.
+    fn keysym_level(&self, keysym: Keysym) -> Option<(usize, Keysym)> {
+        // A mouse button is always there, whatever the layout.
+        if keysym.button().is_some() {
+            return Some((0, keysym));
+        }
+
+        // A raw keycode is always typed as itself.
+        if let Some(keycode) = keysym.keycode() {
+            return self.kc_to_ks.get(&keycode).map(|_| (0, keysym));
+        }
+
+        if self.num_lock_needed(keysym) == Some(true) {
+            return Some((0, keysym));
+        }
+
+        let &(keycode, level) = self.ks_to_kc.get(&keysym.0)?;
+        Some((level, Keysym(self.kc_to_ks[&keycode][0])))
+    }
+
+    /// Checks whether the key that produces a keysym only produces it
+    /// with Num Lock on, as with `KP_1`, giving Some(true), or only
+    /// with Num Lock off, as with `KP_End` on the same key, giving
+    /// Some(false). Gives None for keysyms that don't depend on Num
+    /// Lock, including keypad keys like `KP_Enter` that are the same
+    /// either way.
+    fn num_lock_needed(&self, keysym: Keysym) -> Option<bool> {
+        let &(keycode, _) = self.ks_to_kc.get(&keysym.0)?;
+        let off = self.keycode_to_keysym(keycode, false);
+        let on = self.keycode_to_keysym(keycode, true);
+        if off == on {
+            None
+        } else if keysym == on {
+            Some(true)
+        } else if keysym == off {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Gets the keysym a keycode produces. This is the keycode's
+    /// first keysym, except that keypad keys produce their second
+    /// keysym (the digits, rather than the arrows) when Num Lock is
+    /// on.
+    fn keycode_to_keysym(&self, keycode: u8, num_lock: bool) -> Keysym {
+        // All valid keycodes must have at least one associated
+        // keysym, so we can `unwrap` here.
+        let keysyms = self.kc_to_ks.get(&keycode).unwrap();
+        match keysyms.get(1) {
+            Some(&numeric) if num_lock && is_keypad(numeric) => Keysym(numeric),
+            _ => Keysym(keysyms[0]),
+        }
+    }
+}
+
+/// Turns a set of modifiers into an X11 modifier mask, with AltGr as
+/// the modifier `altgr` (one of Mod1 to Mod5).
+fn modifier_mask(mods: &ModField, altgr: Modifier) -> u16 {
+    if mods.any {
+        return ModMask::ANY.into();
+    }
+
+    // I wonder if we can use some bitfield crate or something to
+    // make this a little more elegant?
+    let mut n = 0;
+
+    let mut mask_if = |flag, mask| {
+        if flag {
+            n |= mask;
+        }
+    };
+
+    mask_if(mods.mod_shift, ModMask::SHIFT);
+    mask_if(mods.mod_control, ModMask::CONTROL);
+    mask_if(mods.mod1, ModMask::M1);
+    mask_if(mods.mod2, ModMask::M2);
+    mask_if(mods.mod3, ModMask::M3);
+    mask_if(mods.mod4, ModMask::M4);
+    mask_if(mods.mod5, ModMask::M5);
+    if mods.mod_altgr {
+        n |= modifier_bit(Modifier::AltGr, altgr);
+    }
+
+    n
+}
+
+/// Gets the bit for a single modifier in an X11 modifier mask, with
+/// AltGr as the modifier `altgr` (one of Mod1 to Mod5).
+fn modifier_bit(modifier: Modifier, altgr: Modifier) -> u16 {
+    match modifier {
+        Modifier::Shift => ModMask::SHIFT,
+        Modifier::Control => ModMask::CONTROL,
+        Modifier::Mod1 => ModMask::M1,
+        Modifier::Mod2 => ModMask::M2,
+        Modifier::Mod3 => ModMask::M3,
+        Modifier::Mod4 => ModMask::M4,
+        Modifier::Mod5 => ModMask::M5,
+        Modifier::AltGr => return modifier_bit(altgr, Modifier::Mod5),
+    }
+    .into()
+}
+
+impl From<u16> for ModField {
+    fn from(n: u16) -> Self {
         Self {
             mod_shift: n & u16::from(ModMask::SHIFT) != 0,
             mod_control: n & u16::from(ModMask::CONTROL) != 0,
@@ -269,6 +2222,433 @@ impl From<u16> for ModField {
             mod3: n & u16::from(ModMask::M3) != 0,
             mod4: n & u16::from(ModMask::M4) != 0,
             mod5: n & u16::from(ModMask::M5) != 0,
+            mod_altgr: false,
+            any: n & u16::from(ModMask::ANY) != 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfgfile::LineText;
+    use std::convert::TryFrom;
+
+    /// Makes a KeyMap for a keyboard with just one letter key (38) and
+    /// one keypad key (87).
+    fn mk_keymap() -> KeyMap {
+        KeyMap::new(38, 87 - 38 + 1, mk_reply(2 * (87 - 39))).unwrap()
+    }
+
+    /// Makes the reply for `mk_keymap`, with `padding` keysyms between
+    /// the letter key and the keypad key.
+    fn mk_reply(padding: usize) -> GetKeyboardMappingReply {
+        GetKeyboardMappingReply {
+            keysyms_per_keycode: 2,
+            sequence: 0,
+            keysyms: [
+                vec![0x61, 0x41], // a, A
+                vec![NO_SYMBOL; padding],
+                vec![0xff9c, 0xffb1], // KP_End, KP_1
+            ]
+            .concat(),
+        }
+    }
+
+    #[test]
+    fn short_keymap_test() {
+        // One keysym short, which would otherwise shift the keypad
+        // key's keysyms onto the wrong keycodes.
+        assert!(KeyMap::new(38, 87 - 38 + 1, mk_reply(2 * (87 - 39) - 1)).is_err());
+        // One keycode short.
+        assert!(KeyMap::new(38, 87 - 38 + 1, mk_reply(2 * (87 - 40))).is_err());
+
+        let mut empty = mk_reply(0);
+        empty.keysyms_per_keycode = 0;
+        empty.keysyms.clear();
+        assert!(KeyMap::new(38, 2, empty).is_err());
+    }
+
+    #[test]
+    fn keypad_num_lock_test() {
+        let keymap = mk_keymap();
+
+        // Keypad keys produce digits only with Num Lock on.
+        assert_eq!(keymap.keycode_to_keysym(87, false), Keysym(0xff9c));
+        assert_eq!(keymap.keycode_to_keysym(87, true), Keysym(0xffb1));
+
+        // Other keys aren't affected by Num Lock.
+        assert_eq!(keymap.keycode_to_keysym(38, true), Keysym(0x61));
+
+        // And binding to KP_1 grabs the right key.
+        assert_eq!(keymap.keysym_to_keycode(Keysym(0xffb1)).unwrap(), 87);
+    }
+
+    #[test]
+    fn keypad_test() {
+        // The keypad's 1 (87) and Enter (104) keys.
+        let mut keysyms = vec![NO_SYMBOL; 2 * (104 - 87 + 1)];
+        keysyms[0] = 0xff9c; // KP_End
+        keysyms[1] = 0xffb1; // KP_1
+        keysyms[2 * (104 - 87)] = 0xff8d; // KP_Enter
+        keysyms[2 * (104 - 87) + 1] = 0xff8d;
+        let keymap = KeyMap::new(
+            87,
+            104 - 87 + 1,
+            GetKeyboardMappingReply {
+                keysyms_per_keycode: 2,
+                sequence: 0,
+                keysyms,
+            },
+        )
+        .unwrap();
+        let kp = |name| Keysym::try_from(LineText::new("foo", 1, name)).unwrap();
+
+        assert_eq!(keymap.num_lock_needed(kp("KP_1")), Some(true));
+        assert_eq!(keymap.num_lock_needed(kp("KP_End")), Some(false));
+        assert_eq!(keymap.num_lock_needed(kp("KP_Enter")), None);
+        assert_eq!(keymap.num_lock_needed(kp("a")), None);
+
+        // KP_1 is typed without Shift, so it's level 0, not 1.
+        assert_eq!(keymap.keysym_level(kp("KP_1")), Some((0, kp("KP_1"))));
+        assert_eq!(keymap.keysym_level(kp("KP_End")), Some((0, kp("KP_End"))));
+
+        // KP_Enter reads the same whether Num Lock is on or not.
+        assert_eq!(keymap.keycode_to_keysym(104, false), kp("KP_Enter"));
+        assert_eq!(keymap.keycode_to_keysym(104, true), kp("KP_Enter"));
+        assert_eq!(keymap.keysym_to_keycode(kp("KP_Enter")).unwrap(), 104);
+
+        // With Num Lock on Mod2 (0x10), KP_1 is only grabbed with it on,
+        // KP_End only with it off, and KP_Enter either way; Caps Lock
+        // (0x2) can be on or off regardless.
+        assert_eq!(keypad_lock_masks(0x10, Some(true)), vec![0x10, 0x12]);
+        assert_eq!(keypad_lock_masks(0x10, Some(false)), vec![0, 0x2]);
+        assert_eq!(keypad_lock_masks(0x10, None), vec![0, 0x2, 0x10, 0x12]);
+    }
+
+    #[test]
+    fn keysym_level_test() {
+        let keymap = mk_keymap();
+        assert_eq!(keymap.keysym_level(Keysym(0x61)), Some((0, Keysym(0x61))));
+        assert_eq!(keymap.keysym_level(Keysym(0x41)), Some((1, Keysym(0x61))));
+        assert_eq!(keymap.keysym_level(Keysym(0x40)), None);
+        assert!(keymap.keysym_to_keycode(Keysym(0x40)).is_err());
+
+        // Raw keycodes are only there if the keyboard has them.
+        let raw = Keysym::from_keycode(38);
+        assert_eq!(keymap.keysym_level(raw), Some((0, raw)));
+        assert_eq!(keymap.keysym_to_keycode(raw).unwrap(), 38);
+        assert!(keymap.keysym_to_keycode(Keysym::from_keycode(200)).is_err());
+    }
+
+    #[test]
+    fn modifier_keycode_test() {
+        // Two keycodes per modifier: Shift_L and Shift_R, Caps Lock,
+        // Control_L, and Alt_L, with nothing on the rest.
+        let mut keycodes = vec![50, 62, 66, 0, 37, 0, 64, 0];
+        keycodes.extend(vec![0; 8]);
+        let mapping = GetModifierMappingReply {
+            sequence: 0,
+            length: 0,
+            keycodes,
+        };
+
+        let keycode = |modifier| modifier_keycode(&mapping, modifier, Modifier::Mod1);
+        assert_eq!(keycode(Modifier::Shift).unwrap(), 50);
+        assert_eq!(keycode(Modifier::Control).unwrap(), 37);
+        assert_eq!(keycode(Modifier::Mod1).unwrap(), 64);
+        assert!(keycode(Modifier::Mod4).is_err());
+
+        // AltGr is pressed with the key for the modifier it's on.
+        assert_eq!(keycode(Modifier::AltGr).unwrap(), 64);
+        assert!(modifier_keycode(&mapping, Modifier::AltGr, Modifier::Mod5).is_err());
+    }
+
+    #[test]
+    fn held_modifiers_test() {
+        // Shift_L and Shift_R, Caps Lock, Control_L, and Alt_L, with
+        // Super_L on Mod4.
+        let mut keycodes = vec![50, 62, 66, 0, 37, 0, 64, 0];
+        keycodes.extend(vec![0, 0, 0, 0, 133, 0, 0, 0]);
+        let mapping = GetModifierMappingReply {
+            sequence: 0,
+            length: 0,
+            keycodes,
+        };
+        let down = |pressed: &[u8]| {
+            let mut keys = [0; 32];
+            for &keycode in pressed {
+                keys[usize::from(keycode / 8)] |= 1 << (keycode % 8);
+            }
+            keys
+        };
+
+        assert_eq!(held_modifiers(&mapping, &down(&[])), vec![]);
+        assert_eq!(held_modifiers(&mapping, &down(&[133, 55])), vec![133]);
+        assert_eq!(held_modifiers(&mapping, &down(&[37, 62, 66])), vec![62, 37]);
+    }
+
+    #[test]
+    fn altgr_modifier_test() {
+        // A keyboard with AltGr (keycode 92) on Mod3, and Alt_L (64)
+        // on Mod1.
+        let mut keysyms = vec![NO_SYMBOL; 2 * (92 - 64 + 1)];
+        keysyms[0] = 0xffe9; // Alt_L
+        keysyms[2 * (92 - 64)] = ISO_LEVEL3_SHIFT;
+        let keymap = KeyMap::new(
+            64,
+            92 - 64 + 1,
+            GetKeyboardMappingReply {
+                keysyms_per_keycode: 2,
+                sequence: 0,
+                keysyms,
+            },
+        )
+        .unwrap();
+
+        let mut keycodes = vec![0; 16];
+        keycodes[3 * 2] = 64;
+        let mut mapping = GetModifierMappingReply {
+            sequence: 0,
+            length: 0,
+            keycodes,
+        };
+        assert_eq!(altgr_modifier(&mapping, &keymap), None);
+
+        mapping.keycodes[5 * 2 + 1] = 92;
+        assert_eq!(altgr_modifier(&mapping, &keymap), Some(Modifier::Mod3));
+    }
+
+    #[test]
+    fn lowest_display_test() {
+        let sockets = ["X10", "X1", "junk", "X2"];
+        let picked = lowest_display(sockets.iter().map(|s| s.to_string()));
+        assert_eq!(picked.as_deref(), Some(":1"));
+
+        let none = lowest_display(["junk", "Xfoo"].iter().map(|s| s.to_string()));
+        assert_eq!(none, None);
+    }
+
+    #[test]
+    fn no_display_error_test() {
+        let message = |given: Option<&str>, env: Option<&str>| {
+            no_display_error(given, env.map(OsString::from)).to_string()
+        };
+        let unset = "Cannot connect to X server: DISPLAY is not set \
+                     (are you in a graphical session?)";
+        assert_eq!(message(None, None), unset);
+        assert_eq!(message(None, Some("")), unset);
+        assert_eq!(
+            message(None, Some("nonsense")),
+            "Cannot connect to X server: \"nonsense\" is not a valid display name"
+        );
+        // The display given on the command line is the one that was
+        // used, so it's the one to blame.
+        assert_eq!(
+            message(Some("::"), Some(":0")),
+            "Cannot connect to X server: \"::\" is not a valid display name"
+        );
+    }
+
+    #[test]
+    fn access_error_test() {
+        use x11rb::x11_utils::X11Error;
+
+        let error = |error_kind| {
+            ReplyError::X11Error(X11Error {
+                error_kind,
+                error_code: 0,
+                sequence: 0,
+                bad_value: 0,
+                minor_opcode: 0,
+                major_opcode: 0,
+            })
+        };
+        assert!(is_access_error(&error(ErrorKind::Access)));
+        assert!(!is_access_error(&error(ErrorKind::Value)));
+        assert!(!is_access_error(&ReplyError::ConnectionError(
+            ConnectionError::UnknownError
+        )));
+    }
+
+    #[test]
+    fn modifier_mask_round_trip_test() {
+        // Every combination of modifiers survives a trip through an
+        // X11 mask and back.
+        for bits in 0..(1 << 7) {
+            let bit = |n| bits & (1 << n) != 0;
+            let mods = ModField {
+                mod_shift: bit(0),
+                mod_control: bit(1),
+                mod1: bit(2),
+                mod2: bit(3),
+                mod3: bit(4),
+                mod4: bit(5),
+                mod5: bit(6),
+                mod_altgr: false,
+                any: false,
+            };
+            let mask = modifier_mask(&mods, Modifier::Mod5);
+            assert_eq!(ModField::from(mask), mods, "mask {:#x}", mask);
+        }
+
+        // Each field lines up with the X11 modifier it's named for.
+        let none = ModField::from(0);
+        let fields: [(ModField, ModMask); 7] = [
+            (
+                ModField {
+                    mod_shift: true,
+                    ..none
+                },
+                ModMask::SHIFT,
+            ),
+            (
+                ModField {
+                    mod_control: true,
+                    ..none
+                },
+                ModMask::CONTROL,
+            ),
+            (ModField { mod1: true, ..none }, ModMask::M1),
+            (ModField { mod2: true, ..none }, ModMask::M2),
+            (ModField { mod3: true, ..none }, ModMask::M3),
+            (ModField { mod4: true, ..none }, ModMask::M4),
+            (ModField { mod5: true, ..none }, ModMask::M5),
+        ];
+        for (mods, mask) in fields.iter() {
+            assert_eq!(modifier_mask(mods, Modifier::Mod5), u16::from(*mask));
+        }
+
+        // AltGr is grabbed as whichever modifier it's mapped to, and
+        // reads back as AltGr.
+        let altgr = ModField {
+            mod_altgr: true,
+            ..none
+        };
+        for (modifier, mask) in [(Modifier::Mod5, ModMask::M5), (Modifier::Mod3, ModMask::M3)] {
+            assert_eq!(modifier_mask(&altgr, modifier), u16::from(mask));
+            assert_eq!(modifier_bit(Modifier::AltGr, modifier), u16::from(mask));
+            let read = ModField::from(modifier_mask(&altgr, modifier)).with_altgr(modifier);
+            assert_eq!(read, altgr);
+        }
+
+        // Matching any modifiers is grabbing with AnyModifier.
+        let any = ModField { any: true, ..none };
+        assert_eq!(modifier_mask(&any, Modifier::Mod5), u16::from(ModMask::ANY));
+        assert_eq!(ModField::from(modifier_mask(&any, Modifier::Mod5)), any);
+
+        // Bits for things we don't track, like Caps Lock and the
+        // mouse buttons, are dropped.
+        let with_extras = u16::from(ModMask::LOCK) | u16::from(ModMask::M1) | 0x100;
+        assert_eq!(ModField::from(with_extras), ModField { mod1: true, ..none });
+    }
+
+    #[test]
+    fn synthetic_test() {
+        let event = |response_type| KeyPressEvent {
+            response_type,
+            detail: 38,
+            sequence: 0,
+            time: 0,
+            root: 0,
+            event: 0,
+            child: 0,
+            root_x: 0,
+            root_y: 0,
+            event_x: 0,
+            event_y: 0,
+            state: 0,
+            same_screen: true,
+        };
+        assert!(!is_synthetic(&Event::KeyPress(event(KEY_PRESS_EVENT))));
+        assert!(is_synthetic(&Event::KeyPress(event(
+            KEY_PRESS_EVENT | 0x80
+        ))));
+        assert!(is_synthetic(&Event::KeyRelease(event(
+            KEY_RELEASE_EVENT | 0x80
+        ))));
+    }
+
+    #[test]
+    fn repeat_test() {
+        let event = |detail, time| KeyPressEvent {
+            response_type: KEY_PRESS_EVENT,
+            detail,
+            sequence: 0,
+            time,
+            root: 0,
+            event: 0,
+            child: 0,
+            root_x: 0,
+            root_y: 0,
+            event_x: 0,
+            event_y: 0,
+            state: 0,
+            same_screen: true,
+        };
+        let release = event(38, 1000);
+        let next = |detail, time| Some(Event::KeyPress(event(detail, time)));
+        assert_eq!(
+            classify_release(&release, next(38, 1000).as_ref()),
+            ReleaseKind::Repeat
+        );
+        // A real press of the same key comes later than the release.
+        assert_eq!(
+            classify_release(&release, next(38, 1080).as_ref()),
+            ReleaseKind::Release
+        );
+        assert_eq!(
+            classify_release(&release, next(39, 1000).as_ref()),
+            ReleaseKind::Release
+        );
+        // If nothing's come in yet, the key must have come up.
+        assert_eq!(classify_release(&release, None), ReleaseKind::Release);
+        let other = Some(Event::KeyRelease(event(38, 1000)));
+        assert_eq!(
+            classify_release(&release, other.as_ref()),
+            ReleaseKind::Release
+        );
+    }
+
+    #[test]
+    fn is_down_test() {
+        let mut keys = [0; 32];
+        keys[4] = 0b0100_0000;
+        assert!(is_down(&keys, 38));
+        assert!(!is_down(&keys, 37));
+        assert!(!is_down(&keys, 39));
+        keys[31] = 0x80;
+        assert!(is_down(&keys, 255));
+    }
+
+    #[test]
+    fn without_grabs_on_test() {
+        let grab = |keysym| Grab {
+            key: Key {
+                modifiers: ModField::from(0),
+                main_key: Keysym(keysym),
+            },
+            sync: false,
+        };
+        let source = RecordingKeySource::default();
+        source.hold_grabs(&[grab(0x61), grab(0x62)]).unwrap();
+        source.grabbed.borrow_mut().clear();
+
+        // Typing `a` lets go of our grab on it while the key is sent,
+        // so it can't set off the binding again, but keeps `b`.
+        source
+            .without_grabs_on(&[Keysym(0x61)], || {
+                assert_eq!(*source.ungrabbed.borrow(), vec![(Keysym(0x61), 0)]);
+                assert!(source.grabbed.borrow().is_empty());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(*source.grabbed.borrow(), vec![(Keysym(0x61), 0, false)]);
+        assert_eq!(source.held.borrow().len(), 2);
+
+        // The grab comes back even if sending the keys fails.
+        let sent = source.without_grabs_on(&[Keysym(0x62)], || Err("no XTEST".into()));
+        assert!(sent.is_err());
+        assert_eq!(source.grabbed.borrow().len(), 2);
+    }
+}